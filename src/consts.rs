@@ -9,6 +9,13 @@
 //! These constants use the names Python's pickle.py uses.  They are not in an
 //! enum because it's not very useful to make it one.
 
+use std::char;
+use num_bigint::{BigInt, Sign};
+
+macro_rules! try_opt {
+    ($e:expr) => { match $e { Some(v) => v, None => return None } }
+}
+
 pub const MARK             : u8 = b'(';    // push special markobject on stack
 pub const STOP             : u8 = b'.';    // every pickle ends with STOP
 pub const POP              : u8 = b'0';    // discard topmost stack item
@@ -64,15 +71,226 @@ pub const ADDITEMS         : u8 = b'\x90'; // modify set by adding topmost stack
 pub const FROZENSET        : u8 = b'\x91'; // build frozenset from topmost stack items
 pub const MEMOIZE          : u8 = b'\x94'; // store top of the stack in memo
 pub const FRAME            : u8 = b'\x95'; // indicate the beginning of a new frame
+pub const BYTEARRAY8       : u8 = b'\x96'; // push bytearray
+pub const NEXT_BUFFER      : u8 = b'\x97'; // push next out-of-band buffer
+pub const READONLY_BUFFER  : u8 = b'\x98'; // make top of stack readonly
+pub const BUILD            : u8 = b'b';    // call __setstate__ or __dict__.update()
+pub const INST             : u8 = b'i';    // build & push class instance
+pub const OBJ              : u8 = b'o';    // build & push class instance
+pub const NEWOBJ           : u8 = b'\x81'; // build object by applying cls.__new__ to argtuple
+pub const NEWOBJ_EX        : u8 = b'\x92'; // like NEWOBJ but work with keyword only arguments
+
+// Ops for persistent IDs and the extension registry; these are not supported
+// by the deserializer.
+pub const PERSID           : u8 = b'P';    // push persistent object; id is taken from string arg
+pub const BINPERSID        : u8 = b'Q';    //  "       "         "  ;  "  "   "     "  stack
+pub const EXT1             : u8 = b'\x82'; // push object from extension registry; 1-byte index
+pub const EXT2             : u8 = b'\x83'; // ditto, but 2-byte index
+pub const EXT4             : u8 = b'\x84'; // ditto, but 4-byte index
+
+/// Kinds of opcode arguments, named like in Python's pickletools.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    None,
+    Uint1,
+    Uint2,
+    Int4,
+    Uint4,
+    Uint8,
+    Float8,
+    DecimalNlShort,         // int or bool, as text line
+    DecimalNlLong,          // long with optional "L" suffix, as text line
+    FloatNl,
+    StringNl,               // quoted and escaped string, as text line
+    StringNlNoescape,
+    StringNlNoescapePair,   // two unescaped text lines
+    UnicodeStringNl,        // raw-unicode-escaped string, as text line
+    String1,
+    String4,
+    Bytes1,
+    Bytes4,
+    Bytes8,
+    Bytearray8,
+    UnicodeString1,
+    UnicodeString4,
+    UnicodeString8,
+    Long1,
+    Long4,
+}
+
+/// Description of an opcode, for tools that don't execute pickles.
+pub struct OpcodeInfo {
+    pub code: u8,
+    pub name: &'static str,
+    pub arg: ArgKind,
+    pub proto: u8,        // protocol that introduced the opcode
+    pub mark: bool,       // pops all stack items down to the topmost MARK
+    pub pops: usize,      // other stack items popped
+    pub pushes: usize,    // stack items pushed
+}
+
+macro_rules! op {
+    ($code:ident, $arg:ident, $proto:expr, $mark:expr, $pops:expr, $pushes:expr) => {
+        OpcodeInfo { code: $code, name: stringify!($code), arg: ArgKind::$arg,
+                     proto: $proto, mark: $mark, pops: $pops, pushes: $pushes }
+    }
+}
+
+/// All opcodes, in the order of pickletools' table.  MARK is special, since
+/// it pushes the markobject.
+pub const OPCODES: &'static [OpcodeInfo] = &[
+    op!(INT,              DecimalNlShort,       0, false, 0, 1),
+    op!(BININT,           Int4,                 1, false, 0, 1),
+    op!(BININT1,          Uint1,                1, false, 0, 1),
+    op!(BININT2,          Uint2,                1, false, 0, 1),
+    op!(LONG,             DecimalNlLong,        0, false, 0, 1),
+    op!(LONG1,            Long1,                2, false, 0, 1),
+    op!(LONG4,            Long4,                2, false, 0, 1),
+    op!(STRING,           StringNl,             0, false, 0, 1),
+    op!(BINSTRING,        String4,              1, false, 0, 1),
+    op!(SHORT_BINSTRING,  String1,              1, false, 0, 1),
+    op!(BINBYTES,         Bytes4,               3, false, 0, 1),
+    op!(SHORT_BINBYTES,   Bytes1,               3, false, 0, 1),
+    op!(BINBYTES8,        Bytes8,               4, false, 0, 1),
+    op!(BYTEARRAY8,       Bytearray8,           5, false, 0, 1),
+    op!(NEXT_BUFFER,      None,                 5, false, 0, 1),
+    op!(READONLY_BUFFER,  None,                 5, false, 1, 1),
+    op!(NONE,             None,                 0, false, 0, 1),
+    op!(NEWTRUE,          None,                 2, false, 0, 1),
+    op!(NEWFALSE,         None,                 2, false, 0, 1),
+    op!(UNICODE,          UnicodeStringNl,      0, false, 0, 1),
+    op!(SHORT_BINUNICODE, UnicodeString1,       4, false, 0, 1),
+    op!(BINUNICODE,       UnicodeString4,       1, false, 0, 1),
+    op!(BINUNICODE8,      UnicodeString8,       4, false, 0, 1),
+    op!(FLOAT,            FloatNl,              0, false, 0, 1),
+    op!(BINFLOAT,         Float8,               1, false, 0, 1),
+    op!(EMPTY_LIST,       None,                 1, false, 0, 1),
+    op!(APPEND,           None,                 0, false, 2, 1),
+    op!(APPENDS,          None,                 1, true,  1, 1),
+    op!(LIST,             None,                 0, true,  0, 1),
+    op!(EMPTY_TUPLE,      None,                 1, false, 0, 1),
+    op!(TUPLE,            None,                 0, true,  0, 1),
+    op!(TUPLE1,           None,                 2, false, 1, 1),
+    op!(TUPLE2,           None,                 2, false, 2, 1),
+    op!(TUPLE3,           None,                 2, false, 3, 1),
+    op!(EMPTY_DICT,       None,                 1, false, 0, 1),
+    op!(DICT,             None,                 0, true,  0, 1),
+    op!(SETITEM,          None,                 0, false, 3, 1),
+    op!(SETITEMS,         None,                 1, true,  1, 1),
+    op!(EMPTY_SET,        None,                 4, false, 0, 1),
+    op!(ADDITEMS,         None,                 4, true,  1, 1),
+    op!(FROZENSET,        None,                 4, true,  0, 1),
+    op!(POP,              None,                 0, false, 1, 0),
+    op!(DUP,              None,                 0, false, 1, 2),
+    op!(MARK,             None,                 0, false, 0, 0),
+    op!(POP_MARK,         None,                 1, true,  0, 0),
+    op!(GET,              DecimalNlShort,       0, false, 0, 1),
+    op!(BINGET,           Uint1,                1, false, 0, 1),
+    op!(LONG_BINGET,      Uint4,                1, false, 0, 1),
+    op!(PUT,              DecimalNlShort,       0, false, 0, 0),
+    op!(BINPUT,           Uint1,                1, false, 0, 0),
+    op!(LONG_BINPUT,      Uint4,                1, false, 0, 0),
+    op!(MEMOIZE,          None,                 4, false, 0, 0),
+    op!(EXT1,             Uint1,                2, false, 0, 1),
+    op!(EXT2,             Uint2,                2, false, 0, 1),
+    op!(EXT4,             Int4,                 2, false, 0, 1),
+    op!(GLOBAL,           StringNlNoescapePair, 0, false, 0, 1),
+    op!(STACK_GLOBAL,     None,                 4, false, 2, 1),
+    op!(REDUCE,           None,                 0, false, 2, 1),
+    op!(BUILD,            None,                 0, false, 2, 1),
+    op!(INST,             StringNlNoescapePair, 0, true,  0, 1),
+    op!(OBJ,              None,                 1, true,  0, 1),
+    op!(NEWOBJ,           None,                 2, false, 2, 1),
+    op!(NEWOBJ_EX,        None,                 4, false, 3, 1),
+    op!(PROTO,            Uint1,                2, false, 0, 0),
+    op!(STOP,             None,                 0, false, 1, 0),
+    op!(FRAME,            Uint8,                4, false, 0, 0),
+    op!(PERSID,           StringNlNoescape,     0, false, 0, 1),
+    op!(BINPERSID,        None,                 1, false, 1, 1),
+];
+
+/// Look up the description of an opcode.
+pub fn opcode_info(code: u8) -> Option<&'static OpcodeInfo> {
+    OPCODES.iter().find(|info| info.code == code)
+}
+
+// Decoding of opcode arguments, shared by the deserializer and disassembler.
+
+/// Decode the argument of the STRING opcode.  It is quoted and escaped with
+/// "normal" Python string escape rules.
+pub fn unescape_string(slice: &[u8]) -> Option<Vec<u8>> {
+    // Remove quotes if they appear.
+    let slice = if (slice.len() >= 2) &&
+        (slice[0] == slice[slice.len() - 1]) &&
+        (slice[0] == b'"' || slice[0] == b'\'')
+    {
+        &slice[1..slice.len() - 1]
+    } else {
+        slice
+    };
+    let mut result = Vec::with_capacity(slice.len());
+    let mut iter = slice.iter();
+    while let Some(&b) = iter.next() {
+        match b {
+            b'\\' => match iter.next() {
+                Some(&b'\\') => result.push(b'\\'),
+                Some(&b'\'') => result.push(b'\''),
+                Some(&b'"') => result.push(b'"'),
+                Some(&b'a') => result.push(b'\x07'),
+                Some(&b'b') => result.push(b'\x08'),
+                Some(&b't') => result.push(b'\x09'),
+                Some(&b'n') => result.push(b'\x0a'),
+                Some(&b'v') => result.push(b'\x0b'),
+                Some(&b'f') => result.push(b'\x0c'),
+                Some(&b'r') => result.push(b'\x0d'),
+                Some(&b'x') => {
+                    let v1 = try_opt!(iter.next().and_then(|&ch| (ch as char).to_digit(16)));
+                    let v2 = try_opt!(iter.next().and_then(|&ch| (ch as char).to_digit(16)));
+                    result.push(16*(v1 as u8) + (v2 as u8));
+                },
+                _ => return None,
+            },
+            _ => result.push(b)
+        }
+    }
+    Some(result)
+}
+
+/// Decode the argument of the UNICODE opcode.  It is encoded with
+/// "raw-unicode-escape", which only knows the \uXXXX and \UYYYYYYYY escapes.
+/// The backslash is escaped in this way, too.
+pub fn unescape_unicode(s: &[u8]) -> Option<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut iter = s.iter();
+    while let Some(&b) = iter.next() {
+        match b {
+            b'\\' => {
+                let nescape = match iter.next() {
+                    Some(&b'u') => 4,
+                    Some(&b'U') => 8,
+                    _ => return None,
+                };
+                let mut accum = 0;
+                for _i in 0..nescape {
+                    accum *= 16;
+                    accum += try_opt!(iter.next().and_then(|&ch| (ch as char).to_digit(16)));
+                }
+                result.push(try_opt!(char::from_u32(accum)));
+            }
+            _ => result.push(b as char)
+        }
+    }
+    Some(result)
+}
 
-// Ops only used for classes and recursive objects; these are unsupported.
-// pub const PERSID           : u8 = b'P';    // push persistent object; id is taken from string arg
-// pub const BINPERSID        : u8 = b'Q';    //  "       "         "  ;  "  "   "     "  stack
-// pub const BUILD            : u8 = b'b';    // call __setstate__ or __dict__.update()
-// pub const INST             : u8 = b'i';    // build & push class instance
-// pub const OBJ              : u8 = b'o';    // build & push class instance
-// pub const NEWOBJ           : u8 = b'\x81'; // build object by applying cls.__new__ to argtuple
-// pub const EXT1             : u8 = b'\x82'; // push object from extension registry; 1-byte index
-// pub const EXT2             : u8 = b'\x83'; // ditto, but 2-byte index
-// pub const EXT4             : u8 = b'\x84'; // ditto, but 4-byte index
-// pub const NEWOBJ_EX        : u8 = b'\x92'; // like NEWOBJ but work with keyword only arguments
+/// Decode a binary-encoded long integer.
+pub fn decode_long(bytes: &[u8]) -> BigInt {
+    // BigInt::from_bytes_le doesn't like a sign bit in the bytes, therefore
+    // we have to extract that ourselves and do the two-s complement.
+    let negative = !bytes.is_empty() && (bytes[bytes.len() - 1] & 0x80 != 0);
+    let mut val = BigInt::from_bytes_le(Sign::Plus, bytes);
+    if negative {
+        val = val - (BigInt::from(1) << (bytes.len() * 8));
+    }
+    val
+}
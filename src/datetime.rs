@@ -0,0 +1,378 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Values of Python's `datetime` module.
+//!
+//! Python pickles dates, times and datetimes as a call of the class with a
+//! packed bytestring, and timedeltas with their days, seconds and
+//! microseconds.  Of the `tzinfo` implementations, only the fixed-offset
+//! `datetime.timezone` is supported.
+//!
+//! With the `chrono` feature, the types can be converted to and from the
+//! corresponding `chrono` types.
+
+use std::fmt;
+
+/// A date, as `datetime.date`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    /// Year, from 1 to 9999
+    pub year: u16,
+    /// Month, from 1 to 12
+    pub month: u8,
+    /// Day, from 1 to the number of days in the month
+    pub day: u8,
+}
+
+/// A time of day, as `datetime.time`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Time {
+    /// Hour, from 0 to 23
+    pub hour: u8,
+    /// Minute, from 0 to 59
+    pub minute: u8,
+    /// Second, from 0 to 59
+    pub second: u8,
+    /// Microsecond, from 0 to 999999
+    pub microsecond: u32,
+    /// Time zone, if any
+    pub tzinfo: Option<TimeZone>,
+    /// Whether this is the second of two times that are repeated in local
+    /// time (used since Python 3.6)
+    pub fold: bool,
+}
+
+/// A date with time of day, as `datetime.datetime`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    /// The date
+    pub date: Date,
+    /// The time of day, with the time zone
+    pub time: Time,
+}
+
+/// A duration, as `datetime.timedelta`.
+///
+/// Like in Python, it is normalized so that only `days` can be negative.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimeDelta {
+    /// Days, from -999999999 to 999999999
+    pub days: i32,
+    /// Seconds, from 0 to 86399
+    pub seconds: i32,
+    /// Microseconds, from 0 to 999999
+    pub microseconds: i32,
+}
+
+/// A fixed offset from UTC, as `datetime.timezone`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimeZone {
+    /// Offset from UTC, strictly between -24 and 24 hours
+    pub offset: TimeDelta,
+    /// Name of the time zone, if given
+    pub name: Option<String>,
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl Date {
+    /// Construct a date, if it is valid.
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Date> {
+        if year < 1 || year > 9999 || month < 1 || month > 12 || day < 1 ||
+            day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Date { year: year, month: month, day: day })
+    }
+
+    /// Decode the 4-byte state that Python pickles.
+    pub fn from_state(state: &[u8]) -> Option<Date> {
+        if state.len() != 4 {
+            return None;
+        }
+        Date::new((state[0] as u16) << 8 | state[1] as u16, state[2], state[3])
+    }
+
+    /// Encode the 4-byte state that Python pickles.
+    pub fn to_state(&self) -> Vec<u8> {
+        vec![(self.year >> 8) as u8, self.year as u8, self.month, self.day]
+    }
+}
+
+impl Time {
+    /// Construct a time without time zone, if it is valid.
+    pub fn new(hour: u8, minute: u8, second: u8, microsecond: u32) -> Option<Time> {
+        if hour > 23 || minute > 59 || second > 59 || microsecond > 999_999 {
+            return None;
+        }
+        Some(Time { hour: hour, minute: minute, second: second,
+                    microsecond: microsecond, tzinfo: None, fold: false })
+    }
+
+    /// Decode the 6-byte state that Python pickles, with the time zone.
+    pub fn from_state(state: &[u8], tzinfo: Option<TimeZone>) -> Option<Time> {
+        if state.len() != 6 {
+            return None;
+        }
+        let us = (state[3] as u32) << 16 | (state[4] as u32) << 8 | state[5] as u32;
+        Time::new(state[0] & 0x7f, state[1], state[2], us).map(|time| {
+            Time { tzinfo: tzinfo, fold: state[0] & 0x80 != 0, ..time }
+        })
+    }
+
+    /// Encode the 6-byte state that Python pickles.  The fold is only
+    /// included for pickle protocols above 3, as in Python.
+    pub fn to_state(&self, proto: u8) -> Vec<u8> {
+        let fold = if self.fold && proto > 3 { 0x80 } else { 0 };
+        vec![self.hour | fold, self.minute, self.second, (self.microsecond >> 16) as u8,
+             (self.microsecond >> 8) as u8, self.microsecond as u8]
+    }
+}
+
+impl DateTime {
+    /// Construct a datetime from date and time.
+    pub fn new(date: Date, time: Time) -> DateTime {
+        DateTime { date: date, time: time }
+    }
+
+    /// Decode the 10-byte state that Python pickles, with the time zone.
+    pub fn from_state(state: &[u8], tzinfo: Option<TimeZone>) -> Option<DateTime> {
+        if state.len() != 10 {
+            return None;
+        }
+        let date = try_opt!(Date::from_state(&[state[0], state[1], state[2] & 0x7f, state[3]]));
+        let time = try_opt!(Time::from_state(&state[4..], tzinfo));
+        Some(DateTime::new(date, Time { fold: state[2] & 0x80 != 0, ..time }))
+    }
+
+    /// Encode the 10-byte state that Python pickles.  The fold is only
+    /// included for pickle protocols above 3, as in Python.
+    pub fn to_state(&self, proto: u8) -> Vec<u8> {
+        let mut state = self.date.to_state();
+        if self.time.fold && proto > 3 {
+            state[2] |= 0x80;
+        }
+        state.extend(Time { fold: false, ..self.time.clone() }.to_state(proto));
+        state
+    }
+
+    /// Format as ISO 8601, like Python's `isoformat()`.
+    pub fn isoformat(&self) -> String {
+        format!("{}T{}", self.date, self.time)
+    }
+}
+
+impl TimeDelta {
+    /// Construct a timedelta, normalizing seconds and microseconds into
+    /// their range.  Returns None if the number of days is out of range.
+    pub fn new(days: i64, seconds: i64, microseconds: i64) -> Option<TimeDelta> {
+        let seconds = try_opt!(seconds.checked_add(div_floor(microseconds, 1_000_000)));
+        let days = try_opt!(days.checked_add(div_floor(seconds, 86400)));
+        if days < -999_999_999 || days > 999_999_999 {
+            return None;
+        }
+        Some(TimeDelta { days: days as i32, seconds: mod_floor(seconds, 86400) as i32,
+                         microseconds: mod_floor(microseconds, 1_000_000) as i32 })
+    }
+
+    /// Return the total number of microseconds.
+    pub fn total_microseconds(&self) -> i64 {
+        (self.days as i64 * 86400 + self.seconds as i64) * 1_000_000 + self.microseconds as i64
+    }
+
+    /// Return the total number of seconds, like Python's `total_seconds()`.
+    pub fn total_seconds(&self) -> f64 {
+        self.total_microseconds() as f64 / 1e6
+    }
+}
+
+fn div_floor(a: i64, b: i64) -> i64 {
+    let d = a / b;
+    if a % b < 0 { d - 1 } else { d }
+}
+
+fn mod_floor(a: i64, b: i64) -> i64 {
+    let m = a % b;
+    if m < 0 { m + b } else { m }
+}
+
+impl TimeZone {
+    /// Construct a time zone with the given offset from UTC in seconds.
+    pub fn from_offset(seconds: i32) -> Option<TimeZone> {
+        if seconds <= -86400 || seconds >= 86400 {
+            return None;
+        }
+        Some(TimeZone { offset: TimeDelta::new(0, seconds as i64, 0).unwrap(), name: None })
+    }
+
+    /// Return the UTC time zone.
+    pub fn utc() -> TimeZone {
+        TimeZone::from_offset(0).unwrap()
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+// Format the time zone offset as +HH:MM, like Python's isoformat().
+fn write_offset(f: &mut fmt::Formatter, offset: &TimeDelta) -> fmt::Result {
+    let us = offset.total_microseconds();
+    let (sign, us) = if us < 0 { ('-', -us) } else { ('+', us) };
+    let secs = us / 1_000_000;
+    try!(write!(f, "{}{:02}:{:02}", sign, secs / 3600, secs / 60 % 60));
+    if us % 60_000_000 != 0 {
+        try!(write!(f, ":{:02}", secs % 60));
+        if us % 1_000_000 != 0 {
+            try!(write!(f, ".{:06}", us % 1_000_000));
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:02}:{:02}:{:02}", self.hour, self.minute, self.second));
+        if self.microsecond != 0 {
+            try!(write!(f, ".{:06}", self.microsecond));
+        }
+        match self.tzinfo {
+            Some(ref tz) => write_offset(f, &tz.offset),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.date, self.time)
+    }
+}
+
+impl fmt::Display for TimeDelta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.days != 0 {
+            let plural = if self.days.abs() == 1 { "" } else { "s" };
+            try!(write!(f, "{} day{}, ", self.days, plural));
+        }
+        try!(write!(f, "{}:{:02}:{:02}", self.seconds / 3600, self.seconds / 60 % 60,
+                    self.seconds % 60));
+        if self.microseconds != 0 {
+            try!(write!(f, ".{:06}", self.microseconds));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => f.write_str(name),
+            None if self.offset.total_microseconds() == 0 => f.write_str("UTC"),
+            None => {
+                try!(f.write_str("UTC"));
+                write_offset(f, &self.offset)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+mod chrono_impls {
+    use chrono::{self, Datelike, Timelike, Offset};
+    use super::{Date, Time, DateTime, TimeDelta, TimeZone};
+
+    impl Date {
+        /// Convert from a `chrono::NaiveDate`, if the year is in range.
+        pub fn from_naive(date: &chrono::NaiveDate) -> Option<Date> {
+            if date.year() < 1 || date.year() > 9999 {
+                return None;
+            }
+            Date::new(date.year() as u16, date.month() as u8, date.day() as u8)
+        }
+
+        /// Convert to a `chrono::NaiveDate`.
+        pub fn to_naive(&self) -> Option<chrono::NaiveDate> {
+            chrono::NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.day as u32)
+        }
+    }
+
+    impl Time {
+        /// Convert from a `chrono::NaiveTime`.  Leap seconds are not
+        /// supported.
+        pub fn from_naive(time: &chrono::NaiveTime) -> Option<Time> {
+            Time::new(time.hour() as u8, time.minute() as u8, time.second() as u8,
+                      time.nanosecond() / 1000)
+        }
+
+        /// Convert to a `chrono::NaiveTime`, ignoring the time zone.
+        pub fn to_naive(&self) -> Option<chrono::NaiveTime> {
+            chrono::NaiveTime::from_hms_micro_opt(self.hour as u32, self.minute as u32,
+                                                  self.second as u32, self.microsecond)
+        }
+    }
+
+    impl DateTime {
+        /// Convert from a `chrono::NaiveDateTime`.
+        pub fn from_naive(datetime: &chrono::NaiveDateTime) -> Option<DateTime> {
+            let date = try_opt!(Date::from_naive(&datetime.date()));
+            let time = try_opt!(Time::from_naive(&datetime.time()));
+            Some(DateTime::new(date, time))
+        }
+
+        /// Convert to a `chrono::NaiveDateTime`, ignoring the time zone.
+        pub fn to_naive(&self) -> Option<chrono::NaiveDateTime> {
+            let date = try_opt!(self.date.to_naive());
+            let time = try_opt!(self.time.to_naive());
+            Some(chrono::NaiveDateTime::new(date, time))
+        }
+
+        /// Convert from a `chrono::DateTime` with fixed offset.
+        pub fn from_chrono(datetime: &chrono::DateTime<chrono::FixedOffset>) -> Option<DateTime> {
+            let offset = datetime.offset().local_minus_utc().num_seconds();
+            let tz = try_opt!(TimeZone::from_offset(offset as i32));
+            let mut result = try_opt!(DateTime::from_naive(&datetime.naive_local()));
+            result.time.tzinfo = Some(tz);
+            Some(result)
+        }
+
+        /// Convert to a `chrono::DateTime` with fixed offset.  Returns None
+        /// for naive datetimes, and for offsets with microseconds.
+        pub fn to_chrono(&self) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+            let offset = match self.time.tzinfo {
+                Some(ref tz) if tz.offset.microseconds == 0 =>
+                    tz.offset.days * 86400 + tz.offset.seconds,
+                _ => return None,
+            };
+            let offset = try_opt!(chrono::FixedOffset::east_opt(offset));
+            let local = try_opt!(self.to_naive());
+            Some(chrono::DateTime::from_utc(local - chrono::Duration::seconds(
+                offset.local_minus_utc().num_seconds()), offset))
+        }
+    }
+
+    impl TimeDelta {
+        /// Convert from a `chrono::Duration`, if it is in range.
+        pub fn from_duration(duration: &chrono::Duration) -> Option<TimeDelta> {
+            let us = try_opt!(duration.num_microseconds());
+            TimeDelta::new(0, 0, us)
+        }
+
+        /// Convert to a `chrono::Duration`.
+        pub fn to_duration(&self) -> chrono::Duration {
+            chrono::Duration::microseconds(self.total_microseconds())
+        }
+    }
+}
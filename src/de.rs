@@ -11,14 +11,16 @@
 //! `value_from_*` functions exported here, not the generic `from_*` functions.
 
 use std::io;
+use std::cmp;
 use std::mem;
 use std::str;
 use std::char;
 use std::vec;
+use std::rc::Rc;
 use std::io::{BufReader, BufRead, Read};
 use std::str::FromStr;
-use std::collections::BTreeMap;
-use num_bigint::{BigInt, Sign};
+use std::collections::{BTreeMap, BTreeSet};
+use num_bigint::BigInt;
 use num_traits::ToPrimitive;
 use byteorder::{ByteOrder, BigEndian, LittleEndian};
 use iter_read::{IterRead, IterReadItem};
@@ -27,6 +29,9 @@ use serde::de;
 use super::error::{Error, ErrorCode, Result};
 use super::consts::*;
 use super::value;
+use super::datetime::{Date, Time, DateTime, TimeDelta, TimeZone};
+use super::numbers::{Decimal, Fraction, Complex};
+use super::graph::{Graph, GraphValue, GraphObject, NodeId};
 
 type MemoId = u32;
 
@@ -35,6 +40,66 @@ enum Global {
     Set,         // builtins/__builtin__.set
     Frozenset,   // builtins/__builtin__.frozenset
     Encode,      // _codecs.encode
+    Reconstructor,  // copyreg/copy_reg._reconstructor
+    NewObj,      // copyreg/copy_reg.__newobj__
+    NewObjEx,    // copyreg/copy_reg.__newobj_ex__
+    Decimal,     // decimal.Decimal
+    Fraction,    // fractions.Fraction
+    Complex,     // builtins/__builtin__.complex
+    ByteArray,   // builtins/__builtin__.bytearray
+    Date,        // datetime.date
+    Time,        // datetime.time
+    DateTime,    // datetime.datetime
+    TimeDelta,   // datetime.timedelta
+    TimeZone,    // datetime.timezone
+    OrderedDict, // collections.OrderedDict
+    DefaultDict, // collections.defaultdict
+    Deque,       // collections.deque
+    Counter,     // collections.Counter
+    #[cfg(feature = "numpy")]
+    NdArray,     // numpy.core.multiarray._reconstruct
+    #[cfg(feature = "numpy")]
+    NdArrayFromBuffer,  // numpy.core.numeric._frombuffer
+    #[cfg(feature = "numpy")]
+    DType,       // numpy.dtype
+    Other(String, String),  // any other global, usually a class
+}
+
+impl Global {
+    // Return the module and name of the global, as written by Python 3.
+    fn into_name(self) -> (String, String) {
+        let (module, name) = match self {
+            Global::Set => ("builtins", "set"),
+            Global::Frozenset => ("builtins", "frozenset"),
+            Global::Encode => ("_codecs", "encode"),
+            Global::Reconstructor => ("copyreg", "_reconstructor"),
+            Global::NewObj => ("copyreg", "__newobj__"),
+            Global::NewObjEx => ("copyreg", "__newobj_ex__"),
+            Global::Decimal => ("decimal", "Decimal"),
+            Global::Fraction => ("fractions", "Fraction"),
+            Global::Complex => ("builtins", "complex"),
+            Global::ByteArray => ("builtins", "bytearray"),
+            Global::Date => ("datetime", "date"),
+            Global::Time => ("datetime", "time"),
+            Global::DateTime => ("datetime", "datetime"),
+            Global::TimeDelta => ("datetime", "timedelta"),
+            Global::TimeZone => ("datetime", "timezone"),
+            Global::OrderedDict => ("collections", "OrderedDict"),
+            Global::DefaultDict => ("collections", "defaultdict"),
+            Global::Deque => ("collections", "deque"),
+            Global::Counter => ("collections", "Counter"),
+            #[cfg(feature = "numpy")]
+            Global::NdArray => ("numpy.core.multiarray", "_reconstruct"),
+            #[cfg(feature = "numpy")]
+            Global::NdArrayFromBuffer => ("numpy.core.numeric", "_frombuffer"),
+            #[cfg(feature = "numpy")]
+            Global::DType => ("numpy", "dtype"),
+            Global::Other(ref module, ref name) if module == "__builtin__" =>
+                ("builtins", &**name),
+            Global::Other(module, name) => return (module, name),
+        };
+        (module.into(), name.into())
+    }
 }
 
 /// Our intermediate representation of a value.
@@ -56,12 +121,402 @@ enum Value {
     Int(BigInt),
     F64(f64),
     Bytes(Vec<u8>),
+    ByteArray(Vec<u8>),
     String(String),
     List(Vec<Value>),
     Tuple(Vec<Value>),
     Set(Vec<Value>),
     FrozenSet(Vec<Value>),
     Dict(Vec<(Value, Value)>),
+    Object(Box<Object>),
+    Decimal(Decimal),
+    Fraction(Fraction),
+    Complex(Complex),
+    Date(Date),
+    Time(Time),
+    DateTime(DateTime),
+    TimeDelta(TimeDelta),
+    TimeZone(TimeZone),
+    OrderedDict(Vec<(Value, Value)>),
+    DefaultDict(Box<DefaultDict>),
+    Deque(Vec<Value>, Option<usize>),
+    Counter(Vec<(Value, Value)>),
+}
+
+/// Intermediate representation of a class instance.
+#[derive(Clone, Debug, PartialEq)]
+struct Object {
+    class: (String, String),
+    args: Vec<Value>,
+    kwargs: Vec<(Value, Value)>,
+    state: Option<Value>,
+}
+
+/// Intermediate representation of a `collections.defaultdict`.
+#[derive(Clone, Debug, PartialEq)]
+struct DefaultDict {
+    factory: Option<(String, String)>,
+    items: Vec<(Value, Value)>,
+}
+
+// Convert module globals anywhere in the arguments given to a resolver or a
+// persistent loader, like the storage classes in PyTorch checkpoints, to
+// `module.name` strings.
+fn globals_to_names(value: Value) -> Value {
+    fn all(values: Vec<Value>) -> Vec<Value> {
+        values.into_iter().map(globals_to_names).collect()
+    }
+    fn pairs(pairs: Vec<(Value, Value)>) -> Vec<(Value, Value)> {
+        pairs.into_iter().map(|(k, v)| (globals_to_names(k), globals_to_names(v))).collect()
+    }
+    match value {
+        Value::Global(global) => {
+            let (module, name) = global.into_name();
+            Value::String(format!("{}.{}", module, name))
+        }
+        Value::List(v) => Value::List(all(v)),
+        Value::Tuple(v) => Value::Tuple(all(v)),
+        Value::Set(v) => Value::Set(all(v)),
+        Value::FrozenSet(v) => Value::FrozenSet(all(v)),
+        Value::Dict(v) => Value::Dict(pairs(v)),
+        Value::OrderedDict(v) => Value::OrderedDict(pairs(v)),
+        Value::DefaultDict(d) => {
+            let d = *d;
+            Value::DefaultDict(Box::new(DefaultDict { factory: d.factory, items: pairs(d.items) }))
+        }
+        Value::Deque(v, maxlen) => Value::Deque(all(v), maxlen),
+        Value::Counter(v) => Value::Counter(pairs(v)),
+        Value::Object(obj) => {
+            let obj = *obj;
+            Value::Object(Box::new(Object {
+                class: obj.class,
+                args: all(obj.args),
+                kwargs: pairs(obj.kwargs),
+                state: obj.state.map(globals_to_names),
+            }))
+        }
+        other => other,
+    }
+}
+
+// Drop the items that don't fit into a deque with the given maximum length.
+fn trim_deque(items: &mut Vec<Value>, maxlen: Option<usize>) {
+    if let Some(maxlen) = maxlen {
+        if items.len() > maxlen {
+            let excess = items.len() - maxlen;
+            items.drain(..excess);
+        }
+    }
+}
+
+// Drop values one by one, instead of recursively, which would overflow the
+// call stack for deeply nested values.
+fn drop_values(mut values: Vec<Value>) {
+    while let Some(value) = values.pop() {
+        match value {
+            Value::List(items) | Value::Tuple(items) | Value::Set(items) |
+            Value::FrozenSet(items) | Value::Deque(items, _) => values.extend(items),
+            Value::Dict(items) | Value::OrderedDict(items) | Value::Counter(items) => {
+                for (key, value) in items {
+                    values.push(key);
+                    values.push(value);
+                }
+            }
+            Value::DefaultDict(d) => {
+                for (key, value) in d.items {
+                    values.push(key);
+                    values.push(value);
+                }
+            }
+            Value::Object(obj) => {
+                let obj = *obj;
+                values.extend(obj.args);
+                for (key, value) in obj.kwargs {
+                    values.push(key);
+                    values.push(value);
+                }
+                values.extend(obj.state);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Value {
+    // Convert a value produced by a `GlobalResolver` into our representation.
+    fn from_value(value: value::Value) -> Value {
+        fn from_values(values: Vec<value::Value>) -> Vec<Value> {
+            values.into_iter().map(Value::from_value).collect()
+        }
+        fn from_dict(dict: BTreeMap<value::HashableValue, value::Value>) -> Vec<(Value, Value)> {
+            dict.into_iter().map(|(k, v)| (Value::from_value(k.into_value()),
+                                           Value::from_value(v))).collect()
+        }
+        fn from_set(set: BTreeSet<value::HashableValue>) -> Vec<Value> {
+            set.into_iter().map(|v| Value::from_value(v.into_value())).collect()
+        }
+        match value {
+            value::Value::None => Value::None,
+            value::Value::Bool(v) => Value::Bool(v),
+            value::Value::I64(v) => Value::I64(v),
+            value::Value::Int(v) => Value::Int(v),
+            value::Value::F64(v) => Value::F64(v),
+            value::Value::Bytes(v) => Value::Bytes(v),
+            value::Value::ByteArray(v) => Value::ByteArray(v),
+            value::Value::String(v) => Value::String(v),
+            value::Value::List(v) => Value::List(from_values(v)),
+            value::Value::Tuple(v) => Value::Tuple(from_values(v)),
+            value::Value::Set(v) => Value::Set(from_set(v)),
+            value::Value::FrozenSet(v) => Value::FrozenSet(from_set(v)),
+            value::Value::Dict(v) => Value::Dict(from_dict(v)),
+            value::Value::Object(obj) => {
+                let obj = *obj;
+                Value::Object(Box::new(Object {
+                    class: obj.class,
+                    args: from_values(obj.args),
+                    kwargs: from_dict(obj.kwargs),
+                    state: obj.state.map(Value::from_value),
+                }))
+            }
+            value::Value::OrderedDict(v) => {
+                Value::OrderedDict(v.into_iter().map(|(k, v)| (Value::from_value(k.into_value()),
+                                                               Value::from_value(v))).collect())
+            }
+            value::Value::DefaultDict(d) => {
+                let d = *d;
+                Value::DefaultDict(Box::new(DefaultDict {
+                    factory: d.factory,
+                    items: from_dict(d.items),
+                }))
+            }
+            value::Value::Deque(d) => Value::Deque(from_values(d.items), d.maxlen),
+            value::Value::Counter(v) => Value::Counter(from_dict(v)),
+            value::Value::Decimal(v) => Value::Decimal(v),
+            value::Value::Fraction(v) => Value::Fraction(v),
+            value::Value::Complex(v) => Value::Complex(v),
+            value::Value::Date(v) => Value::Date(v),
+            value::Value::Time(v) => Value::Time(v),
+            value::Value::DateTime(v) => Value::DateTime(v),
+            value::Value::TimeDelta(v) => Value::TimeDelta(v),
+        }
+    }
+}
+
+// Convert a value of the library types that we support, which contain no
+// memo references.
+fn library_value(value: Value) -> value::Value {
+    match value {
+        Value::Decimal(v) => value::Value::Decimal(v),
+        Value::Fraction(v) => value::Value::Fraction(v),
+        Value::Complex(v) => value::Value::Complex(v),
+        Value::Date(v) => value::Value::Date(v),
+        Value::Time(v) => value::Value::Time(v),
+        Value::DateTime(v) => value::Value::DateTime(v),
+        Value::TimeDelta(v) => value::Value::TimeDelta(v),
+        // A time zone not attached to a time is represented as a generic object.
+        Value::TimeZone(tz) => {
+            let mut args = vec![value::Value::TimeDelta(tz.offset)];
+            args.extend(tz.name.map(value::Value::String));
+            value::Value::Object(Box::new(value::Object {
+                class: ("datetime".into(), "timezone".into()),
+                args: args,
+                kwargs: BTreeMap::new(),
+                state: None,
+            }))
+        }
+        other => unreachable!("not a library value: {:?}", other),
+    }
+}
+
+/// Resolves module globals that the deserializer doesn't know by itself.
+///
+/// When a pickle stream calls such a global (using the REDUCE opcode), the
+/// resolvers registered with `Deserializer::add_resolver` are asked in turn,
+/// and the first one that handles the global produces the resulting value.
+/// Module globals anywhere in the arguments are given as `module.name`
+/// strings.
+pub trait GlobalResolver {
+    /// Return true if the global `module.name` is handled by this resolver.
+    fn handles(&self, module: &str, name: &str) -> bool;
+
+    /// Produce the value of calling the global `module.name` with the given
+    /// arguments.
+    fn reduce(&self, module: &str, name: &str, args: Vec<value::Value>) -> Result<value::Value>;
+}
+
+/// Loads objects that the pickle refers to by persistent ID.
+///
+/// Pickles written with a `persistent_id` hook (or a `PersistentId` on the
+/// `Serializer`) contain references to objects stored outside of the pickle.
+/// The loader registered with `Deserializer::set_persistent_load` produces
+/// the values for these references.  Module globals anywhere in an ID are
+/// given as `module.name` strings.
+pub trait PersistentLoad {
+    /// Produce the object referred to by the persistent ID.  Protocol 0 only
+    /// supports string IDs; other protocols support arbitrary values.
+    fn persistent_load(&self, pid: value::Value) -> Result<value::Value>;
+}
+
+/// The module globals that are allowed in safe mode by default.  These are
+/// the builtins that the deserializer supports by itself.
+pub const SAFE_GLOBALS: &'static [(&'static str, &'static str)] = &[
+    ("_codecs", "encode"),
+    ("__builtin__", "set"),
+    ("__builtin__", "frozenset"),
+    ("builtins", "set"),
+    ("builtins", "frozenset"),
+    ("__builtin__", "bytearray"),
+    ("builtins", "bytearray"),
+    ("copy_reg", "_reconstructor"),
+    ("copy_reg", "__newobj__"),
+    ("copy_reg", "__newobj_ex__"),
+    ("copyreg", "_reconstructor"),
+    ("copyreg", "__newobj__"),
+    ("copyreg", "__newobj_ex__"),
+    ("__builtin__", "complex"),
+    ("builtins", "complex"),
+    ("decimal", "Decimal"),
+    ("fractions", "Fraction"),
+    ("datetime", "date"),
+    ("datetime", "time"),
+    ("datetime", "datetime"),
+    ("datetime", "timedelta"),
+    ("datetime", "timezone"),
+    ("collections", "OrderedDict"),
+    ("collections", "defaultdict"),
+    ("collections", "deque"),
+    ("collections", "Counter"),
+];
+
+/// Options for unpickling.
+///
+/// By default, no resource limits are imposed and all module globals are
+/// accepted.  When decoding pickles from untrusted sources, all limits
+/// should be set, and safe mode should be enabled.
+#[derive(Clone, Debug)]
+pub struct DeOptions {
+    decode_strings: bool,
+    max_depth: usize,
+    max_stack_size: usize,
+    max_memo_size: usize,
+    max_string_len: usize,
+    max_alloc: usize,
+    safe_globals: Option<BTreeSet<(String, String)>>,
+    extensions: BTreeMap<u32, (String, String)>,
+}
+
+impl DeOptions {
+    /// Construct with default options: strings are not decoded, no limits.
+    pub fn new() -> Self {
+        DeOptions {
+            decode_strings: false,
+            max_depth: usize::max_value(),
+            max_stack_size: usize::max_value(),
+            max_memo_size: usize::max_value(),
+            max_string_len: usize::max_value(),
+            max_alloc: usize::max_value(),
+            safe_globals: None,
+            extensions: BTreeMap::new(),
+        }
+    }
+
+    /// Decide whether strings (STRING opcodes, saved only by protocols 0-2)
+    /// are decoded as UTF-8 strings or left as byte vectors.
+    pub fn decode_strings(mut self, decode_strings: bool) -> Self {
+        self.decode_strings = decode_strings;
+        self
+    }
+
+    /// Set the maximum nesting depth of values, and of MARKs on the stack.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Set the maximum number of items on the pickle stack.
+    pub fn max_stack_size(mut self, max_stack_size: usize) -> Self {
+        self.max_stack_size = max_stack_size;
+        self
+    }
+
+    /// Set the maximum number of entries in the pickle memo.
+    pub fn max_memo_size(mut self, max_memo_size: usize) -> Self {
+        self.max_memo_size = max_memo_size;
+        self
+    }
+
+    /// Set the maximum length of a single string, bytestring, long integer or
+    /// text argument.  The length is checked before anything is read.
+    pub fn max_string_len(mut self, max_string_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self
+    }
+
+    /// Set the maximum number of bytes allocated while decoding.
+    ///
+    /// This is an estimate: string data is counted exactly, all other
+    /// values with the size of their representation, but not including
+    /// overhead like spare capacity of vectors.
+    pub fn max_alloc(mut self, max_alloc: usize) -> Self {
+        self.max_alloc = max_alloc;
+        self
+    }
+
+    /// Enable or disable safe mode.
+    ///
+    /// In safe mode, the pickle may only refer to module globals in an
+    /// allowlist, which initially contains the `SAFE_GLOBALS` (and
+    /// `numpy::SAFE_GLOBALS` and `pandas::SAFE_GLOBALS` with the `numpy` and
+    /// `pandas` features).  All other globals, including those that a
+    /// `GlobalResolver` would handle, are rejected with
+    /// `ErrorCode::UnsupportedGlobal`.
+    pub fn safe(mut self, safe: bool) -> Self {
+        if !safe {
+            self.safe_globals = None;
+        } else if self.safe_globals.is_none() {
+            let globals = SAFE_GLOBALS.iter();
+            #[cfg(feature = "numpy")]
+            let globals = globals.chain(::numpy::SAFE_GLOBALS);
+            #[cfg(feature = "pandas")]
+            let globals = globals.chain(::pandas::SAFE_GLOBALS);
+            self.safe_globals = Some(globals.map(|&(m, g)| (m.into(), g.into())).collect());
+        }
+        self
+    }
+
+    /// Add the global `module.name` to the allowlist.  This enables safe mode.
+    pub fn allow_global(mut self, module: &str, name: &str) -> Self {
+        self = self.safe(true);
+        if let Some(ref mut globals) = self.safe_globals {
+            globals.insert((module.into(), name.into()));
+        }
+        self
+    }
+
+    /// Register the global `module.name` under an extension code, like
+    /// Python's `copyreg.add_extension`.  EXT opcodes with this code then
+    /// refer to the global.  In safe mode, it must still be allowlisted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the code is not between 1 and 0x7fffffff, or if the code or
+    /// the global is already registered with a different global or code.
+    pub fn add_extension(mut self, code: u32, module: &str, name: &str) -> Self {
+        assert!(code >= 1 && code <= 0x7fff_ffff, "extension code {} out of range", code);
+        let global = (module.to_owned(), name.to_owned());
+        for (&other_code, other) in &self.extensions {
+            assert!((other_code == code) == (*other == global),
+                    "extension code {} or global {}.{} already registered", code, module, name);
+        }
+        self.extensions.insert(code, global);
+        self
+    }
+}
+
+impl Default for DeOptions {
+    fn default() -> Self {
+        DeOptions::new()
+    }
 }
 
 /// Decodes pickle streams into values.
@@ -69,10 +524,25 @@ pub struct Deserializer<R: Read> {
     rdr: BufReader<R>,
     pos: usize,
     value: Option<Value>,                  // next value to deserialize
-    memo: BTreeMap<MemoId, (Value, i32)>,  // pickle memo (value, number of refs)
-    stack: Vec<Value>,                     // topmost items on the stack
-    stacks: Vec<Vec<Value>>,               // items further down the stack, between MARKs
-    decode_strings: bool,                  // protocol specific switch
+    memo: BTreeMap<MemoId, (Rc<Value>, i32, usize)>,  // pickle memo (value, number of refs,
+                                           // nesting depth)
+    converted: BTreeMap<MemoId, (value::Value, i32, usize)>,  // converted memo (value,
+                                           // number of refs, accounted size)
+    stack: Vec<(Value, usize)>,            // topmost items on the stack, with their
+                                           // nesting depth
+    stacks: Vec<Vec<(Value, usize)>>,      // items further down the stack, between MARKs
+    marked: usize,                         // number of items in `stacks`
+    popped: usize,                         // deepest item popped by the current opcode
+    depth: usize,                          // nesting depth while converting values
+    allocated: usize,                      // estimated number of bytes allocated
+    lazy: bool,                            // top-level container is parsed lazily
+    lazy_memo: Option<MemoId>,             // memo ID of the lazy container
+    memo_gets: Option<BTreeMap<MemoId, usize>>,  // memo references left to parse, if
+                                           // the top-level container can be lazy
+    options: DeOptions,
+    resolvers: Vec<Box<GlobalResolver>>,   // user-supplied global resolvers
+    persistent_load: Option<Box<PersistentLoad>>,  // user-supplied persistent loader
+    buffers: vec::IntoIter<Vec<u8>>,       // out-of-band buffers (protocol 5)
 }
 
 impl<R: Read> Deserializer<R> {
@@ -80,17 +550,74 @@ impl<R: Read> Deserializer<R> {
     /// strings (STRING opcodes, saved only by protocols 0-2) are decoded as
     /// UTF-8 strings or left as byte vectors.
     pub fn new(rdr: R, decode_strings: bool) -> Deserializer<R> {
+        Deserializer::with_options(rdr, DeOptions::new().decode_strings(decode_strings))
+    }
+
+    /// Construct a new Deserializer with the given options.
+    pub fn with_options(rdr: R, options: DeOptions) -> Deserializer<R> {
         Deserializer {
             rdr: BufReader::new(rdr),
             pos: 0,
             value: None,
             memo: BTreeMap::new(),
+            converted: BTreeMap::new(),
             stack: Vec::with_capacity(128),
             stacks: Vec::with_capacity(16),
-            decode_strings: decode_strings,
+            marked: 0,
+            popped: 0,
+            depth: 0,
+            allocated: 0,
+            lazy: false,
+            lazy_memo: None,
+            memo_gets: None,
+            options: options,
+            resolvers: Vec::new(),
+            persistent_load: None,
+            buffers: Vec::new().into_iter(),
         }
     }
 
+    /// Give the out-of-band buffers that the pickle stream refers to, in
+    /// order.  These are the buffers collected from Python's
+    /// `buffer_callback` while pickling with protocol 5.  They are decoded
+    /// as bytestrings.
+    pub fn set_buffers(&mut self, buffers: Vec<Vec<u8>>) {
+        self.buffers = buffers.into_iter();
+    }
+
+    /// Register a resolver for module globals that are not supported by
+    /// the deserializer itself.  Resolvers are tried in order of registration.
+    pub fn add_resolver<G: GlobalResolver + 'static>(&mut self, resolver: G) {
+        self.resolvers.push(Box::new(resolver));
+    }
+
+    /// Set the loader for objects referred to by persistent ID.  Without
+    /// one, pickles with persistent IDs are rejected.
+    pub fn set_persistent_load<P: PersistentLoad + 'static>(&mut self, loader: P) {
+        self.persistent_load = Some(Box::new(loader));
+    }
+
+    /// Decode a complete pickle from the stream into a `value::Value`.
+    ///
+    /// Unlike deserializing `value::Value` via serde, this keeps all Python
+    /// types (such as sets and long integers) intact.
+    pub fn decode_value(&mut self) -> Result<value::Value> {
+        let intermediate_value = try!(self.parse_value());
+        self.deserialize_value(intermediate_value)
+    }
+
+    /// Decode a complete pickle from the stream into a `Graph`.
+    ///
+    /// This preserves the identity of shared containers and objects, and
+    /// also supports recursive structures.
+    pub fn decode_graph(&mut self) -> Result<Graph> {
+        let intermediate_value = try!(self.parse_value());
+        let mut graph = Graph { nodes: Vec::new(), root: GraphValue::None };
+        let mut refs = BTreeMap::new();
+        graph.root = try!(self.graph_value(intermediate_value, &mut graph.nodes, &mut refs));
+        Ok(graph)
+    }
+
     /// Get the next value to deserialize, either by parsing the pickle stream
     /// or from `self.value`.
     fn get_next_value(&mut self) -> Result<Value> {
@@ -104,291 +631,600 @@ impl<R: Read> Deserializer<R> {
     /// pickle until the STOP opcode.
     fn parse_value(&mut self) -> Result<Value> {
         loop {
-            match try!(self.read_byte()) {
-                // Specials
-                PROTO => {
-                    // Ignore this, as it is only important for instances (read
-                    // the version byte).
-                    try!(self.read_byte());
-                }
-                FRAME => {
-                    // We'll ignore framing. But we still have to gobble up the length.
-                    try!(self.read_bytes(8));
-                }
-                STOP => return self.pop(),
-                MARK => {
-                    let stack = mem::replace(&mut self.stack, Vec::with_capacity(128));
-                    self.stacks.push(stack);
+            if let Some(value) = try!(self.parse_opcode()) {
+                return Ok(value);
+            }
+        }
+    }
+
+    // Parse the start of a pickle.  If `scan_toplevel` found that the value
+    // is a list or dict, as CPython writes them, only the empty container is
+    // parsed and None is returned.  Its items are then parsed one batch at a
+    // time by `parse_lazy`, so that they can be visited without building the
+    // whole value first.
+    fn parse_toplevel(&mut self) -> Result<Option<Value>> {
+        loop {
+            match try!(self.peek_byte()) {
+                Some(PROTO) | Some(FRAME) => { try!(self.parse_opcode()); }
+                Some(EMPTY_LIST) | Some(EMPTY_DICT) if self.memo_gets.is_some() => {
+                    try!(self.parse_opcode());
+                    self.lazy = true;
+                    return Ok(None);
                 }
-                POP => {
-                    if self.stack.is_empty() {
-                        try!(self.pop_mark());
-                    } else {
-                        try!(self.pop());
+                _ => return self.parse_value().map(Some),
+            }
+        }
+    }
+
+    // Parse opcodes until the lazily parsed top-level container gets new
+    // items, and take them out of it.  Returns None at the end of the pickle,
+    // with the final value left in `self.value`.
+    fn parse_lazy(&mut self) -> Result<Option<Value>> {
+        while self.lazy {
+            let code = try!(self.peek_byte());
+            if let Some(value) = try!(self.parse_opcode()) {
+                self.lazy = false;
+                self.value = Some(value);
+                break;
+            }
+            match code {
+                Some(APPEND) | Some(APPENDS) | Some(SETITEM) | Some(SETITEMS) => {
+                    if let Some(items) = self.take_lazy_items() {
+                        return Ok(Some(items));
                     }
-                },
-                POP_MARK => { try!(self.pop_mark()); },
-                DUP => { let top = try!(self.top()).clone(); self.stack.push(top); },
-
-                // Memo saving ops
-                PUT => {
-                    let bytes = try!(self.read_line());
-                    let memo_id = try!(self.parse_ascii(bytes));
-                    try!(self.memoize(memo_id));
-                }
-                BINPUT => {
-                    let memo_id = try!(self.read_byte());
-                    try!(self.memoize(memo_id as MemoId));
-                }
-                LONG_BINPUT => {
-                    let bytes = try!(self.read_bytes(4));
-                    let memo_id = LittleEndian::read_u32(&bytes);
-                    try!(self.memoize(memo_id as MemoId));
-                }
-                MEMOIZE => {
-                    let memo_id = self.memo.len();
-                    try!(self.memoize(memo_id as MemoId));
                 }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    // Take the items out of the lazily parsed container, which is at the
+    // bottom of the stack.
+    fn take_lazy_items(&mut self) -> Option<Value> {
+        fn take(value: &mut Value) -> Option<Value> {
+            match *value {
+                Value::List(ref mut items) if !items.is_empty() =>
+                    Some(Value::List(mem::replace(items, Vec::new()))),
+                Value::Dict(ref mut items) if !items.is_empty() =>
+                    Some(Value::Dict(mem::replace(items, Vec::new()))),
+                _ => None,
+            }
+        }
+        let memo_id = {
+            let bottom = match self.stacks.first_mut() {
+                Some(stack) => stack.first_mut(),
+                None => self.stack.first_mut(),
+            };
+            match bottom {
+                Some(&mut (Value::MemoRef(id), _)) => id,
+                Some(&mut (ref mut value, _)) => return take(value),
+                None => return None,
+            }
+        };
+        // References to the container can't be resolved anymore.
+        self.lazy_memo = Some(memo_id);
+        self.memo.get_mut(&memo_id).and_then(|&mut (ref mut value, _, _)| take(Rc::make_mut(value)))
+    }
 
-                // Memo getting ops
-                GET => {
-                    let bytes = try!(self.read_line());
-                    let memo_id = try!(self.parse_ascii(bytes));
-                    try!(self.push_memo_ref(memo_id));
-                }
-                BINGET => {
-                    let memo_id = try!(self.read_byte()) as MemoId;
-                    try!(self.push_memo_ref(memo_id));
+    // Check that the pickle's final value is the lazily visited container.
+    fn end_lazy(&mut self) -> Result<()> {
+        match self.value.take() {
+            Some(Value::MemoRef(id)) if self.lazy_memo == Some(id) => Ok(()),
+            Some(Value::List(ref items)) if items.is_empty() => Ok(()),
+            Some(Value::Dict(ref items)) if items.is_empty() => Ok(()),
+            Some(other) => Self::stack_error("visited list or dict", &other, self.pos),
+            None => Ok(()),
+        }
+    }
+
+    // Parse a single opcode.  Returns the final value at the STOP opcode.
+    fn parse_opcode(&mut self) -> Result<Option<Value>> {
+        if self.stack.len() + self.marked > self.options.max_stack_size {
+            return self.error(ErrorCode::StackLimitExceeded);
+        }
+        // Every opcode creates at most one new value.
+        try!(self.account(mem::size_of::<Value>()));
+        self.popped = 0;
+        match try!(self.read_byte()) {
+            // Specials
+            PROTO => {
+                // Ignore this, as it is only important for instances (read
+                // the version byte).
+                try!(self.read_byte());
+            }
+            FRAME => {
+                // We'll ignore framing. But we still have to gobble up the length.
+                try!(self.read_bytes(8));
+            }
+            STOP => return self.pop().map(Some),
+            MARK => {
+                if self.stacks.len() >= self.options.max_depth {
+                    return self.error(ErrorCode::DepthLimitExceeded);
                 }
-                LONG_BINGET => {
-                    let bytes = try!(self.read_bytes(4));
-                    let memo_id = LittleEndian::read_u32(&bytes);
-                    try!(self.push_memo_ref(memo_id as MemoId));
+                let stack = mem::replace(&mut self.stack, Vec::with_capacity(128));
+                self.marked += stack.len();
+                self.stacks.push(stack);
+            }
+            POP => {
+                if self.stack.is_empty() {
+                    try!(self.pop_mark());
+                } else {
+                    try!(self.pop());
                 }
+            },
+            POP_MARK => { try!(self.pop_mark()); },
+            DUP => {
+                let (top, depth) = match self.stack.last() {
+                    Some(&(ref value, depth)) => (value.clone(), depth),
+                    None => return self.error(ErrorCode::StackUnderflow),
+                };
+                // A copy of a memo reference is just another reference.
+                self.add_memo_refs(&top);
+                self.stack.push((top, depth));
+            }
 
-                // Singletons
-                NONE => self.stack.push(Value::None),
-                NEWFALSE => self.stack.push(Value::Bool(false)),
-                NEWTRUE => self.stack.push(Value::Bool(true)),
+            // Memo saving ops
+            PUT => {
+                let bytes = try!(self.read_line());
+                let memo_id = try!(self.parse_ascii(bytes));
+                try!(self.memoize(memo_id));
+            }
+            BINPUT => {
+                let memo_id = try!(self.read_byte());
+                try!(self.memoize(memo_id as MemoId));
+            }
+            LONG_BINPUT => {
+                let bytes = try!(self.read_bytes(4));
+                let memo_id = LittleEndian::read_u32(&bytes);
+                try!(self.memoize(memo_id as MemoId));
+            }
+            MEMOIZE => {
+                let memo_id = self.memo.len();
+                try!(self.memoize(memo_id as MemoId));
+            }
 
-                // ASCII-formatted numbers
-                INT => {
-                    let line = try!(self.read_line());
-                    let val = try!(self.decode_text_int(line));
-                    self.stack.push(val);
-                }
-                LONG => {
-                    let line = try!(self.read_line());
-                    let long = try!(self.decode_text_long(line));
-                    self.stack.push(long);
-                }
-                FLOAT => {
-                    let line = try!(self.read_line());
-                    let f = try!(self.parse_ascii(line));
-                    self.stack.push(Value::F64(f));
-                }
+            // Memo getting ops
+            GET => {
+                let bytes = try!(self.read_line());
+                let memo_id = try!(self.parse_ascii(bytes));
+                try!(self.push_memo_ref(memo_id));
+            }
+            BINGET => {
+                let memo_id = try!(self.read_byte()) as MemoId;
+                try!(self.push_memo_ref(memo_id));
+            }
+            LONG_BINGET => {
+                let bytes = try!(self.read_bytes(4));
+                let memo_id = LittleEndian::read_u32(&bytes);
+                try!(self.push_memo_ref(memo_id as MemoId));
+            }
 
-                // ASCII-formatted strings
-                STRING => {
-                    let line = try!(self.read_line());
-                    let string = try!(self.decode_escaped_string(&line));
-                    self.stack.push(string);
-                }
-                UNICODE => {
-                    let line = try!(self.read_line());
-                    let string = try!(self.decode_escaped_unicode(&line));
-                    self.stack.push(string);
-                }
+            // Singletons
+            NONE => self.push(Value::None),
+            NEWFALSE => self.push(Value::Bool(false)),
+            NEWTRUE => self.push(Value::Bool(true)),
 
-                // Binary-coded numbers
-                BINFLOAT => {
-                    let bytes = try!(self.read_bytes(8));
-                    self.stack.push(Value::F64(BigEndian::read_f64(&bytes)));
-                }
-                BININT => {
-                    let bytes = try!(self.read_bytes(4));
-                    self.stack.push(Value::I64(LittleEndian::read_i32(&bytes) as i64));
-                }
-                BININT1 => {
-                    let byte = try!(self.read_byte());
-                    self.stack.push(Value::I64(byte as i64));
-                }
-                BININT2 => {
-                    let bytes = try!(self.read_bytes(2));
-                    self.stack.push(Value::I64(LittleEndian::read_u16(&bytes) as i64));
-                }
-                LONG1 => {
-                    let bytes = try!(self.read_u8_prefixed_bytes());
-                    let long = self.decode_binary_long(bytes);
-                    self.stack.push(long);
-                }
-                LONG4 => {
-                    let bytes = try!(self.read_i32_prefixed_bytes());
-                    let long = self.decode_binary_long(bytes);
-                    self.stack.push(long);
-                }
+            // ASCII-formatted numbers
+            INT => {
+                let line = try!(self.read_line());
+                let val = try!(self.decode_text_int(line));
+                self.push(val);
+            }
+            LONG => {
+                let line = try!(self.read_line());
+                let long = try!(self.decode_text_long(line));
+                self.push(long);
+            }
+            FLOAT => {
+                let line = try!(self.read_line());
+                let f = try!(self.parse_ascii(line));
+                self.push(Value::F64(f));
+            }
 
-                // Length-prefixed (byte)strings
-                SHORT_BINBYTES => {
-                    let string = try!(self.read_u8_prefixed_bytes());
-                    self.stack.push(Value::Bytes(string));
-                }
-                BINBYTES => {
-                    let string = try!(self.read_u32_prefixed_bytes());
-                    self.stack.push(Value::Bytes(string));
-                }
-                BINBYTES8 => {
-                    let string = try!(self.read_u64_prefixed_bytes());
-                    self.stack.push(Value::Bytes(string));
-                }
-                SHORT_BINSTRING => {
-                    let string = try!(self.read_u8_prefixed_bytes());
-                    let decoded = try!(self.decode_string(string));
-                    self.stack.push(decoded);
-                }
-                BINSTRING => {
-                    let string = try!(self.read_i32_prefixed_bytes());
-                    let decoded = try!(self.decode_string(string));
-                    self.stack.push(decoded);
-                }
-                SHORT_BINUNICODE => {
-                    let string = try!(self.read_u8_prefixed_bytes());
-                    let decoded = try!(self.decode_unicode(string));
-                    self.stack.push(decoded);
-                }
-                BINUNICODE => {
-                    let string = try!(self.read_u32_prefixed_bytes());
-                    let decoded = try!(self.decode_unicode(string));
-                    self.stack.push(decoded);
-                }
-                BINUNICODE8 => {
-                    let string = try!(self.read_u64_prefixed_bytes());
-                    let decoded = try!(self.decode_unicode(string));
-                    self.stack.push(decoded);
-                }
+            // ASCII-formatted strings
+            STRING => {
+                let line = try!(self.read_line());
+                let string = try!(self.decode_escaped_string(&line));
+                self.push(string);
+            }
+            UNICODE => {
+                let line = try!(self.read_line());
+                let string = try!(self.decode_escaped_unicode(&line));
+                self.push(string);
+            }
 
-                // Tuples
-                EMPTY_TUPLE => self.stack.push(Value::Tuple(Vec::new())),
-                TUPLE1 => {
-                    let item = try!(self.pop());
-                    self.stack.push(Value::Tuple(vec![item]));
-                 }
-                 TUPLE2 => {
-                    let item2 = try!(self.pop());
-                    let item1 = try!(self.pop());
-                    self.stack.push(Value::Tuple(vec![item1, item2]));
-                 }
-                 TUPLE3 => {
-                    let item3 = try!(self.pop());
-                    let item2 = try!(self.pop());
-                    let item1 = try!(self.pop());
-                    self.stack.push(Value::Tuple(vec![item1, item2, item3]));
-                }
-                TUPLE => {
-                    let items = try!(self.pop_mark());
-                    self.stack.push(Value::Tuple(items));
-                }
+            // Binary-coded numbers
+            BINFLOAT => {
+                let bytes = try!(self.read_bytes(8));
+                self.push(Value::F64(BigEndian::read_f64(&bytes)));
+            }
+            BININT => {
+                let bytes = try!(self.read_bytes(4));
+                self.push(Value::I64(LittleEndian::read_i32(&bytes) as i64));
+            }
+            BININT1 => {
+                let byte = try!(self.read_byte());
+                self.push(Value::I64(byte as i64));
+            }
+            BININT2 => {
+                let bytes = try!(self.read_bytes(2));
+                self.push(Value::I64(LittleEndian::read_u16(&bytes) as i64));
+            }
+            LONG1 => {
+                let bytes = try!(self.read_u8_prefixed_bytes());
+                let long = self.decode_binary_long(bytes);
+                self.push(long);
+            }
+            LONG4 => {
+                let bytes = try!(self.read_i32_prefixed_bytes());
+                let long = self.decode_binary_long(bytes);
+                self.push(long);
+            }
 
-                // Lists
-                EMPTY_LIST => self.stack.push(Value::List(Vec::new())),
-                LIST => {
-                    let items = try!(self.pop_mark());
-                    self.stack.push(Value::List(items));
-                }
-                APPEND => {
-                    let value = try!(self.pop());
-                    try!(self.modify_list(|list| list.push(value)));
+            // Length-prefixed (byte)strings
+            SHORT_BINBYTES => {
+                let string = try!(self.read_u8_prefixed_bytes());
+                self.push(Value::Bytes(string));
+            }
+            BINBYTES => {
+                let string = try!(self.read_u32_prefixed_bytes());
+                self.push(Value::Bytes(string));
+            }
+            BINBYTES8 => {
+                let string = try!(self.read_u64_prefixed_bytes());
+                self.push(Value::Bytes(string));
+            }
+            BYTEARRAY8 => {
+                let string = try!(self.read_u64_prefixed_bytes());
+                self.push(Value::ByteArray(string));
+            }
+            NEXT_BUFFER => {
+                // Buffers are writable, like bytearrays, unless followed
+                // by READONLY_BUFFER.
+                match self.buffers.next() {
+                    Some(buffer) => self.push(Value::ByteArray(buffer)),
+                    None => return self.error(ErrorCode::MissingBuffer),
                 }
-                APPENDS => {
-                    let items = try!(self.pop_mark());
-                    try!(self.modify_list(|list| list.extend(items)));
+            }
+            READONLY_BUFFER => {
+                match self.stack.last_mut() {
+                    Some(&mut (ref mut top, _)) => if let Value::ByteArray(ref mut buffer) = *top {
+                        let buffer = mem::replace(buffer, Vec::new());
+                        *top = Value::Bytes(buffer);
+                    },
+                    None => return self.error(ErrorCode::StackUnderflow),
                 }
+            }
+            SHORT_BINSTRING => {
+                let string = try!(self.read_u8_prefixed_bytes());
+                let decoded = try!(self.decode_string(string));
+                self.push(decoded);
+            }
+            BINSTRING => {
+                let string = try!(self.read_i32_prefixed_bytes());
+                let decoded = try!(self.decode_string(string));
+                self.push(decoded);
+            }
+            SHORT_BINUNICODE => {
+                let string = try!(self.read_u8_prefixed_bytes());
+                let decoded = try!(self.decode_unicode(string));
+                self.push(decoded);
+            }
+            BINUNICODE => {
+                let string = try!(self.read_u32_prefixed_bytes());
+                let decoded = try!(self.decode_unicode(string));
+                self.push(decoded);
+            }
+            BINUNICODE8 => {
+                let string = try!(self.read_u64_prefixed_bytes());
+                let decoded = try!(self.decode_unicode(string));
+                self.push(decoded);
+            }
 
-                // Dicts
-                EMPTY_DICT => self.stack.push(Value::Dict(Vec::new())),
-                DICT => {
-                    let items = try!(self.pop_mark());
-                    let mut dict = Vec::with_capacity(items.len() / 2);
-                    Self::extend_dict(&mut dict, items);
-                    self.stack.push(Value::Dict(dict));
-                }
-                SETITEM => {
-                    let value = try!(self.pop());
-                    let key = try!(self.pop());
-                    try!(self.modify_dict(|dict| dict.push((key, value))));
-                }
-                SETITEMS => {
-                    let items = try!(self.pop_mark());
-                    try!(self.modify_dict(|dict| Self::extend_dict(dict, items)));
-                }
+            // Tuples
+            EMPTY_TUPLE => self.push(Value::Tuple(Vec::new())),
+            TUPLE1 => {
+                let item = try!(self.pop());
+                self.push(Value::Tuple(vec![item]));
+             }
+             TUPLE2 => {
+                let item2 = try!(self.pop());
+                let item1 = try!(self.pop());
+                self.push(Value::Tuple(vec![item1, item2]));
+             }
+             TUPLE3 => {
+                let item3 = try!(self.pop());
+                let item2 = try!(self.pop());
+                let item1 = try!(self.pop());
+                self.push(Value::Tuple(vec![item1, item2, item3]));
+            }
+            TUPLE => {
+                let items = try!(self.pop_mark());
+                self.push(Value::Tuple(items));
+            }
 
-                // Sets and frozensets
-                EMPTY_SET => self.stack.push(Value::Set(Vec::new())),
-                FROZENSET => {
-                    let items = try!(self.pop_mark());
-                    self.stack.push(Value::FrozenSet(items));
-                }
-                ADDITEMS => {
-                    let items = try!(self.pop_mark());
-                    try!(self.modify_set(|set| set.extend(items)));
-                }
+            // Lists
+            EMPTY_LIST => self.push(Value::List(Vec::new())),
+            LIST => {
+                let items = try!(self.pop_mark());
+                self.push(Value::List(items));
+            }
+            APPEND => {
+                let value = try!(self.pop());
+                try!(self.modify_list(|list| list.push(value)));
+                self.deepen_top();
+            }
+            APPENDS => {
+                let items = try!(self.pop_mark());
+                try!(self.modify_list(|list| list.extend(items)));
+                self.deepen_top();
+            }
 
-                // Arbitrary module globals, used here for unpickling set and frozenset
-                // from protocols < 4
-                GLOBAL => {
-                    let modname = try!(self.read_line());
-                    let globname = try!(self.read_line());
-                    let value = try!(self.decode_global(modname, globname));
-                    self.stack.push(value);
-                }
-                STACK_GLOBAL => {
-                    let globname = match try!(self.pop()) {
-                        Value::String(string) => string.into_bytes(),
-                        other => return Self::stack_error("string", &other, self.pos),
-                    };
-                    let modname = match try!(self.pop()) {
-                        Value::String(string) => string.into_bytes(),
-                        other => return Self::stack_error("string", &other, self.pos),
-                    };
-                    let value = try!(self.decode_global(modname, globname));
-                    self.stack.push(value);
+            // Dicts
+            EMPTY_DICT => self.push(Value::Dict(Vec::new())),
+            DICT => {
+                let items = try!(self.pop_mark());
+                let mut dict = Vec::with_capacity(items.len() / 2);
+                Self::extend_dict(&mut dict, items);
+                self.push(Value::Dict(dict));
+            }
+            SETITEM => {
+                let value = try!(self.pop());
+                let key = try!(self.pop());
+                try!(self.modify_dict(|dict| dict.push((key, value))));
+                self.deepen_top();
+            }
+            SETITEMS => {
+                let items = try!(self.pop_mark());
+                try!(self.modify_dict(|dict| Self::extend_dict(dict, items)));
+                self.deepen_top();
+            }
+
+            // Sets and frozensets
+            EMPTY_SET => self.push(Value::Set(Vec::new())),
+            FROZENSET => {
+                let items = try!(self.pop_mark());
+                self.push(Value::FrozenSet(items));
+            }
+            ADDITEMS => {
+                let items = try!(self.pop_mark());
+                try!(self.modify_set(|set| set.extend(items)));
+                self.deepen_top();
+            }
+
+            // Arbitrary module globals, used here for unpickling set and frozenset
+            // from protocols < 4
+            GLOBAL => {
+                let modname = try!(self.read_line());
+                let globname = try!(self.read_line());
+                let value = try!(self.decode_global(modname, globname));
+                self.push(value);
+            }
+            STACK_GLOBAL => {
+                let globname = match try!(self.pop_resolve()) {
+                    Value::String(string) => string.into_bytes(),
+                    other => return Self::stack_error("string", &other, self.pos),
+                };
+                let modname = match try!(self.pop_resolve()) {
+                    Value::String(string) => string.into_bytes(),
+                    other => return Self::stack_error("string", &other, self.pos),
+                };
+                let value = try!(self.decode_global(modname, globname));
+                self.popped = 0;
+                self.push(value);
+            }
+
+            // Module globals from the extension registry
+            EXT1 => {
+                let code = try!(self.read_byte());
+                let value = try!(self.decode_extension(code as u32));
+                self.push(value);
+            }
+            EXT2 => {
+                let bytes = try!(self.read_bytes(2));
+                let value = try!(self.decode_extension(LittleEndian::read_u16(&bytes) as u32));
+                self.push(value);
+            }
+            EXT4 => {
+                let bytes = try!(self.read_bytes(4));
+                let value = try!(self.decode_extension(LittleEndian::read_u32(&bytes)));
+                self.push(value);
+            }
+            REDUCE => {
+                let argtuple = match try!(self.pop_resolve()) {
+                    Value::Tuple(args) => args,
+                    other => return Self::stack_error("tuple", &other, self.pos),
+                };
+                let depth = self.unwrap_depth();
+                let global = try!(self.pop_resolve());
+                self.popped = depth;
+                try!(self.reduce_global(global, argtuple));
+            }
+
+            // Class instances
+            NEWOBJ => {
+                let args = try!(self.pop_tuple());
+                let depth = self.unwrap_depth();
+                let class = try!(self.pop_class());
+                self.popped = depth;
+                self.push_object(class, args, Vec::new());
+            }
+            NEWOBJ_EX => {
+                let kwargs = match try!(self.pop_resolve()) {
+                    Value::Dict(kwargs) => kwargs,
+                    other => return Self::stack_error("dict", &other, self.pos),
+                };
+                let args = try!(self.pop_tuple());
+                let depth = self.unwrap_depth();
+                let class = try!(self.pop_class());
+                self.popped = depth;
+                self.push_object(class, args, kwargs);
+            }
+            OBJ => {
+                let mut args = try!(self.pop_mark());
+                if args.is_empty() {
+                    return self.error(ErrorCode::StackUnderflow);
                 }
-                REDUCE => {
-                    let argtuple = match try!(self.pop_resolve()) {
-                        Value::Tuple(args) => args,
-                        other => return Self::stack_error("tuple", &other, self.pos),
-                    };
-                    let global = try!(self.pop_resolve());
-                    try!(self.reduce_global(global, argtuple));
+                let class = match self.resolve(Some(args.remove(0))) {
+                    Some(Value::Global(Global::Other(modname, globname))) => (modname, globname),
+                    Some(other) => return Self::stack_error("class", &other, self.pos),
+                    None => return self.error(ErrorCode::StackUnderflow),
+                };
+                self.push_object(class, args, Vec::new());
+            }
+            INST => {
+                let modname = try!(self.read_line());
+                let globname = try!(self.read_line());
+                let class = match try!(self.decode_global(modname, globname)) {
+                    Value::Global(Global::Other(modname, globname)) => (modname, globname),
+                    other => return Self::stack_error("class", &other, self.pos),
+                };
+                let args = try!(self.pop_mark());
+                self.push_object(class, args, Vec::new());
+            }
+            BUILD => {
+                let new_state = try!(self.pop());
+                let pos = self.pos;
+                {
+                    let top = try!(self.top());
+                    match *top {
+                        Value::Object(ref mut obj) => obj.state = Some(new_state),
+                        // Attributes of an OrderedDict, like the `_metadata` of
+                        // PyTorch state dicts, are kept by making it an object
+                        // with the dict as its argument.
+                        Value::OrderedDict(_) => {
+                            let dict = mem::replace(top, Value::None);
+                            *top = Value::Object(Box::new(Object {
+                                class: ("collections".into(), "OrderedDict".into()),
+                                args: vec![dict],
+                                kwargs: Vec::new(),
+                                state: Some(new_state),
+                            }));
+                        }
+                        _ => return Self::stack_error("object", top, pos),
+                    }
                 }
+                // The object may be a new level above the dict.
+                let depth = self.stack.last().map_or(0, |&(_, depth)| depth);
+                self.popped = cmp::max(self.popped, depth);
+                self.deepen_top();
+            }
 
-                // Unsupported (mostly class instance building) opcodes
-                code => return self.error(ErrorCode::Unsupported(code as char))
+            // Objects stored outside of the pickle
+            PERSID => {
+                let line = try!(self.read_line());
+                let pid = match String::from_utf8(line) {
+                    Ok(pid) => pid,
+                    Err(_) => return self.error(ErrorCode::StringNotUTF8),
+                };
+                try!(self.persistent_load(PERSID, value::Value::String(pid)));
             }
+            BINPERSID => {
+                let pid = try!(self.pop_resolve());
+                let pid = globals_to_names(try!(self.resolve_deep(pid, &mut Vec::new())));
+                let pid = try!(self.deserialize_value(pid));
+                try!(self.persistent_load(BINPERSID, pid));
+            }
+
+            code => return self.error(ErrorCode::Unsupported(code as char))
+        }
+        // Values are checked for their nesting depth as they are built, so
+        // that deeply nested values never exist.
+        match self.stack.last() {
+            Some(&(_, depth)) if depth > self.options.max_depth =>
+                self.error(ErrorCode::DepthLimitExceeded),
+            _ => Ok(None),
         }
     }
 
+    // Push a value made by the current opcode.  It is nested one level deeper
+    // than the values that the opcode popped.
+    fn push(&mut self, value: Value) {
+        let depth = self.popped + 1;
+        self.stack.push((value, depth));
+    }
+
     // Pop the stack top item.
     fn pop(&mut self) -> Result<Value> {
         match self.stack.pop() {
-            Some(v) => Ok(v),
+            Some((v, depth)) => {
+                self.popped = cmp::max(self.popped, depth);
+                Ok(v)
+            }
             None    => self.error(ErrorCode::StackUnderflow)
         }
     }
 
     // Pop the stack top item, and resolve it if it is a memo reference.
     fn pop_resolve(&mut self) -> Result<Value> {
-        let top = self.stack.pop();
-        match self.resolve(top) {
+        let top = try!(self.pop());
+        match self.resolve(Some(top)) {
             Some(v) => Ok(v),
             None    => self.error(ErrorCode::StackUnderflow)
         }
     }
 
+    // Get the nesting depth of the items of the tuple or dict just popped,
+    // which are passed on without the container.
+    fn unwrap_depth(&self) -> usize {
+        self.popped.saturating_sub(1)
+    }
+
+    // Update the nesting depth of the stack top item, after the values
+    // popped by the current opcode were added to it.
+    fn deepen_top(&mut self) {
+        let depth = self.popped + 1;
+        if let Some(&mut (ref value, ref mut top_depth)) = self.stack.last_mut() {
+            *top_depth = cmp::max(*top_depth, depth);
+            if let Value::MemoRef(id) = *value {
+                if let Some(&mut (_, _, ref mut memo_depth)) = self.memo.get_mut(&id) {
+                    *memo_depth = cmp::max(*memo_depth, depth);
+                }
+            }
+        }
+    }
+
+    // Pop the stack top item, which must be a tuple.
+    fn pop_tuple(&mut self) -> Result<Vec<Value>> {
+        match try!(self.pop_resolve()) {
+            Value::Tuple(items) => Ok(items),
+            other => Self::stack_error("tuple", &other, self.pos),
+        }
+    }
+
+    // Pop the stack top item, which must be a class reference.
+    fn pop_class(&mut self) -> Result<(String, String)> {
+        match try!(self.pop_resolve()) {
+            Value::Global(Global::Other(modname, globname)) => Ok((modname, globname)),
+            other => Self::stack_error("class", &other, self.pos),
+        }
+    }
+
+    // Push a new class instance without state.
+    fn push_object(&mut self, class: (String, String), args: Vec<Value>,
+                   kwargs: Vec<(Value, Value)>) {
+        self.push(Value::Object(Box::new(Object {
+            class: class,
+            args: args,
+            kwargs: kwargs,
+            state: None,
+        })));
+    }
+
     // Pop all topmost stack items until the next MARK.
     fn pop_mark(&mut self) -> Result<Vec<Value>> {
         match self.stacks.pop() {
-            Some(new) => Ok(mem::replace(&mut self.stack, new)),
+            Some(new) => {
+                self.marked -= new.len();
+                let items = mem::replace(&mut self.stack, new);
+                let mut values = Vec::with_capacity(items.len());
+                for (value, depth) in items {
+                    self.popped = cmp::max(self.popped, depth);
+                    values.push(value);
+                }
+                Ok(values)
+            }
             None      => self.error(ErrorCode::StackUnderflow)
         }
     }
@@ -398,79 +1234,257 @@ impl<R: Read> Deserializer<R> {
         match self.stack.last_mut() {
             // Since some operations like APPEND do things to the stack top, we
             // need to provide the reference to the "real" object here, not the
-            // MemoRef variant.
-            Some(&mut Value::MemoRef(n)) =>
+            // MemoRef variant.  It is copied first if it is shared.
+            Some(&mut (Value::MemoRef(n), _)) =>
                 self.memo.get_mut(&n)
-                         .map(|&mut (ref mut v, _)| v)
+                         .map(|&mut (ref mut v, _, _)| Rc::make_mut(v))
                          .ok_or_else(|| Error::Syntax(ErrorCode::MissingMemo(n))),
-            Some(other_value) => Ok(other_value),
+            Some(&mut (ref mut other_value, _)) => Ok(other_value),
             None => Err(Error::Eval(ErrorCode::StackUnderflow, self.pos)),
         }
     }
 
     // Pushes a memo reference on the stack, and increases the usage counter.
     fn push_memo_ref(&mut self, memo_id: MemoId) -> Result<()> {
-        self.stack.push(Value::MemoRef(memo_id));
-        match self.memo.get_mut(&memo_id) {
-            Some(&mut (_, ref mut count)) => { *count = *count + 1; Ok(()) }
-            None => Err(Error::Eval(ErrorCode::MissingMemo(memo_id), self.pos)),
+        if let Some(n) = self.memo_gets.as_mut().and_then(|gets| gets.get_mut(&memo_id)) {
+            *n = n.saturating_sub(1);
         }
+        let depth = match self.memo.get_mut(&memo_id) {
+            Some(&mut (_, ref mut count, depth)) => { *count = *count + 1; depth }
+            None => return Err(Error::Eval(ErrorCode::MissingMemo(memo_id), self.pos)),
+        };
+        self.stack.push((Value::MemoRef(memo_id), depth));
+        Ok(())
     }
 
     // Memoize the current stack top with the given ID.  Moves the actual
     // object into the memo, and saves a reference on the stack instead.
     fn memoize(&mut self, memo_id: MemoId) -> Result<()> {
-        let mut item = try!(self.pop());
-        if let Value::MemoRef(id) = item {
-            // TODO: is this even possible?
-            item = match self.memo.get(&id) {
-                Some(&(ref v, _)) => v.clone(),
+        let item = match try!(self.pop()) {
+            // The value is shared by both memo entries.
+            Value::MemoRef(id) => match self.resolve_shared(id) {
+                Some(value) => value,
                 None => return Err(Error::Eval(ErrorCode::MissingMemo(id), self.pos)),
-            };
+            },
+            item => Rc::new(item),
+        };
+        let depth = self.popped;
+        if self.memo.len() >= self.options.max_memo_size && !self.memo.contains_key(&memo_id) {
+            return self.error(ErrorCode::MemoLimitExceeded);
         }
-        self.memo.insert(memo_id, (item, 1));
-        self.stack.push(Value::MemoRef(memo_id));
+        self.memo.insert(memo_id, (item, 1, depth));
+        self.stack.push((Value::MemoRef(memo_id), depth));
         Ok(())
     }
 
+    // Check whether the rest of the pickle can still refer to a memo entry.
+    // While parsing, this is only known if the references were counted by
+    // `scan_toplevel`.
+    fn memo_needed(&self, id: MemoId, parsed: bool) -> bool {
+        match self.memo_gets {
+            Some(ref gets) => gets.get(&id).map_or(false, |&n| n > 0),
+            None => !parsed,
+        }
+    }
+
+    // Resolve memo reference during stream decoding, sharing the value with
+    // the memo.
+    fn resolve_shared(&mut self, id: MemoId) -> Option<Rc<Value>> {
+        let count = match self.memo.get_mut(&id) {
+            Some(&mut (_, ref mut count, _)) => { *count = *count - 1; *count }
+            None => return None,
+        };
+        // Unless the references were counted, we can't remove it from the
+        // memo here, since we haven't decoded the whole stream yet and there
+        // may be further references to the value.
+        if count <= 0 && !self.memo_needed(id, false) {
+            return self.memo.remove(&id).map(|(value, _, _)| value);
+        }
+        self.memo.get(&id).map(|&(ref value, _, _)| value.clone())
+    }
+
     // Resolve memo reference during stream decoding.
     fn resolve(&mut self, maybe_memo: Option<Value>) -> Option<Value> {
         match maybe_memo {
-            Some(Value::MemoRef(id)) => {
-                self.memo.get_mut(&id).map(|&mut (ref val, ref mut count)| {
-                    // We can't remove it from the memo here, since we haven't
-                    // decoded the whole stream yet and there may be further
-                    // references to the value.
-                    *count = *count - 1;
-                    val.clone()
-                })
-            },
+            Some(Value::MemoRef(id)) => self.resolve_shared(id).map(|value| self.unshare(value)),
             other => other
         }
     }
 
-    // Resolve memo reference during Value deserializing.
+    // Get a memoized value for modification, which requires a copy if it is
+    // still shared.
+    fn unshare(&mut self, value: Rc<Value>) -> Value {
+        match Rc::try_unwrap(value) {
+            Ok(value) => value,
+            Err(value) => {
+                let copy = (*value).clone();
+                self.add_memo_refs(&copy);
+                copy
+            }
+        }
+    }
+
+    // Replace all memo references within a value by copies of the memoized
+    // values, so that it can be converted while the stream is still decoded.
+    fn resolve_deep(&mut self, value: Value, visiting: &mut Vec<MemoId>) -> Result<Value> {
+        self.nested(|slf| slf.resolve_deep_contents(value, visiting))
+    }
+
+    fn resolve_deep_contents(&mut self, value: Value, visiting: &mut Vec<MemoId>)
+                             -> Result<Value> {
+        fn resolve_all<R: Read>(slf: &mut Deserializer<R>, values: Vec<Value>,
+                                visiting: &mut Vec<MemoId>) -> Result<Vec<Value>> {
+            values.into_iter().map(|v| slf.resolve_deep(v, visiting)).collect()
+        }
+        fn resolve_pairs<R: Read>(slf: &mut Deserializer<R>, pairs: Vec<(Value, Value)>,
+                                  visiting: &mut Vec<MemoId>) -> Result<Vec<(Value, Value)>> {
+            pairs.into_iter().map(|(k, v)| Ok((try!(slf.resolve_deep(k, visiting)),
+                                               try!(slf.resolve_deep(v, visiting))))).collect()
+        }
+        Ok(match value {
+            Value::MemoRef(id) => {
+                if visiting.contains(&id) {
+                    return Err(Error::Syntax(ErrorCode::Recursive));
+                }
+                let value = match self.resolve(Some(Value::MemoRef(id))) {
+                    Some(value) => value,
+                    None => return self.error(ErrorCode::MissingMemo(id)),
+                };
+                visiting.push(id);
+                let result = self.resolve_deep(value, visiting);
+                visiting.pop();
+                try!(result)
+            }
+            Value::List(v) => Value::List(try!(resolve_all(self, v, visiting))),
+            Value::Tuple(v) => Value::Tuple(try!(resolve_all(self, v, visiting))),
+            Value::Set(v) => Value::Set(try!(resolve_all(self, v, visiting))),
+            Value::FrozenSet(v) => Value::FrozenSet(try!(resolve_all(self, v, visiting))),
+            Value::Dict(v) => Value::Dict(try!(resolve_pairs(self, v, visiting))),
+            Value::OrderedDict(v) => Value::OrderedDict(try!(resolve_pairs(self, v, visiting))),
+            Value::DefaultDict(d) => {
+                let d = *d;
+                Value::DefaultDict(Box::new(DefaultDict {
+                    factory: d.factory,
+                    items: try!(resolve_pairs(self, d.items, visiting)),
+                }))
+            }
+            Value::Deque(v, maxlen) => Value::Deque(try!(resolve_all(self, v, visiting)), maxlen),
+            Value::Counter(v) => Value::Counter(try!(resolve_pairs(self, v, visiting))),
+            Value::Object(obj) => {
+                let obj = *obj;
+                let state = match obj.state {
+                    Some(state) => Some(try!(self.resolve_deep(state, visiting))),
+                    None => None,
+                };
+                Value::Object(Box::new(Object {
+                    class: obj.class,
+                    args: try!(resolve_all(self, obj.args, visiting)),
+                    kwargs: try!(resolve_pairs(self, obj.kwargs, visiting)),
+                    state: state,
+                }))
+            }
+            other => other,
+        })
+    }
+
+    // Increase the usage counters of all memo references within a value.
+    // This is required whenever a copy of a memoized value is made.
+    fn add_memo_refs(&mut self, value: &Value) {
+        match *value {
+            Value::MemoRef(id) => {
+                if let Some(&mut (_, ref mut count, _)) = self.memo.get_mut(&id) {
+                    *count = *count + 1;
+                }
+            }
+            Value::List(ref items) | Value::Tuple(ref items) |
+            Value::Set(ref items) | Value::FrozenSet(ref items) | Value::Deque(ref items, _) => {
+                for item in items {
+                    self.add_memo_refs(item);
+                }
+            }
+            Value::Dict(ref items) | Value::OrderedDict(ref items) | Value::Counter(ref items) => {
+                for &(ref key, ref value) in items {
+                    self.add_memo_refs(key);
+                    self.add_memo_refs(value);
+                }
+            }
+            Value::DefaultDict(ref d) => {
+                for &(ref key, ref value) in &d.items {
+                    self.add_memo_refs(key);
+                    self.add_memo_refs(value);
+                }
+            }
+            Value::Object(ref obj) => {
+                for arg in &obj.args {
+                    self.add_memo_refs(arg);
+                }
+                for &(ref key, ref value) in &obj.kwargs {
+                    self.add_memo_refs(key);
+                    self.add_memo_refs(value);
+                }
+                if let Some(ref state) = obj.state {
+                    self.add_memo_refs(state);
+                }
+            }
+            _ => { }
+        }
+    }
+
+    // Resolve memo reference during Value deserializing.  The value is still
+    // shared if there are references left.
     fn resolve_recursive<T, F>(&mut self, id: MemoId, f: F) -> Result<T>
-        where F: Fn(&mut Self, Value) -> Result<T>
+        where F: FnOnce(&mut Self, Rc<Value>) -> Result<T>
     {
         // Take the value from the memo while visiting it.  This prevents us
         // from trying to depickle recursive structures, which we can't do
         // because our Values aren't references.
-        let (value, mut count) = match self.memo.remove(&id) {
+        if self.lazy_memo == Some(id) {
+            return Err(Error::Syntax(ErrorCode::Recursive));
+        }
+        let (value, mut count, depth) = match self.memo.remove(&id) {
             Some(entry) => entry,
             None => return Err(Error::Syntax(ErrorCode::Recursive)),
         };
         count -= 1;
-        if count <= 0 {
+        // While parsing lazily, further references may follow.
+        if count <= 0 && !self.memo_needed(id, !self.lazy) {
             f(self, value)
             // No need to put it back.
         } else {
             let result = f(self, value.clone());
-            self.memo.insert(id, (value, count));
+            self.memo.insert(id, (value, count, depth));
             result
         }
     }
 
+    /// Check if there is no more data in the stream.
+    pub fn at_end(&mut self) -> Result<bool> {
+        match self.rdr.fill_buf() {
+            Ok(buf) => Ok(buf.is_empty()),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
+    // Forget all state of the previous pickle, so that the next one in the
+    // stream can be decoded.
+    fn reset(&mut self) {
+        let mut values: Vec<_> = self.value.take().into_iter().collect();
+        // Values shared by several memo entries are dropped with the last.
+        values.extend(mem::replace(&mut self.memo, BTreeMap::new())
+                          .into_iter().filter_map(|(_, entry)| Rc::try_unwrap(entry.0).ok()));
+        for stack in self.stacks.drain(..).chain(Some(self.stack.split_off(0))) {
+            values.extend(stack.into_iter().map(|(value, _)| value));
+        }
+        drop_values(values);
+        self.converted.clear();
+        self.marked = 0;
+        self.depth = 0;
+        self.allocated = 0;
+        self.lazy = false;
+        self.lazy_memo = None;
+        self.memo_gets = None;
+    }
+
     /// Assert that we reached the end of the stream.
     pub fn end(&mut self) -> Result<()> {
         let mut buf = [0];
@@ -483,17 +1497,32 @@ impl<R: Read> Deserializer<R> {
 
     fn read_line(&mut self) -> Result<Vec<u8>> {
         let mut buf = Vec::with_capacity(16);
-        match self.rdr.read_until(b'\n', &mut buf) {
+        // Allow for the line ending in addition to the maximum length.
+        let limit = self.options.max_string_len.saturating_add(2);
+        match self.rdr.by_ref().take(limit as u64).read_until(b'\n', &mut buf) {
             Ok(_) => {
                 self.pos += buf.len();
-                buf.pop(); // remove newline
-                if buf.last() == Some(&b'\r') { buf.pop(); }
+                if buf.last() == Some(&b'\n') {
+                    buf.pop();
+                    if buf.last() == Some(&b'\r') { buf.pop(); }
+                }
+                if buf.len() > self.options.max_string_len {
+                    return self.error(ErrorCode::LengthLimitExceeded(buf.len() as u64));
+                }
+                try!(self.account(buf.len()));
                 Ok(buf)
             },
             Err(err) => Err(Error::Io(err))
         }
     }
 
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        match self.rdr.fill_buf() {
+            Ok(buf) => Ok(buf.first().cloned()),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
     #[inline]
     fn read_byte(&mut self) -> Result<u8> {
         let mut buf = [0];
@@ -514,28 +1543,59 @@ impl<R: Read> Deserializer<R> {
         }
     }
 
+    // Read a string argument with the given length, checking the limits
+    // before anything is allocated.
+    fn read_string_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        if len > self.options.max_string_len as u64 || len > usize::max_value() as u64 {
+            return self.error(ErrorCode::LengthLimitExceeded(len));
+        }
+        try!(self.account(len as usize));
+        self.read_bytes(len as usize)
+    }
+
     fn read_i32_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
         let lenbytes = try!(self.read_bytes(4));
         match LittleEndian::read_i32(&lenbytes) {
             0          => Ok(vec![]),
             l if l < 0 => self.error(ErrorCode::NegativeLength),
-            l          => self.read_bytes(l as usize)
+            l          => self.read_string_bytes(l as u64)
         }
     }
 
     fn read_u64_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
         let lenbytes = try!(self.read_bytes(8));
-        self.read_bytes(LittleEndian::read_u64(&lenbytes) as usize)
+        self.read_string_bytes(LittleEndian::read_u64(&lenbytes))
     }
 
     fn read_u32_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
         let lenbytes = try!(self.read_bytes(4));
-        self.read_bytes(LittleEndian::read_u32(&lenbytes) as usize)
+        self.read_string_bytes(LittleEndian::read_u32(&lenbytes) as u64)
     }
 
     fn read_u8_prefixed_bytes(&mut self) -> Result<Vec<u8>> {
         let lenbyte = try!(self.read_byte());
-        self.read_bytes(lenbyte as usize)
+        self.read_string_bytes(lenbyte as u64)
+    }
+
+    // Count the given number of bytes against the allocation limit.
+    fn account(&mut self, bytes: usize) -> Result<()> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        if self.allocated > self.options.max_alloc {
+            return self.error(ErrorCode::AllocationLimitExceeded);
+        }
+        Ok(())
+    }
+
+    // Convert a value nested one level deeper, checking the limits.
+    fn nested<T, F>(&mut self, f: F) -> Result<T> where F: FnOnce(&mut Self) -> Result<T> {
+        if self.depth >= self.options.max_depth {
+            return self.error(ErrorCode::DepthLimitExceeded);
+        }
+        try!(self.account(mem::size_of::<value::Value>()));
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
     }
 
     // Parse an expected ASCII literal from the stream or raise an error.
@@ -572,83 +1632,25 @@ impl<R: Read> Deserializer<R> {
     // Decode an escaped string.  These are encoded with "normal" Python string
     // escape rules.
     fn decode_escaped_string(&self, slice: &[u8]) -> Result<Value> {
-        // Remove quotes if they appear.
-        let slice = if (slice.len() >= 2) &&
-            (slice[0] == slice[slice.len() - 1]) &&
-            (slice[0] == b'"' || slice[0] == b'\'')
-        {
-            &slice[1..slice.len() - 1]
-        } else {
-            slice
-        };
-        let mut result = Vec::with_capacity(slice.len());
-        let mut iter = slice.iter();
-        while let Some(&b) = iter.next() {
-            match b {
-                b'\\' => match iter.next() {
-                    Some(&b'\\') => result.push(b'\\'),
-                    Some(&b'a') => result.push(b'\x07'),
-                    Some(&b'b') => result.push(b'\x08'),
-                    Some(&b't') => result.push(b'\x09'),
-                    Some(&b'n') => result.push(b'\x0a'),
-                    Some(&b'v') => result.push(b'\x0b'),
-                    Some(&b'f') => result.push(b'\x0c'),
-                    Some(&b'r') => result.push(b'\x0d'),
-                    Some(&b'x') => {
-                        match iter.next()
-                                  .and_then(|&ch1| (ch1 as char).to_digit(16))
-                                  .and_then(|v1| iter.next()
-                                            .and_then(|&ch2| (ch2 as char).to_digit(16))
-                                            .map(|v2| 16*(v1 as u8) + (v2 as u8)))
-                        {
-                            Some(v) => result.push(v),
-                            None => return self.error(ErrorCode::InvalidLiteral(slice.into()))
-                        }
-                    },
-                    _ => return self.error(ErrorCode::InvalidLiteral(slice.into())),
-                },
-                _ => result.push(b)
-            }
+        match unescape_string(slice) {
+            Some(result) => self.decode_string(result),
+            None => self.error(ErrorCode::InvalidLiteral(slice.into())),
         }
-        self.decode_string(result)
     }
 
     // Decode escaped Unicode strings. These are encoded with "raw-unicode-escape",
     // which only knows the \uXXXX and \UYYYYYYYY escapes. The backslash is escaped
     // in this way, too.
     fn decode_escaped_unicode(&self, s: &[u8]) -> Result<Value> {
-        let mut result = String::with_capacity(s.len());
-        let mut iter = s.iter();
-        while let Some(&b) = iter.next() {
-            match b {
-                b'\\' => {
-                    let nescape = match iter.next() {
-                        Some(&b'u') => 4,
-                        Some(&b'U') => 8,
-                        _ => return self.error(ErrorCode::InvalidLiteral(s.into())),
-                    };
-                    let mut accum = 0;
-                    for _i in 0..nescape {
-                        accum *= 16;
-                        match iter.next().and_then(|&ch| (ch as char).to_digit(16)) {
-                            Some(v) => accum += v,
-                            None => return self.error(ErrorCode::InvalidLiteral(s.into()))
-                        }
-                    }
-                    match char::from_u32(accum) {
-                        Some(v) => result.push(v),
-                        None => return self.error(ErrorCode::InvalidLiteral(s.into()))
-                    }
-                }
-                _ => result.push(b as char)
-            }
+        match unescape_unicode(s) {
+            Some(result) => Ok(Value::String(result)),
+            None => self.error(ErrorCode::InvalidLiteral(s.into())),
         }
-        Ok(Value::String(result))
     }
 
     // Decode a string - either as Unicode or as bytes.
     fn decode_string(&self, string: Vec<u8>) -> Result<Value> {
-        if self.decode_strings {
+        if self.options.decode_strings {
             self.decode_unicode(string)
         } else {
             Ok(Value::Bytes(string))
@@ -665,24 +1667,20 @@ impl<R: Read> Deserializer<R> {
 
     // Decode a binary-encoded long integer.
     fn decode_binary_long(&self, bytes: Vec<u8>) -> Value {
-        // BigInt::from_bytes_le doesn't like a sign bit in the bytes, therefore
-        // we have to extract that ourselves and do the two-s complement.
-        let negative = !bytes.is_empty() && (bytes[bytes.len() - 1] & 0x80 != 0);
-        let mut val = BigInt::from_bytes_le(Sign::Plus, &bytes);
-        if negative {
-            val = val - (BigInt::from(1) << (bytes.len() * 8));
-        }
-        Value::Int(val)
+        Value::Int(decode_long(&bytes))
     }
 
-    // Modify the stack-top list.
+    // Modify the stack-top list or deque.
     fn modify_list<F>(&mut self, f: F) -> Result<()> where F: FnOnce(&mut Vec<Value>) {
         let pos = self.pos;
         let top = try!(self.top());
-        if let Value::List(ref mut list) = *top {
-            Ok(f(list))
-        } else {
-            Self::stack_error("list", top, pos)
+        match *top {
+            Value::List(ref mut list) => Ok(f(list)),
+            Value::Deque(ref mut items, maxlen) => {
+                f(items);
+                Ok(trim_deque(items, maxlen))
+            }
+            _ => Self::stack_error("list", top, pos),
         }
     }
 
@@ -697,16 +1695,17 @@ impl<R: Read> Deserializer<R> {
         }
     }
 
-    // Modify the stack-top dict.
+    // Modify the stack-top dict, or one of the dict types from `collections`.
     fn modify_dict<F>(&mut self, f: F) -> Result<()>
         where F: FnOnce(&mut Vec<(Value, Value)>)
     {
         let pos = self.pos;
         let top = try!(self.top());
-        if let Value::Dict(ref mut dict) = *top {
-            Ok(f(dict))
-        } else {
-            Self::stack_error("dict", top, pos)
+        match *top {
+            Value::Dict(ref mut dict) | Value::OrderedDict(ref mut dict) |
+            Value::Counter(ref mut dict) => Ok(f(dict)),
+            Value::DefaultDict(ref mut dict) => Ok(f(&mut dict.items)),
+            _ => Self::stack_error("dict", top, pos),
         }
     }
 
@@ -725,29 +1724,96 @@ impl<R: Read> Deserializer<R> {
 
     // Push the Value::Global referenced by modname and globname.
     fn decode_global(&mut self, modname: Vec<u8>, globname: Vec<u8>) -> Result<Value> {
+        if let Some(ref globals) = self.options.safe_globals {
+            let allowed = match (str::from_utf8(&modname), str::from_utf8(&globname)) {
+                (Ok(m), Ok(g)) => globals.contains(&(m.into(), g.into())),
+                _ => false,
+            };
+            if !allowed {
+                return self.error(ErrorCode::UnsupportedGlobal(modname, globname));
+            }
+        }
         let value = match (&*modname, &*globname) {
             (b"_codecs", b"encode") => Value::Global(Global::Encode),
             (b"__builtin__", b"set") | (b"builtins", b"set") =>
                 Value::Global(Global::Set),
             (b"__builtin__", b"frozenset") | (b"builtins", b"frozenset") =>
                 Value::Global(Global::Frozenset),
-            _ => return self.error(ErrorCode::UnsupportedGlobal(modname, globname)),
+            (b"copy_reg", b"_reconstructor") | (b"copyreg", b"_reconstructor") =>
+                Value::Global(Global::Reconstructor),
+            (b"copy_reg", b"__newobj__") | (b"copyreg", b"__newobj__") =>
+                Value::Global(Global::NewObj),
+            (b"copy_reg", b"__newobj_ex__") | (b"copyreg", b"__newobj_ex__") =>
+                Value::Global(Global::NewObjEx),
+            (b"__builtin__", b"complex") | (b"builtins", b"complex") =>
+                Value::Global(Global::Complex),
+            (b"__builtin__", b"bytearray") | (b"builtins", b"bytearray") =>
+                Value::Global(Global::ByteArray),
+            (b"decimal", b"Decimal") => Value::Global(Global::Decimal),
+            (b"fractions", b"Fraction") => Value::Global(Global::Fraction),
+            (b"datetime", b"date") => Value::Global(Global::Date),
+            (b"datetime", b"time") => Value::Global(Global::Time),
+            (b"datetime", b"datetime") => Value::Global(Global::DateTime),
+            (b"datetime", b"timedelta") => Value::Global(Global::TimeDelta),
+            (b"datetime", b"timezone") => Value::Global(Global::TimeZone),
+            (b"collections", b"OrderedDict") => Value::Global(Global::OrderedDict),
+            (b"collections", b"defaultdict") => Value::Global(Global::DefaultDict),
+            (b"collections", b"deque") => Value::Global(Global::Deque),
+            (b"collections", b"Counter") => Value::Global(Global::Counter),
+            #[cfg(feature = "numpy")]
+            (b"numpy.core.multiarray", b"_reconstruct") |
+            (b"numpy._core.multiarray", b"_reconstruct") => Value::Global(Global::NdArray),
+            #[cfg(feature = "numpy")]
+            (b"numpy.core.numeric", b"_frombuffer") |
+            (b"numpy._core.numeric", b"_frombuffer") =>
+                Value::Global(Global::NdArrayFromBuffer),
+            #[cfg(feature = "numpy")]
+            (b"numpy", b"dtype") => Value::Global(Global::DType),
+            _ => match (String::from_utf8(modname), String::from_utf8(globname)) {
+                (Ok(modname), Ok(globname)) => Value::Global(Global::Other(modname, globname)),
+                _ => return self.error(ErrorCode::StringNotUTF8),
+            },
         };
         Ok(value)
     }
 
+    // Push the Value::Global registered under the extension code.
+    fn decode_extension(&mut self, code: u32) -> Result<Value> {
+        let (modname, globname) = match self.options.extensions.get(&code) {
+            Some(&(ref modname, ref globname)) =>
+                (modname.clone().into_bytes(), globname.clone().into_bytes()),
+            None => return self.error(ErrorCode::UnknownExtension(code)),
+        };
+        self.decode_global(modname, globname)
+    }
+
+    // Push the object loaded for the persistent ID by the user's loader.
+    fn persistent_load(&mut self, code: u8, pid: value::Value) -> Result<()> {
+        let pos = self.pos;
+        let result = match self.persistent_load {
+            Some(ref loader) => loader.persistent_load(pid),
+            None => return self.error(ErrorCode::Unsupported(code as char)),
+        };
+        let result = try!(result.map_err(|err| match err {
+            Error::Syntax(code) => Error::Eval(code, pos),
+            other => other,
+        }));
+        self.push(Value::from_value(result));
+        Ok(())
+    }
+
     // Handle the REDUCE opcode for the few Global objects we support.
     fn reduce_global(&mut self, global: Value, mut argtuple: Vec<Value>) -> Result<()> {
         match global {
             Value::Global(Global::Set) => {
                 match self.resolve(argtuple.pop()) {
-                    Some(Value::List(items)) => Ok(self.stack.push(Value::Set(items))),
+                    Some(Value::List(items)) => Ok(self.push(Value::Set(items))),
                     _ => self.error(ErrorCode::InvalidValue("set() arg".into())),
                 }
             }
             Value::Global(Global::Frozenset) => {
                 match self.resolve(argtuple.pop()) {
-                    Some(Value::List(items)) => Ok(self.stack.push(Value::FrozenSet(items))),
+                    Some(Value::List(items)) => Ok(self.push(Value::FrozenSet(items))),
                     _ => self.error(ErrorCode::InvalidValue("set() arg".into())),
                 }
             }
@@ -763,16 +1829,359 @@ impl<R: Read> Deserializer<R> {
                         // encoded bytes.  It never contains codepoints
                         // above 0xff.
                         let bytes = s.chars().map(|ch| ch as u8).collect();
-                        self.stack.push(Value::Bytes(bytes));
+                        self.push(Value::Bytes(bytes));
                         Ok(())
                     }
                     _ => self.error(ErrorCode::InvalidValue("encode() arg".into())),
                 }
             }
+            Value::Global(Global::Reconstructor) => {
+                // Instance of a class pickled with protocol 0 or 1, as
+                // copyreg._reconstructor(cls, base, state).  The state is
+                // only given if base is not `object`.
+                if argtuple.len() != 3 {
+                    return self.error(ErrorCode::InvalidValue("_reconstructor() args".into()));
+                }
+                let class = match self.resolve(Some(argtuple.remove(0))) {
+                    Some(Value::Global(Global::Other(modname, globname))) => (modname, globname),
+                    _ => return self.error(ErrorCode::InvalidValue("_reconstructor() arg".into())),
+                };
+                let args = match argtuple.pop() {
+                    Some(Value::None) | None => Vec::new(),
+                    Some(state) => vec![state],
+                };
+                self.push_object(class, args, Vec::new());
+                Ok(())
+            }
+            Value::Global(Global::NewObj) => {
+                // Same as NEWOBJ, for protocols 0 and 1: __newobj__(cls, *args).
+                if argtuple.is_empty() {
+                    return self.error(ErrorCode::InvalidValue("__newobj__() args".into()));
+                }
+                let class = match self.resolve(Some(argtuple.remove(0))) {
+                    Some(Value::Global(Global::Other(modname, globname))) => (modname, globname),
+                    _ => return self.error(ErrorCode::InvalidValue("__newobj__() arg".into())),
+                };
+                self.push_object(class, argtuple, Vec::new());
+                Ok(())
+            }
+            Value::Global(Global::NewObjEx) => {
+                // Same as NEWOBJ_EX: __newobj_ex__(cls, args, kwargs).
+                if argtuple.len() != 3 {
+                    return self.error(ErrorCode::InvalidValue("__newobj_ex__() args".into()));
+                }
+                let kwargs = self.resolve(argtuple.pop());
+                let args = self.resolve(argtuple.pop());
+                let class = self.resolve(argtuple.pop());
+                match (class, args, kwargs) {
+                    (Some(Value::Global(Global::Other(modname, globname))),
+                     Some(Value::Tuple(args)), Some(Value::Dict(kwargs))) => {
+                        self.push_object((modname, globname), args, kwargs);
+                        Ok(())
+                    }
+                    _ => self.error(ErrorCode::InvalidValue("__newobj_ex__() arg".into())),
+                }
+            }
+            Value::Global(Global::ByteArray) => {
+                // Pickled with a bytestring, which Python 3 encodes with
+                // `_codecs.encode` before protocol 3.  Python 2 pickles it
+                // as bytearray(text, 'latin-1').
+                let mut args = argtuple.into_iter();
+                let first = self.resolve(args.next());
+                let encoding = match self.resolve(args.next()) {
+                    Some(Value::String(s)) => Some(s.into_bytes()),
+                    Some(Value::Bytes(b)) => Some(b),
+                    None => None,
+                    _ => return self.error(ErrorCode::InvalidValue("bytearray() arg".into())),
+                };
+                let bytes = match (first, encoding) {
+                    (None, None) => Some(Vec::new()),
+                    (Some(Value::Bytes(b)), None) | (Some(Value::ByteArray(b)), None) => Some(b),
+                    (Some(Value::String(s)), Some(ref enc))
+                        if enc == b"latin-1" || enc == b"latin1" =>
+                        if s.chars().all(|ch| (ch as u32) < 256) {
+                            Some(s.chars().map(|ch| ch as u8).collect())
+                        } else {
+                            None
+                        },
+                    _ => None,
+                };
+                match bytes {
+                    Some(bytes) => Ok(self.push(Value::ByteArray(bytes))),
+                    None => self.error(ErrorCode::InvalidValue("bytearray() arg".into())),
+                }
+            }
+            Value::Global(Global::Decimal) => {
+                // Pickled with its string representation.
+                let decimal = match self.resolve(argtuple.into_iter().next()) {
+                    Some(Value::String(s)) => Decimal::new(&s),
+                    Some(Value::Bytes(b)) => str::from_utf8(&b).ok().and_then(Decimal::new),
+                    _ => None,
+                };
+                match decimal {
+                    Some(decimal) => Ok(self.push(Value::Decimal(decimal))),
+                    None => self.error(ErrorCode::InvalidValue("Decimal() arg".into())),
+                }
+            }
+            Value::Global(Global::Fraction) => {
+                // Pickled with numerator and denominator, or with its string
+                // representation by older Pythons.
+                let mut args = argtuple.into_iter();
+                let first = self.resolve(args.next());
+                let second = self.resolve(args.next());
+                let fraction = match (first, second) {
+                    (Some(Value::String(s)), None) => Fraction::from_str(&s),
+                    (Some(Value::Bytes(b)), None) =>
+                        str::from_utf8(&b).ok().and_then(Fraction::from_str),
+                    (Some(n), d) => match (Self::to_bigint(n), d.map(Self::to_bigint)) {
+                        (Some(n), Some(Some(d))) => Fraction::new(n, d),
+                        (Some(n), None) => Some(Fraction::from(n)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match fraction {
+                    Some(fraction) => Ok(self.push(Value::Fraction(fraction))),
+                    None => self.error(ErrorCode::InvalidValue("Fraction() arg".into())),
+                }
+            }
+            Value::Global(Global::Complex) => {
+                let mut parts = [0.0; 2];
+                if argtuple.len() > 2 {
+                    return self.error(ErrorCode::InvalidValue("complex() args".into()));
+                }
+                for (i, arg) in argtuple.into_iter().enumerate() {
+                    parts[i] = match self.resolve(Some(arg)) {
+                        Some(Value::F64(v)) => v,
+                        Some(Value::I64(v)) => v as f64,
+                        _ => return self.error(ErrorCode::InvalidValue("complex() arg".into())),
+                    };
+                }
+                Ok(self.push(Value::Complex(Complex::new(parts[0], parts[1]))))
+            }
+            Value::Global(Global::Date) => {
+                let date = self.datetime_state(argtuple.into_iter().next())
+                               .and_then(|state| Date::from_state(&state));
+                match date {
+                    Some(date) => Ok(self.push(Value::Date(date))),
+                    None => self.error(ErrorCode::InvalidValue("date() arg".into())),
+                }
+            }
+            Value::Global(Global::Time) => {
+                let mut args = argtuple.into_iter();
+                let state = self.datetime_state(args.next());
+                let tzinfo = try!(self.datetime_tzinfo(args.next()));
+                match state.and_then(|state| Time::from_state(&state, tzinfo)) {
+                    Some(time) => Ok(self.push(Value::Time(time))),
+                    None => self.error(ErrorCode::InvalidValue("time() arg".into())),
+                }
+            }
+            Value::Global(Global::DateTime) => {
+                let mut args = argtuple.into_iter();
+                let state = self.datetime_state(args.next());
+                let tzinfo = try!(self.datetime_tzinfo(args.next()));
+                match state.and_then(|state| DateTime::from_state(&state, tzinfo)) {
+                    Some(datetime) => Ok(self.push(Value::DateTime(datetime))),
+                    None => self.error(ErrorCode::InvalidValue("datetime() arg".into())),
+                }
+            }
+            Value::Global(Global::TimeDelta) => {
+                // Pickled as timedelta(days, seconds, microseconds).
+                let mut parts = [0; 3];
+                if argtuple.len() > 3 {
+                    return self.error(ErrorCode::InvalidValue("timedelta() args".into()));
+                }
+                for (i, arg) in argtuple.into_iter().enumerate() {
+                    match self.resolve(Some(arg)) {
+                        Some(Value::I64(v)) => parts[i] = v,
+                        _ => return self.error(ErrorCode::InvalidValue("timedelta() arg".into())),
+                    }
+                }
+                match TimeDelta::new(parts[0], parts[1], parts[2]) {
+                    Some(delta) => Ok(self.push(Value::TimeDelta(delta))),
+                    None => self.error(ErrorCode::InvalidValue("timedelta() arg".into())),
+                }
+            }
+            Value::Global(Global::TimeZone) => {
+                // Pickled as timezone(offset[, name]).
+                let mut args = argtuple.into_iter();
+                let offset = match self.resolve(args.next()) {
+                    Some(Value::TimeDelta(offset)) => offset,
+                    _ => return self.error(ErrorCode::InvalidValue("timezone() arg".into())),
+                };
+                let name = match self.resolve(args.next()) {
+                    Some(Value::String(name)) => Some(name),
+                    None => None,
+                    _ => return self.error(ErrorCode::InvalidValue("timezone() arg".into())),
+                };
+                if offset.total_microseconds().abs() >= 86400 * 1_000_000 {
+                    return self.error(ErrorCode::InvalidValue("timezone() arg".into()));
+                }
+                Ok(self.push(Value::TimeZone(TimeZone { offset: offset, name: name })))
+            }
+            Value::Global(Global::OrderedDict) => {
+                // The items are added with SETITEMS, but Python 2 pickles
+                // them as a list of pairs.
+                let pairs = match self.resolve(argtuple.into_iter().next()) {
+                    Some(Value::List(pairs)) => pairs,
+                    None => Vec::new(),
+                    _ => return self.error(ErrorCode::InvalidValue("OrderedDict() arg".into())),
+                };
+                let mut items = Vec::with_capacity(pairs.len());
+                for pair in pairs {
+                    let mut pair = match self.resolve(Some(pair)) {
+                        Some(Value::List(pair)) | Some(Value::Tuple(pair)) => pair,
+                        _ => Vec::new(),
+                    };
+                    if pair.len() != 2 {
+                        return self.error(ErrorCode::InvalidValue("OrderedDict() arg".into()));
+                    }
+                    let value = pair.pop().unwrap();
+                    items.push((pair.pop().unwrap(), value));
+                }
+                Ok(self.push(Value::OrderedDict(items)))
+            }
+            Value::Global(Global::DefaultDict) => {
+                // Pickled as defaultdict(factory), and the items are added
+                // with SETITEMS.
+                let factory = match self.resolve(argtuple.into_iter().next()) {
+                    Some(Value::Global(global)) => Some(global.into_name()),
+                    Some(Value::None) | None => None,
+                    _ => return self.error(ErrorCode::InvalidValue("defaultdict() arg".into())),
+                };
+                Ok(self.push(Value::DefaultDict(Box::new(DefaultDict {
+                    factory: factory,
+                    items: Vec::new(),
+                }))))
+            }
+            Value::Global(Global::Deque) => {
+                // Pickled as deque((), maxlen), and the items are added with
+                // APPENDS.  Python 2 pickles them as the first argument.
+                let mut args = argtuple.into_iter();
+                let mut items = match self.resolve(args.next()) {
+                    Some(Value::List(items)) | Some(Value::Tuple(items)) => items,
+                    None => Vec::new(),
+                    _ => return self.error(ErrorCode::InvalidValue("deque() arg".into())),
+                };
+                let maxlen = match self.resolve(args.next()) {
+                    Some(Value::I64(maxlen)) if maxlen >= 0 => Some(maxlen as usize),
+                    Some(Value::None) | None => None,
+                    _ => return self.error(ErrorCode::InvalidValue("deque() arg".into())),
+                };
+                trim_deque(&mut items, maxlen);
+                Ok(self.push(Value::Deque(items, maxlen)))
+            }
+            Value::Global(Global::Counter) => {
+                // Pickled as Counter(dict).
+                match self.resolve(argtuple.into_iter().next()) {
+                    Some(Value::Dict(items)) => Ok(self.push(Value::Counter(items))),
+                    None => Ok(self.push(Value::Counter(Vec::new()))),
+                    _ => self.error(ErrorCode::InvalidValue("Counter() arg".into())),
+                }
+            }
+            #[cfg(feature = "numpy")]
+            Value::Global(Global::NdArray) => {
+                // Pickled as _reconstruct(ndarray, (0,), b'b'), with the
+                // shape, dtype and data given by BUILD.
+                if argtuple.is_empty() {
+                    return self.error(ErrorCode::InvalidValue("_reconstruct() args".into()));
+                }
+                let class = match self.resolve(Some(argtuple.remove(0))) {
+                    Some(Value::Global(Global::Other(modname, globname))) => (modname, globname),
+                    _ => return self.error(ErrorCode::InvalidValue("_reconstruct() arg".into())),
+                };
+                self.push_object(class, argtuple, Vec::new());
+                Ok(())
+            }
+            #[cfg(feature = "numpy")]
+            Value::Global(Global::NdArrayFromBuffer) => self.reduce_frombuffer(argtuple),
+            #[cfg(feature = "numpy")]
+            Value::Global(Global::DType) => {
+                self.push_object(("numpy".into(), "dtype".into()), argtuple, Vec::new());
+                Ok(())
+            }
+            Value::Global(Global::Other(modname, globname)) => {
+                let index = match self.resolvers.iter().position(
+                    |r| r.handles(&modname, &globname)) {
+                    Some(index) => index,
+                    None => return self.error(ErrorCode::UnsupportedGlobal(
+                        modname.into_bytes(), globname.into_bytes())),
+                };
+                let mut args = Vec::with_capacity(argtuple.len());
+                for arg in argtuple {
+                    let arg = globals_to_names(try!(self.resolve_deep(arg, &mut Vec::new())));
+                    args.push(try!(self.deserialize_value(arg)));
+                }
+                let pos = self.pos;
+                let result = try!(self.resolvers[index].reduce(&modname, &globname, args).map_err(
+                    |err| match err {
+                        Error::Syntax(code) => Error::Eval(code, pos),
+                        other => other,
+                    }));
+                self.push(Value::from_value(result));
+                Ok(())
+            }
             other => Self::stack_error("global reference", &other, self.pos),
         }
     }
 
+    // Arrays pickled out-of-band with protocol 5, as _frombuffer(buffer,
+    // dtype, shape, order).  They are converted to the state that BUILD gets
+    // with the other protocols.
+    #[cfg(feature = "numpy")]
+    fn reduce_frombuffer(&mut self, argtuple: Vec<Value>) -> Result<()> {
+        if argtuple.len() != 4 {
+            return self.error(ErrorCode::InvalidValue("_frombuffer() args".into()));
+        }
+        let mut args = argtuple.into_iter();
+        let data = match self.resolve(args.next()) {
+            Some(Value::Bytes(b)) | Some(Value::ByteArray(b)) => b,
+            _ => return self.error(ErrorCode::InvalidValue("_frombuffer() arg".into())),
+        };
+        let dtype = args.next().unwrap();
+        let shape = args.next().unwrap();
+        let fortran_order = match self.resolve(args.next()) {
+            Some(Value::String(ref order)) => order == "F",
+            Some(Value::Bytes(ref order)) => order == b"F",
+            _ => return self.error(ErrorCode::InvalidValue("_frombuffer() arg".into())),
+        };
+        self.push(Value::Object(Box::new(Object {
+            class: ("numpy".into(), "ndarray".into()),
+            args: vec![Value::Tuple(vec![Value::I64(0)]), Value::Bytes(b"b".to_vec())],
+            kwargs: Vec::new(),
+            state: Some(Value::Tuple(vec![Value::I64(1), shape, dtype,
+                                          Value::Bool(fortran_order), Value::Bytes(data)])),
+        })));
+        Ok(())
+    }
+
+    // Get the packed state of a date or time.  Python 2 pickles it as a
+    // string, which may have been decoded to Unicode.
+    fn datetime_state(&mut self, arg: Option<Value>) -> Option<Vec<u8>> {
+        match self.resolve(arg) {
+            Some(Value::Bytes(bytes)) => Some(bytes),
+            Some(Value::String(ref s)) if s.chars().all(|ch| (ch as u32) < 256) =>
+                Some(s.chars().map(|ch| ch as u8).collect()),
+            _ => None,
+        }
+    }
+
+    fn to_bigint(value: Value) -> Option<BigInt> {
+        match value {
+            Value::I64(i) => Some(BigInt::from(i)),
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    fn datetime_tzinfo(&mut self, arg: Option<Value>) -> Result<Option<TimeZone>> {
+        match self.resolve(arg) {
+            Some(Value::TimeZone(tz)) => Ok(Some(tz)),
+            Some(Value::None) | None => Ok(None),
+            _ => self.error(ErrorCode::InvalidValue("tzinfo".into())),
+        }
+    }
+
     fn stack_error<T>(what: &'static str, value: &Value, pos: usize) -> Result<T> {
         let it = format!("{:?}", value);
         Err(Error::Eval(ErrorCode::InvalidStackTop(what, it), pos))
@@ -783,62 +2192,166 @@ impl<R: Read> Deserializer<R> {
     }
 
     fn deserialize_value(&mut self, value: Value) -> Result<value::Value> {
+        self.nested(|slf| slf.deserialize_value_contents(value))
+    }
+
+    fn deserialize_value_contents(&mut self, value: Value) -> Result<value::Value> {
         match value {
             Value::None => Ok(value::Value::None),
             Value::Bool(v) => Ok(value::Value::Bool(v)),
             Value::I64(v) => Ok(value::Value::I64(v)),
             Value::Int(v) => {
                 if let Some(i) = v.to_i64() {
-                    Ok((value::Value::I64(i)))
+                    Ok(value::Value::I64(i))
                 } else {
-                    Ok((value::Value::Int(v)))
+                    Ok(value::Value::Int(v))
                 }
             },
             Value::F64(v) => Ok(value::Value::F64(v)),
             Value::Bytes(v) => Ok(value::Value::Bytes(v)),
+            Value::ByteArray(v) => Ok(value::Value::ByteArray(v)),
             Value::String(v) => Ok(value::Value::String(v)),
-            Value::List(v) => {
-                let new = try!(v.into_iter().map(|v| self.deserialize_value(v)).collect());
-                Ok(value::Value::List(new))
-            },
-            Value::Tuple(v) => {
-                let new = try!(v.into_iter().map(|v| self.deserialize_value(v)).collect());
-                Ok(value::Value::Tuple(new))
-            },
-            Value::Set(v) => {
-                let new = try!(v.into_iter().map(|v| self.deserialize_value(v)
-                                                 .and_then(|rv| rv.into_hashable())).collect());
-                Ok(value::Value::Set(new))
-            },
-            Value::FrozenSet(v) => {
-                let new = try!(v.into_iter().map(|v| self.deserialize_value(v)
-                                                 .and_then(|rv| rv.into_hashable())).collect());
-                Ok(value::Value::FrozenSet(new))
-            },
-            Value::Dict(v) => {
-                let mut map = BTreeMap::new();
-                for (key, value) in v {
-                    let real_key = try!(self.deserialize_value(key).and_then(|rv| rv.into_hashable()));
-                    let real_value = try!(self.deserialize_value(value));
-                    map.insert(real_key, real_value);
-                }
-                Ok(value::Value::Dict(map))
-            },
-            Value::MemoRef(memo_id) => {
-                self.resolve_recursive(memo_id, |slf, value| slf.deserialize_value(value))
-            },
+            Value::List(v) => self.deserialize_values(v).map(value::Value::List),
+            Value::Tuple(v) => self.deserialize_values(v).map(value::Value::Tuple),
+            Value::Set(v) => self.deserialize_set(v).map(value::Value::Set),
+            Value::FrozenSet(v) => self.deserialize_set(v).map(value::Value::FrozenSet),
+            Value::Dict(v) => self.deserialize_dict(v).map(value::Value::Dict),
+            Value::Object(obj) => self.deserialize_object(*obj),
+            // Kept out of this function, which is called recursively.
+            Value::Decimal(_) | Value::Fraction(_) | Value::Complex(_) | Value::Date(_) |
+            Value::Time(_) | Value::DateTime(_) | Value::TimeDelta(_) |
+            Value::TimeZone(_) => Ok(library_value(value)),
+            Value::OrderedDict(_) | Value::DefaultDict(_) | Value::Deque(..) |
+            Value::Counter(_) => self.deserialize_collection(value),
+            Value::MemoRef(memo_id) => self.deserialize_memo(memo_id),
             Value::Global(_) => Err(Error::Syntax(ErrorCode::UnresolvedGlobal)),
         }
     }
-}
 
-impl<R: Read> de::Deserializer for Deserializer<R> {
-    type Error = Error;
+    // Convert a memoized value.  It is converted only once, and further
+    // references get copies of the result.
+    fn deserialize_memo(&mut self, id: MemoId) -> Result<value::Value> {
+        let copy = match self.converted.get_mut(&id) {
+            Some(&mut (_, ref mut count, size)) => {
+                *count -= 1;
+                if *count > 0 { Some(size) } else { None }
+            }
+            None => None,
+        };
+        if let Some(size) = copy {
+            // The copy needs as much memory as the original conversion.
+            try!(self.account(size));
+            return Ok(self.converted[&id].0.clone());
+        }
+        if let Some((value, _, _)) = self.converted.remove(&id) {
+            return Ok(value);
+        }
+        // While converting, the value is in neither map, which detects
+        // recursive structures.
+        let (value, count, _) = match self.memo.remove(&id) {
+            Some(entry) => entry,
+            None => return Err(Error::Syntax(ErrorCode::Recursive)),
+        };
+        let allocated = self.allocated;
+        let value = self.unshare(value);
+        let result = try!(self.deserialize_value(value));
+        if count > 1 {
+            let size = self.allocated - allocated;
+            self.converted.insert(id, (result.clone(), count - 1, size));
+        }
+        Ok(result)
+    }
+
+    fn deserialize_values(&mut self, values: Vec<Value>) -> Result<Vec<value::Value>> {
+        values.into_iter().map(|v| self.deserialize_value(v)).collect()
+    }
+
+    fn deserialize_set(&mut self, values: Vec<Value>) -> Result<BTreeSet<value::HashableValue>> {
+        values.into_iter().map(|v| self.deserialize_value(v).and_then(|rv| rv.into_hashable()))
+                          .collect()
+    }
 
-    fn deserialize<V>(&mut self, mut visitor: V) -> Result<V::Value>
+    fn deserialize_dict(&mut self, items: Vec<(Value, Value)>)
+                        -> Result<BTreeMap<value::HashableValue, value::Value>> {
+        let mut map = BTreeMap::new();
+        for (key, value) in items {
+            let real_key = try!(self.deserialize_value(key).and_then(|rv| rv.into_hashable()));
+            let real_value = try!(self.deserialize_value(value));
+            map.insert(real_key, real_value);
+        }
+        Ok(map)
+    }
+
+    fn deserialize_ordered_dict(&mut self, items: Vec<(Value, Value)>)
+                                -> Result<Vec<(value::HashableValue, value::Value)>> {
+        let mut result: Vec<(value::HashableValue, value::Value)> = Vec::with_capacity(items.len());
+        let mut positions: BTreeMap<_, usize> = BTreeMap::new();
+        for (key, value) in items {
+            let real_key = try!(self.deserialize_value(key).and_then(|rv| rv.into_hashable()));
+            let real_value = try!(self.deserialize_value(value));
+            // Like in Python, a repeated key keeps its first position.
+            if let Some(&index) = positions.get(&real_key) {
+                result[index].1 = real_value;
+            } else {
+                positions.insert(real_key.clone(), result.len());
+                result.push((real_key, real_value));
+            }
+        }
+        Ok(result)
+    }
+
+    // Convert the `collections` types.
+    fn deserialize_collection(&mut self, value: Value) -> Result<value::Value> {
+        Ok(match value {
+            Value::OrderedDict(items) =>
+                value::Value::OrderedDict(try!(self.deserialize_ordered_dict(items))),
+            Value::DefaultDict(d) => {
+                let d = *d;
+                value::Value::DefaultDict(Box::new(value::DefaultDict {
+                    factory: d.factory,
+                    items: try!(self.deserialize_dict(d.items)),
+                }))
+            }
+            Value::Deque(items, maxlen) => value::Value::Deque(value::Deque {
+                items: try!(self.deserialize_values(items)),
+                maxlen: maxlen,
+            }),
+            Value::Counter(items) => value::Value::Counter(try!(self.deserialize_dict(items))),
+            other => unreachable!("not a collection: {:?}", other),
+        })
+    }
+
+    fn deserialize_object(&mut self, obj: Object) -> Result<value::Value> {
+        let args = try!(self.deserialize_values(obj.args));
+        let kwargs = try!(self.deserialize_dict(obj.kwargs));
+        let state = match obj.state {
+            Some(state) => Some(try!(self.deserialize_value(state))),
+            None => None,
+        };
+        Ok(value::Value::Object(Box::new(value::Object {
+            class: obj.class,
+            args: args,
+            kwargs: kwargs,
+            state: state,
+        })))
+    }
+
+    // Deserialize the next value with the given visitor.
+    fn deserialize_contents<V>(&mut self, mut visitor: V) -> Result<V::Value>
         where V: de::Visitor
     {
-        let value = try!(self.get_next_value());
+        let value = match self.value.take() {
+            Some(value) => value,
+            // At the start of a pickle, lists and dicts may be visited lazily.
+            None if self.stack.is_empty() && self.stacks.is_empty() && !self.lazy &&
+                    self.memo_gets.is_some() => {
+                match try!(self.parse_toplevel()) {
+                    Some(value) => value,
+                    None => return self.visit_lazy(visitor),
+                }
+            }
+            None => try!(self.parse_value()),
+        };
         match value {
             Value::None => visitor.visit_unit(),
             Value::Bool(v) => visitor.visit_bool(v),
@@ -851,49 +2364,359 @@ impl<R: Read> de::Deserializer for Deserializer<R> {
                 }
             },
             Value::F64(v) => visitor.visit_f64(v),
-            Value::Bytes(v) => visitor.visit_byte_buf(v),
+            Value::Bytes(v) | Value::ByteArray(v) => visitor.visit_byte_buf(v),
             Value::String(v) => visitor.visit_string(v),
             Value::List(v) => {
                 let len = v.len();
                 visitor.visit_seq(SeqVisitor {
                     de: self,
-                    iter: v.into_iter(),
+                    iter: Items::Owned(v.into_iter()),
                     len: len,
+                    lazy: false,
                 })
             },
             Value::Tuple(v) => {
                 visitor.visit_seq(SeqVisitor {
                     len: v.len(),
-                    iter: v.into_iter(),
+                    iter: Items::Owned(v.into_iter()),
                     de: self,
+                    lazy: false,
                 })
             }
             Value::Set(v) | Value::FrozenSet(v) => {
                 visitor.visit_seq(SeqVisitor {
                     de: self,
                     len: v.len(),
-                    iter: v.into_iter(),
+                    iter: Items::Owned(v.into_iter()),
+                    lazy: false,
                 })
             },
-            Value::Dict(v) => {
+            Value::Dict(v) | Value::OrderedDict(v) | Value::Counter(v) => {
                 let len = v.len();
                 visitor.visit_map(MapVisitor {
                     de: self,
-                    iter: v.into_iter(),
+                    iter: Items::Owned(v.into_iter()),
                     value: None,
                     len: len,
+                    lazy: false,
                 })
             },
-            Value::MemoRef(memo_id) => {
-                self.resolve_recursive(memo_id, |slf, value| {
-                    slf.value = Some(value);
-                    de::Deserialize::deserialize(slf)
+            Value::DefaultDict(d) => {
+                let d = *d;
+                visitor.visit_map(MapVisitor {
+                    de: self,
+                    len: d.items.len(),
+                    iter: Items::Owned(d.items.into_iter()),
+                    value: None,
+                    lazy: false,
+                })
+            }
+            Value::Deque(v, _) => {
+                visitor.visit_seq(SeqVisitor {
+                    de: self,
+                    len: v.len(),
+                    iter: Items::Owned(v.into_iter()),
+                    lazy: false,
                 })
+            }
+            Value::Object(obj) => {
+                // Objects are visited as their state, which is usually the
+                // instance dictionary.
+                match obj.state {
+                    Some(state) => {
+                        self.value = Some(state);
+                        de::Deserializer::deserialize(self, visitor)
+                    }
+                    None => visitor.visit_map(MapVisitor {
+                        de: self,
+                        iter: Items::Owned(Vec::new().into_iter()),
+                        value: None,
+                        len: 0,
+                        lazy: false,
+                    }),
+                }
+            },
+            // Exact numbers are visited as their string representation, and
+            // complex numbers as a tuple of real and imaginary part.
+            Value::Decimal(v) => visitor.visit_string(v.to_string()),
+            Value::Fraction(v) => visitor.visit_string(v.to_string()),
+            Value::Complex(v) => {
+                visitor.visit_seq(SeqVisitor {
+                    de: self,
+                    iter: Items::Owned(vec![Value::F64(v.re), Value::F64(v.im)].into_iter()),
+                    len: 2,
+                    lazy: false,
+                })
+            }
+            // Dates and times are visited as ISO 8601 strings, and
+            // timedeltas as their number of seconds.
+            Value::Date(v) => visitor.visit_string(v.to_string()),
+            Value::Time(v) => visitor.visit_string(v.to_string()),
+            Value::DateTime(v) => visitor.visit_string(v.isoformat()),
+            Value::TimeDelta(v) => visitor.visit_f64(v.total_seconds()),
+            // Like other objects without state.
+            Value::TimeZone(_) => visitor.visit_map(MapVisitor {
+                de: self,
+                iter: Items::Owned(Vec::new().into_iter()),
+                value: None,
+                len: 0,
+                lazy: false,
+            }),
+            Value::MemoRef(memo_id) => {
+                self.resolve_recursive(memo_id, |slf, value| slf.visit_shared(value, visitor))
             },
             Value::Global(_) => Err(Error::Syntax(ErrorCode::UnresolvedGlobal)),
         }
     }
 
+    // Visit the top-level container that is parsed lazily.  If the pickle
+    // ends before any items are added, its final value is visited as usual.
+    fn visit_lazy<V: de::Visitor>(&mut self, mut visitor: V) -> Result<V::Value> {
+        match try!(self.parse_lazy()) {
+            Some(Value::Dict(items)) => visitor.visit_map(MapVisitor {
+                de: self,
+                len: items.len(),
+                iter: Items::Owned(items.into_iter()),
+                value: None,
+                lazy: true,
+            }),
+            Some(Value::List(items)) => visitor.visit_seq(SeqVisitor {
+                de: self,
+                len: items.len(),
+                iter: Items::Owned(items.into_iter()),
+                lazy: true,
+            }),
+            _ => self.deserialize_contents(visitor),
+        }
+    }
+
+    // Visit a memoized value.  If it is still referenced elsewhere, the items
+    // of containers are copied one at a time while they are visited, instead
+    // of copying the whole value first.
+    fn visit_shared<V>(&mut self, value: Rc<Value>, mut visitor: V) -> Result<V::Value>
+        where V: de::Visitor
+    {
+        let value = match Rc::try_unwrap(value) {
+            Ok(value) => {
+                self.value = Some(value);
+                return de::Deserialize::deserialize(self);
+            }
+            Err(value) => value,
+        };
+        match *value {
+            Value::List(ref v) | Value::Tuple(ref v) | Value::Set(ref v) |
+            Value::FrozenSet(ref v) | Value::Deque(ref v, _) => {
+                return visitor.visit_seq(SeqVisitor {
+                    de: self,
+                    len: v.len(),
+                    iter: Items::Shared(value.clone(), seq_items, 0),
+                    lazy: false,
+                });
+            }
+            Value::Dict(ref v) | Value::OrderedDict(ref v) | Value::Counter(ref v) => {
+                return visitor.visit_map(MapVisitor {
+                    de: self,
+                    len: v.len(),
+                    iter: Items::Shared(value.clone(), map_items, 0),
+                    value: None,
+                    lazy: false,
+                });
+            }
+            Value::DefaultDict(ref d) => {
+                return visitor.visit_map(MapVisitor {
+                    de: self,
+                    len: d.items.len(),
+                    iter: Items::Shared(value.clone(), map_items, 0),
+                    value: None,
+                    lazy: false,
+                });
+            }
+            _ => {}
+        }
+        let copy = self.unshare(value);
+        self.value = Some(copy);
+        de::Deserialize::deserialize(self)
+    }
+}
+
+// Items of a visited container.  The items of a memoized container that is
+// still referenced elsewhere are copied when they are visited.
+enum Items<T> {
+    Owned(vec::IntoIter<T>),
+    Shared(Rc<Value>, fn(&Value) -> &[T], usize),
+}
+
+impl<T: Clone> Items<T> {
+    // Get the next item, and whether it is a copy.
+    fn next(&mut self) -> Option<(T, bool)> {
+        match *self {
+            Items::Owned(ref mut iter) => iter.next().map(|item| (item, false)),
+            Items::Shared(ref value, items, ref mut index) => {
+                let item = items(value).get(*index).cloned();
+                *index += 1;
+                item.map(|item| (item, true))
+            }
+        }
+    }
+}
+
+fn seq_items(value: &Value) -> &[Value] {
+    match *value {
+        Value::List(ref v) | Value::Tuple(ref v) | Value::Set(ref v) |
+        Value::FrozenSet(ref v) | Value::Deque(ref v, _) => v,
+        _ => &[],
+    }
+}
+
+fn map_items(value: &Value) -> &[(Value, Value)] {
+    match *value {
+        Value::Dict(ref v) | Value::OrderedDict(ref v) | Value::Counter(ref v) => v,
+        Value::DefaultDict(ref d) => &d.items,
+        _ => &[],
+    }
+}
+
+impl<R: Read> Deserializer<R> {
+    // Convert a value into a graph value.  Memoized containers and objects
+    // become nodes of the graph, and all memo references to them become
+    // references to the node.
+    fn graph_value(&mut self, value: Value, nodes: &mut Vec<GraphValue>,
+                   refs: &mut BTreeMap<MemoId, GraphValue>) -> Result<GraphValue> {
+        self.nested(|slf| slf.graph_value_contents(value, nodes, refs))
+    }
+
+    fn graph_value_contents(&mut self, value: Value, nodes: &mut Vec<GraphValue>,
+                            refs: &mut BTreeMap<MemoId, GraphValue>) -> Result<GraphValue> {
+        Ok(match value {
+            Value::None => GraphValue::None,
+            Value::Bool(v) => GraphValue::Bool(v),
+            Value::I64(v) => GraphValue::I64(v),
+            Value::Int(v) => {
+                if let Some(i) = v.to_i64() {
+                    GraphValue::I64(i)
+                } else {
+                    GraphValue::Int(v)
+                }
+            },
+            Value::F64(v) => GraphValue::F64(v),
+            Value::Bytes(v) => GraphValue::Bytes(v),
+            Value::ByteArray(v) => GraphValue::ByteArray(v),
+            Value::String(v) => GraphValue::String(v),
+            Value::List(v) => GraphValue::List(try!(self.graph_values(v, nodes, refs))),
+            Value::Tuple(v) => GraphValue::Tuple(try!(self.graph_values(v, nodes, refs))),
+            Value::Set(v) => GraphValue::Set(try!(self.graph_values(v, nodes, refs))),
+            Value::FrozenSet(v) => GraphValue::FrozenSet(try!(self.graph_values(v, nodes, refs))),
+            Value::Dict(v) => GraphValue::Dict(try!(self.graph_pairs(v, nodes, refs))),
+            Value::Object(obj) => {
+                let obj = *obj;
+                let state = match obj.state {
+                    Some(state) => Some(try!(self.graph_value(state, nodes, refs))),
+                    None => None,
+                };
+                GraphValue::Object(Box::new(GraphObject {
+                    class: obj.class,
+                    args: try!(self.graph_values(obj.args, nodes, refs)),
+                    kwargs: try!(self.graph_pairs(obj.kwargs, nodes, refs)),
+                    state: state,
+                }))
+            }
+            Value::OrderedDict(_) | Value::DefaultDict(_) | Value::Deque(..) |
+            Value::Counter(_) => try!(self.graph_collection(value, nodes, refs)),
+            Value::Decimal(v) => GraphValue::Decimal(v),
+            Value::Fraction(v) => GraphValue::Fraction(v),
+            Value::Complex(v) => GraphValue::Complex(v),
+            Value::Date(v) => GraphValue::Date(v),
+            Value::Time(v) => GraphValue::Time(v),
+            Value::DateTime(v) => GraphValue::DateTime(v),
+            Value::TimeDelta(v) => GraphValue::TimeDelta(v),
+            Value::TimeZone(tz) => {
+                let mut args = vec![GraphValue::TimeDelta(tz.offset)];
+                args.extend(tz.name.map(GraphValue::String));
+                GraphValue::Object(Box::new(GraphObject {
+                    class: ("datetime".into(), "timezone".into()),
+                    args: args,
+                    kwargs: Vec::new(),
+                    state: None,
+                }))
+            }
+            Value::MemoRef(memo_id) => {
+                if let Some(value) = refs.get(&memo_id) {
+                    return Ok(value.clone());
+                }
+                let value = match self.memo.remove(&memo_id) {
+                    Some((value, _, _)) => self.unshare(value),
+                    None => return Err(Error::Syntax(ErrorCode::MissingMemo(memo_id))),
+                };
+                match value {
+                    Value::List(_) | Value::Tuple(_) | Value::Set(_) | Value::FrozenSet(_) |
+                    Value::Dict(_) | Value::Object(_) | Value::OrderedDict(_) |
+                    Value::DefaultDict(_) | Value::Deque(..) | Value::Counter(_) |
+                    Value::ByteArray(_) => {
+                        // Register the node before converting its contents,
+                        // so that recursive references can find it.
+                        let id: NodeId = nodes.len();
+                        nodes.push(GraphValue::None);
+                        refs.insert(memo_id, GraphValue::Ref(id));
+                        nodes[id] = try!(self.graph_value(value, nodes, refs));
+                        GraphValue::Ref(id)
+                    }
+                    _ => {
+                        // Immutable scalars have no identity worth keeping.
+                        let value = try!(self.graph_value(value, nodes, refs));
+                        refs.insert(memo_id, value.clone());
+                        value
+                    }
+                }
+            }
+            Value::Global(_) => return Err(Error::Syntax(ErrorCode::UnresolvedGlobal)),
+        })
+    }
+
+    fn graph_collection(&mut self, value: Value, nodes: &mut Vec<GraphValue>,
+                        refs: &mut BTreeMap<MemoId, GraphValue>) -> Result<GraphValue> {
+        Ok(match value {
+            Value::OrderedDict(items) =>
+                GraphValue::OrderedDict(try!(self.graph_pairs(items, nodes, refs))),
+            Value::DefaultDict(d) => {
+                let d = *d;
+                GraphValue::DefaultDict(d.factory, try!(self.graph_pairs(d.items, nodes, refs)))
+            }
+            Value::Deque(items, maxlen) =>
+                GraphValue::Deque(try!(self.graph_values(items, nodes, refs)), maxlen),
+            Value::Counter(items) =>
+                GraphValue::Counter(try!(self.graph_pairs(items, nodes, refs))),
+            other => unreachable!("not a collection: {:?}", other),
+        })
+    }
+
+    fn graph_values(&mut self, values: Vec<Value>, nodes: &mut Vec<GraphValue>,
+                    refs: &mut BTreeMap<MemoId, GraphValue>) -> Result<Vec<GraphValue>> {
+        values.into_iter().map(|v| self.graph_value(v, nodes, refs)).collect()
+    }
+
+    fn graph_pairs(&mut self, items: Vec<(Value, Value)>, nodes: &mut Vec<GraphValue>,
+                   refs: &mut BTreeMap<MemoId, GraphValue>) -> Result<Vec<(GraphValue, GraphValue)>> {
+        items.into_iter().map(|(k, v)| Ok((try!(self.graph_value(k, nodes, refs)),
+                                           try!(self.graph_value(v, nodes, refs))))).collect()
+    }
+}
+
+impl<R: Read> Drop for Deserializer<R> {
+    fn drop(&mut self) {
+        // Values left after an error may be nested arbitrarily deep.
+        self.reset();
+    }
+}
+
+impl<R: Read> de::Deserializer for Deserializer<R> {
+    type Error = Error;
+
+    fn deserialize<V>(&mut self, visitor: V) -> Result<V::Value>
+        where V: de::Visitor
+    {
+        self.nested(|slf| slf.deserialize_contents(visitor))
+    }
+
     #[inline]
     fn deserialize_bool<V: de::Visitor>(&mut self, visitor: V) -> Result<V::Value> {
         self.deserialize(visitor)
@@ -1065,6 +2888,25 @@ impl<R: Read> de::VariantVisitor for Deserializer<R> {
     fn visit_variant<V>(&mut self) -> Result<V> where V: de::Deserialize {
         let value = try!(self.get_next_value());
         match value {
+            Value::MemoRef(memo_id) => {
+                self.resolve_recursive(memo_id, |slf, value| {
+                    slf.value = Some(slf.unshare(value));
+                    slf.visit_variant()
+                })
+            }
+            // Unit variants written as a plain string.
+            Value::String(_) => {
+                self.value = Some(value);
+                de::Deserialize::deserialize(self)
+            }
+            // Variants written as a dict {'Variant': value}.
+            Value::Dict(mut v) if v.len() == 1 => {
+                let (variant, args) = v.pop().unwrap();
+                self.value = Some(variant);
+                let res = de::Deserialize::deserialize(self);
+                self.value = Some(args);
+                res
+            }
             Value::Tuple(mut v) => {
                 if v.len() == 2 {
                     let args = v.pop();
@@ -1077,7 +2919,7 @@ impl<R: Read> de::VariantVisitor for Deserializer<R> {
                     de::Deserialize::deserialize(self)
                 }
             }
-             _ => Err(Error::Syntax(ErrorCode::Custom("enums must be tuples".into())))
+             _ => Err(Error::Syntax(ErrorCode::Custom("enums must be tuples or dicts".into())))
         }
     }
 
@@ -1101,8 +2943,26 @@ impl<R: Read> de::VariantVisitor for Deserializer<R> {
 
 struct SeqVisitor<'a, R: Read + 'a> {
     de: &'a mut Deserializer<R>,
-    iter: vec::IntoIter<Value>,
+    iter: Items<Value>,
     len: usize,
+    lazy: bool,  // more items can be parsed from the stream
+}
+
+impl<'a, R: Read> SeqVisitor<'a, R> {
+    // Parse the next batch of items of a lazily parsed list.
+    fn refill(&mut self) -> Result<()> {
+        match try!(self.de.parse_lazy()) {
+            Some(Value::List(items)) => {
+                self.len = items.len();
+                self.iter = Items::Owned(items.into_iter());
+            }
+            _ => {
+                self.lazy = false;
+                try!(self.de.end_lazy());
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a, R: Read> de::SeqVisitor for SeqVisitor<'a, R> {
@@ -1111,17 +2971,26 @@ impl<'a, R: Read> de::SeqVisitor for SeqVisitor<'a, R> {
     fn visit<T>(&mut self) -> Result<Option<T>>
         where T: de::Deserialize
     {
-        match self.iter.next() {
-            Some(value) => {
-                self.len -= 1;
-                self.de.value = Some(value);
-                Ok(Some(try!(de::Deserialize::deserialize(self.de))))
+        loop {
+            match self.iter.next() {
+                Some((value, copied)) => {
+                    self.len -= 1;
+                    if copied {
+                        self.de.add_memo_refs(&value);
+                    }
+                    self.de.value = Some(value);
+                    return Ok(Some(try!(de::Deserialize::deserialize(self.de))));
+                }
+                None if self.lazy => try!(self.refill()),
+                None => return Ok(None),
             }
-            None => Ok(None),
         }
     }
 
     fn end(&mut self) -> Result<()> {
+        while self.len == 0 && self.lazy {
+            try!(self.refill());
+        }
         if self.len == 0 {
             Ok(())
         } else {
@@ -1130,15 +2999,33 @@ impl<'a, R: Read> de::SeqVisitor for SeqVisitor<'a, R> {
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.len, Some(self.len))
+        (self.len, if self.lazy { None } else { Some(self.len) })
     }
 }
 
 struct MapVisitor<'a, R: Read + 'a> {
     de: &'a mut Deserializer<R>,
-    iter: vec::IntoIter<(Value, Value)>,
+    iter: Items<(Value, Value)>,
     value: Option<Value>,
     len: usize,
+    lazy: bool,  // more items can be parsed from the stream
+}
+
+impl<'a, R: Read> MapVisitor<'a, R> {
+    // Parse the next batch of items of a lazily parsed dict.
+    fn refill(&mut self) -> Result<()> {
+        match try!(self.de.parse_lazy()) {
+            Some(Value::Dict(items)) => {
+                self.len = items.len();
+                self.iter = Items::Owned(items.into_iter());
+            }
+            _ => {
+                self.lazy = false;
+                try!(self.de.end_lazy());
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a, R: Read> de::MapVisitor for MapVisitor<'a, R> {
@@ -1147,14 +3034,21 @@ impl<'a, R: Read> de::MapVisitor for MapVisitor<'a, R> {
     fn visit_key<T>(&mut self) -> Result<Option<T>>
         where T: de::Deserialize
     {
-        match self.iter.next() {
-            Some((key, value)) => {
-                self.len -= 1;
-                self.value = Some(value);
-                self.de.value = Some(key);
-                Ok(Some(try!(de::Deserialize::deserialize(self.de))))
+        loop {
+            match self.iter.next() {
+                Some(((key, value), copied)) => {
+                    self.len -= 1;
+                    if copied {
+                        self.de.add_memo_refs(&key);
+                        self.de.add_memo_refs(&value);
+                    }
+                    self.value = Some(value);
+                    self.de.value = Some(key);
+                    return Ok(Some(try!(de::Deserialize::deserialize(self.de))));
+                }
+                None if self.lazy => try!(self.refill()),
+                None => return Ok(None),
             }
-            None => Ok(None),
         }
     }
 
@@ -1167,6 +3061,9 @@ impl<'a, R: Read> de::MapVisitor for MapVisitor<'a, R> {
     }
 
     fn end(&mut self) -> Result<()> {
+        while self.len == 0 && self.lazy {
+            try!(self.refill());
+        }
         if self.len == 0 {
             Ok(())
         } else {
@@ -1181,10 +3078,85 @@ impl<'a, R: Read> de::MapVisitor for MapVisitor<'a, R> {
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.len, Some(self.len))
+        (self.len, if self.lazy { None } else { Some(self.len) })
+    }
+}
+
+
+/// An iterator over consecutive pickles in one stream, as written by
+/// repeated calls to Python's `pickle.dump`.
+///
+/// Each pickle is decoded with a fresh memo.  Iteration ends at the end of
+/// the stream, or after the first error.
+pub struct StreamDeserializer<R: Read, T> {
+    de: Deserializer<R>,
+    decode: fn(&mut Deserializer<R>) -> Result<T>,
+    offset: usize,
+    failed: bool,
+}
+
+impl<R: Read, T: de::Deserialize> StreamDeserializer<R, T> {
+    /// Construct a stream deserializer decoding serde values.
+    pub fn new(rdr: R, options: DeOptions) -> StreamDeserializer<R, T> {
+        StreamDeserializer::with_decoder(rdr, options, de::Deserialize::deserialize)
+    }
+}
+
+impl<R: Read> StreamDeserializer<R, value::Value> {
+    /// Construct a stream deserializer decoding `value::Value`s, keeping all
+    /// Python types intact.
+    pub fn values(rdr: R, options: DeOptions) -> StreamDeserializer<R, value::Value> {
+        StreamDeserializer::with_decoder(rdr, options, Deserializer::decode_value)
+    }
+}
+
+impl<R: Read, T> StreamDeserializer<R, T> {
+    fn with_decoder(rdr: R, options: DeOptions, decode: fn(&mut Deserializer<R>) -> Result<T>)
+                    -> StreamDeserializer<R, T> {
+        StreamDeserializer {
+            de: Deserializer::with_options(rdr, options),
+            decode: decode,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// Get the byte offset in the stream after the last successfully
+    /// decoded pickle, which is where the next one starts.
+    pub fn byte_offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Get the underlying `Deserializer`, e.g. to register resolvers.
+    pub fn deserializer(&mut self) -> &mut Deserializer<R> {
+        &mut self.de
     }
 }
 
+impl<R: Read, T> Iterator for StreamDeserializer<R, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.failed {
+            return None;
+        }
+        match self.de.at_end() {
+            Ok(true) => return None,
+            Ok(false) => {}
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        }
+        self.de.reset();
+        let result = (self.decode)(&mut self.de);
+        match result {
+            Ok(_) => self.offset = self.de.pos,
+            Err(_) => self.failed = true,
+        }
+        Some(result)
+    }
+}
 
 /// Decodes a value from a `std::io::Read`.
 pub fn from_reader<R: io::Read, T: de::Deserialize>(rdr: R) -> Result<T> {
@@ -1195,9 +3167,128 @@ pub fn from_reader<R: io::Read, T: de::Deserialize>(rdr: R) -> Result<T> {
     Ok(value)
 }
 
+/// Decodes a value from a `std::io::Read`, with the given options.
+pub fn from_reader_with_options<R: io::Read, T: de::Deserialize>(rdr: R, options: DeOptions)
+                                                               -> Result<T> {
+    let mut de = Deserializer::with_options(rdr, options);
+    let value = try!(de::Deserialize::deserialize(&mut de));
+    try!(de.end());
+    Ok(value)
+}
+
+/// Decodes a value from a `std::io::Read`, with the given out-of-band buffers.
+pub fn from_reader_with_buffers<R: io::Read, T: de::Deserialize>(rdr: R, buffers: Vec<Vec<u8>>)
+                                                               -> Result<T> {
+    let mut de = Deserializer::new(rdr, false);
+    de.set_buffers(buffers);
+    let value = try!(de::Deserialize::deserialize(&mut de));
+    try!(de.end());
+    Ok(value)
+}
+
+// Split the argument of the given kind from the start of `data`, without
+// copying it.  Returns the argument and the rest of the data.
+fn split_arg(data: &[u8], kind: ArgKind) -> Option<(&[u8], &[u8])> {
+    fn fixed(data: &[u8], n: usize) -> Option<(&[u8], &[u8])> {
+        if data.len() < n { None } else { Some(data.split_at(n)) }
+    }
+    fn line(data: &[u8]) -> Option<(&[u8], &[u8])> {
+        let end = try_opt!(data.iter().position(|&b| b == b'\n'));
+        Some((&data[..end], &data[end + 1..]))
+    }
+    fn prefixed(data: &[u8], n: usize) -> Option<(&[u8], &[u8])> {
+        let (len, rest) = try_opt!(fixed(data, n));
+        // Negative lengths of String4 and Long4 don't fit into the data.
+        fixed(rest, try_opt!(LittleEndian::read_uint(len, n).to_usize()))
+    }
+    match kind {
+        ArgKind::None => Some((&data[..0], data)),
+        ArgKind::Uint1 => fixed(data, 1),
+        ArgKind::Uint2 => fixed(data, 2),
+        ArgKind::Int4 | ArgKind::Uint4 => fixed(data, 4),
+        ArgKind::Uint8 | ArgKind::Float8 => fixed(data, 8),
+        ArgKind::DecimalNlShort | ArgKind::DecimalNlLong | ArgKind::FloatNl |
+        ArgKind::StringNl | ArgKind::StringNlNoescape | ArgKind::UnicodeStringNl => line(data),
+        ArgKind::StringNlNoescapePair => {
+            let (_, rest) = try_opt!(line(data));
+            let (_, rest) = try_opt!(line(rest));
+            Some(data.split_at(data.len() - rest.len()))
+        }
+        ArgKind::String1 | ArgKind::Bytes1 | ArgKind::UnicodeString1 | ArgKind::Long1 =>
+            prefixed(data, 1),
+        ArgKind::String4 | ArgKind::Bytes4 | ArgKind::UnicodeString4 | ArgKind::Long4 =>
+            prefixed(data, 4),
+        ArgKind::Bytes8 | ArgKind::Bytearray8 | ArgKind::UnicodeString8 => prefixed(data, 8),
+    }
+}
+
+// Check whether the final value of a pickle is the list or dict created by
+// its first opcode, so that its items can be visited while the pickle is
+// parsed.  If so, returns the number of memo references to each memo ID.
+// The opcodes are skipped without copying their arguments.
+fn scan_toplevel(mut data: &[u8]) -> Option<BTreeMap<MemoId, usize>> {
+    let mut gets = BTreeMap::new();
+    // Number of stack items above each MARK.  The container is the first
+    // item of the bottom segment, and must stay there until STOP.
+    let mut segments = vec![0usize];
+    while let Some((&opcode, rest)) = data.split_first() {
+        let info = try_opt!(opcode_info(opcode));
+        let (arg, rest) = try_opt!(split_arg(rest, info.arg));
+        data = rest;
+        if segments == [0] {
+            match opcode {
+                PROTO | FRAME => continue,
+                EMPTY_LIST | EMPTY_DICT => { segments[0] = 1; continue; }
+                _ => return None,
+            }
+        }
+        match opcode {
+            STOP => return if segments == [1] { Some(gets) } else { None },
+            MARK => { segments.push(0); continue; }
+            POP if segments.last() == Some(&0) => { segments.pop(); continue; }
+            GET | BINGET | LONG_BINGET => {
+                let id = match opcode {
+                    GET => {
+                        let line = arg.split(|&b| b == b'\r').next().unwrap_or(arg);
+                        try_opt!(str::from_utf8(line).ok().and_then(|id| id.parse().ok()))
+                    }
+                    BINGET => arg[0] as MemoId,
+                    _ => LittleEndian::read_u32(arg),
+                };
+                *gets.entry(id).or_insert(0) += 1;
+            }
+            _ => {}
+        }
+        if info.mark {
+            segments.pop();
+        }
+        // These modify the container in place, the others would consume it.
+        let keeps = match opcode {
+            APPEND | APPENDS | SETITEM | SETITEMS => 0,
+            _ => 1,
+        };
+        let bottom = segments.len() == 1;
+        let items = try_opt!(segments.last_mut());
+        if *items < info.pops + if bottom { keeps } else { 0 } {
+            return None;
+        }
+        *items = *items - info.pops + info.pushes;
+    }
+    None
+}
+
 /// Decodes a value from a byte slice `&[u8]`.
 pub fn from_slice<T: de::Deserialize>(v: &[u8]) -> Result<T> {
-    from_reader(io::Cursor::new(v))
+    from_slice_with_options(v, DeOptions::new())
+}
+
+/// Decodes a value from a byte slice `&[u8]`, with the given options.
+pub fn from_slice_with_options<T: de::Deserialize>(v: &[u8], options: DeOptions) -> Result<T> {
+    let mut de = Deserializer::with_options(io::Cursor::new(v), options);
+    de.memo_gets = scan_toplevel(v);
+    let value = try!(de::Deserialize::deserialize(&mut de));
+    try!(de.end());
+    Ok(value)
 }
 
 /// Decodes a value from any iterator supported as a reader.
@@ -1209,8 +3300,26 @@ pub fn from_iter<E: IterReadItem, I: Iterator<Item=E>,
 /// Decodes a value from a `std::io::Read`.
 pub fn value_from_reader<R: io::Read>(rdr: R) -> Result<value::Value> {
     let mut de = Deserializer::new(rdr, false);
-    let intermediate_value = try!(de.parse_value());
-    let value = try!(de.deserialize_value(intermediate_value));
+    let value = try!(de.decode_value());
+    try!(de.end());
+    Ok(value)
+}
+
+/// Decodes a value from a `std::io::Read`, with the given options.
+pub fn value_from_reader_with_options<R: io::Read>(rdr: R, options: DeOptions)
+                                                   -> Result<value::Value> {
+    let mut de = Deserializer::with_options(rdr, options);
+    let value = try!(de.decode_value());
+    try!(de.end());
+    Ok(value)
+}
+
+/// Decodes a value from a `std::io::Read`, with the given out-of-band buffers.
+pub fn value_from_reader_with_buffers<R: io::Read>(rdr: R, buffers: Vec<Vec<u8>>)
+                                                   -> Result<value::Value> {
+    let mut de = Deserializer::new(rdr, false);
+    de.set_buffers(buffers);
+    let value = try!(de.decode_value());
     try!(de.end());
     Ok(value)
 }
@@ -1220,7 +3329,25 @@ pub fn value_from_slice(v: &[u8]) -> Result<value::Value> {
     value_from_reader(io::Cursor::new(v))
 }
 
+/// Decodes a value from a byte slice `&[u8]`, with the given options.
+pub fn value_from_slice_with_options(v: &[u8], options: DeOptions) -> Result<value::Value> {
+    value_from_reader_with_options(io::Cursor::new(v), options)
+}
+
 /// Decodes a value from any iterator supported as a reader.
 pub fn value_from_iter<E: IterReadItem, I: Iterator<Item=E>>(it: I) -> Result<value::Value> {
     value_from_reader(IterRead::new(it))
 }
+
+/// Decodes an object graph from a `std::io::Read`.
+pub fn graph_from_reader<R: io::Read>(rdr: R) -> Result<Graph> {
+    let mut de = Deserializer::new(rdr, false);
+    let graph = try!(de.decode_graph());
+    try!(de.end());
+    Ok(graph)
+}
+
+/// Decodes an object graph from a byte slice `&[u8]`.
+pub fn graph_from_slice(v: &[u8]) -> Result<Graph> {
+    graph_from_reader(io::Cursor::new(v))
+}
@@ -0,0 +1,429 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Pickle disassembler
+//!
+//! This walks a pickle stream without executing it, yielding the opcodes
+//! together with their decoded arguments.  The `dis` function formats them
+//! like Python's `pickletools.dis`.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use super::error::{Error, ErrorCode, Result};
+use super::consts::*;
+
+/// Decoded argument of an opcode.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i64),
+    Bool(bool),
+    Long(BigInt),
+    Float(f64),
+    Bytes(Vec<u8>),
+    ByteArray(Vec<u8>),
+    String(String),
+}
+
+/// Formats the argument like Python's `repr()`.
+impl fmt::Display for Arg {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arg::Int(i) => write!(fmt, "{}", i),
+            Arg::Bool(b) => write!(fmt, "{}", if b { "True" } else { "False" }),
+            Arg::Long(ref i) => write!(fmt, "{}", i),
+            Arg::Float(f) => fmt_float(fmt, f),
+            Arg::Bytes(ref b) => fmt_bytes(fmt, b),
+            Arg::ByteArray(ref b) => {
+                try!(write!(fmt, "bytearray("));
+                try!(fmt_bytes(fmt, b));
+                write!(fmt, ")")
+            }
+            Arg::String(ref s) => fmt_string(fmt, s),
+        }
+    }
+}
+
+fn fmt_float(fmt: &mut fmt::Formatter, f: f64) -> fmt::Result {
+    if f.is_nan() {
+        write!(fmt, "nan")
+    } else if f.is_infinite() {
+        write!(fmt, "{}", if f > 0. { "inf" } else { "-inf" })
+    } else if f != 0. && (f.abs() < 1e-4 || f.abs() >= 1e16) {
+        // Python uses a signed exponent with at least two digits.
+        let repr = format!("{:e}", f);
+        let mut parts = repr.splitn(2, 'e');
+        let mantissa = parts.next().unwrap();
+        let exp: i32 = parts.next().unwrap().parse().unwrap();
+        write!(fmt, "{}e{}{:02}", mantissa, if exp < 0 { '-' } else { '+' }, exp.abs())
+    } else {
+        let repr = format!("{}", f);
+        if repr.contains('.') {
+            write!(fmt, "{}", repr)
+        } else {
+            write!(fmt, "{}.0", repr)
+        }
+    }
+}
+
+// Python prefers single quotes, unless only double quotes avoid escaping.
+fn quote_char<I: Iterator<Item=char> + Clone>(chars: I) -> char {
+    if chars.clone().any(|c| c == '\'') && !chars.clone().any(|c| c == '"') {
+        '"'
+    } else {
+        '\''
+    }
+}
+
+fn fmt_bytes(fmt: &mut fmt::Formatter, b: &[u8]) -> fmt::Result {
+    let quote = quote_char(b.iter().map(|&c| c as char));
+    try!(write!(fmt, "b{}", quote));
+    for &c in b {
+        match c {
+            b'\\' => try!(write!(fmt, "\\\\")),
+            b'\t' => try!(write!(fmt, "\\t")),
+            b'\n' => try!(write!(fmt, "\\n")),
+            b'\r' => try!(write!(fmt, "\\r")),
+            _ if c as char == quote => try!(write!(fmt, "\\{}", quote)),
+            0x20...0x7e => try!(write!(fmt, "{}", c as char)),
+            _ => try!(write!(fmt, "\\x{:02x}", c)),
+        }
+    }
+    write!(fmt, "{}", quote)
+}
+
+fn fmt_string(fmt: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    let quote = quote_char(s.chars());
+    try!(write!(fmt, "{}", quote));
+    for c in s.chars() {
+        match c {
+            '\\' => try!(write!(fmt, "\\\\")),
+            '\t' => try!(write!(fmt, "\\t")),
+            '\n' => try!(write!(fmt, "\\n")),
+            '\r' => try!(write!(fmt, "\\r")),
+            _ if c == quote => try!(write!(fmt, "\\{}", quote)),
+            ' ' => try!(write!(fmt, " ")),
+            // Approximation of Python's notion of non-printable characters.
+            _ if c.is_control() || c.is_whitespace() || c == '\u{ad}' => {
+                let n = c as u32;
+                if n <= 0xff {
+                    try!(write!(fmt, "\\x{:02x}", n))
+                } else if n <= 0xffff {
+                    try!(write!(fmt, "\\u{:04x}", n))
+                } else {
+                    try!(write!(fmt, "\\U{:08x}", n))
+                }
+            }
+            _ => try!(write!(fmt, "{}", c)),
+        }
+    }
+    write!(fmt, "{}", quote)
+}
+
+/// A single opcode found in a pickle stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    /// Offset of the opcode in the stream.
+    pub offset: usize,
+    pub opcode: u8,
+    /// Name of the opcode, as used by Python.
+    pub name: &'static str,
+    pub arg: Option<Arg>,
+}
+
+/// An iterator over the instructions of a pickle stream, up to and
+/// including the first STOP opcode.
+pub struct Disassembler<R: Read> {
+    rdr: BufReader<R>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Disassembler<R> {
+    /// Construct a new Disassembler.
+    pub fn new(rdr: R) -> Disassembler<R> {
+        Disassembler {
+            rdr: BufReader::new(rdr),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    fn error<T>(&self, reason: ErrorCode) -> Result<T> {
+        Err(Error::Eval(reason, self.pos))
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0];
+        match self.rdr.read(&mut buf) {
+            Ok(1) => { self.pos += 1; Ok(Some(buf[0])) },
+            Ok(_) => Ok(None),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
+    fn read_bytes(&mut self, n: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self.rdr.by_ref().take(n).read_to_end(&mut buf) {
+            Ok(m) if n == m as u64 => { self.pos += m; Ok(buf) },
+            Ok(_) => self.error(ErrorCode::EOFWhileParsing),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self.rdr.read_until(b'\n', &mut buf) {
+            Ok(_) => {
+                self.pos += buf.len();
+                if buf.pop() != Some(b'\n') {
+                    return self.error(ErrorCode::EOFWhileParsing);
+                }
+                Ok(buf)
+            }
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
+    fn read_uint(&mut self, n: u64) -> Result<u64> {
+        let bytes = try!(self.read_bytes(n));
+        Ok(LittleEndian::read_uint(&bytes, n as usize))
+    }
+
+    fn read_prefixed_bytes(&mut self, n: u64) -> Result<Vec<u8>> {
+        let len = try!(self.read_uint(n));
+        self.read_bytes(len)
+    }
+
+    fn read_utf8(&mut self, n: u64) -> Result<String> {
+        let bytes = try!(self.read_prefixed_bytes(n));
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => self.error(ErrorCode::StringNotUTF8),
+        }
+    }
+
+    fn parse_ascii<T: str::FromStr>(&self, line: &[u8]) -> Result<T> {
+        match str::from_utf8(line).ok().and_then(|s| s.parse().ok()) {
+            Some(v) => Ok(v),
+            None => self.error(ErrorCode::InvalidLiteral(line.into())),
+        }
+    }
+
+    fn parse_int(&self, line: &[u8]) -> Result<Arg> {
+        match self.parse_ascii(line) {
+            Ok(i) => Ok(Arg::Int(i)),
+            Err(_) => self.parse_ascii(line).map(Arg::Long),
+        }
+    }
+
+    fn read_arg(&mut self, kind: ArgKind) -> Result<Option<Arg>> {
+        Ok(Some(match kind {
+            ArgKind::None => return Ok(None),
+            ArgKind::Uint1 => Arg::Int(try!(self.read_uint(1)) as i64),
+            ArgKind::Uint2 => Arg::Int(try!(self.read_uint(2)) as i64),
+            ArgKind::Uint4 => Arg::Int(try!(self.read_uint(4)) as i64),
+            ArgKind::Uint8 => {
+                let v = try!(self.read_uint(8));
+                match v.to_i64() {
+                    Some(i) => Arg::Int(i),
+                    None => Arg::Long(BigInt::from(v)),
+                }
+            }
+            ArgKind::Int4 => {
+                let bytes = try!(self.read_bytes(4));
+                Arg::Int(LittleEndian::read_i32(&bytes) as i64)
+            }
+            ArgKind::Float8 => {
+                let bytes = try!(self.read_bytes(8));
+                Arg::Float(BigEndian::read_f64(&bytes))
+            }
+            ArgKind::DecimalNlShort => {
+                let line = try!(self.read_line());
+                match &line[..] {
+                    b"00" => Arg::Bool(false),
+                    b"01" => Arg::Bool(true),
+                    _ => try!(self.parse_int(&line)),
+                }
+            }
+            ArgKind::DecimalNlLong => {
+                let mut line = try!(self.read_line());
+                if line.last() == Some(&b'L') {
+                    line.pop();
+                }
+                Arg::Long(try!(self.parse_ascii(&line)))
+            }
+            ArgKind::FloatNl => {
+                let line = try!(self.read_line());
+                Arg::Float(try!(self.parse_ascii(&line)))
+            }
+            ArgKind::StringNl => {
+                let line = try!(self.read_line());
+                match unescape_string(&line) {
+                    Some(bytes) => Arg::String(latin1(&bytes)),
+                    None => return self.error(ErrorCode::InvalidLiteral(line)),
+                }
+            }
+            ArgKind::StringNlNoescape => Arg::String(latin1(&try!(self.read_line()))),
+            ArgKind::StringNlNoescapePair => {
+                let first = latin1(&try!(self.read_line()));
+                let second = latin1(&try!(self.read_line()));
+                Arg::String(format!("{} {}", first, second))
+            }
+            ArgKind::UnicodeStringNl => {
+                let line = try!(self.read_line());
+                match unescape_unicode(&line) {
+                    Some(s) => Arg::String(s),
+                    None => return self.error(ErrorCode::InvalidLiteral(line)),
+                }
+            }
+            ArgKind::String1 => Arg::String(latin1(&try!(self.read_prefixed_bytes(1)))),
+            ArgKind::String4 => {
+                let bytes = try!(self.read_bytes(4));
+                match LittleEndian::read_i32(&bytes) {
+                    l if l < 0 => return self.error(ErrorCode::NegativeLength),
+                    l => Arg::String(latin1(&try!(self.read_bytes(l as u64)))),
+                }
+            }
+            ArgKind::Bytes1 => Arg::Bytes(try!(self.read_prefixed_bytes(1))),
+            ArgKind::Bytes4 => Arg::Bytes(try!(self.read_prefixed_bytes(4))),
+            ArgKind::Bytes8 => Arg::Bytes(try!(self.read_prefixed_bytes(8))),
+            ArgKind::Bytearray8 => Arg::ByteArray(try!(self.read_prefixed_bytes(8))),
+            ArgKind::UnicodeString1 => Arg::String(try!(self.read_utf8(1))),
+            ArgKind::UnicodeString4 => Arg::String(try!(self.read_utf8(4))),
+            ArgKind::UnicodeString8 => Arg::String(try!(self.read_utf8(8))),
+            ArgKind::Long1 => Arg::Long(decode_long(&try!(self.read_prefixed_bytes(1)))),
+            ArgKind::Long4 => {
+                let bytes = try!(self.read_bytes(4));
+                match LittleEndian::read_i32(&bytes) {
+                    l if l < 0 => return self.error(ErrorCode::NegativeLength),
+                    l => Arg::Long(decode_long(&try!(self.read_bytes(l as u64)))),
+                }
+            }
+        }))
+    }
+
+    fn read_instruction(&mut self) -> Result<Instruction> {
+        let offset = self.pos;
+        let code = match try!(self.read_byte()) {
+            Some(code) => code,
+            None => return self.error(ErrorCode::EOFWhileParsing),
+        };
+        let info = match opcode_info(code) {
+            Some(info) => info,
+            None => return Err(Error::Eval(ErrorCode::Unsupported(code as char), offset)),
+        };
+        let arg = try!(self.read_arg(info.arg));
+        Ok(Instruction { offset: offset, opcode: code, name: info.name, arg: arg })
+    }
+}
+
+impl<R: Read> Iterator for Disassembler<R> {
+    type Item = Result<Instruction>;
+
+    fn next(&mut self) -> Option<Result<Instruction>> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_instruction();
+        match result {
+            Ok(ref instr) if instr.opcode != STOP => (),
+            _ => self.done = true,
+        }
+        Some(result)
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Disassemble a pickle from a byte slice into a list of instructions.
+pub fn disassemble(v: &[u8]) -> Result<Vec<Instruction>> {
+    Disassembler::new(v).collect()
+}
+
+/// Write a symbolic disassembly of a pickle, in the same layout as Python's
+/// `pickletools.dis`.
+pub fn dis<R: Read, W: Write>(rdr: R, out: &mut W) -> Result<()> {
+    // Crude emulation of the unpickler stack; true for the markobject.
+    let mut stack = Vec::new();
+    let mut markstack = Vec::new();
+    let mut memo_len = 0;
+    let mut maxproto = 0;
+    for instr in Disassembler::new(rdr) {
+        let instr = try!(instr);
+        let info = opcode_info(instr.opcode).unwrap();
+        maxproto = maxproto.max(info.proto);
+
+        let code = match instr.opcode {
+            0x20...0x7e => (instr.opcode as char).to_string(),
+            c => format!("\\x{:02x}", c),
+        };
+        let mut line = format!("{:5}: {:<4} {}{}", instr.offset, code,
+                               "    ".repeat(markstack.len()), instr.name);
+
+        // See whether a MARK is popped; a POP can also consume it.
+        let mut error = None;
+        let mut markmsg = None;
+        let mut numtopop = info.pops;
+        if info.mark || (instr.opcode == POP && stack.last() == Some(&true)) {
+            match markstack.pop() {
+                Some(markpos) => {
+                    markmsg = Some(format!("(MARK at {})", markpos));
+                    while stack.pop() == Some(false) { }
+                    if !info.mark {
+                        numtopop = 0;
+                    }
+                }
+                None => {
+                    markmsg = Some("no MARK exists on stack".into());
+                    error = Some(ErrorCode::StackUnderflow);
+                }
+            }
+        }
+        if instr.opcode == MEMOIZE {
+            markmsg = Some(format!("(as {})", memo_len));
+        }
+        match instr.opcode {
+            PUT | BINPUT | LONG_BINPUT | MEMOIZE => memo_len += 1,
+            _ => (),
+        }
+
+        if instr.arg.is_some() || markmsg.is_some() {
+            line.push_str(&" ".repeat(10usize.saturating_sub(instr.name.len())));
+            if let Some(ref arg) = instr.arg {
+                line.push_str(&format!(" {}", arg));
+            }
+            if let Some(ref msg) = markmsg {
+                line.push_str(&format!(" {}", msg));
+            }
+        }
+        try!(writeln!(out, "{}", line));
+
+        if let Some(code) = error {
+            return Err(Error::Eval(code, instr.offset));
+        }
+        if stack.len() < numtopop {
+            return Err(Error::Eval(ErrorCode::StackUnderflow, instr.offset));
+        }
+        let newlen = stack.len() - numtopop;
+        stack.truncate(newlen);
+        if instr.opcode == MARK {
+            markstack.push(instr.offset);
+            stack.push(true);
+        }
+        for _ in 0..info.pushes {
+            stack.push(false);
+        }
+    }
+    try!(writeln!(out, "highest protocol among opcodes = {}", maxproto));
+    Ok(())
+}
@@ -34,8 +34,12 @@ pub enum ErrorCode {
     UnresolvedGlobal,
     /// A "module global" isn't supported
     UnsupportedGlobal(Vec<u8>, Vec<u8>),
+    /// An extension code isn't registered
+    UnknownExtension(u32),
     /// A value was missing from the memo
     MissingMemo(u32),
+    /// An out-of-band buffer was referenced, but not given
+    MissingBuffer,
     /// Invalid literal found
     InvalidLiteral(Vec<u8>),
     /// Found trailing bytes after STOP opcode
@@ -52,6 +56,18 @@ pub enum ErrorCode {
     UnknownField(String),
     /// Missing field
     MissingField(&'static str),
+    /// Unsupported pickle protocol requested for writing
+    UnsupportedProtocol(u8),
+    /// Maximum nesting depth exceeded
+    DepthLimitExceeded,
+    /// Maximum stack size exceeded
+    StackLimitExceeded,
+    /// Maximum number of memo entries exceeded
+    MemoLimitExceeded,
+    /// Maximum string length exceeded
+    LengthLimitExceeded(u64),
+    /// Maximum number of allocated bytes exceeded
+    AllocationLimitExceeded,
     /// Custom error
     Custom(String),
 }
@@ -72,7 +88,9 @@ impl fmt::Display for ErrorCode {
             ErrorCode::UnsupportedGlobal(ref m, ref g) =>
                 write!(fmt, "unsupported global: {}.{}",
                        String::from_utf8_lossy(m), String::from_utf8_lossy(g)),
+            ErrorCode::UnknownExtension(c) => write!(fmt, "unregistered extension code {}", c),
             ErrorCode::MissingMemo(n) => write!(fmt, "missing memo with id {}", n),
+            ErrorCode::MissingBuffer => write!(fmt, "missing out-of-band buffer"),
             ErrorCode::InvalidLiteral(ref l) =>
                 write!(fmt, "literal is invalid: {}", String::from_utf8_lossy(l)),
             ErrorCode::TrailingBytes => write!(fmt, "trailing bytes found"),
@@ -82,6 +100,12 @@ impl fmt::Display for ErrorCode {
             ErrorCode::UnknownVariant(ref v) => write!(fmt, "unknown variant: {}", v),
             ErrorCode::UnknownField(ref f) => write!(fmt, "unknown field: {}", f),
             ErrorCode::MissingField(f) => write!(fmt, "missing field: {}", f),
+            ErrorCode::UnsupportedProtocol(p) => write!(fmt, "unsupported protocol: {}", p),
+            ErrorCode::DepthLimitExceeded => write!(fmt, "nesting depth limit exceeded"),
+            ErrorCode::StackLimitExceeded => write!(fmt, "stack size limit exceeded"),
+            ErrorCode::MemoLimitExceeded => write!(fmt, "memo size limit exceeded"),
+            ErrorCode::LengthLimitExceeded(l) => write!(fmt, "length limit exceeded: {}", l),
+            ErrorCode::AllocationLimitExceeded => write!(fmt, "allocation limit exceeded"),
             ErrorCode::Custom(ref s) => fmt.write_str(s),
         }
     }
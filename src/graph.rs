@@ -0,0 +1,235 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Python object graphs, preserving shared and recursive references.
+//!
+//! `value::Value` is a tree, so it can't represent a list that contains
+//! itself, or tell apart two equal objects from the same object referenced
+//! twice.  A `Graph` stores all mutable containers and class instances as
+//! nodes in an arena, and refers to them by `NodeId`, the same way Python
+//! refers to them by identity.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use error::{Error, ErrorCode, Result};
+use value::{self, HashableValue};
+use datetime::{Date, Time, DateTime, TimeDelta};
+use numbers::{Decimal, Fraction, Complex};
+use num_bigint::BigInt;
+
+/// Index of a node in a `Graph`.
+pub type NodeId = usize;
+
+/// A value within a `Graph`.
+///
+/// Sets and dictionaries are represented as `Vec`s, since their items can
+/// be references to other nodes, which are not hashable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GraphValue {
+    /// None
+    None,
+    /// Boolean
+    Bool(bool),
+    /// Short integer
+    I64(i64),
+    /// Long integer (unbounded length)
+    Int(BigInt),
+    /// Float
+    F64(f64),
+    /// Decimal
+    Decimal(Decimal),
+    /// Rational number
+    Fraction(Fraction),
+    /// Complex number
+    Complex(Complex),
+    /// Bytestring
+    Bytes(Vec<u8>),
+    /// Mutable bytestring
+    ByteArray(Vec<u8>),
+    /// Unicode string
+    String(String),
+    /// List
+    List(Vec<GraphValue>),
+    /// Tuple
+    Tuple(Vec<GraphValue>),
+    /// Set
+    Set(Vec<GraphValue>),
+    /// Frozen (immutable) set
+    FrozenSet(Vec<GraphValue>),
+    /// Dictionary (map)
+    Dict(Vec<(GraphValue, GraphValue)>),
+    /// Ordered dictionary, in insertion order
+    OrderedDict(Vec<(GraphValue, GraphValue)>),
+    /// Dictionary with the module and name of its default factory
+    DefaultDict(Option<(String, String)>, Vec<(GraphValue, GraphValue)>),
+    /// Double-ended queue with its maximum length
+    Deque(Vec<GraphValue>, Option<usize>),
+    /// Multiset
+    Counter(Vec<(GraphValue, GraphValue)>),
+    /// Instance of a Python class
+    Object(Box<GraphObject>),
+    /// Date
+    Date(Date),
+    /// Time of day
+    Time(Time),
+    /// Date with time of day
+    DateTime(DateTime),
+    /// Duration
+    TimeDelta(TimeDelta),
+    /// Reference to a node of the graph
+    Ref(NodeId),
+}
+
+/// Instance of a Python class within a `Graph`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphObject {
+    /// Module and name of the class
+    pub class: (String, String),
+    /// Positional arguments for creating the instance
+    pub args: Vec<GraphValue>,
+    /// Keyword arguments for creating the instance
+    pub kwargs: Vec<(GraphValue, GraphValue)>,
+    /// State of the instance, usually its attribute dictionary
+    pub state: Option<GraphValue>,
+}
+
+/// A decoded pickle with all shared and recursive references preserved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Graph {
+    /// All nodes of the graph, indexed by `NodeId`
+    pub nodes: Vec<GraphValue>,
+    /// The toplevel value of the pickle
+    pub root: GraphValue,
+}
+
+impl Graph {
+    /// Return the toplevel value of the pickle.
+    pub fn root(&self) -> &GraphValue {
+        &self.root
+    }
+
+    /// Return the node with the given ID.
+    ///
+    /// Panics if the ID doesn't belong to this graph.
+    pub fn node(&self, id: NodeId) -> &GraphValue {
+        &self.nodes[id]
+    }
+
+    /// Follow a reference to its node; other values are returned unchanged.
+    pub fn get<'a>(&'a self, value: &'a GraphValue) -> &'a GraphValue {
+        match *value {
+            GraphValue::Ref(id) => self.node(id),
+            ref other => other,
+        }
+    }
+
+    /// Convert the graph into a tree of `value::Value`.
+    ///
+    /// Shared nodes are copied for each reference.  If the graph contains a
+    /// cycle, `ErrorCode::Recursive` is returned.
+    pub fn to_value(&self) -> Result<value::Value> {
+        self.convert(&self.root, &mut Vec::new())
+    }
+
+    fn convert(&self, value: &GraphValue, visiting: &mut Vec<NodeId>) -> Result<value::Value> {
+        Ok(match *value {
+            GraphValue::None => value::Value::None,
+            GraphValue::Bool(v) => value::Value::Bool(v),
+            GraphValue::I64(v) => value::Value::I64(v),
+            GraphValue::Int(ref v) => value::Value::Int(v.clone()),
+            GraphValue::F64(v) => value::Value::F64(v),
+            GraphValue::Bytes(ref v) => value::Value::Bytes(v.clone()),
+            GraphValue::ByteArray(ref v) => value::Value::ByteArray(v.clone()),
+            GraphValue::String(ref v) => value::Value::String(v.clone()),
+            GraphValue::List(ref v) => value::Value::List(try!(self.convert_values(v, visiting))),
+            GraphValue::Tuple(ref v) => value::Value::Tuple(try!(self.convert_values(v, visiting))),
+            GraphValue::Set(ref v) => value::Value::Set(try!(self.convert_set(v, visiting))),
+            GraphValue::FrozenSet(ref v) => value::Value::FrozenSet(try!(self.convert_set(v, visiting))),
+            GraphValue::Dict(ref v) => value::Value::Dict(try!(self.convert_dict(v, visiting))),
+            GraphValue::Object(ref obj) => {
+                let state = match obj.state {
+                    Some(ref state) => Some(try!(self.convert(state, visiting))),
+                    None => None,
+                };
+                value::Value::Object(Box::new(value::Object {
+                    class: obj.class.clone(),
+                    args: try!(self.convert_values(&obj.args, visiting)),
+                    kwargs: try!(self.convert_dict(&obj.kwargs, visiting)),
+                    state: state,
+                }))
+            }
+            GraphValue::OrderedDict(ref v) =>
+                value::Value::OrderedDict(try!(self.convert_ordered_dict(v, visiting))),
+            GraphValue::DefaultDict(ref factory, ref v) =>
+                value::Value::DefaultDict(Box::new(value::DefaultDict {
+                    factory: factory.clone(),
+                    items: try!(self.convert_dict(v, visiting)),
+                })),
+            GraphValue::Deque(ref v, maxlen) => value::Value::Deque(value::Deque {
+                items: try!(self.convert_values(v, visiting)),
+                maxlen: maxlen,
+            }),
+            GraphValue::Counter(ref v) =>
+                value::Value::Counter(try!(self.convert_dict(v, visiting))),
+            GraphValue::Decimal(ref v) => value::Value::Decimal(v.clone()),
+            GraphValue::Fraction(ref v) => value::Value::Fraction(v.clone()),
+            GraphValue::Complex(v) => value::Value::Complex(v),
+            GraphValue::Date(v) => value::Value::Date(v),
+            GraphValue::Time(ref v) => value::Value::Time(v.clone()),
+            GraphValue::DateTime(ref v) => value::Value::DateTime(v.clone()),
+            GraphValue::TimeDelta(v) => value::Value::TimeDelta(v),
+            GraphValue::Ref(id) => {
+                if visiting.contains(&id) {
+                    return Err(Error::Syntax(ErrorCode::Recursive));
+                }
+                visiting.push(id);
+                let result = self.convert(self.node(id), visiting);
+                visiting.pop();
+                try!(result)
+            }
+        })
+    }
+
+    fn convert_values(&self, values: &[GraphValue], visiting: &mut Vec<NodeId>)
+                      -> Result<Vec<value::Value>> {
+        values.iter().map(|v| self.convert(v, visiting)).collect()
+    }
+
+    fn convert_set(&self, values: &[GraphValue], visiting: &mut Vec<NodeId>)
+                   -> Result<BTreeSet<HashableValue>> {
+        values.iter().map(|v| self.convert(v, visiting).and_then(|rv| rv.into_hashable()))
+                     .collect()
+    }
+
+    fn convert_dict(&self, items: &[(GraphValue, GraphValue)], visiting: &mut Vec<NodeId>)
+                    -> Result<BTreeMap<HashableValue, value::Value>> {
+        let mut map = BTreeMap::new();
+        for &(ref key, ref value) in items {
+            let real_key = try!(self.convert(key, visiting).and_then(|rv| rv.into_hashable()));
+            let real_value = try!(self.convert(value, visiting));
+            map.insert(real_key, real_value);
+        }
+        Ok(map)
+    }
+
+    fn convert_ordered_dict(&self, items: &[(GraphValue, GraphValue)], visiting: &mut Vec<NodeId>)
+                            -> Result<Vec<(HashableValue, value::Value)>> {
+        let mut result: Vec<(HashableValue, value::Value)> = Vec::with_capacity(items.len());
+        let mut positions: BTreeMap<_, usize> = BTreeMap::new();
+        for &(ref key, ref value) in items {
+            let real_key = try!(self.convert(key, visiting).and_then(|rv| rv.into_hashable()));
+            let real_value = try!(self.convert(value, visiting));
+            // Like in Python, a repeated key keeps its first position.
+            if let Some(&index) = positions.get(&real_key) {
+                result[index].1 = real_value;
+            } else {
+                positions.insert(real_key.clone(), result.len());
+                result.push((real_key, real_value));
+            }
+        }
+        Ok(result)
+    }
+}
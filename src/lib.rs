@@ -11,9 +11,10 @@
 //! Please see the [Python docs](http://docs.python.org/library/pickle) for
 //! details on the Pickle format.
 //!
-//! This crate supports all Pickle protocols (0 to 4) when reading, and writing
-//! protocol 2 (compatible with Python 2 and 3), or protocol 3 (compatible with
-//! Python 3 only).
+//! This crate supports all Pickle protocols (0 to 5) when reading and writing.
+//! By default, it writes protocol 2 (compatible with Python 2 and 3) or
+//! protocol 3 (compatible with Python 3 only).  The others can be selected
+//! with `SerOptions`.
 //!
 //! # Supported types
 //!
@@ -26,11 +27,19 @@
 //! * Boolean (Rust `bool`)
 //! * Integers (Rust `i64` or bigints from num)
 //! * Floats (Rust `f64`)
+//! * Decimals, fractions and complex numbers (types in the `numbers` module)
 //! * Strings (Rust `Vec<u8>`)
+//! * Bytearrays (Rust `Vec<u8>`)
 //! * Unicode strings (Rust `String`)
 //! * Lists and tuples (Rust `Vec<Value>`)
 //! * Sets and frozensets (Rust `HashSet<Value>`)
 //! * Dictionaries (Rust `HashMap<Value, Value>`)
+//! * Ordered dictionaries, defaultdicts, deques and counters from `collections`
+//!   (Rust `Vec<(HashableValue, Value)>`, `DefaultDict`, `Deque` and
+//!   `BTreeMap<HashableValue, Value>`)
+//! * Instances of classes (Rust `Object`, with class name, arguments and state)
+//! * Dates, times, datetimes and timedeltas (types in the `datetime` module,
+//!   convertible to `chrono` types with the `chrono` feature)
 //!
 //! # Exported API
 //!
@@ -40,6 +49,44 @@
 //! types (notably, long integers and sets, which serde's generic types don't
 //! handle).  These functions, called `value_from_*` and `value_to_*`, will
 //! correctly (un)pickle these types.
+//!
+//! When decoding a byte slice into serde types, the items of a top-level list
+//! or dict are visited batch by batch while the pickle is parsed, so that the
+//! whole container is never held in memory twice.  This requires a quick scan
+//! of the pickle first, to check that the container is its final value.
+//! Memoized items are kept until the last reference to them was visited.
+//!
+//! Options for writing pickles, such as the protocol, memoization of repeated
+//! values, or the representation of enums, are given with `SerOptions` to
+//! the `*_with_options` functions.  Likewise, `DeOptions` are used for
+//! reading pickles, and allow setting limits on the resources used when
+//! decoding untrusted data, as well as a safe mode that only accepts an
+//! allowlist of module globals.
+//!
+//! Streams of several pickles written back-to-back, as by repeated calls to
+//! `pickle.dump`, can be decoded one after another with a `StreamDeserializer`.
+//! Lists too large to keep in memory can be written item by item with a
+//! `ListWriter`.
+//!
+//! Other module globals called by a pickle stream can be supported by
+//! registering a `GlobalResolver` with `Deserializer::add_resolver`.  Objects
+//! stored outside of the pickle by persistent ID are supported with the
+//! `PersistentId` and `PersistentLoad` hooks.
+//!
+//! Pickles with recursive or shared references, which can't be represented
+//! by `Value`, can be decoded into a `Graph` using the `graph_from_*`
+//! functions, and written again using the `graph_to_*` functions.
+//!
+//! With the `numpy` feature, NumPy arrays are decoded into objects that can
+//! be converted to a typed `numpy::NdArray`, and written back so that NumPy
+//! loads them.  With the `pandas` feature, pickled pandas DataFrames and
+//! Series can be loaded as a `pandas::DataFrame` or `pandas::Series`.
+//! With the `torch` feature, the tensors of PyTorch checkpoints can be loaded
+//! with `torch::load`.
+//!
+//! For debugging, the `disasm` module lists the opcodes of a pickle without
+//! executing it, like Python's `pickletools.dis`.  Unused memo entries can be
+//! removed from a pickle with `optimize`, like `pickletools.optimize` does.
 
 #![cfg_attr(test, feature(test))]
 #![cfg_attr(test, feature(custom_attribute, custom_derive, plugin))]
@@ -48,41 +95,96 @@
 extern crate serde;
 extern crate num_bigint;
 extern crate num_traits;
+extern crate num_integer;
 extern crate byteorder;
 extern crate iter_read;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "torch")]
+extern crate zip;
 
 pub use self::ser::{
     Serializer,
+    SerOptions,
+    EnumStyle,
+    ListWriter,
+    PersistentId,
     to_writer,
     to_vec,
+    to_writer_with_options,
+    to_vec_with_options,
     value_to_writer,
     value_to_vec,
+    value_to_writer_with_options,
+    value_to_vec_with_options,
+    graph_to_writer,
+    graph_to_vec,
+    optimize,
+    optimize_with_framing,
+    to_writer_with_buffers,
+    to_vec_with_buffers,
+    value_to_writer_with_buffers,
+    value_to_vec_with_buffers,
 };
 
 pub use self::de::{
     Deserializer,
+    DeOptions,
+    GlobalResolver,
+    PersistentLoad,
+    StreamDeserializer,
+    SAFE_GLOBALS,
     from_reader,
+    from_reader_with_options,
+    from_reader_with_buffers,
     from_slice,
+    from_slice_with_options,
     from_iter,
     value_from_reader,
+    value_from_reader_with_options,
+    value_from_reader_with_buffers,
     value_from_slice,
-    value_from_iter
+    value_from_slice_with_options,
+    value_from_iter,
+    graph_from_reader,
+    graph_from_slice,
 };
 
 pub use self::value::{
     Value,
     HashableValue,
+    Object,
+    DefaultDict,
+    Deque,
     to_value,
     from_value,
 };
 
+pub use self::graph::{
+    Graph,
+    GraphValue,
+    GraphObject,
+    NodeId,
+};
+
 pub use self::error::{Error, ErrorCode, Result};
 
+#[macro_use]
+mod consts;
 pub mod ser;
 pub mod de;
 pub mod error;
 pub mod value;
-mod consts;
+pub mod graph;
+pub mod disasm;
+pub mod datetime;
+pub mod numbers;
+#[cfg(feature = "numpy")]
+pub mod numpy;
+#[cfg(feature = "pandas")]
+pub mod pandas;
+#[cfg(feature = "torch")]
+pub mod torch;
 mod value_impls;
 
 #[cfg(test)]
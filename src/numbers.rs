@@ -0,0 +1,233 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! Numeric types of Python beyond `int` and `float`.
+//!
+//! Python pickles decimals as a call of `decimal.Decimal` with their string
+//! representation, fractions with their numerator and denominator, and
+//! complex numbers with their real and imaginary parts.
+
+use std::fmt;
+use std::cmp;
+use std::f64;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{Signed, Zero, One, ToPrimitive};
+
+/// A decimal number, as `decimal.Decimal`.
+///
+/// The text is kept as given, since trailing zeros are significant for
+/// decimals: `1.10` and `1.1` compare equal, but are not the same value.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Decimal {
+    text: String,
+}
+
+enum Parsed {
+    Finite(BigInt, i64),
+    Infinite(bool),
+    NaN,
+}
+
+// Parse the text of a decimal, as accepted by Python's `Decimal()`, apart
+// from surrounding whitespace and underscores.
+fn parse_decimal(text: &str) -> Option<Parsed> {
+    let (negative, rest) = match text.as_bytes().first() {
+        Some(&b'-') => (true, &text[1..]),
+        Some(&b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let lower = rest.to_lowercase();
+    if lower == "inf" || lower == "infinity" {
+        return Some(Parsed::Infinite(negative));
+    }
+    for prefix in &["nan", "snan"] {
+        if lower.starts_with(prefix) && lower[prefix.len()..].bytes().all(|b| b.is_ascii_digit()) {
+            return Some(Parsed::NaN);
+        }
+    }
+    let (mantissa, exponent) = match rest.find(|ch| ch == 'e' || ch == 'E') {
+        Some(index) => (&rest[..index], try_opt!(parse_exponent(&rest[index + 1..]))),
+        None => (rest, 0),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(index) => (&mantissa[..index], &mantissa[index + 1..]),
+        None => (mantissa, ""),
+    };
+    if int_part.len() + frac_part.len() == 0 ||
+        !int_part.bytes().chain(frac_part.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits = format!("{}{}", int_part, frac_part);
+    let coefficient = try_opt!(BigInt::parse_bytes(digits.as_bytes(), 10));
+    let exponent = try_opt!(exponent.checked_sub(frac_part.len() as i64));
+    Some(Parsed::Finite(if negative { -coefficient } else { coefficient }, exponent))
+}
+
+fn parse_exponent(text: &str) -> Option<i64> {
+    try_opt!(strip_plus(text)).parse().ok()
+}
+
+// Check that the text is an integer with an optional sign, and strip a
+// leading plus sign, which Rust's parsers don't accept everywhere.
+fn strip_plus(text: &str) -> Option<&str> {
+    let digits = text.trim_start_matches(|ch| ch == '+' || ch == '-');
+    if digits.is_empty() || text.len() - digits.len() > 1 ||
+        !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(text.trim_start_matches('+'))
+}
+
+impl Decimal {
+    /// Construct a decimal from its text, if it is valid.
+    pub fn new(text: &str) -> Option<Decimal> {
+        parse_decimal(text).map(|_| Decimal { text: text.into() })
+    }
+
+    /// Return the text of the decimal.
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Return the coefficient and exponent of a finite decimal, whose value
+    /// is `coefficient * 10**exponent`.  Returns None for infinities and NaNs.
+    pub fn to_parts(&self) -> Option<(BigInt, i64)> {
+        match parse_decimal(&self.text) {
+            Some(Parsed::Finite(coefficient, exponent)) => Some((coefficient, exponent)),
+            _ => None,
+        }
+    }
+
+    /// Return true if the decimal is a (quiet or signaling) NaN.
+    pub fn is_nan(&self) -> bool {
+        match parse_decimal(&self.text) {
+            Some(Parsed::NaN) => true,
+            _ => false,
+        }
+    }
+
+    /// Convert to the nearest float.
+    pub fn to_f64(&self) -> f64 {
+        match parse_decimal(&self.text) {
+            Some(Parsed::Finite(..)) => self.text.parse().unwrap_or(f64::NAN),
+            Some(Parsed::Infinite(false)) => f64::INFINITY,
+            Some(Parsed::Infinite(true)) => f64::NEG_INFINITY,
+            _ => f64::NAN,
+        }
+    }
+}
+
+/// A rational number, as `fractions.Fraction`.
+///
+/// Like in Python, it is normalized to lowest terms with a positive
+/// denominator.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fraction {
+    numerator: BigInt,
+    denominator: BigInt,
+}
+
+impl Fraction {
+    /// Construct a fraction, if the denominator is not zero.
+    pub fn new(numerator: BigInt, denominator: BigInt) -> Option<Fraction> {
+        if denominator.is_zero() {
+            return None;
+        }
+        let mut gcd = numerator.gcd(&denominator);
+        if denominator.is_negative() {
+            gcd = -gcd;
+        }
+        Some(Fraction { numerator: numerator / &gcd, denominator: denominator / &gcd })
+    }
+
+    /// Parse the string representation written by `str()`, as in `3/4`.
+    pub fn from_str(text: &str) -> Option<Fraction> {
+        let (numerator, denominator) = match text.find('/') {
+            Some(index) => (&text[..index], &text[index + 1..]),
+            None => (text, "1"),
+        };
+        let numerator = try_opt!(parse_int(numerator));
+        let denominator = try_opt!(parse_int(denominator));
+        Fraction::new(numerator, denominator)
+    }
+
+    /// Return the numerator.
+    pub fn numerator(&self) -> &BigInt {
+        &self.numerator
+    }
+
+    /// Return the denominator, which is always positive.
+    pub fn denominator(&self) -> &BigInt {
+        &self.denominator
+    }
+
+    /// Convert to the nearest float.
+    pub fn to_f64(&self) -> f64 {
+        // Scale down numbers that are too large for floats.
+        let shift = cmp::max(self.numerator.bits(), self.denominator.bits()).saturating_sub(1000);
+        let numerator = self.numerator.abs() >> shift;
+        let denominator = &self.denominator >> shift;
+        let result = numerator.to_f64().unwrap_or(f64::INFINITY) /
+            denominator.to_f64().unwrap_or(f64::INFINITY);
+        if self.numerator.is_negative() { -result } else { result }
+    }
+}
+
+fn parse_int(text: &str) -> Option<BigInt> {
+    BigInt::parse_bytes(try_opt!(strip_plus(text)).as_bytes(), 10)
+}
+
+impl From<BigInt> for Fraction {
+    fn from(value: BigInt) -> Fraction {
+        Fraction { numerator: value, denominator: BigInt::one() }
+    }
+}
+
+/// A complex number, as `complex`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Complex {
+    /// Real part
+    pub re: f64,
+    /// Imaginary part
+    pub im: f64,
+}
+
+impl Complex {
+    /// Construct a complex number.
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re: re, im: im }
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl fmt::Display for Fraction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.denominator.is_one() {
+            write!(f, "{}", self.numerator)
+        } else {
+            write!(f, "{}/{}", self.numerator, self.denominator)
+        }
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Like Python, omit a real part of positive zero.
+        if self.re == 0.0 && self.re.is_sign_positive() {
+            write!(f, "{}j", self.im)
+        } else if self.im.is_sign_negative() {
+            write!(f, "({}{}j)", self.re, self.im)
+        } else {
+            write!(f, "({}+{}j)", self.re, self.im)
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! NumPy arrays, available with the `numpy` feature.
+//!
+//! NumPy pickles an array as `numpy.core.multiarray._reconstruct(ndarray,
+//! (0,), b'b')`, followed by BUILD with a state tuple that contains the
+//! shape, the `numpy.dtype` and the raw data.  With protocol 5, contiguous
+//! arrays are pickled as `numpy.core.numeric._frombuffer(buffer, dtype,
+//! shape, order)` instead.
+//!
+//! The deserializer decodes both into a `Value::Object` of class
+//! `numpy.ndarray`, with the state of the first form, and the serializer
+//! writes such objects like NumPy does.  `NdArray` converts from and to
+//! these objects.
+
+use std::fmt;
+use std::collections::BTreeMap;
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use error::{Error, ErrorCode, Result};
+use numbers::Complex;
+use value::Value;
+
+/// The NumPy globals that are allowed in safe mode, in addition to the
+/// deserializer's `SAFE_GLOBALS`.
+pub const SAFE_GLOBALS: &'static [(&'static str, &'static str)] = &[
+    ("numpy", "ndarray"),
+    ("numpy", "dtype"),
+    ("numpy.core.multiarray", "_reconstruct"),
+    ("numpy._core.multiarray", "_reconstruct"),
+    ("numpy.core.numeric", "_frombuffer"),
+    ("numpy._core.numeric", "_frombuffer"),
+];
+
+/// The data type of array elements, as `numpy.dtype`.
+///
+/// Only the scalar types with a fixed layout are supported: booleans,
+/// integers, floats, complex numbers and fixed-size byte and Unicode strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DType {
+    /// The kind of the type: `b` (boolean), `i` (signed integer), `u`
+    /// (unsigned integer), `f` (float), `c` (complex), `S` (bytestring) or
+    /// `U` (Unicode string, as UCS-4)
+    pub kind: char,
+    /// Size of an element in bytes
+    pub itemsize: usize,
+    /// True if the elements are stored in big-endian byte order
+    pub big_endian: bool,
+}
+
+impl DType {
+    /// Construct a data type, if it is supported.
+    pub fn new(kind: char, itemsize: usize, big_endian: bool) -> Option<DType> {
+        let valid = match kind {
+            'b' => itemsize == 1,
+            'i' | 'u' => [1, 2, 4, 8].contains(&itemsize),
+            'f' => [2, 4, 8, 16].contains(&itemsize),
+            'c' => [8, 16, 32].contains(&itemsize),
+            'S' => true,
+            'U' => itemsize % 4 == 0,
+            _ => false,
+        };
+        if valid {
+            Some(DType { kind: kind, itemsize: itemsize, big_endian: big_endian })
+        } else {
+            None
+        }
+    }
+
+    /// Parse a type string, like NumPy's `dtype.str`, e.g. `<f8` or `|b1`.
+    /// Without the byte order character, native byte order is used.
+    pub fn from_descr(descr: &str) -> Option<DType> {
+        let (big_endian, rest) = match descr.chars().next() {
+            Some('<') | Some('|') => (false, &descr[1..]),
+            Some('>') => (true, &descr[1..]),
+            Some('=') => (cfg!(target_endian = "big"), &descr[1..]),
+            _ => (cfg!(target_endian = "big"), descr),
+        };
+        let kind = try_opt!(rest.chars().next());
+        let count: usize = try_opt!(rest[kind.len_utf8()..].parse().ok());
+        // The length of Unicode strings is given in characters.
+        let itemsize = if kind == 'U' { try_opt!(count.checked_mul(4)) } else { count };
+        DType::new(kind, itemsize, big_endian)
+    }
+
+    /// Return the type string, like NumPy's `dtype.str`.
+    pub fn descr(&self) -> String {
+        format!("{}{}", self.byteorder_char(), self.name())
+    }
+
+    // The type string without byte order, as given to `numpy.dtype()` when
+    // pickled.
+    fn name(&self) -> String {
+        let count = if self.kind == 'U' { self.itemsize / 4 } else { self.itemsize };
+        format!("{}{}", self.kind, count)
+    }
+
+    fn byteorder_char(&self) -> char {
+        if self.itemsize == 1 || self.kind == 'S' {
+            '|'
+        } else if self.big_endian {
+            '>'
+        } else {
+            '<'
+        }
+    }
+
+    /// Convert a `numpy.dtype` object.
+    pub fn from_value(value: &Value) -> Result<DType> {
+        let obj = match *value {
+            Value::Object(ref obj) if obj.class.0 == "numpy" && obj.class.1 == "dtype" => obj,
+            _ => return invalid("numpy dtype"),
+        };
+        let name = match obj.args.first() {
+            Some(&Value::String(ref name)) => name.clone(),
+            Some(&Value::Bytes(ref name)) => String::from_utf8_lossy(name).into_owned(),
+            _ => return invalid("numpy dtype"),
+        };
+        // The byte order is given by the state, if any.
+        let byteorder = match obj.state {
+            Some(Value::Tuple(ref state)) => match state.get(1) {
+                Some(&Value::String(ref order)) => order.clone(),
+                Some(&Value::Bytes(ref order)) => String::from_utf8_lossy(order).into_owned(),
+                _ => return invalid("numpy dtype"),
+            },
+            _ => String::new(),
+        };
+        match DType::from_descr(&format!("{}{}", byteorder, name)) {
+            Some(dtype) => Ok(dtype),
+            None => Err(Error::Syntax(ErrorCode::InvalidValue(
+                format!("unsupported numpy dtype {}", name)))),
+        }
+    }
+
+    /// Convert to a `numpy.dtype` object.
+    pub fn to_value(&self) -> Value {
+        // Strings need the element size in the state, so their byte order
+        // is given in the argument instead.
+        let (name, state) = match self.kind {
+            'S' | 'U' => (self.descr(), None),
+            _ => (self.name(), Some(Value::Tuple(vec![
+                Value::I64(3), Value::String(self.byteorder_char().to_string()),
+                Value::None, Value::None, Value::None,
+                Value::I64(-1), Value::I64(-1), Value::I64(0)]))),
+        };
+        Value::Object(Box::new(::value::Object {
+            class: ("numpy".into(), "dtype".into()),
+            args: vec![Value::String(name), Value::Bool(false), Value::Bool(true)],
+            kwargs: BTreeMap::new(),
+            state: state,
+        }))
+    }
+}
+
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.descr())
+    }
+}
+
+/// Rust types that can be the elements of an `NdArray`.
+pub trait Element: Sized {
+    /// The data type of the elements, in little-endian byte order.
+    fn dtype() -> DType;
+
+    /// Read an element from its bytes.
+    fn read(bytes: &[u8], big_endian: bool) -> Self;
+
+    /// Append the bytes of the element, in little-endian byte order.
+    fn write(&self, out: &mut Vec<u8>);
+}
+
+macro_rules! impl_element {
+    ($ty:ty, $kind:expr, $size:expr, $read:ident, $write:ident) => {
+        impl Element for $ty {
+            fn dtype() -> DType {
+                DType { kind: $kind, itemsize: $size, big_endian: false }
+            }
+
+            fn read(bytes: &[u8], big_endian: bool) -> $ty {
+                if big_endian { BigEndian::$read(bytes) } else { LittleEndian::$read(bytes) }
+            }
+
+            fn write(&self, out: &mut Vec<u8>) {
+                let mut buf = [0; $size];
+                LittleEndian::$write(&mut buf, *self);
+                out.extend_from_slice(&buf);
+            }
+        }
+    }
+}
+
+impl_element!(i16, 'i', 2, read_i16, write_i16);
+impl_element!(i32, 'i', 4, read_i32, write_i32);
+impl_element!(i64, 'i', 8, read_i64, write_i64);
+impl_element!(u16, 'u', 2, read_u16, write_u16);
+impl_element!(u32, 'u', 4, read_u32, write_u32);
+impl_element!(u64, 'u', 8, read_u64, write_u64);
+impl_element!(f32, 'f', 4, read_f32, write_f32);
+impl_element!(f64, 'f', 8, read_f64, write_f64);
+
+impl Element for i8 {
+    fn dtype() -> DType { DType { kind: 'i', itemsize: 1, big_endian: false } }
+    fn read(bytes: &[u8], _: bool) -> i8 { bytes[0] as i8 }
+    fn write(&self, out: &mut Vec<u8>) { out.push(*self as u8) }
+}
+
+impl Element for u8 {
+    fn dtype() -> DType { DType { kind: 'u', itemsize: 1, big_endian: false } }
+    fn read(bytes: &[u8], _: bool) -> u8 { bytes[0] }
+    fn write(&self, out: &mut Vec<u8>) { out.push(*self) }
+}
+
+impl Element for bool {
+    fn dtype() -> DType { DType { kind: 'b', itemsize: 1, big_endian: false } }
+    fn read(bytes: &[u8], _: bool) -> bool { bytes[0] != 0 }
+    fn write(&self, out: &mut Vec<u8>) { out.push(*self as u8) }
+}
+
+impl Element for Complex {
+    fn dtype() -> DType { DType { kind: 'c', itemsize: 16, big_endian: false } }
+
+    fn read(bytes: &[u8], big_endian: bool) -> Complex {
+        Complex::new(f64::read(&bytes[..8], big_endian), f64::read(&bytes[8..], big_endian))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        self.re.write(out);
+        self.im.write(out);
+    }
+}
+
+/// A NumPy array, as `numpy.ndarray`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NdArray {
+    /// Data type of the elements
+    pub dtype: DType,
+    /// Length of each dimension
+    pub shape: Vec<usize>,
+    /// True if the data is in Fortran (column-major) order instead of C
+    /// (row-major) order
+    pub fortran_order: bool,
+    /// The raw data of the elements
+    pub data: Vec<u8>,
+}
+
+impl NdArray {
+    /// Construct an array in C order from its elements.
+    pub fn from_vec<T: Element>(shape: Vec<usize>, items: &[T]) -> Result<NdArray> {
+        if num_elements(&shape) != Some(items.len()) {
+            return invalid("array shape");
+        }
+        let dtype = T::dtype();
+        let mut data = Vec::with_capacity(items.len() * dtype.itemsize);
+        for item in items {
+            item.write(&mut data);
+        }
+        Ok(NdArray { dtype: dtype, shape: shape, fortran_order: false, data: data })
+    }
+
+    /// Return the number of elements, or None if the shape is too large.
+    pub fn len(&self) -> Option<usize> {
+        num_elements(&self.shape)
+    }
+
+    /// Return the elements in C order, if their type matches the data type.
+    pub fn to_vec<T: Element>(&self) -> Result<Vec<T>> {
+        let dtype = T::dtype();
+        if dtype.kind != self.dtype.kind || dtype.itemsize != self.dtype.itemsize {
+            return Err(Error::Syntax(ErrorCode::InvalidValue(
+                format!("array of dtype {}", self.dtype))));
+        }
+        let size = dtype.itemsize;
+        let len = match self.len() {
+            Some(len) if len.checked_mul(size) == Some(self.data.len()) => len,
+            _ => return invalid("array data"),
+        };
+        let read = |index: usize| T::read(&self.data[index * size..(index + 1) * size],
+                                          self.dtype.big_endian);
+        if !self.fortran_order || self.shape.len() < 2 {
+            return Ok((0..len).map(read).collect());
+        }
+        // Walk through the indices in C order, and find the elements at
+        // their position in Fortran order.
+        let mut strides = Vec::with_capacity(self.shape.len());
+        let mut stride = 1usize;
+        for &len in &self.shape {
+            strides.push(stride);
+            stride = match stride.checked_mul(len) {
+                Some(stride) => stride,
+                None => return invalid("array shape"),
+            };
+        }
+        let mut index = vec![0; self.shape.len()];
+        let mut result = Vec::with_capacity(len);
+        for _ in 0..len {
+            result.push(read(index.iter().zip(&strides).map(|(i, s)| i * s).sum()));
+            for dim in (0..index.len()).rev() {
+                index[dim] += 1;
+                if index[dim] < self.shape[dim] {
+                    break;
+                }
+                index[dim] = 0;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Convert a `numpy.ndarray` object.
+    pub fn from_value(value: &Value) -> Result<NdArray> {
+        let obj = match *value {
+            Value::Object(ref obj) if obj.class.0 == "numpy" && obj.class.1 == "ndarray" => obj,
+            _ => return invalid("numpy array"),
+        };
+        // The state is (version, shape, dtype, is_fortran, data), without
+        // the version for NumPy before 1.0.
+        let state = match obj.state {
+            Some(Value::Tuple(ref state)) if state.len() == 5 => &state[1..],
+            Some(Value::Tuple(ref state)) if state.len() == 4 => &state[..],
+            _ => return invalid("numpy array state"),
+        };
+        let mut shape = Vec::new();
+        match state[0] {
+            Value::Tuple(ref dims) => for dim in dims {
+                match *dim {
+                    Value::I64(len) if len >= 0 => shape.push(len as usize),
+                    _ => return invalid("numpy array shape"),
+                }
+            },
+            _ => return invalid("numpy array shape"),
+        }
+        let dtype = try!(DType::from_value(&state[1]));
+        let fortran_order = match state[2] {
+            Value::Bool(b) => b,
+            Value::I64(i) => i != 0,
+            _ => return invalid("numpy array state"),
+        };
+        let data = match state[3] {
+            Value::Bytes(ref b) | Value::ByteArray(ref b) => b.clone(),
+            // Written by Python 2, and decoded as Unicode.
+            Value::String(ref s) if s.chars().all(|ch| (ch as u32) < 256) =>
+                s.chars().map(|ch| ch as u8).collect(),
+            _ => return invalid("numpy array data"),
+        };
+        let array = NdArray { dtype: dtype, shape: shape, fortran_order: fortran_order,
+                              data: data };
+        if array.len().and_then(|len| len.checked_mul(dtype.itemsize)) != Some(array.data.len()) {
+            return invalid("numpy array data");
+        }
+        Ok(array)
+    }
+
+    /// Convert to a `numpy.ndarray` object.
+    pub fn to_value(&self) -> Value {
+        let shape = self.shape.iter().map(|&len| Value::I64(len as i64)).collect();
+        Value::Object(Box::new(::value::Object {
+            class: ("numpy".into(), "ndarray".into()),
+            args: vec![Value::Tuple(vec![Value::I64(0)]), Value::Bytes(b"b".to_vec())],
+            kwargs: BTreeMap::new(),
+            state: Some(Value::Tuple(vec![
+                Value::I64(1), Value::Tuple(shape), self.dtype.to_value(),
+                Value::Bool(self.fortran_order), Value::Bytes(self.data.clone())])),
+        }))
+    }
+}
+
+fn num_elements(shape: &[usize]) -> Option<usize> {
+    shape.iter().fold(Some(1), |n, &len| n.and_then(|n| n.checked_mul(len)))
+}
+
+fn invalid<T>(what: &str) -> Result<T> {
+    Err(Error::Syntax(ErrorCode::InvalidValue(what.into())))
+}
@@ -0,0 +1,539 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! pandas DataFrames and Series, available with the `pandas` feature.
+//!
+//! pandas pickles a DataFrame or Series as an object whose state holds a
+//! block manager.  The manager consists of the axes, which are `Index`
+//! objects, and blocks of columns with the same dtype, whose values are
+//! NumPy arrays (or pandas arrays backed by one, like `DatetimeArray`).
+//!
+//! `DataFrame::from_reader` and `Series::from_reader` decode such pickles
+//! with a resolver that keeps calls to pandas functions and classes as
+//! `Value::Object`s of the called global, and reconstruct the columns from
+//! these objects.  `value_from_reader` gives the objects themselves, which
+//! `DataFrame::from_value` and `Series::from_value` convert.
+
+use std::io::Read;
+use std::collections::BTreeMap;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use de::{Deserializer, DeOptions, GlobalResolver};
+use error::{Error, ErrorCode, Result};
+use numpy::{DType, Element, NdArray};
+use value::{Value, HashableValue, Object};
+
+/// The pandas globals that are allowed in safe mode, in addition to the
+/// deserializer's `SAFE_GLOBALS` and `numpy::SAFE_GLOBALS`.
+pub const SAFE_GLOBALS: &'static [(&'static str, &'static str)] = &[
+    ("builtins", "slice"),
+    ("__builtin__", "slice"),
+    ("pandas.core.frame", "DataFrame"),
+    ("pandas.core.series", "Series"),
+    ("pandas.core.internals.managers", "BlockManager"),
+    ("pandas.core.internals.managers", "SingleBlockManager"),
+    ("pandas.core.internals.blocks", "new_block"),
+    ("pandas._libs.internals", "_unpickle_block"),
+    ("pandas.core.indexes.base", "_new_Index"),
+    ("pandas.core.indexes.base", "Index"),
+    ("pandas.core.indexes.range", "RangeIndex"),
+    ("pandas.core.indexes.numeric", "Int64Index"),
+    ("pandas.core.indexes.numeric", "UInt64Index"),
+    ("pandas.core.indexes.numeric", "Float64Index"),
+    ("pandas.core.indexes.datetimes", "_new_DatetimeIndex"),
+    ("pandas.core.indexes.datetimes", "DatetimeIndex"),
+    ("pandas.core.indexes.timedeltas", "TimedeltaIndex"),
+    ("pandas._libs.arrays", "__pyx_unpickle_NDArrayBacked"),
+    ("pandas.core.arrays.datetimes", "DatetimeArray"),
+    ("pandas.core.arrays.timedeltas", "TimedeltaArray"),
+];
+
+/// The values of a column, or of an index.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    /// Booleans
+    Bool(Vec<bool>),
+    /// Signed integers of any size
+    Int(Vec<i64>),
+    /// Unsigned integers of any size
+    UInt(Vec<u64>),
+    /// Floats of any size
+    Float(Vec<f64>),
+    /// Datetimes, as nanoseconds since the Unix epoch, with `None` for NaT
+    DateTime(Vec<Option<i64>>),
+    /// Timedeltas in nanoseconds, with `None` for NaT
+    TimeDelta(Vec<Option<i64>>),
+    /// Python objects, usually strings
+    Object(Vec<Value>),
+}
+
+impl Column {
+    /// Return the number of values.
+    pub fn len(&self) -> usize {
+        match *self {
+            Column::Bool(ref v) => v.len(),
+            Column::Int(ref v) => v.len(),
+            Column::UInt(ref v) => v.len(),
+            Column::Float(ref v) => v.len(),
+            Column::DateTime(ref v) | Column::TimeDelta(ref v) => v.len(),
+            Column::Object(ref v) => v.len(),
+        }
+    }
+
+    /// Convert the values to `Value`s, with datetimes and timedeltas as
+    /// integers and NaT as `None`.
+    pub fn into_values(self) -> Vec<Value> {
+        match self {
+            Column::Bool(v) => v.into_iter().map(Value::Bool).collect(),
+            Column::Int(v) => v.into_iter().map(Value::I64).collect(),
+            Column::UInt(v) => v.into_iter().map(|u| {
+                if u > i64::max_value() as u64 { Value::Int(BigInt::from(u)) }
+                else { Value::I64(u as i64) }
+            }).collect(),
+            Column::Float(v) => v.into_iter().map(Value::F64).collect(),
+            Column::DateTime(v) | Column::TimeDelta(v) =>
+                v.into_iter().map(|t| t.map_or(Value::None, Value::I64)).collect(),
+            Column::Object(v) => v,
+        }
+    }
+
+    fn slice(&self, start: usize, end: usize) -> Column {
+        match *self {
+            Column::Bool(ref v) => Column::Bool(v[start..end].to_vec()),
+            Column::Int(ref v) => Column::Int(v[start..end].to_vec()),
+            Column::UInt(ref v) => Column::UInt(v[start..end].to_vec()),
+            Column::Float(ref v) => Column::Float(v[start..end].to_vec()),
+            Column::DateTime(ref v) => Column::DateTime(v[start..end].to_vec()),
+            Column::TimeDelta(ref v) => Column::TimeDelta(v[start..end].to_vec()),
+            Column::Object(ref v) => Column::Object(v[start..end].to_vec()),
+        }
+    }
+}
+
+/// A pandas DataFrame, as `pandas.DataFrame`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataFrame {
+    /// Labels of the rows
+    pub index: Column,
+    /// Labels and values of the columns, in order
+    pub columns: Vec<(Value, Column)>,
+}
+
+impl DataFrame {
+    /// Decode a pickled DataFrame from a reader.
+    pub fn from_reader<R: Read>(rdr: R, options: DeOptions) -> Result<DataFrame> {
+        DataFrame::from_value(&try!(value_from_reader(rdr, options)))
+    }
+
+    /// Convert a `pandas.DataFrame` object.
+    pub fn from_value(value: &Value) -> Result<DataFrame> {
+        let (axes, blocks) = try!(manager(try!(frame_state(value, "DataFrame")).0));
+        if axes.len() != 2 {
+            return invalid("DataFrame axes");
+        }
+        let labels = try!(index_values(axes[0], None)).into_values();
+        let mut block_values = Vec::with_capacity(blocks.len());
+        for (values, placement) in blocks {
+            block_values.push((try!(array_values(values)),
+                               try!(placement_indices(placement, labels.len()))));
+        }
+        // The values of 2-D blocks have a row for each column.
+        let nrows = block_values.iter().find(|&&(_, ref locs)| !locs.is_empty())
+                                       .map(|&(ref values, ref locs)| values.len() / locs.len());
+        let index = try!(index_values(axes[1], nrows));
+        let nrows = index.len();
+        let mut columns = vec![None; labels.len()];
+        for (values, locs) in block_values {
+            if values.len() != locs.len() * nrows {
+                return invalid("DataFrame block");
+            }
+            for (i, &loc) in locs.iter().enumerate() {
+                match columns.get_mut(loc) {
+                    Some(column) => *column = Some(values.slice(i * nrows, (i + 1) * nrows)),
+                    None => return invalid("DataFrame block"),
+                }
+            }
+        }
+        let mut result = Vec::with_capacity(labels.len());
+        for (label, column) in labels.into_iter().zip(columns) {
+            match column {
+                Some(column) => result.push((label, column)),
+                None => return invalid("DataFrame block"),
+            }
+        }
+        Ok(DataFrame { index: index, columns: result })
+    }
+}
+
+/// A pandas Series, as `pandas.Series`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Series {
+    /// Name of the series, usually a string or `None`
+    pub name: Value,
+    /// Labels of the values
+    pub index: Column,
+    /// The values
+    pub values: Column,
+}
+
+impl Series {
+    /// Decode a pickled Series from a reader.
+    pub fn from_reader<R: Read>(rdr: R, options: DeOptions) -> Result<Series> {
+        Series::from_value(&try!(value_from_reader(rdr, options)))
+    }
+
+    /// Convert a `pandas.Series` object.
+    pub fn from_value(value: &Value) -> Result<Series> {
+        let (mgr, state) = try!(frame_state(value, "Series"));
+        let (axes, blocks) = try!(manager(mgr));
+        if axes.len() != 1 || blocks.len() != 1 {
+            return invalid("Series block");
+        }
+        let values = try!(array_values(blocks[0].0));
+        let index = try!(index_values(axes[0], Some(values.len())));
+        if values.len() != index.len() {
+            return invalid("Series block");
+        }
+        let name = get(state, "_name").or_else(|| get(state, "name")).cloned();
+        Ok(Series { name: name.unwrap_or(Value::None), index: index, values: values })
+    }
+}
+
+/// Decode a pickle from a reader, keeping calls to pandas functions and
+/// classes as objects.
+pub fn value_from_reader<R: Read>(rdr: R, options: DeOptions) -> Result<Value> {
+    let mut de = Deserializer::with_options(rdr, options);
+    de.add_resolver(Resolver);
+    let value = try!(de.decode_value());
+    try!(de.end());
+    Ok(value)
+}
+
+// Keeps calls to pandas functions and classes as objects of the called
+// global.  The helpers that create an instance of the class given as the
+// first argument, _new_Index(cls, d) with `d` as keyword arguments and
+// Cython's __pyx_unpickle_X(cls, checksum, state), produce an object of
+// that class instead.
+struct Resolver;
+
+impl GlobalResolver for Resolver {
+    fn handles(&self, module: &str, name: &str) -> bool {
+        module.split('.').next() == Some("pandas") ||
+            ((module == "builtins" || module == "__builtin__") && name == "slice")
+    }
+
+    fn reduce(&self, module: &str, name: &str, mut args: Vec<Value>) -> Result<Value> {
+        let kwargs = name.starts_with("_new_");
+        if !kwargs && !name.starts_with("__pyx_unpickle_") {
+            return Ok(new_object((module.into(), name.into()), args, BTreeMap::new(), None));
+        }
+        if args.len() < 2 {
+            return invalid(&format!("{}() args", name));
+        }
+        // The class is given as its `module.name`.
+        let class = match args.remove(0) {
+            Value::String(ref class) => match class.rfind('.') {
+                Some(dot) => (class[..dot].to_owned(), class[dot + 1..].to_owned()),
+                None => return invalid(&format!("{}() arg", name)),
+            },
+            _ => return invalid(&format!("{}() arg", name)),
+        };
+        match args.pop() {
+            Some(Value::Dict(items)) if kwargs => Ok(new_object(class, Vec::new(), items, None)),
+            Some(_) if kwargs => invalid(&format!("{}() arg", name)),
+            Some(Value::None) | None => Ok(new_object(class, Vec::new(), BTreeMap::new(), None)),
+            state => Ok(new_object(class, Vec::new(), BTreeMap::new(), state)),
+        }
+    }
+}
+
+fn new_object(class: (String, String), args: Vec<Value>,
+              kwargs: BTreeMap<HashableValue, Value>, state: Option<Value>) -> Value {
+    Value::Object(Box::new(Object { class: class, args: args, kwargs: kwargs, state: state }))
+}
+
+fn invalid<T>(what: &str) -> Result<T> {
+    Err(Error::Syntax(ErrorCode::InvalidValue(format!("pandas {}", what))))
+}
+
+fn get<'a>(map: &'a BTreeMap<HashableValue, Value>, key: &str) -> Option<&'a Value> {
+    map.get(&HashableValue::String(key.into()))
+}
+
+fn object<'a>(value: &'a Value, what: &str) -> Result<&'a Object> {
+    match *value {
+        Value::Object(ref obj) => Ok(obj),
+        _ => invalid(what),
+    }
+}
+
+fn items(value: &Value) -> Option<&[Value]> {
+    match *value {
+        Value::List(ref items) | Value::Tuple(ref items) => Some(items),
+        _ => None,
+    }
+}
+
+fn is_ndarray(value: &Value) -> bool {
+    match *value {
+        Value::Object(ref obj) => obj.class.0 == "numpy" && obj.class.1 == "ndarray",
+        _ => false,
+    }
+}
+
+fn to_usize(value: &Value) -> Option<usize> {
+    match *value {
+        Value::I64(i) if i >= 0 => Some(i as usize),
+        _ => None,
+    }
+}
+
+// Get the block manager and the state dict of a DataFrame or Series.
+fn frame_state<'a>(value: &'a Value, class: &str)
+                   -> Result<(&'a Value, &'a BTreeMap<HashableValue, Value>)> {
+    let obj = try!(object(value, class));
+    if obj.class.1 != class {
+        return invalid(class);
+    }
+    let state = match obj.state {
+        Some(Value::Dict(ref state)) => state,
+        _ => return invalid(class),
+    };
+    // Called `_data` before pandas 1.1.
+    match get(state, "_mgr").or_else(|| get(state, "_data")) {
+        Some(mgr) => Ok((mgr, state)),
+        None => invalid(class),
+    }
+}
+
+// Get the axes and the blocks, as (values, placement), of a block manager.
+fn manager(value: &Value) -> Result<(Vec<&Value>, Vec<(&Value, &Value)>)> {
+    let obj = try!(object(value, "block manager"));
+    // Since pandas 1.3, pickled as BlockManager(blocks, axes), with blocks
+    // created by _unpickle_block(values, placement, ndim).  For a
+    // SingleBlockManager, both arguments are single items.
+    if obj.args.len() >= 2 {
+        let axes = items(&obj.args[1]).map_or(vec![&obj.args[1]], |axes| axes.iter().collect());
+        let mut blocks = Vec::new();
+        for block in items(&obj.args[0]).unwrap_or(&obj.args[..1]) {
+            let block = try!(object(block, "block"));
+            if block.args.len() < 2 {
+                return invalid("block");
+            }
+            blocks.push((&block.args[0], &block.args[1]));
+        }
+        return Ok((axes, blocks));
+    }
+    // Before, the state is (axes, values, items, extra), where extra has
+    // the axes and the blocks, as dicts, under the key "0.14.1".
+    let extra = match obj.state {
+        Some(Value::Tuple(ref state)) if state.len() >= 4 => match state[3] {
+            Value::Dict(ref extra) => get(extra, "0.14.1"),
+            _ => None,
+        },
+        _ => None,
+    };
+    let extra = match extra {
+        Some(&Value::Dict(ref extra)) => extra,
+        _ => return invalid("block manager"),
+    };
+    let (axes, blocks) = match (get(extra, "axes").and_then(items),
+                                get(extra, "blocks").and_then(items)) {
+        (Some(axes), Some(blocks)) => (axes, blocks),
+        _ => return invalid("block manager"),
+    };
+    let mut result = Vec::new();
+    for block in blocks {
+        match *block {
+            Value::Dict(ref block) => match (get(block, "values"), get(block, "mgr_locs")) {
+                (Some(values), Some(locs)) => result.push((values, locs)),
+                _ => return invalid("block"),
+            },
+            _ => return invalid("block"),
+        }
+    }
+    Ok((axes.iter().collect(), result))
+}
+
+// Get the column positions of a block, given as a slice or an array, for a
+// frame with the given number of columns.
+fn placement_indices(value: &Value, ncols: usize) -> Result<Vec<usize>> {
+    if let Value::Object(ref obj) = *value {
+        if obj.class.1 == "slice" && obj.args.len() == 3 {
+            let start = if obj.args[0] == Value::None { Some(0) } else { to_usize(&obj.args[0]) };
+            let step = if obj.args[2] == Value::None { Some(1) } else { to_usize(&obj.args[2]) };
+            return match (start, to_usize(&obj.args[1]), step) {
+                (Some(start), Some(stop), Some(step)) if step > 0 && stop <= ncols =>
+                    Ok((start..stop).step_by(step).collect()),
+                _ => invalid("block placement"),
+            };
+        }
+    }
+    match try!(array_values(value)) {
+        Column::Int(locs) if locs.iter().all(|&loc| loc >= 0) =>
+            Ok(locs.into_iter().map(|loc| loc as usize).collect()),
+        _ => invalid("block placement"),
+    }
+}
+
+// Get the labels of an Index, which is created by _new_Index(cls, d) with
+// the arguments in `d`.  The length of a RangeIndex is checked against the
+// number of values it labels, if known, before the labels are created.
+fn index_values(value: &Value, len: Option<usize>) -> Result<Column> {
+    let obj = try!(object(value, "index"));
+    if let Some(data) = get(&obj.kwargs, "data") {
+        return array_values(data);
+    }
+    let (start, stop) = match (get(&obj.kwargs, "start"), get(&obj.kwargs, "stop")) {
+        (Some(&Value::I64(start)), Some(&Value::I64(stop))) => (start, stop),
+        _ => return invalid("index"),
+    };
+    let step = match get(&obj.kwargs, "step") {
+        Some(&Value::I64(step)) if step != 0 => step,
+        Some(&Value::None) | None => 1,
+        _ => return invalid("index"),
+    };
+    let count = match range_len(start, stop, step) {
+        Some(count) if len.map_or(count <= MAX_RANGE_LEN, |len| count == len) => count,
+        _ => return invalid("index"),
+    };
+    // No overflow, since all values are between start and stop.
+    Ok(Column::Int((0..count as i64).map(|k| start + k * step).collect()))
+}
+
+// The longest RangeIndex accepted without values to check it against, as
+// the index of a frame without columns.
+const MAX_RANGE_LEN: usize = 1 << 24;
+
+// Get the number of values of range(start, stop, step), if it fits.
+fn range_len(start: i64, stop: i64, step: i64) -> Option<usize> {
+    let span = try_opt!(if step > 0 { stop.checked_sub(start) } else { start.checked_sub(stop) });
+    let step = try_opt!(step.checked_abs());
+    if span <= 0 {
+        return Some(0);
+    }
+    (span / step + if span % step == 0 { 0 } else { 1 }).to_usize()
+}
+
+// Get the values of a NumPy array, or of a pandas array backed by one, in C
+// order.
+fn array_values(value: &Value) -> Result<Column> {
+    let obj = try!(object(value, "array"));
+    if obj.class.0 != "numpy" || obj.class.1 != "ndarray" {
+        // Arrays like DatetimeArray have the state (dtype, ndarray, attrs),
+        // or a dict with the ndarray.
+        let inner = match obj.state {
+            Some(Value::Tuple(ref state)) if state.len() == 3 =>
+                state.iter().find(|v| is_ndarray(v)),
+            Some(Value::Dict(ref state)) => get(state, "_ndarray").or_else(|| get(state, "_data")),
+            _ => None,
+        };
+        return match inner {
+            Some(inner) if is_ndarray(inner) => array_values(inner),
+            _ => invalid("array"),
+        };
+    }
+    let state = match obj.state {
+        Some(Value::Tuple(ref state)) if state.len() == 5 => state,
+        _ => return invalid("array"),
+    };
+    let dtype = try!(object(&state[2], "dtype"));
+    let name = match dtype.args.first() {
+        Some(&Value::String(ref name)) => name.clone(),
+        Some(&Value::Bytes(ref name)) => String::from_utf8_lossy(name).into_owned(),
+        _ => return invalid("dtype"),
+    };
+    if name.starts_with('O') {
+        // Object arrays are pickled with a list of the items in C order.
+        return match state[4] {
+            Value::List(ref items) => Ok(Column::Object(items.clone())),
+            _ => invalid("array"),
+        };
+    }
+    if name.starts_with('M') || name.starts_with('m') {
+        return datetime_values(obj, state, dtype, name.starts_with('M'));
+    }
+    let array = try!(NdArray::from_value(value));
+    Ok(match (array.dtype.kind, array.dtype.itemsize) {
+        ('b', _) => Column::Bool(try!(array.to_vec())),
+        ('i', 1) => Column::Int(try!(widen::<i8, _>(&array))),
+        ('i', 2) => Column::Int(try!(widen::<i16, _>(&array))),
+        ('i', 4) => Column::Int(try!(widen::<i32, _>(&array))),
+        ('i', 8) => Column::Int(try!(array.to_vec())),
+        ('u', 1) => Column::UInt(try!(widen::<u8, _>(&array))),
+        ('u', 2) => Column::UInt(try!(widen::<u16, _>(&array))),
+        ('u', 4) => Column::UInt(try!(widen::<u32, _>(&array))),
+        ('u', 8) => Column::UInt(try!(array.to_vec())),
+        ('f', 4) => Column::Float(try!(widen::<f32, _>(&array))),
+        ('f', 8) => Column::Float(try!(array.to_vec())),
+        _ => return Err(Error::Syntax(ErrorCode::InvalidValue(
+            format!("unsupported pandas dtype {}", array.dtype)))),
+    })
+}
+
+// Get the values of an array as a larger type of the same kind.
+fn widen<T: Element, U: From<T>>(array: &NdArray) -> Result<Vec<U>> {
+    Ok(try!(array.to_vec::<T>()).into_iter().map(From::from).collect())
+}
+
+// Get the values of a datetime64 or timedelta64 array in nanoseconds.  The
+// dtype state has the byte order, and the unit in its metadata as (unit,
+// multiplier, 1, 1).
+fn datetime_values(obj: &Object, state: &[Value], dtype: &Object, datetime: bool)
+                   -> Result<Column> {
+    let dtype_state = match dtype.state {
+        Some(Value::Tuple(ref state)) if state.len() == 9 => state,
+        _ => return invalid("dtype"),
+    };
+    let byteorder = match dtype_state[1] {
+        Value::String(ref order) => order.clone(),
+        Value::Bytes(ref order) => String::from_utf8_lossy(order).into_owned(),
+        _ => return invalid("dtype"),
+    };
+    let (unit, count) = match items(&dtype_state[8]) {
+        Some(meta) if meta.len() == 4 => match (&meta[0], &meta[1]) {
+            (&Value::Bytes(ref unit), &Value::I64(count)) =>
+                (String::from_utf8_lossy(unit).into_owned(), count),
+            (&Value::String(ref unit), &Value::I64(count)) => (unit.clone(), count),
+            _ => return invalid("dtype"),
+        },
+        _ => return invalid("dtype"),
+    };
+    let factor = match &*unit {
+        "ns" => 1,
+        "us" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" => 60_000_000_000,
+        "h" => 3_600_000_000_000,
+        "D" => 86_400_000_000_000,
+        _ => return Err(Error::Syntax(ErrorCode::InvalidValue(
+            format!("unsupported pandas datetime unit {}", unit)))),
+    };
+    let (factor, int_dtype) = match (count.checked_mul(factor),
+                                     DType::from_descr(&format!("{}i8", byteorder))) {
+        (Some(factor), Some(int_dtype)) => (factor, int_dtype),
+        _ => return invalid("dtype"),
+    };
+    // The values are 64-bit integers, with the smallest one for NaT.
+    let mut int_state = state.to_vec();
+    int_state[2] = int_dtype.to_value();
+    let int_array = Value::Object(Box::new(Object { state: Some(Value::Tuple(int_state)),
+                                                    ..obj.clone() }));
+    let mut values = Vec::new();
+    for value in try!(try!(NdArray::from_value(&int_array)).to_vec::<i64>()) {
+        if value == i64::min_value() {
+            values.push(None);
+        } else {
+            match value.checked_mul(factor) {
+                Some(value) => values.push(Some(value)),
+                None => return invalid("datetime"),
+            }
+        }
+    }
+    Ok(if datetime { Column::DateTime(values) } else { Column::TimeDelta(values) })
+}
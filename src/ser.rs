@@ -7,42 +7,523 @@
 //! Pickle serialization
 
 use std::io;
-use std::collections::BTreeSet;
+use std::cmp;
+use std::io::Write;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use serde::ser;
 use serde::ser::Serialize;
 use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
 use num_bigint::BigInt;
-use num_traits::Signed;
+use num_traits::{Signed, ToPrimitive};
 
 use super::consts::*;
-use super::error::{Error, Result};
+use super::error::{Error, ErrorCode, Result};
 use super::value::{Value, HashableValue};
+use super::graph::{Graph, GraphValue, GraphObject, NodeId};
+use super::disasm::{self, Arg, Instruction};
+use super::datetime::{Date, Time, DateTime, TimeDelta, TimeZone};
+use super::numbers::{Decimal, Fraction, Complex};
+
+type MemoId = u32;
+
+// Sizes for protocol 4 frames, as used by Python.
+const FRAME_SIZE_MIN: usize = 4;
+const FRAME_SIZE_TARGET: usize = 64 * 1024;
+
+/// How Rust enum variants are represented in the pickle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnumStyle {
+    /// Variants are written as tuples: `('Variant',)` for unit variants,
+    /// `('Variant', value)` for all others.  This is the default.
+    Tuple,
+    /// Unit variants are written as plain strings `'Variant'`, all others as
+    /// dicts `{'Variant': value}`, like serde_json does.
+    Dict,
+}
+
+/// Options for pickling.
+#[derive(Clone, Debug)]
+pub struct SerOptions {
+    proto: u8,
+    memoize: bool,
+    framing: bool,
+    py2_strings: bool,
+    enum_style: EnumStyle,
+    batch_size: usize,
+    out_of_band: bool,
+    extensions: HashMap<(String, String), u32>,
+}
+
+impl SerOptions {
+    /// Construct with default options: protocol 3, no memoization.
+    pub fn new() -> Self {
+        SerOptions {
+            proto: 3,
+            memoize: false,
+            framing: true,
+            py2_strings: false,
+            enum_style: EnumStyle::Tuple,
+            batch_size: 1000,
+            out_of_band: false,
+            extensions: HashMap::new(),
+        }
+    }
+
+    /// Set the pickle protocol to write.  All protocols from 0 to 5 are
+    /// supported.  Protocols 0 and 1 are the old text and binary protocols,
+    /// 2 is compatible with Python 2 and 3, 3 with Python 3, 4 with Python
+    /// 3.4 and later, and 5 with Python 3.8 and later.
+    pub fn proto(mut self, proto: u8) -> Self {
+        self.proto = proto;
+        self
+    }
+
+    /// Enable or disable memoization.
+    ///
+    /// With memoization, strings and bytestrings are written only once, and
+    /// later occurrences refer back to the first one, as Python does.  When
+    /// writing a `Value`, the same applies to containers and objects that
+    /// are equal to one written before; they will be the same object when
+    /// unpickled.
+    pub fn memoize(mut self, memoize: bool) -> Self {
+        self.memoize = memoize;
+        self
+    }
+
+    /// Enable or disable framing of the output (protocol 4 and later only).
+    /// This is enabled by default.
+    pub fn framing(mut self, framing: bool) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Write strings as Python 2 `str` objects (UTF-8 encoded bytestrings)
+    /// instead of `unicode` objects.  This only affects protocols 0 to 2.
+    pub fn py2_strings(mut self, py2_strings: bool) -> Self {
+        self.py2_strings = py2_strings;
+        self
+    }
+
+    /// Select how Rust enum variants are written.
+    pub fn enum_style(mut self, enum_style: EnumStyle) -> Self {
+        self.enum_style = enum_style;
+        self
+    }
+
+    /// Set the maximum number of items added to lists, dicts and sets by a
+    /// single opcode.  The default, as in Python, is 1000.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = cmp::max(batch_size, 1);
+        self
+    }
+
+    /// Register the global `module.name` under an extension code, like
+    /// Python's `copyreg.add_extension`.  With protocol 2 and later, the
+    /// global is then written as the code.  The unpickler needs the same
+    /// registration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the code is not between 1 and 0x7fffffff, or if the code or
+    /// the global is already registered with a different global or code.
+    pub fn add_extension(mut self, code: u32, module: &str, name: &str) -> Self {
+        assert!(code >= 1 && code <= 0x7fff_ffff, "extension code {} out of range", code);
+        let global = (module.to_owned(), name.to_owned());
+        for (other, &other_code) in &self.extensions {
+            assert!((other_code == code) == (*other == global),
+                    "extension code {} or global {}.{} already registered", code, module, name);
+        }
+        self.extensions.insert(global, code);
+        self
+    }
+}
+
+impl Default for SerOptions {
+    fn default() -> Self {
+        SerOptions::new()
+    }
+}
+
+/// Replaces values with references to objects stored outside of the pickle.
+///
+/// When writing `Value`s with a hook set by `Serializer::set_persistent_id`,
+/// every value (but not dict keys or set items) is given to the hook first.
+/// If it returns a persistent ID, that is written instead of the value, to
+/// be resolved by a `PersistentLoad` when unpickling.
+pub trait PersistentId {
+    /// Return the persistent ID for the value, or None to write it normally.
+    /// Protocol 0 only supports IDs that are ASCII strings.
+    fn persistent_id(&self, value: &Value) -> Option<Value>;
+}
+
+// A writer that collects output into frames, as used by protocol 4.  Unless
+// framing is started, output is passed through directly.
+struct Framer<W> {
+    writer: W,
+    frame: Option<Vec<u8>>,
+}
+
+impl<W: io::Write> Framer<W> {
+    fn start_framing(&mut self) {
+        self.frame = Some(Vec::with_capacity(FRAME_SIZE_TARGET));
+    }
 
+    fn end_framing(&mut self) -> io::Result<()> {
+        try!(self.commit_frame(true));
+        self.frame = None;
+        Ok(())
+    }
+
+    // Write out the current frame if it is big enough, or if forced to.
+    fn commit_frame(&mut self, force: bool) -> io::Result<()> {
+        if let Some(ref mut frame) = self.frame {
+            if frame.len() >= FRAME_SIZE_TARGET || (force && !frame.is_empty()) {
+                if frame.len() >= FRAME_SIZE_MIN {
+                    try!(self.writer.write_all(&[FRAME]));
+                    try!(self.writer.write_u64::<LittleEndian>(frame.len() as u64));
+                }
+                try!(self.writer.write_all(frame));
+                frame.clear();
+            }
+        }
+        Ok(())
+    }
+
+    // Write a big payload with its opcode header.  Python writes these
+    // outside of frames to avoid copying them.
+    fn write_large(&mut self, header: &[u8], payload: &[u8]) -> io::Result<()> {
+        if self.frame.is_some() && payload.len() >= FRAME_SIZE_TARGET {
+            try!(self.commit_frame(true));
+            try!(self.writer.write_all(header));
+            self.writer.write_all(payload)
+        } else {
+            try!(self.write_all(header));
+            self.write_all(payload)
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for Framer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.frame {
+            Some(ref mut frame) => frame.write(buf),
+            None => self.writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
 
 /// A structure for serializing Rust values into a Pickle stream.
 pub struct Serializer<W> {
-    writer: W,
-    use_proto_3: bool,
+    writer: Framer<W>,
+    proto: u8,
+    framing: bool,
+    memoize: bool,
+    py2_strings: bool,
+    enum_style: EnumStyle,
+    batch_size: usize,
+    extensions: HashMap<(String, String), u32>,  // extension codes of globals
+    memo_len: MemoId,                        // number of memoized values
+    strings: HashMap<String, MemoId>,        // memoized strings
+    bytestrings: HashMap<Vec<u8>, MemoId>,   // memoized bytestrings
+    shared: HashMap<usize, usize>,           // address of shared value -> group
+    groups: HashMap<usize, MemoId>,          // memoized groups of equal values
+    nodes: HashMap<NodeId, MemoId>,          // memoized graph nodes
+    buffers: Option<Vec<Vec<u8>>>,           // collected out-of-band buffers
+    persistent_id: Option<Box<PersistentId>>,  // user-supplied persistent ID hook
 }
 
 impl<W: io::Write> Serializer<W> {
     pub fn new(writer: W, use_proto_3: bool) -> Self {
+        Serializer::with_options(writer, proto_options(use_proto_3))
+    }
+
+    /// Construct a serializer with the given options.
+    pub fn with_options(writer: W, options: SerOptions) -> Self {
         Serializer {
-            writer: writer,
-            use_proto_3: use_proto_3,
+            writer: Framer { writer: writer, frame: None },
+            proto: options.proto,
+            framing: options.framing,
+            memoize: options.memoize,
+            py2_strings: options.py2_strings,
+            enum_style: options.enum_style,
+            batch_size: options.batch_size,
+            extensions: options.extensions,
+            memo_len: 0,
+            strings: HashMap::new(),
+            bytestrings: HashMap::new(),
+            shared: HashMap::new(),
+            groups: HashMap::new(),
+            nodes: HashMap::new(),
+            buffers: if options.out_of_band { Some(Vec::new()) } else { None },
+            persistent_id: None,
+        }
+    }
+
+    /// Set a hook that can replace values with persistent IDs.  It is only
+    /// used for `Value`s written with `encode_value`.
+    pub fn set_persistent_id<P: PersistentId + 'static>(&mut self, hook: P) {
+        self.persistent_id = Some(Box::new(hook));
+    }
+
+    /// Encode a complete pickle of the value into the stream.
+    ///
+    /// This is the same as `value_to_writer_with_options`, but uses the hooks
+    /// set on the serializer.
+    pub fn encode_value(&mut self, value: &Value) -> Result<()> {
+        try!(self.start());
+        try!(self.serialize_toplevel_value(value));
+        self.end()
+    }
+
+    // Check the options, and start a pickle with its header.
+    fn start(&mut self) -> Result<()> {
+        match self.proto {
+            5 => {}
+            0 ... 4 if self.buffers.is_none() => {}
+            proto => return Err(Error::Syntax(ErrorCode::UnsupportedProtocol(proto))),
+        }
+        if self.proto >= 2 {
+            try!(self.writer.write_all(&[PROTO, self.proto]));
         }
+        if self.proto >= 4 && self.framing {
+            self.writer.start_framing();
+        }
+        Ok(())
+    }
+
+    // Finish a pickle started with `start`.
+    fn end(&mut self) -> Result<()> {
+        try!(self.write_opcode(STOP));
+        self.writer.end_framing().map_err(From::from)
     }
 
     /// Unwrap the `Writer` from the `Serializer`.
     pub fn into_inner(self) -> W {
-        self.writer
+        self.writer.writer
     }
 
     #[inline]
     fn write_opcode(&mut self, opcode: u8) -> Result<()> {
+        try!(self.writer.commit_frame(false));
         self.writer.write_all(&[opcode]).map_err(From::from)
     }
 
+    // Write an opcode with a newline-terminated argument.
+    fn write_text(&mut self, opcode: u8, arg: &[u8]) -> Result<()> {
+        try!(self.write_opcode(opcode));
+        try!(self.writer.write_all(arg));
+        self.writer.write_all(b"\n").map_err(From::from)
+    }
+
+    // Write an opcode with a length-prefixed string argument.
+    fn write_counted(&mut self, opcode: u8, len_size: usize, payload: &[u8]) -> Result<()> {
+        try!(self.writer.commit_frame(false));
+        let mut header = Vec::with_capacity(9);
+        header.push(opcode);
+        match len_size {
+            1 => try!(header.write_u8(payload.len() as u8)),
+            4 => try!(header.write_u32::<LittleEndian>(payload.len() as u32)),
+            _ => try!(header.write_u64::<LittleEndian>(payload.len() as u64)),
+        }
+        self.writer.write_large(&header, payload).map_err(From::from)
+    }
+
+    // Put the stack top into the memo, and return its memo ID.
+    fn write_put(&mut self) -> Result<MemoId> {
+        let memo_id = self.memo_len;
+        self.memo_len += 1;
+        if self.proto >= 4 {
+            try!(self.write_opcode(MEMOIZE));
+        } else if self.proto == 0 {
+            try!(self.write_text(PUT, memo_id.to_string().as_bytes()));
+        } else if memo_id < 256 {
+            try!(self.write_opcode(BINPUT));
+            try!(self.writer.write_u8(memo_id as u8));
+        } else {
+            try!(self.write_opcode(LONG_BINPUT));
+            try!(self.writer.write_u32::<LittleEndian>(memo_id));
+        }
+        Ok(memo_id)
+    }
+
+    fn write_get(&mut self, memo_id: MemoId) -> Result<()> {
+        if self.proto == 0 {
+            self.write_text(GET, memo_id.to_string().as_bytes())
+        } else if memo_id < 256 {
+            try!(self.write_opcode(BINGET));
+            self.writer.write_u8(memo_id as u8).map_err(From::from)
+        } else {
+            try!(self.write_opcode(LONG_BINGET));
+            self.writer.write_u32::<LittleEndian>(memo_id).map_err(From::from)
+        }
+    }
+
+    // Write an integer with protocols 0 and 1, which don't have LONG1.
+    fn write_old_int(&mut self, value: i64) -> Result<()> {
+        if value < -0x8000_0000 || value >= 0x8000_0000 {
+            self.write_old_long(&value.to_string())
+        } else if self.proto == 0 {
+            self.write_text(INT, value.to_string().as_bytes())
+        } else if 0 <= value && value < 0x100 {
+            try!(self.write_opcode(BININT1));
+            self.writer.write_u8(value as u8).map_err(From::from)
+        } else if 0 <= value && value < 0x10000 {
+            try!(self.write_opcode(BININT2));
+            self.writer.write_u16::<LittleEndian>(value as u16).map_err(From::from)
+        } else {
+            try!(self.write_opcode(BININT));
+            self.writer.write_i32::<LittleEndian>(value as i32).map_err(From::from)
+        }
+    }
+
+    fn write_old_long(&mut self, repr: &str) -> Result<()> {
+        // The "L" suffix is required by Python 2.
+        self.write_text(LONG, format!("{}L", repr).as_bytes())
+    }
+
+    // Write a bytestring in-band (as opposed to an out-of-band buffer).
+    fn write_bytes(&mut self, value: &[u8]) -> Result<()> {
+        if self.memoize {
+            if let Some(&memo_id) = self.bytestrings.get(value) {
+                return self.write_get(memo_id);
+            }
+        }
+        if self.proto == 0 {
+            try!(self.write_text(STRING, &escape_string(value)));
+        } else if value.len() < 256 {
+            let op = if self.proto >= 3 { SHORT_BINBYTES } else { SHORT_BINSTRING };
+            try!(self.write_counted(op, 1, value));
+        } else if self.proto >= 3 && value.len() <= 0xffff_ffff {
+            try!(self.write_counted(BINBYTES, 4, value));
+        } else if self.proto >= 4 {
+            try!(self.write_counted(BINBYTES8, 8, value));
+        } else if value.len() <= 0x7fff_ffff {
+            // BINSTRING has a signed length.
+            try!(self.write_counted(BINSTRING, 4, value));
+        } else {
+            return Err(Error::Syntax(ErrorCode::InvalidLength(value.len())));
+        }
+        if self.memoize {
+            let memo_id = try!(self.write_put());
+            self.bytestrings.insert(value.into(), memo_id);
+        }
+        Ok(())
+    }
+
+    fn serialize_bytearray(&mut self, value: &[u8]) -> Result<()> {
+        if let Some(ref mut buffers) = self.buffers {
+            // Without READONLY_BUFFER, the buffer is writable.
+            buffers.push(value.to_vec());
+        } else if self.proto >= 5 {
+            return self.write_counted(BYTEARRAY8, 8, value);
+        } else {
+            let module = if self.proto >= 3 { "builtins" } else { "__builtin__" };
+            let nargs = if value.is_empty() { 0 } else { 1 };
+            return self.write_reduce(module, "bytearray", nargs,
+                                     |slf| slf.write_latin1_bytes(value));
+        }
+        self.write_opcode(NEXT_BUFFER)
+    }
+
+    fn write_empty_list(&mut self) -> Result<()> {
+        if self.proto == 0 {
+            try!(self.write_opcode(MARK));
+            self.write_opcode(LIST)
+        } else {
+            self.write_opcode(EMPTY_LIST)
+        }
+    }
+
+    fn write_empty_dict(&mut self) -> Result<()> {
+        if self.proto == 0 {
+            try!(self.write_opcode(MARK));
+            self.write_opcode(DICT)
+        } else {
+            self.write_opcode(EMPTY_DICT)
+        }
+    }
+
+    fn write_empty_tuple(&mut self) -> Result<()> {
+        if self.proto == 0 {
+            try!(self.write_opcode(MARK));
+            self.write_opcode(TUPLE)
+        } else {
+            self.write_opcode(EMPTY_TUPLE)
+        }
+    }
+
+    // Write items in batches, each one started by MARK and ended by `opcode`.
+    fn serialize_batched<I, F>(&mut self, items: I, opcode: u8, mut f: F) -> Result<()>
+        where I: Iterator, F: FnMut(&mut Self, I::Item) -> Result<()>
+    {
+        let mut items = items.peekable();
+        while items.peek().is_some() {
+            try!(self.write_opcode(MARK));
+            for item in items.by_ref().take(self.batch_size) {
+                try!(f(self, item));
+            }
+            try!(self.write_opcode(opcode));
+        }
+        Ok(())
+    }
+
+    // Append the item on the stack top to a list started by `serialize_seq`.
+    fn append_seq_item(&mut self, state: &mut Option<usize>) -> Result<()> {
+        if self.proto == 0 {
+            return self.write_opcode(APPEND);
+        }
+        // Batch appends as in Python pickle
+        *state.as_mut().unwrap() += 1;
+        if state.unwrap() == self.batch_size {
+            try!(self.write_opcode(APPENDS));
+            try!(self.write_opcode(MARK));
+            *state = Some(0);
+        }
+        Ok(())
+    }
+
+    // Append items to the list on the stack top.
+    fn serialize_list_items<'a, T: 'a, I, F>(&mut self, items: I, f: F) -> Result<()>
+        where I: IntoIterator<Item=&'a T>, F: Fn(&mut Self, &T) -> Result<()>
+    {
+        if self.proto == 0 {
+            for item in items {
+                try!(f(self, item));
+                try!(self.write_opcode(APPEND));
+            }
+            Ok(())
+        } else {
+            self.serialize_batched(items.into_iter(), APPENDS, f)
+        }
+    }
+
+    // Add items to the dict on the stack top.
+    fn serialize_dict_items<'a, K: 'a, V: 'a, I, FK, FV>(&mut self, items: I, fk: FK, fv: FV)
+                                                         -> Result<()>
+        where I: IntoIterator<Item=(&'a K, &'a V)>,
+              FK: Fn(&mut Self, &K) -> Result<()>, FV: Fn(&mut Self, &V) -> Result<()>
+    {
+        if self.proto == 0 {
+            for (key, value) in items {
+                try!(fk(self, key));
+                try!(fv(self, value));
+                try!(self.write_opcode(SETITEM));
+            }
+            Ok(())
+        } else {
+            self.serialize_batched(items.into_iter(), SETITEMS, |slf, (key, value)| {
+                try!(fk(slf, key));
+                fv(slf, value)
+            })
+        }
+    }
+
     fn serialize_hashable_value(&mut self, value: &HashableValue) -> Result<()> {
         use serde::Serializer;
         match *value {
@@ -51,16 +532,68 @@ impl<W: io::Write> Serializer<W> {
             HashableValue::Bool(b) => self.serialize_bool(b),
             HashableValue::I64(i)  => self.serialize_i64(i),
             HashableValue::F64(f)  => self.serialize_f64(f),
-            HashableValue::Bytes(ref b) => self.serialize_bytes(b),
+            HashableValue::Bytes(ref b) => self.write_bytes(b),
             HashableValue::String(ref s) => self.serialize_str(s),
             HashableValue::Int(ref i) => self.serialize_bigint(i),
-            HashableValue::FrozenSet(ref s) => self.serialize_set(s, b"frozenset"),
+            HashableValue::FrozenSet(ref s) =>
+                self.serialize_set(s, true, |slf, v| slf.serialize_hashable_value(v)),
             HashableValue::Tuple(ref t) =>
                 self.serialize_tuplevalue(t, |slf, v| slf.serialize_hashable_value(v)),
+            HashableValue::Decimal(ref d) => self.serialize_decimal(d),
+            HashableValue::Fraction(ref f) => self.serialize_fraction(f),
+            HashableValue::Complex(c) => self.serialize_complex(c),
+            HashableValue::Date(ref d) => self.serialize_date(d),
+            HashableValue::Time(ref t) => self.serialize_time(t),
+            HashableValue::DateTime(ref d) => self.serialize_datetime(d),
+            HashableValue::TimeDelta(ref d) => self.serialize_timedelta(d),
+        }
+    }
+
+    fn serialize_toplevel_value(&mut self, value: &Value) -> Result<()> {
+        if self.memoize {
+            self.shared = find_shared(value);
         }
+        let result = self.serialize_value(value);
+        // The addresses are only valid while we are borrowing the value.
+        self.shared.clear();
+        self.groups.clear();
+        result
     }
 
     fn serialize_value(&mut self, value: &Value) -> Result<()> {
+        if let Some(pid) = self.persistent_id.as_ref().and_then(|hook| hook.persistent_id(value)) {
+            return self.write_persistent_id(&pid);
+        }
+        if !self.shared.is_empty() {
+            let group = self.shared.get(&(value as *const Value as usize)).cloned();
+            if let Some(group) = group {
+                if let Some(&memo_id) = self.groups.get(&group) {
+                    return self.write_get(memo_id);
+                }
+                try!(self.serialize_value_contents(value));
+                let memo_id = try!(self.write_put());
+                self.groups.insert(group, memo_id);
+                return Ok(());
+            }
+        }
+        self.serialize_value_contents(value)
+    }
+
+    fn write_persistent_id(&mut self, pid: &Value) -> Result<()> {
+        if self.proto == 0 {
+            return match *pid {
+                Value::String(ref s) if s.is_ascii() && !s.contains('\n') =>
+                    self.write_text(PERSID, s.as_bytes()),
+                _ => Err(Error::Syntax(ErrorCode::InvalidValue(
+                    "persistent ID for protocol 0 must be an ASCII string".into()))),
+            };
+        }
+        // Like Python, the ID itself is not given to the hook, but its items are.
+        try!(self.serialize_value_contents(pid));
+        self.write_opcode(BINPERSID)
+    }
+
+    fn serialize_value_contents(&mut self, value: &Value) -> Result<()> {
         use serde::Serializer;
         match *value {
             // Cases covered by the Serializer trait
@@ -69,31 +602,14 @@ impl<W: io::Write> Serializer<W> {
             Value::I64(i)  => self.serialize_i64(i),
             Value::F64(f)  => self.serialize_f64(f),
             Value::Bytes(ref b) => self.serialize_bytes(b),
+            Value::ByteArray(ref b) => self.serialize_bytearray(b),
             Value::String(ref s) => self.serialize_str(s),
             Value::List(ref l) => {
-                try!(self.write_opcode(EMPTY_LIST));
-                for chunk in l.chunks(1000) {
-                    try!(self.write_opcode(MARK));
-                    for item in chunk {
-                        try!(self.serialize_value(item));
-                    }
-                    try!(self.write_opcode(APPENDS));
-                }
-                Ok(())
+                try!(self.write_empty_list());
+                self.serialize_list_items(l, |slf, v| slf.serialize_value(v))
             },
             Value::Dict(ref d) => {
-                try!(self.write_opcode(EMPTY_DICT));
-                try!(self.write_opcode(MARK));
-                for (n, (key, value)) in d.iter().enumerate() {
-                    if n % 1000 == 999 {
-                        try!(self.write_opcode(SETITEMS));
-                        try!(self.write_opcode(MARK));
-                    }
-                    try!(self.serialize_hashable_value(&key));
-                    try!(self.serialize_value(value));
-                }
-                try!(self.write_opcode(SETITEMS));
-                Ok(())
+                self.serialize_dict(d)
             }
 
             // Others
@@ -104,15 +620,302 @@ impl<W: io::Write> Serializer<W> {
                 self.serialize_tuplevalue(t, |slf, v| slf.serialize_value(v))
             },
             Value::Set(ref s) => {
-                self.serialize_set(s, b"set")
+                self.serialize_set(s, false, |slf, v| slf.serialize_hashable_value(v))
             },
             Value::FrozenSet(ref s) => {
-                self.serialize_set(s, b"frozenset")
+                self.serialize_set(s, true, |slf, v| slf.serialize_hashable_value(v))
+            }
+            Value::Object(ref obj) => {
+                let kwargs = if obj.kwargs.is_empty() {
+                    None
+                } else {
+                    Some(|slf: &mut Self| slf.serialize_dict(&obj.kwargs))
+                };
+                try!(self.serialize_newobj(&obj.class, &obj.args,
+                                           |slf, v| slf.serialize_value(v), kwargs));
+                if let Some(ref state) = obj.state {
+                    try!(self.serialize_value(state));
+                    try!(self.write_opcode(BUILD));
+                }
+                Ok(())
+            }
+            Value::OrderedDict(ref d) => {
+                try!(self.write_reduce("collections", "OrderedDict", 0, |_| Ok(())));
+                self.serialize_dict_items(d.iter().map(|&(ref k, ref v)| (k, v)),
+                                          |slf, k| slf.serialize_hashable_value(k),
+                                          |slf, v| slf.serialize_value(v))
+            }
+            Value::DefaultDict(ref d) => {
+                try!(self.write_defaultdict(&d.factory));
+                self.serialize_dict_items(&d.items, |slf, k| slf.serialize_hashable_value(k),
+                                          |slf, v| slf.serialize_value(v))
+            }
+            Value::Deque(ref d) => {
+                try!(self.write_deque(d.maxlen));
+                self.serialize_list_items(&d.items, |slf, v| slf.serialize_value(v))
+            }
+            Value::Counter(ref d) =>
+                self.write_reduce("collections", "Counter", 1, |slf| slf.serialize_dict(d)),
+            Value::Decimal(ref d) => self.serialize_decimal(d),
+            Value::Fraction(ref f) => self.serialize_fraction(f),
+            Value::Complex(c) => self.serialize_complex(c),
+            Value::Date(ref d) => self.serialize_date(d),
+            Value::Time(ref t) => self.serialize_time(t),
+            Value::DateTime(ref d) => self.serialize_datetime(d),
+            Value::TimeDelta(ref d) => self.serialize_timedelta(d),
+        }
+    }
+
+    // Write a call of the global `modname.globname` with `nargs` arguments,
+    // which are written by `args`.  Python writes objects reduced to such a
+    // call this way.
+    fn write_reduce<F>(&mut self, modname: &str, globname: &str, nargs: usize, args: F)
+                       -> Result<()>
+        where F: FnOnce(&mut Self) -> Result<()>
+    {
+        try!(self.write_global(modname, globname));
+        if nargs == 0 {
+            try!(self.write_empty_tuple());
+            return self.write_opcode(REDUCE);
+        }
+        let short = self.proto >= 2 && nargs <= 3;
+        if !short {
+            try!(self.write_opcode(MARK));
+        }
+        try!(args(self));
+        try!(self.write_opcode(match nargs {
+            _ if !short => TUPLE,
+            1 => TUPLE1,
+            2 => TUPLE2,
+            _ => TUPLE3,
+        }));
+        self.write_opcode(REDUCE)
+    }
+
+    // Write a new defaultdict, to which the items are added.
+    fn write_defaultdict(&mut self, factory: &Option<(String, String)>) -> Result<()> {
+        let (module, name) = match *factory {
+            Some((ref module, ref name)) => (module, name),
+            None => return self.write_reduce("collections", "defaultdict", 0, |_| Ok(())),
+        };
+        let module = match &**module {
+            "builtins" | "__builtin__" if self.proto < 3 => "__builtin__",
+            "builtins" | "__builtin__" => "builtins",
+            module => module,
+        };
+        self.write_reduce("collections", "defaultdict", 1, |slf| slf.write_global(module, name))
+    }
+
+    // Write a new deque, to which the items are added.
+    fn write_deque(&mut self, maxlen: Option<usize>) -> Result<()> {
+        match maxlen {
+            Some(maxlen) => self.write_reduce("collections", "deque", 2, |slf| {
+                try!(slf.write_empty_tuple());
+                slf.write_old_int(maxlen as i64)
+            }),
+            None => self.write_reduce("collections", "deque", 0, |_| Ok(())),
+        }
+    }
+
+    // Write a bytestring that must be bytes in Python 3, which writes it as
+    // `_codecs.encode(text, 'latin1')` for protocols before 3.
+    fn write_latin1_bytes(&mut self, value: &[u8]) -> Result<()> {
+        use serde::Serializer;
+        if self.proto >= 3 || self.py2_strings {
+            return self.write_bytes(value);
+        }
+        let text: String = value.iter().map(|&b| b as char).collect();
+        self.write_reduce("_codecs", "encode", 2, |slf| {
+            try!(slf.serialize_str(&text));
+            slf.serialize_str("latin1")
+        })
+    }
+
+    fn serialize_date(&mut self, date: &Date) -> Result<()> {
+        let state = date.to_state();
+        self.write_reduce("datetime", "date", 1, |slf| slf.write_latin1_bytes(&state))
+    }
+
+    fn serialize_time(&mut self, time: &Time) -> Result<()> {
+        let state = time.to_state(self.proto);
+        let nargs = if time.tzinfo.is_some() { 2 } else { 1 };
+        self.write_reduce("datetime", "time", nargs, |slf| {
+            try!(slf.write_latin1_bytes(&state));
+            slf.serialize_tzinfo(&time.tzinfo)
+        })
+    }
+
+    fn serialize_datetime(&mut self, datetime: &DateTime) -> Result<()> {
+        let state = datetime.to_state(self.proto);
+        let nargs = if datetime.time.tzinfo.is_some() { 2 } else { 1 };
+        self.write_reduce("datetime", "datetime", nargs, |slf| {
+            try!(slf.write_latin1_bytes(&state));
+            slf.serialize_tzinfo(&datetime.time.tzinfo)
+        })
+    }
+
+    fn serialize_tzinfo(&mut self, tzinfo: &Option<TimeZone>) -> Result<()> {
+        use serde::Serializer;
+        let tz = match *tzinfo {
+            Some(ref tz) => tz,
+            None => return Ok(()),
+        };
+        let nargs = if tz.name.is_some() { 2 } else { 1 };
+        self.write_reduce("datetime", "timezone", nargs, |slf| {
+            try!(slf.serialize_timedelta(&tz.offset));
+            match tz.name {
+                Some(ref name) => slf.serialize_str(name),
+                None => Ok(()),
             }
+        })
+    }
+
+    fn serialize_decimal(&mut self, decimal: &Decimal) -> Result<()> {
+        use serde::Serializer;
+        self.write_reduce("decimal", "Decimal", 1, |slf| slf.serialize_str(decimal.as_str()))
+    }
+
+    fn serialize_fraction(&mut self, fraction: &Fraction) -> Result<()> {
+        self.write_reduce("fractions", "Fraction", 2, |slf| {
+            try!(slf.write_int(fraction.numerator()));
+            slf.write_int(fraction.denominator())
+        })
+    }
+
+    fn serialize_complex(&mut self, complex: Complex) -> Result<()> {
+        use serde::Serializer;
+        let modname = if self.proto >= 3 { "builtins" } else { "__builtin__" };
+        self.write_reduce(modname, "complex", 2, |slf| {
+            try!(slf.serialize_f64(complex.re));
+            slf.serialize_f64(complex.im)
+        })
+    }
+
+    // Write an integer with the shortest opcode, like Python does.
+    fn write_int(&mut self, value: &BigInt) -> Result<()> {
+        match value.to_i64() {
+            Some(i) if -0x8000_0000 <= i && i < 0x8000_0000 => self.write_old_int(i),
+            _ => self.serialize_bigint(value),
         }
     }
 
+    fn serialize_timedelta(&mut self, delta: &TimeDelta) -> Result<()> {
+        // Python writes the small integers with the shortest opcodes.
+        self.write_reduce("datetime", "timedelta", 3, |slf| {
+            try!(slf.write_old_int(delta.days as i64));
+            try!(slf.write_old_int(delta.seconds as i64));
+            slf.write_old_int(delta.microseconds as i64)
+        })
+    }
+
+    fn serialize_dict(&mut self, d: &BTreeMap<HashableValue, Value>) -> Result<()> {
+        try!(self.write_empty_dict());
+        self.serialize_dict_items(d, |slf, k| slf.serialize_hashable_value(k),
+                                  |slf, v| slf.serialize_value(v))
+    }
+
+    // Write a new instance of a class, created with `class.__new__(class,
+    // *args, **kwargs)`.  `kwargs` writes the keyword arguments dict, if any.
+    fn serialize_newobj<T, F, K>(&mut self, class: &(String, String), args: &[T], f: F,
+                                 kwargs: Option<K>) -> Result<()>
+        where F: Fn(&mut Self, &T) -> Result<()>, K: FnOnce(&mut Self) -> Result<()>
+    {
+        #[cfg(feature = "numpy")]
+        {
+            // NumPy objects can't be created with __new__, and are written
+            // the way NumPy pickles them.
+            if kwargs.is_none() && class.0 == "numpy" {
+                if class.1 == "ndarray" {
+                    return self.write_reduce(
+                        "numpy.core.multiarray", "_reconstruct", args.len() + 1, |slf| {
+                            try!(slf.write_global(&class.0, &class.1));
+                            for arg in args {
+                                try!(f(slf, arg));
+                            }
+                            Ok(())
+                        });
+                } else if class.1 == "dtype" {
+                    return self.write_reduce("numpy", "dtype", args.len(), |slf| {
+                        for arg in args {
+                            try!(f(slf, arg));
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        if self.proto >= 4 || (self.proto >= 2 && kwargs.is_none()) {
+            try!(self.write_global(&class.0, &class.1));
+            try!(self.serialize_tuplevalue(args, f));
+            return match kwargs {
+                None => self.write_opcode(NEWOBJ),
+                Some(kwargs) => {
+                    try!(kwargs(self));
+                    self.write_opcode(NEWOBJ_EX)
+                }
+            };
+        }
+        // Older protocols don't have these opcodes (NEWOBJ_EX is new in
+        // protocol 4), and call the helpers copyreg.__newobj__(cls, *args) or
+        // __newobj_ex__(cls, args, kwargs).
+        let module = if self.proto >= 3 { "copyreg" } else { "copy_reg" };
+        match kwargs {
+            None => {
+                try!(self.write_global(module, "__newobj__"));
+                try!(self.write_opcode(MARK));
+                try!(self.write_global(&class.0, &class.1));
+                for arg in args {
+                    try!(f(self, arg));
+                }
+            }
+            Some(kwargs) => {
+                try!(self.write_global(module, "__newobj_ex__"));
+                try!(self.write_opcode(MARK));
+                try!(self.write_global(&class.0, &class.1));
+                try!(self.serialize_tuplevalue(args, f));
+                try!(kwargs(self));
+            }
+        }
+        try!(self.write_opcode(TUPLE));
+        self.write_opcode(REDUCE)
+    }
+
+    fn write_global(&mut self, modname: &str, globname: &str) -> Result<()> {
+        use serde::Serializer;
+        if self.proto >= 2 && !self.extensions.is_empty() {
+            let key = (modname.to_owned(), globname.to_owned());
+            if let Some(&code) = self.extensions.get(&key) {
+                return if code <= 0xff {
+                    try!(self.write_opcode(EXT1));
+                    self.writer.write_u8(code as u8).map_err(From::from)
+                } else if code <= 0xffff {
+                    try!(self.write_opcode(EXT2));
+                    self.writer.write_u16::<LittleEndian>(code as u16).map_err(From::from)
+                } else {
+                    try!(self.write_opcode(EXT4));
+                    self.writer.write_u32::<LittleEndian>(code).map_err(From::from)
+                };
+            }
+        }
+        if self.proto >= 4 {
+            try!(self.serialize_str(modname));
+            try!(self.serialize_str(globname));
+            return self.write_opcode(STACK_GLOBAL);
+        }
+        try!(self.write_opcode(GLOBAL));
+        try!(self.writer.write_all(modname.as_bytes()));
+        try!(self.writer.write_all(b"\n"));
+        try!(self.writer.write_all(globname.as_bytes()));
+        self.writer.write_all(b"\n").map_err(From::from)
+    }
+
     fn serialize_bigint(&mut self, i: &BigInt) -> Result<()> {
+        if self.proto < 2 {
+            return match i.to_i64() {
+                Some(i) => self.write_old_int(i),
+                None => self.write_old_long(&i.to_string()),
+            };
+        }
         let bytes = if i.is_negative() {
             let n_bytes = i.to_bytes_le().1.len();
             let pos = i + (BigInt::from(1) << (n_bytes * 8));
@@ -145,7 +948,14 @@ impl<W: io::Write> Serializer<W> {
         where F: Fn(&mut Self, &T) -> Result<()>
     {
         if t.is_empty() {
-            self.write_opcode(EMPTY_TUPLE)
+            self.write_empty_tuple()
+        } else if self.proto < 2 || t.len() > 3 {
+            try!(self.write_opcode(MARK));
+            for item in t.iter() {
+                try!(f(self, item));
+            }
+            try!(self.write_opcode(TUPLE));
+            Ok(())
         } else if t.len() == 1 {
             try!(f(self, &t[0]));
             self.write_opcode(TUPLE1)
@@ -153,42 +963,498 @@ impl<W: io::Write> Serializer<W> {
             try!(f(self, &t[0]));
             try!(f(self, &t[1]));
             self.write_opcode(TUPLE2)
-        } else if t.len() == 3 {
+        } else {
             try!(f(self, &t[0]));
             try!(f(self, &t[1]));
             try!(f(self, &t[2]));
             self.write_opcode(TUPLE3)
-        } else {
+        }
+    }
+
+    fn serialize_set<'a, T: 'a, I, F>(&mut self, items: I, frozen: bool, f: F) -> Result<()>
+        where I: IntoIterator<Item=&'a T>, F: Fn(&mut Self, &T) -> Result<()>
+    {
+        if self.proto >= 4 {
+            if frozen {
+                try!(self.write_opcode(MARK));
+                for item in items {
+                    try!(f(self, item));
+                }
+                return self.write_opcode(FROZENSET);
+            }
+            try!(self.write_opcode(EMPTY_SET));
+            return self.serialize_set_items(items, f);
+        }
+        // Older protocols call set() or frozenset() with a list of items.
+        let modname = if self.proto >= 3 { "builtins" } else { "__builtin__" };
+        try!(self.write_global(modname, if frozen { "frozenset" } else { "set" }));
+        if self.proto < 2 {
             try!(self.write_opcode(MARK));
-            for item in t.iter() {
-                try!(f(self, item));
+        }
+        try!(self.write_empty_list());
+        try!(self.serialize_list_items(items, f));
+        try!(self.write_opcode(if self.proto < 2 { TUPLE } else { TUPLE1 }));
+        self.write_opcode(REDUCE)
+    }
+
+    // Add items to the set on the stack top, for protocol 4.
+    fn serialize_set_items<'a, T: 'a, I, F>(&mut self, items: I, f: F) -> Result<()>
+        where I: IntoIterator<Item=&'a T>, F: Fn(&mut Self, &T) -> Result<()>
+    {
+        self.serialize_batched(items.into_iter(), ADDITEMS, f)
+    }
+
+    // Start writing an enum variant that has a value; `close_variant` must
+    // be called after the value is written.
+    fn open_variant(&mut self, variant: &str) -> Result<()> {
+        use serde::Serializer;
+        match self.enum_style {
+            EnumStyle::Tuple => if self.proto < 2 {
+                try!(self.write_opcode(MARK));
+            },
+            EnumStyle::Dict => {
+                try!(self.write_empty_dict());
+                if self.proto > 0 {
+                    try!(self.write_opcode(MARK));
+                }
             }
-            try!(self.write_opcode(TUPLE));
-            Ok(())
         }
+        self.serialize_str(variant)
     }
 
-    fn serialize_set(&mut self, items: &BTreeSet<HashableValue>, name: &[u8]) -> Result<()> {
-        try!(self.write_opcode(GLOBAL));
-        if self.use_proto_3 {
-            try!(self.writer.write(b"builtins\n"));
+    fn close_variant(&mut self) -> Result<()> {
+        match self.enum_style {
+            EnumStyle::Tuple => self.write_opcode(if self.proto < 2 { TUPLE } else { TUPLE2 }),
+            EnumStyle::Dict => self.write_opcode(if self.proto == 0 { SETITEM } else { SETITEMS }),
+        }
+    }
+
+    fn serialize_graph(&mut self, graph: &Graph) -> Result<()> {
+        let result = self.serialize_graph_value(graph, graph.root());
+        self.nodes.clear();
+        result
+    }
+
+    fn serialize_graph_value(&mut self, graph: &Graph, value: &GraphValue) -> Result<()> {
+        use serde::Serializer;
+        match *value {
+            GraphValue::None    => self.serialize_unit(),
+            GraphValue::Bool(b) => self.serialize_bool(b),
+            GraphValue::I64(i)  => self.serialize_i64(i),
+            GraphValue::F64(f)  => self.serialize_f64(f),
+            GraphValue::Bytes(ref b) => self.serialize_bytes(b),
+            GraphValue::ByteArray(ref b) => self.serialize_bytearray(b),
+            GraphValue::String(ref s) => self.serialize_str(s),
+            GraphValue::Int(ref i) => self.serialize_bigint(i),
+            GraphValue::List(ref l) => {
+                try!(self.write_empty_list());
+                self.serialize_list_items(l, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Tuple(ref t) => {
+                self.serialize_tuplevalue(t, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Set(ref s) => {
+                self.serialize_set(s, false, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::FrozenSet(ref s) => {
+                self.serialize_set(s, true, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Dict(ref d) => {
+                try!(self.write_empty_dict());
+                self.serialize_graph_dict_items(graph, d)
+            }
+            GraphValue::Object(ref obj) => {
+                try!(self.serialize_graph_newobj(graph, obj));
+                self.serialize_graph_object_state(graph, &obj.state)
+            }
+            GraphValue::OrderedDict(ref d) => {
+                try!(self.write_reduce("collections", "OrderedDict", 0, |_| Ok(())));
+                self.serialize_graph_dict_items(graph, d)
+            }
+            GraphValue::DefaultDict(ref factory, ref d) => {
+                try!(self.write_defaultdict(factory));
+                self.serialize_graph_dict_items(graph, d)
+            }
+            GraphValue::Deque(ref l, maxlen) => {
+                try!(self.write_deque(maxlen));
+                self.serialize_list_items(l, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Counter(ref d) => {
+                self.write_reduce("collections", "Counter", 1, |slf| {
+                    try!(slf.write_empty_dict());
+                    slf.serialize_graph_dict_items(graph, d)
+                })
+            }
+            GraphValue::Decimal(ref d) => self.serialize_decimal(d),
+            GraphValue::Fraction(ref f) => self.serialize_fraction(f),
+            GraphValue::Complex(c) => self.serialize_complex(c),
+            GraphValue::Date(ref d) => self.serialize_date(d),
+            GraphValue::Time(ref t) => self.serialize_time(t),
+            GraphValue::DateTime(ref d) => self.serialize_datetime(d),
+            GraphValue::TimeDelta(ref d) => self.serialize_timedelta(d),
+            GraphValue::Ref(id) => self.serialize_graph_node(graph, id),
+        }
+    }
+
+    fn serialize_graph_node(&mut self, graph: &Graph, id: NodeId) -> Result<()> {
+        if let Some(&memo_id) = self.nodes.get(&id) {
+            return self.write_get(memo_id);
+        }
+        // Mutable containers and objects are memoized before their contents
+        // are written, so that recursive references to them can be resolved.
+        match *graph.node(id) {
+            GraphValue::List(ref l) => {
+                try!(self.write_empty_list());
+                try!(self.memoize_node(id));
+                self.serialize_list_items(l, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Dict(ref d) => {
+                try!(self.write_empty_dict());
+                try!(self.memoize_node(id));
+                self.serialize_graph_dict_items(graph, d)
+            }
+            GraphValue::Set(ref s) if self.proto >= 4 => {
+                try!(self.write_opcode(EMPTY_SET));
+                try!(self.memoize_node(id));
+                self.serialize_set_items(s, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Object(ref obj) => {
+                try!(self.serialize_graph_newobj(graph, obj));
+                try!(self.memoize_node(id));
+                self.serialize_graph_object_state(graph, &obj.state)
+            }
+            GraphValue::OrderedDict(ref d) => {
+                try!(self.write_reduce("collections", "OrderedDict", 0, |_| Ok(())));
+                try!(self.memoize_node(id));
+                self.serialize_graph_dict_items(graph, d)
+            }
+            GraphValue::DefaultDict(ref factory, ref d) => {
+                try!(self.write_defaultdict(factory));
+                try!(self.memoize_node(id));
+                self.serialize_graph_dict_items(graph, d)
+            }
+            GraphValue::Deque(ref l, maxlen) => {
+                try!(self.write_deque(maxlen));
+                try!(self.memoize_node(id));
+                self.serialize_list_items(l, |slf, v| slf.serialize_graph_value(graph, v))
+            }
+            GraphValue::Ref(_) => {
+                Err(Error::Syntax(ErrorCode::InvalidValue("reference to reference".into())))
+            }
+            ref other => {
+                try!(self.serialize_graph_value(graph, other));
+                // Immutable values can only be memoized after they are built.
+                // If one of the items referred back to this node, it has
+                // already been memoized, and we use that instead.
+                if let Some(&memo_id) = self.nodes.get(&id) {
+                    try!(self.write_opcode(POP));
+                    self.write_get(memo_id)
+                } else {
+                    self.memoize_node(id)
+                }
+            }
+        }
+    }
+
+    fn memoize_node(&mut self, id: NodeId) -> Result<()> {
+        let memo_id = try!(self.write_put());
+        self.nodes.insert(id, memo_id);
+        Ok(())
+    }
+
+    fn serialize_graph_dict_items(&mut self, graph: &Graph, items: &[(GraphValue, GraphValue)])
+                                  -> Result<()> {
+        self.serialize_dict_items(items.iter().map(|&(ref k, ref v)| (k, v)),
+                                  |slf, k| slf.serialize_graph_value(graph, k),
+                                  |slf, v| slf.serialize_graph_value(graph, v))
+    }
+
+    fn serialize_graph_newobj(&mut self, graph: &Graph, obj: &GraphObject) -> Result<()> {
+        let kwargs = if obj.kwargs.is_empty() {
+            None
         } else {
-            try!(self.writer.write(b"__builtin__\n"));
-        }
-        try!(self.writer.write_all(name));
-        try!(self.writer.write(b"\n"));
-        try!(self.write_opcode(EMPTY_LIST));
-        try!(self.write_opcode(MARK));
-        for (n, item) in items.iter().enumerate() {
-            if n % 1000 == 999 {
-                try!(self.write_opcode(APPENDS));
-                try!(self.write_opcode(MARK));
+            Some(|slf: &mut Self| {
+                try!(slf.write_empty_dict());
+                slf.serialize_graph_dict_items(graph, &obj.kwargs)
+            })
+        };
+        self.serialize_newobj(&obj.class, &obj.args,
+                              |slf, v| slf.serialize_graph_value(graph, v), kwargs)
+    }
+
+    fn serialize_graph_object_state(&mut self, graph: &Graph, state: &Option<GraphValue>)
+                                    -> Result<()> {
+        if let Some(ref state) = *state {
+            try!(self.serialize_graph_value(graph, state));
+            try!(self.write_opcode(BUILD));
+        }
+        Ok(())
+    }
+}
+
+// Escape a bytestring for the STRING opcode, which expects a quoted Python
+// string literal.
+fn escape_string(value: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len() + 2);
+    result.push(b'\'');
+    for &b in value {
+        match b {
+            b'\\' => result.extend_from_slice(b"\\\\"),
+            b'\t' => result.extend_from_slice(b"\\t"),
+            b'\n' => result.extend_from_slice(b"\\n"),
+            b'\r' => result.extend_from_slice(b"\\r"),
+            b' ' ... b'~' if b != b'\'' => result.push(b),
+            _ => result.extend_from_slice(format!("\\x{:02x}", b).as_bytes()),
+        }
+    }
+    result.push(b'\'');
+    result
+}
+
+// Escape a string for the UNICODE opcode, using the "raw-unicode-escape"
+// codec.  Like Python, we also escape characters that would end the line.
+fn escape_unicode(value: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' | '\0' | '\n' | '\r' | '\x1a' => {
+                result.extend_from_slice(format!("\\u{:04x}", ch as u32).as_bytes())
             }
-            try!(self.serialize_hashable_value(&item));
+            '\x00' ... '\u{ff}' => result.push(ch as u8),
+            '\u{100}' ... '\u{ffff}' => {
+                result.extend_from_slice(format!("\\u{:04x}", ch as u32).as_bytes())
+            }
+            _ => result.extend_from_slice(format!("\\U{:08x}", ch as u32).as_bytes()),
         }
-        try!(self.write_opcode(APPENDS));
-        try!(self.write_opcode(TUPLE1));
-        self.write_opcode(REDUCE)
+    }
+    result
+}
+
+// Find all values that occur more than once within the given value, and
+// return a map from their address to an ID common to all equal values.
+//
+// Only non-empty containers and objects are considered.  Occurrences within
+// a repeated value are not visited, since they will never be written.
+fn find_shared(value: &Value) -> HashMap<usize, usize> {
+    let mut finder = SharedFinder {
+        hashes: HashMap::new(),
+        seen: HashMap::new(),
+        shared: Vec::new(),
+        groups: HashMap::new(),
+    };
+    finder.hash(value);
+    finder.group(value);
+    let SharedFinder { mut groups, shared, .. } = finder;
+    groups.retain(|_, group| shared[*group]);
+    groups
+}
+
+struct SharedFinder<'a> {
+    hashes: HashMap<usize, u64>,                // address -> content hash
+    seen: HashMap<u64, Vec<(&'a Value, usize)>>,  // content hash -> values and groups
+    shared: Vec<bool>,                          // group -> found more than once?
+    groups: HashMap<usize, usize>,              // address -> group
+}
+
+impl<'a> SharedFinder<'a> {
+    fn hash(&mut self, value: &'a Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match *value {
+            Value::None => 0u8.hash(&mut hasher),
+            Value::Bool(b) => (1u8, b).hash(&mut hasher),
+            Value::I64(i) => (2u8, i).hash(&mut hasher),
+            Value::Int(ref i) => (3u8, i).hash(&mut hasher),
+            Value::F64(f) => (4u8, f.to_bits()).hash(&mut hasher),
+            Value::Bytes(ref b) => (5u8, b).hash(&mut hasher),
+            Value::ByteArray(ref b) => (24u8, b).hash(&mut hasher),
+            Value::String(ref s) => (6u8, s).hash(&mut hasher),
+            Value::List(ref l) => {
+                7u8.hash(&mut hasher);
+                for item in l {
+                    self.hash(item).hash(&mut hasher);
+                }
+            }
+            Value::Tuple(ref t) => {
+                8u8.hash(&mut hasher);
+                for item in t {
+                    self.hash(item).hash(&mut hasher);
+                }
+            }
+            Value::Set(ref s) => {
+                9u8.hash(&mut hasher);
+                for item in s {
+                    hash_hashable(item, &mut hasher);
+                }
+            }
+            Value::FrozenSet(ref s) => {
+                10u8.hash(&mut hasher);
+                for item in s {
+                    hash_hashable(item, &mut hasher);
+                }
+            }
+            Value::Dict(ref d) => {
+                11u8.hash(&mut hasher);
+                for (key, value) in d {
+                    hash_hashable(key, &mut hasher);
+                    self.hash(value).hash(&mut hasher);
+                }
+            }
+            Value::Object(ref obj) => {
+                (12u8, &obj.class).hash(&mut hasher);
+                for arg in &obj.args {
+                    self.hash(arg).hash(&mut hasher);
+                }
+                for (key, value) in &obj.kwargs {
+                    hash_hashable(key, &mut hasher);
+                    self.hash(value).hash(&mut hasher);
+                }
+                if let Some(ref state) = obj.state {
+                    self.hash(state).hash(&mut hasher);
+                }
+            }
+            Value::Date(ref d) => (13u8, d).hash(&mut hasher),
+            Value::Time(ref t) => (14u8, t).hash(&mut hasher),
+            Value::DateTime(ref d) => (15u8, d).hash(&mut hasher),
+            Value::TimeDelta(ref d) => (16u8, d).hash(&mut hasher),
+            Value::Decimal(ref d) => (17u8, d).hash(&mut hasher),
+            Value::Fraction(ref f) => (18u8, f).hash(&mut hasher),
+            Value::Complex(c) => (19u8, c.re.to_bits(), c.im.to_bits()).hash(&mut hasher),
+            Value::OrderedDict(ref d) => {
+                20u8.hash(&mut hasher);
+                for &(ref key, ref value) in d {
+                    hash_hashable(key, &mut hasher);
+                    self.hash(value).hash(&mut hasher);
+                }
+            }
+            Value::DefaultDict(ref d) => {
+                (21u8, &d.factory).hash(&mut hasher);
+                for (key, value) in &d.items {
+                    hash_hashable(key, &mut hasher);
+                    self.hash(value).hash(&mut hasher);
+                }
+            }
+            Value::Deque(ref d) => {
+                (22u8, d.maxlen).hash(&mut hasher);
+                for item in &d.items {
+                    self.hash(item).hash(&mut hasher);
+                }
+            }
+            Value::Counter(ref d) => {
+                23u8.hash(&mut hasher);
+                for (key, value) in d {
+                    hash_hashable(key, &mut hasher);
+                    self.hash(value).hash(&mut hasher);
+                }
+            }
+        }
+        let hash = hasher.finish();
+        if is_shareable(value) {
+            self.hashes.insert(value as *const Value as usize, hash);
+        }
+        hash
+    }
+
+    fn group(&mut self, value: &'a Value) {
+        if !is_shareable(value) {
+            return;
+        }
+        let address = value as *const Value as usize;
+        let hash = self.hashes[&address];
+        let existing = self.seen.get(&hash).and_then(
+            |values| values.iter().find(|&&(other, _)| other == value).map(|&(_, group)| group));
+        if let Some(group) = existing {
+            self.shared[group] = true;
+            self.groups.insert(address, group);
+            return;
+        }
+        let group = self.shared.len();
+        self.shared.push(false);
+        self.seen.entry(hash).or_insert_with(Vec::new).push((value, group));
+        self.groups.insert(address, group);
+        match *value {
+            Value::List(ref items) | Value::Tuple(ref items) => {
+                for item in items {
+                    self.group(item);
+                }
+            }
+            Value::Dict(ref d) | Value::Counter(ref d) => {
+                for value in d.values() {
+                    self.group(value);
+                }
+            }
+            Value::OrderedDict(ref d) => {
+                for &(_, ref value) in d {
+                    self.group(value);
+                }
+            }
+            Value::DefaultDict(ref d) => {
+                for value in d.items.values() {
+                    self.group(value);
+                }
+            }
+            Value::Deque(ref d) => {
+                for item in &d.items {
+                    self.group(item);
+                }
+            }
+            Value::Object(ref obj) => {
+                for arg in &obj.args {
+                    self.group(arg);
+                }
+                for value in obj.kwargs.values() {
+                    self.group(value);
+                }
+                if let Some(ref state) = obj.state {
+                    self.group(state);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_shareable(value: &Value) -> bool {
+    match *value {
+        Value::List(ref l) => !l.is_empty(),
+        Value::Tuple(ref t) => !t.is_empty(),
+        Value::Set(ref s) | Value::FrozenSet(ref s) => !s.is_empty(),
+        Value::Dict(ref d) | Value::Counter(ref d) => !d.is_empty(),
+        Value::OrderedDict(ref d) => !d.is_empty(),
+        Value::Deque(ref d) => !d.items.is_empty(),
+        Value::ByteArray(ref b) => !b.is_empty(),
+        Value::Object(_) | Value::DefaultDict(_) => true,
+        _ => false,
+    }
+}
+
+fn hash_hashable<H: Hasher>(value: &HashableValue, hasher: &mut H) {
+    match *value {
+        HashableValue::None => 0u8.hash(hasher),
+        HashableValue::Bool(b) => (1u8, b).hash(hasher),
+        HashableValue::I64(i) => (2u8, i).hash(hasher),
+        HashableValue::Int(ref i) => (3u8, i).hash(hasher),
+        HashableValue::F64(f) => (4u8, f.to_bits()).hash(hasher),
+        HashableValue::Bytes(ref b) => (5u8, b).hash(hasher),
+        HashableValue::String(ref s) => (6u8, s).hash(hasher),
+        HashableValue::Tuple(ref t) => {
+            8u8.hash(hasher);
+            for item in t {
+                hash_hashable(item, hasher);
+            }
+        }
+        HashableValue::FrozenSet(ref s) => {
+            10u8.hash(hasher);
+            for item in s {
+                hash_hashable(item, hasher);
+            }
+        }
+        HashableValue::Date(ref d) => (13u8, d).hash(hasher),
+        HashableValue::Decimal(ref d) => (17u8, d).hash(hasher),
+        HashableValue::Fraction(ref f) => (18u8, f).hash(hasher),
+        HashableValue::Complex(c) => (19u8, c.re.to_bits(), c.im.to_bits()).hash(hasher),
+        HashableValue::Time(ref t) => (14u8, t).hash(hasher),
+        HashableValue::DateTime(ref d) => (15u8, d).hash(hasher),
+        HashableValue::TimeDelta(ref d) => (16u8, d).hash(hasher),
     }
 }
 
@@ -204,11 +1470,17 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_bool(&mut self, value: bool) -> Result<()> {
+        if self.proto < 2 {
+            return self.write_text(INT, if value { b"01" } else { b"00" });
+        }
         self.write_opcode(if value { NEWTRUE } else { NEWFALSE })
     }
 
     #[inline]
     fn serialize_i8(&mut self, value: i8) -> Result<()> {
+        if self.proto < 2 {
+            return self.write_old_int(value as i64);
+        }
         if value > 0 {
             try!(self.write_opcode(BININT1));
             self.writer.write_i8(value).map_err(From::from)
@@ -220,6 +1492,9 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_i16(&mut self, value: i16) -> Result<()> {
+        if self.proto < 2 {
+            return self.write_old_int(value as i64);
+        }
         if value > 0 {
             try!(self.write_opcode(BININT2));
             self.writer.write_i16::<LittleEndian>(value).map_err(From::from)
@@ -231,13 +1506,18 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_i32(&mut self, value: i32) -> Result<()> {
+        if self.proto < 2 {
+            return self.write_old_int(value as i64);
+        }
         try!(self.write_opcode(BININT));
         self.writer.write_i32::<LittleEndian>(value).map_err(From::from)
     }
 
     #[inline]
     fn serialize_i64(&mut self, value: i64) -> Result<()> {
-        if -0x8000_0000 <= value && value < 0x8000_0000 {
+        if self.proto < 2 {
+            self.write_old_int(value)
+        } else if -0x8000_0000 <= value && value < 0x8000_0000 {
             try!(self.write_opcode(BININT));
             self.writer.write_i32::<LittleEndian>(value as i32).map_err(From::from)
         } else {
@@ -254,19 +1534,27 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_u8(&mut self, value: u8) -> Result<()> {
+        if self.proto < 2 {
+            return self.write_old_int(value as i64);
+        }
         try!(self.write_opcode(BININT1));
         self.writer.write_u8(value).map_err(From::from)
     }
 
     #[inline]
     fn serialize_u16(&mut self, value: u16) -> Result<()> {
+        if self.proto < 2 {
+            return self.write_old_int(value as i64);
+        }
         try!(self.write_opcode(BININT2));
         self.writer.write_u16::<LittleEndian>(value).map_err(From::from)
     }
 
     #[inline]
     fn serialize_u32(&mut self, value: u32) -> Result<()> {
-        if value < 0x8000_0000 {
+        if self.proto < 2 {
+            self.write_old_int(value as i64)
+        } else if value < 0x8000_0000 {
             try!(self.write_opcode(BININT));
             self.writer.write_u32::<LittleEndian>(value).map_err(From::from)
         } else {
@@ -281,7 +1569,13 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_u64(&mut self, value: u64) -> Result<()> {
-        if value < 0x8000_0000 {
+        if self.proto < 2 {
+            if value <= i64::max_value() as u64 {
+                self.write_old_int(value as i64)
+            } else {
+                self.write_old_long(&value.to_string())
+            }
+        } else if value < 0x8000_0000 {
             try!(self.write_opcode(BININT));
             self.writer.write_u32::<LittleEndian>(value as u32).map_err(From::from)
         } else {
@@ -301,14 +1595,16 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_f32(&mut self, value: f32) -> Result<()> {
-        try!(self.write_opcode(BINFLOAT));
-        // Yes, this one is big endian.
-        self.writer.write_f64::<BigEndian>(value as f64).map_err(From::from)
+        self.serialize_f64(value as f64)
     }
 
     #[inline]
     fn serialize_f64(&mut self, value: f64) -> Result<()> {
+        if self.proto == 0 {
+            return self.write_text(FLOAT, format!("{:?}", value).as_bytes());
+        }
         try!(self.write_opcode(BINFLOAT));
+        // Yes, this one is big endian.
         self.writer.write_f64::<BigEndian>(value).map_err(From::from)
     }
 
@@ -321,23 +1617,42 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_str(&mut self, value: &str) -> Result<()> {
-        try!(self.write_opcode(BINUNICODE));
-        try!(self.writer.write_u32::<LittleEndian>(value.len() as u32));
-        self.writer.write_all(value.as_bytes()).map_err(From::from)
+        if self.py2_strings && self.proto <= 2 {
+            return self.write_bytes(value.as_bytes());
+        }
+        if self.memoize {
+            if let Some(&memo_id) = self.strings.get(value) {
+                return self.write_get(memo_id);
+            }
+        }
+        let bytes = value.as_bytes();
+        if self.proto == 0 {
+            try!(self.write_text(UNICODE, &escape_unicode(value)));
+        } else if self.proto >= 4 && bytes.len() < 256 {
+            try!(self.write_counted(SHORT_BINUNICODE, 1, bytes));
+        } else if bytes.len() <= 0xffff_ffff {
+            try!(self.write_counted(BINUNICODE, 4, bytes));
+        } else if self.proto >= 4 {
+            try!(self.write_counted(BINUNICODE8, 8, bytes));
+        } else {
+            return Err(Error::Syntax(ErrorCode::InvalidLength(bytes.len())));
+        }
+        if self.memoize {
+            let memo_id = try!(self.write_put());
+            self.strings.insert(value.into(), memo_id);
+        }
+        Ok(())
     }
 
     #[inline]
     fn serialize_bytes(&mut self, value: &[u8]) -> Result<()> {
-        if value.len() < 256 {
-            let op = if self.use_proto_3 { SHORT_BINBYTES } else { SHORT_BINSTRING };
-            try!(self.write_opcode(op));
-            try!(self.writer.write_u8(value.len() as u8));
+        if let Some(ref mut buffers) = self.buffers {
+            buffers.push(value.to_vec());
         } else {
-            let op = if self.use_proto_3 { BINBYTES } else { BINSTRING };
-            try!(self.write_opcode(op));
-            try!(self.writer.write_u32::<LittleEndian>(value.len() as u32));
+            return self.write_bytes(value);
         }
-        self.writer.write_all(value).map_err(From::from)
+        try!(self.write_opcode(NEXT_BUFFER));
+        self.write_opcode(READONLY_BUFFER)
     }
 
     #[inline]
@@ -359,7 +1674,7 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_unit_struct(&mut self, _name: &'static str) -> Result<()> {
-        self.write_opcode(EMPTY_TUPLE)
+        self.write_empty_tuple()
     }
 
     #[inline]
@@ -385,50 +1700,73 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
         self.serialize_map_end(state)
     }
 
-    // We'll use tuples for serializing enums:
+    // By default, we'll use tuples for serializing enums:
     // Variant             ('Variant',)
     // Variant(T)          ('Variant', T)
     // Variant(T1, T2)     ('Variant', [T1, T2])
     // Variant { x: T }    ('Variant', {'x': T})
+    //
+    // With EnumStyle::Dict, unit variants are written as 'Variant', and all
+    // others as {'Variant': ...}.
     #[inline]
     fn serialize_unit_variant(&mut self, _name: &str, _variant_index: usize, variant: &str)
                               -> Result<()> {
-        try!(self.serialize_str(variant));
-        self.write_opcode(TUPLE1)
+        match self.enum_style {
+            EnumStyle::Tuple => {
+                if self.proto < 2 {
+                    try!(self.write_opcode(MARK));
+                    try!(self.serialize_str(variant));
+                    self.write_opcode(TUPLE)
+                } else {
+                    try!(self.serialize_str(variant));
+                    self.write_opcode(TUPLE1)
+                }
+            }
+            EnumStyle::Dict => self.serialize_str(variant),
+        }
     }
 
     #[inline]
     fn serialize_newtype_variant<T>(&mut self, _name: &str, _variant_index: usize, variant: &str,
                                     value: T) -> Result<()> where T: Serialize {
-        try!(self.serialize_str(variant));
+        try!(self.open_variant(variant));
         try!(value.serialize(self));
-        self.write_opcode(TUPLE2)
+        self.close_variant()
     }
 
     #[inline]
     fn serialize_tuple_variant(&mut self, _name: &str, _variant_index: usize, variant: &str,
                                _len: usize) -> Result<()> {
-        try!(self.serialize_str(variant));
-        try!(self.write_opcode(EMPTY_LIST));
-        self.write_opcode(MARK)
+        try!(self.open_variant(variant));
+        try!(self.write_empty_list());
+        if self.proto > 0 {
+            try!(self.write_opcode(MARK));
+        }
+        Ok(())
     }
 
     #[inline]
     fn serialize_tuple_variant_elt<T: Serialize>(&mut self, _state: &mut (),
                                                  value: T) -> Result<()> {
-        value.serialize(self)
+        try!(value.serialize(self));
+        if self.proto == 0 {
+            try!(self.write_opcode(APPEND));
+        }
+        Ok(())
     }
 
     #[inline]
     fn serialize_tuple_variant_end(&mut self, _state: ()) -> Result<()> {
-        try!(self.write_opcode(APPENDS));
-        self.write_opcode(TUPLE2)
+        if self.proto > 0 {
+            try!(self.write_opcode(APPENDS));
+        }
+        self.close_variant()
     }
 
     #[inline]
     fn serialize_struct_variant(&mut self, _name: &str, _variant_index: usize, variant: &str,
                                 len: usize) -> Result<Option<usize>> {
-        try!(self.serialize_str(variant));
+        try!(self.open_variant(variant));
         self.serialize_map(Some(len))
     }
 
@@ -442,7 +1780,7 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
     #[inline]
     fn serialize_struct_variant_end(&mut self, state: Option<usize>) -> Result<()> {
         try!(self.serialize_map_end(state));
-        self.write_opcode(TUPLE2)
+        self.close_variant()
     }
 
     #[inline]
@@ -463,7 +1801,7 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
     #[inline]
     fn serialize_tuple(&mut self, len: usize) -> Result<bool> {
         if len == 0 {
-            try!(self.write_opcode(EMPTY_TUPLE));
+            try!(self.write_empty_tuple());
             Ok(false)
         } else {
             try!(self.write_opcode(MARK));
@@ -484,10 +1822,13 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
         Ok(())
     }
 
+    // With protocol 0, which has no APPENDS and SETITEMS, the state is None
+    // and every item is added by itself.
     #[inline]
     fn serialize_seq(&mut self, len: Option<usize>) -> Result<Option<usize>> {
-        try!(self.write_opcode(EMPTY_LIST));
+        try!(self.write_empty_list());
         match len {
+            _ if self.proto == 0 => Ok(None),
             Some(len) if len == 0 => Ok(None),
             _ => {
                 try!(self.write_opcode(MARK));
@@ -500,14 +1841,7 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
     fn serialize_seq_elt<T>(&mut self, state: &mut Option<usize>,
                             value: T) -> Result<()> where T: Serialize {
         try!(value.serialize(self));
-        // Batch appends as in Python pickle
-        *state.as_mut().unwrap() += 1;
-        if state.unwrap() == 1000 {
-            try!(self.write_opcode(APPENDS));
-            try!(self.write_opcode(MARK));
-            *state = Some(0);
-        }
-        Ok(())
+        self.append_seq_item(state)
     }
 
     #[inline]
@@ -520,15 +1854,14 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
 
     #[inline]
     fn serialize_seq_fixed_size(&mut self, _len: usize) -> Result<Option<usize>> {
-        try!(self.write_opcode(EMPTY_LIST));
-        try!(self.write_opcode(MARK));
-        Ok(Some(0))
+        self.serialize_seq(None)
     }
 
     #[inline]
     fn serialize_map(&mut self, len: Option<usize>) -> Result<Option<usize>> {
-        try!(self.write_opcode(EMPTY_DICT));
+        try!(self.write_empty_dict());
         match len {
+            _ if self.proto == 0 => Ok(None),
             Some(len) if len == 0 => Ok(None),
             _ => {
                 try!(self.write_opcode(MARK));
@@ -545,9 +1878,12 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
     #[inline]
     fn serialize_map_value<T: Serialize>(&mut self, state: &mut Option<usize>, value: T) -> Result<()> {
         try!(value.serialize(self));
+        if self.proto == 0 {
+            return self.write_opcode(SETITEM);
+        }
         // Batch appends as in Python pickle
         *state.as_mut().unwrap() += 1;
-        if state.unwrap() == 1000 {
+        if state.unwrap() == self.batch_size {
             try!(self.write_opcode(SETITEMS));
             try!(self.write_opcode(MARK));
             *state = Some(0);
@@ -564,47 +1900,266 @@ impl<W: io::Write> ser::Serializer for Serializer<W> {
     }
 }
 
-fn wrap_write<W: io::Write, F>(mut writer: W, inner: F, use_proto_3: bool) -> Result<()>
+fn proto_options(use_proto_3: bool) -> SerOptions {
+    SerOptions::new().proto(if use_proto_3 { 3 } else { 2 })
+}
+
+fn wrap_write<W: io::Write, F>(writer: W, inner: F, options: SerOptions) -> Result<()>
     where F: FnOnce(&mut Serializer<W>) -> Result<()>
 {
-    try!(writer.write_all(&[PROTO]));
-    if use_proto_3 {
-        try!(writer.write_all(b"\x03"));
-    } else {
-        try!(writer.write_all(b"\x02"));
-    }
-    let mut ser = Serializer::new(writer, use_proto_3);
+    wrap_write_buffers(writer, inner, options).map(|_| ())
+}
+
+// Write a complete pickle, and return the out-of-band buffers if requested.
+fn wrap_write_buffers<W: io::Write, F>(writer: W, inner: F, options: SerOptions)
+                                       -> Result<Vec<Vec<u8>>>
+    where F: FnOnce(&mut Serializer<W>) -> Result<()>
+{
+    let mut ser = Serializer::with_options(writer, options);
+    try!(ser.start());
     try!(inner(&mut ser));
-    let mut writer = ser.into_inner();
-    writer.write_all(&[STOP]).map_err(From::from)
+    try!(ser.end());
+    Ok(ser.buffers.unwrap_or_default())
+}
+
+/// A writer for a pickled list whose items are given one at a time, so that
+/// the whole list never has to be in memory.
+///
+/// Items are appended in batches, as `Serializer` does for sequences.  The
+/// pickle is complete only after calling `finish`.
+pub struct ListWriter<W: io::Write> {
+    ser: Serializer<W>,
+    state: Option<usize>,
+}
+
+impl<W: io::Write> ListWriter<W> {
+    /// Start writing a list with the given options.  Out-of-band buffers
+    /// and memoization are not supported; the memo would keep every item
+    /// that was written.
+    pub fn new(writer: W, options: SerOptions) -> Result<ListWriter<W>> {
+        if options.out_of_band {
+            return Err(Error::Syntax(ErrorCode::Custom(
+                "out-of-band buffers are not supported by ListWriter".into())));
+        }
+        if options.memoize {
+            return Err(Error::Syntax(ErrorCode::Custom(
+                "memoization is not supported by ListWriter".into())));
+        }
+        let mut ser = Serializer::with_options(writer, options);
+        try!(ser.start());
+        let state = try!(ser::Serializer::serialize_seq(&mut ser, None));
+        Ok(ListWriter { ser: ser, state: state })
+    }
+
+    /// Append an item to the list.
+    pub fn push<T: Serialize>(&mut self, item: &T) -> Result<()> {
+        ser::Serializer::serialize_seq_elt(&mut self.ser, &mut self.state, item)
+    }
+
+    /// Append a `Value` to the list, keeping all Python types intact.
+    pub fn push_value(&mut self, item: &Value) -> Result<()> {
+        try!(self.ser.serialize_toplevel_value(item));
+        self.ser.append_seq_item(&mut self.state)
+    }
+
+    /// Complete the pickle, and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        try!(ser::Serializer::serialize_seq_end(&mut self.ser, self.state));
+        try!(self.ser.end());
+        Ok(self.ser.into_inner())
+    }
 }
 
 
 /// Encode the value into a pickle stream.
 pub fn value_to_writer<W: io::Write>(writer: &mut W, value: &Value, use_proto_3: bool)
                                      -> Result<()> {
-    wrap_write(writer, |ser| ser.serialize_value(value), use_proto_3)
+    value_to_writer_with_options(writer, value, proto_options(use_proto_3))
+}
+
+/// Encode the value into a pickle stream, with the given options.
+pub fn value_to_writer_with_options<W: io::Write>(writer: &mut W, value: &Value,
+                                                  options: SerOptions) -> Result<()> {
+    wrap_write(writer, |ser| ser.serialize_toplevel_value(value), options)
 }
 
 /// Encode the specified struct into a `[u8]` writer.
 #[inline]
 pub fn to_writer<W: io::Write, T: Serialize>(writer: &mut W, value: &T, use_proto_3: bool)
                                              -> Result<()> {
-    wrap_write(writer, |ser| value.serialize(ser), use_proto_3)
+    to_writer_with_options(writer, value, proto_options(use_proto_3))
+}
+
+/// Encode the specified struct into a `[u8]` writer, with the given options.
+#[inline]
+pub fn to_writer_with_options<W: io::Write, T: Serialize>(writer: &mut W, value: &T,
+                                                          options: SerOptions) -> Result<()> {
+    wrap_write(writer, |ser| value.serialize(ser), options)
+}
+
+/// Encode the object graph into a pickle stream, with the given options.
+///
+/// All references to the same node are written as references to the same
+/// object, so the pickle can contain recursive structures.
+pub fn graph_to_writer<W: io::Write>(writer: &mut W, graph: &Graph, options: SerOptions)
+                                     -> Result<()> {
+    wrap_write(writer, |ser| ser.serialize_graph(graph), options)
 }
 
 /// Encode the value into a `Vec<u8>` buffer.
 #[inline]
 pub fn value_to_vec(value: &Value, use_proto_3: bool) -> Result<Vec<u8>> {
+    value_to_vec_with_options(value, proto_options(use_proto_3))
+}
+
+/// Encode the value into a `Vec<u8>` buffer, with the given options.
+#[inline]
+pub fn value_to_vec_with_options(value: &Value, options: SerOptions) -> Result<Vec<u8>> {
     let mut writer = Vec::with_capacity(128);
-    try!(value_to_writer(&mut writer, value, use_proto_3));
+    try!(value_to_writer_with_options(&mut writer, value, options));
     Ok(writer)
 }
 
 /// Encode the specified struct into a `Vec<u8>` buffer.
 #[inline]
 pub fn to_vec<T: Serialize>(value: &T, use_proto_3: bool) -> Result<Vec<u8>> {
+    to_vec_with_options(value, proto_options(use_proto_3))
+}
+
+/// Encode the specified struct into a `Vec<u8>` buffer, with the given options.
+#[inline]
+pub fn to_vec_with_options<T: Serialize>(value: &T, options: SerOptions) -> Result<Vec<u8>> {
     let mut writer = Vec::with_capacity(128);
-    try!(to_writer(&mut writer, value, use_proto_3));
+    try!(to_writer_with_options(&mut writer, value, options));
     Ok(writer)
 }
+
+/// Encode the value into a pickle stream, and return the bytestrings found
+/// as out-of-band buffers.
+///
+/// This requires protocol 5, and corresponds to giving a `buffer_callback` to
+/// Python's `pickle.dump`.  The buffers must be given to the unpickler in the
+/// same order.
+pub fn value_to_writer_with_buffers<W: io::Write>(writer: &mut W, value: &Value,
+                                                  mut options: SerOptions)
+                                                  -> Result<Vec<Vec<u8>>> {
+    options.out_of_band = true;
+    wrap_write_buffers(writer, |ser| ser.serialize_toplevel_value(value), options)
+}
+
+/// Encode the specified struct into a pickle stream, and return the
+/// bytestrings found as out-of-band buffers.  This requires protocol 5.
+pub fn to_writer_with_buffers<W: io::Write, T: Serialize>(writer: &mut W, value: &T,
+                                                          mut options: SerOptions)
+                                                          -> Result<Vec<Vec<u8>>> {
+    options.out_of_band = true;
+    wrap_write_buffers(writer, |ser| value.serialize(ser), options)
+}
+
+/// Encode the value into a `Vec<u8>` buffer, and return it together with
+/// the bytestrings found as out-of-band buffers.  This requires protocol 5.
+pub fn value_to_vec_with_buffers(value: &Value, options: SerOptions)
+                                 -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut writer = Vec::with_capacity(128);
+    let buffers = try!(value_to_writer_with_buffers(&mut writer, value, options));
+    Ok((writer, buffers))
+}
+
+/// Encode the specified struct into a `Vec<u8>` buffer, and return it together
+/// with the bytestrings found as out-of-band buffers.  This requires protocol 5.
+pub fn to_vec_with_buffers<T: Serialize>(value: &T, options: SerOptions)
+                                         -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut writer = Vec::with_capacity(128);
+    let buffers = try!(to_writer_with_buffers(&mut writer, value, options));
+    Ok((writer, buffers))
+}
+
+/// Encode the object graph into a `Vec<u8>` buffer, with the given options.
+#[inline]
+pub fn graph_to_vec(graph: &Graph, options: SerOptions) -> Result<Vec<u8>> {
+    let mut writer = Vec::with_capacity(128);
+    try!(graph_to_writer(&mut writer, graph, options));
+    Ok(writer)
+}
+
+// Get the memo ID argument of a PUT or GET opcode.
+fn memo_arg(instr: &Instruction) -> Result<i64> {
+    match instr.arg {
+        Some(Arg::Int(i)) => Ok(i),
+        Some(Arg::Bool(b)) => Ok(b as i64),
+        ref arg => Err(Error::Eval(ErrorCode::InvalidValue(format!("memo ID {:?}", arg)),
+                                   instr.offset)),
+    }
+}
+
+/// Rewrite a pickle stream without the memo entries that are never fetched,
+/// renumbering the remaining ones, like Python's `pickletools.optimize`.
+/// Streams of protocol 4 and later are re-framed.
+pub fn optimize(input: &[u8]) -> Result<Vec<u8>> {
+    optimize_with_framing(input, true)
+}
+
+/// Like `optimize`, but selects whether streams of protocol 4 and later are
+/// re-framed, or written without frames.
+pub fn optimize_with_framing(input: &[u8], framing: bool) -> Result<Vec<u8>> {
+    let instrs = try!(disasm::disassemble(input));
+    // Find the memo IDs that are fetched, and the protocol to write.
+    let mut fetched = HashMap::new();
+    let mut proto = 0;
+    for instr in &instrs {
+        match instr.opcode {
+            GET | BINGET | LONG_BINGET => {
+                proto = cmp::max(proto, opcode_info(instr.opcode).unwrap().proto);
+                fetched.insert(try!(memo_arg(instr)), None);
+            }
+            PROTO => if let Some(Arg::Int(p)) = instr.arg {
+                proto = cmp::max(proto, p as u8);
+            },
+            _ => {}
+        }
+    }
+
+    let mut ser = Serializer::with_options(Vec::with_capacity(input.len()),
+                                           SerOptions::new().proto(proto));
+    let mut instrs = instrs.iter().peekable();
+    // The PROTO header must come before the first frame.
+    if instrs.peek().map_or(false, |instr| instr.opcode == PROTO) {
+        try!(ser.writer.write_all(&input[..2]));
+        instrs.next();
+    }
+    if proto >= 4 && framing {
+        ser.writer.start_framing();
+    }
+    let mut stored = HashSet::new();
+    while let Some(instr) = instrs.next() {
+        match instr.opcode {
+            PUT | BINPUT | LONG_BINPUT | MEMOIZE => {
+                let id = if instr.opcode == MEMOIZE {
+                    stored.len() as i64
+                } else {
+                    try!(memo_arg(instr))
+                };
+                stored.insert(id);
+                if let Some(new_id) = fetched.get_mut(&id) {
+                    *new_id = Some(try!(ser.write_put()));
+                }
+            }
+            GET | BINGET | LONG_BINGET => {
+                let id = try!(memo_arg(instr));
+                match fetched[&id] {
+                    Some(new_id) => try!(ser.write_get(new_id)),
+                    None => return Err(Error::Eval(ErrorCode::MissingMemo(id as u32),
+                                                   instr.offset)),
+                }
+            }
+            FRAME => {}
+            _ => {
+                let end = instrs.peek().map_or(instr.offset + 1, |next| next.offset);
+                try!(ser.writer.commit_frame(false));
+                try!(ser.writer.write_large(&[], &input[instr.offset..end]));
+            }
+        }
+    }
+    try!(ser.writer.end_framing());
+    Ok(ser.into_inner())
+}
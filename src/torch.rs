@@ -0,0 +1,339 @@
+// Copyright (c) 2015-2016 Georg Brandl.  Licensed under the Apache License,
+// Version 2.0 <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>
+// or the MIT license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at
+// your option. This file may not be copied, modified, or distributed except
+// according to those terms.
+
+//! PyTorch checkpoints, available with the `torch` feature.
+//!
+//! `torch.save` writes a zip archive, which contains the pickle `data.pkl`
+//! and a file with the raw data of each tensor storage.  In the pickle, the
+//! tensors are rebuilt with `torch._utils._rebuild_tensor_v2(storage,
+//! offset, size, stride, ...)`, and the storages are referred to by
+//! persistent IDs of the form `("storage", storage_type, key, location,
+//! numel)`.
+//!
+//! `load` reads all tensors of a checkpoint, which usually is a state dict
+//! of a model, or a dict of several state dicts.  Besides tensors, the
+//! checkpoint may only contain values that the deserializer supports.
+
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::collections::BTreeMap;
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use de::{Deserializer, GlobalResolver, PersistentLoad};
+use error::{Error, ErrorCode, Result};
+use value::{Value, HashableValue, Object};
+
+/// The element type of a tensor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DType {
+    /// `torch.float64`
+    Float64,
+    /// `torch.float32`
+    Float32,
+    /// `torch.float16`
+    Float16,
+    /// `torch.bfloat16`
+    BFloat16,
+    /// `torch.complex128`
+    Complex128,
+    /// `torch.complex64`
+    Complex64,
+    /// `torch.int64`
+    Int64,
+    /// `torch.int32`
+    Int32,
+    /// `torch.int16`
+    Int16,
+    /// `torch.int8`
+    Int8,
+    /// `torch.uint8`
+    UInt8,
+    /// `torch.bool`
+    Bool,
+}
+
+impl DType {
+    // Get the dtype of a storage class, as given in the persistent ID.
+    fn from_storage(name: &str) -> Option<DType> {
+        Some(match name {
+            "torch.DoubleStorage" => DType::Float64,
+            "torch.FloatStorage" => DType::Float32,
+            "torch.HalfStorage" => DType::Float16,
+            "torch.BFloat16Storage" => DType::BFloat16,
+            "torch.ComplexDoubleStorage" => DType::Complex128,
+            "torch.ComplexFloatStorage" => DType::Complex64,
+            "torch.LongStorage" => DType::Int64,
+            "torch.IntStorage" => DType::Int32,
+            "torch.ShortStorage" => DType::Int16,
+            "torch.CharStorage" => DType::Int8,
+            "torch.ByteStorage" => DType::UInt8,
+            "torch.BoolStorage" => DType::Bool,
+            _ => return None,
+        })
+    }
+
+    /// Return the size of an element in bytes.
+    pub fn itemsize(&self) -> usize {
+        match *self {
+            DType::Complex128 => 16,
+            DType::Float64 | DType::Complex64 | DType::Int64 => 8,
+            DType::Float32 | DType::Int32 => 4,
+            DType::Float16 | DType::BFloat16 | DType::Int16 => 2,
+            DType::Int8 | DType::UInt8 | DType::Bool => 1,
+        }
+    }
+}
+
+/// A tensor of a PyTorch checkpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tensor {
+    /// Type of the elements
+    pub dtype: DType,
+    /// Length of each dimension
+    pub shape: Vec<usize>,
+    /// Distance between consecutive elements of each dimension, in elements
+    pub strides: Vec<usize>,
+    /// The raw data in little-endian byte order, from the first element of
+    /// the tensor to the last one.  Tensors that are views of the same
+    /// storage get a copy of the data each.
+    pub data: Vec<u8>,
+}
+
+/// Load the tensors of a PyTorch checkpoint file.
+pub fn load_file<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Tensor>> {
+    load(try!(File::open(path)))
+}
+
+/// Load the tensors of a PyTorch checkpoint.
+///
+/// The tensors are named by their keys in the checkpoint.  Keys of nested
+/// dicts (and the indices of lists) are joined with dots, as in
+/// `model.fc.weight`.
+pub fn load<R: Read + Seek>(reader: R) -> Result<BTreeMap<String, Tensor>> {
+    let mut archive = try!(ZipArchive::new(reader).map_err(zip_error));
+    // The files are in a directory named after the checkpoint.
+    let prefix = match archive.file_names().find(|name| name.ends_with("data.pkl")) {
+        Some(name) => name[..name.len() - 8].to_owned(),
+        None => return invalid("no data.pkl in the checkpoint"),
+    };
+    // Older versions don't record the byte order, and are little-endian.
+    let byteorder = format!("{}byteorder", prefix);
+    let big_endian = archive.file_names().any(|name| name == byteorder) &&
+        try!(read_file(&mut archive, &byteorder)) == b"big";
+    let value = {
+        let pickle = try!(archive.by_name(&format!("{}data.pkl", prefix)).map_err(zip_error));
+        let mut de = Deserializer::new(pickle, false);
+        de.add_resolver(Loader);
+        de.set_persistent_load(Loader);
+        let value = try!(de.decode_value());
+        try!(de.end());
+        value
+    };
+    let mut tensors = Vec::new();
+    collect_tensors(String::new(), value, &mut tensors);
+    let mut storages = BTreeMap::new();
+    let mut result = BTreeMap::new();
+    for (name, args) in tensors {
+        let (key, mut tensor, offset) = try!(tensor_layout(&args));
+        if !storages.contains_key(&key) {
+            let data = try!(read_file(&mut archive, &format!("{}data/{}", prefix, key)));
+            storages.insert(key.clone(), data);
+        }
+        tensor.data = try!(tensor_data(&tensor, &storages[&key], offset, big_endian));
+        result.insert(name, tensor);
+    }
+    Ok(result)
+}
+
+// Rebuilds tensors and storages as objects of the classes `torch.Tensor`
+// with the arguments (storage, offset, size, stride), and e.g.
+// `torch.FloatStorage` with the arguments (key, numel).
+struct Loader;
+
+impl GlobalResolver for Loader {
+    fn handles(&self, module: &str, name: &str) -> bool {
+        module == "torch._utils" && match name {
+            "_rebuild_tensor" | "_rebuild_tensor_v2" | "_rebuild_parameter" |
+            "_rebuild_parameter_with_state" => true,
+            _ => false,
+        }
+    }
+
+    fn reduce(&self, _: &str, name: &str, mut args: Vec<Value>) -> Result<Value> {
+        if name.starts_with("_rebuild_parameter") {
+            // Parameters are tensors with (data, requires_grad, hooks).
+            return match args.into_iter().next() {
+                Some(tensor) => Ok(tensor),
+                None => invalid("parameter"),
+            };
+        }
+        if args.len() < 4 {
+            return invalid("tensor");
+        }
+        args.truncate(4);
+        Ok(Value::Object(Box::new(Object {
+            class: ("torch".into(), "Tensor".into()),
+            args: args,
+            kwargs: BTreeMap::new(),
+            state: None,
+        })))
+    }
+}
+
+impl PersistentLoad for Loader {
+    fn persistent_load(&self, pid: Value) -> Result<Value> {
+        let pid = match pid {
+            Value::Tuple(items) => items,
+            _ => return invalid("persistent ID"),
+        };
+        if pid.len() != 5 || pid[0] != Value::String("storage".into()) {
+            return invalid("persistent ID");
+        }
+        let class = match pid[1] {
+            Value::String(ref class) if DType::from_storage(class).is_some() => class.clone(),
+            Value::String(ref class) => return Err(Error::Syntax(ErrorCode::InvalidValue(
+                format!("unsupported storage type {}", class)))),
+            _ => return invalid("persistent ID"),
+        };
+        let dot = match class.rfind('.') {
+            Some(dot) => dot,
+            None => return invalid("persistent ID"),
+        };
+        Ok(Value::Object(Box::new(Object {
+            class: (class[..dot].into(), class[dot + 1..].into()),
+            args: vec![pid[2].clone(), pid[4].clone()],
+            kwargs: BTreeMap::new(),
+            state: None,
+        })))
+    }
+}
+
+fn invalid<T>(what: &str) -> Result<T> {
+    Err(Error::Syntax(ErrorCode::InvalidValue(format!("torch {}", what))))
+}
+
+fn zip_error(err: ZipError) -> Error {
+    match err {
+        ZipError::Io(err) => Error::Io(err),
+        other => Error::Syntax(ErrorCode::InvalidValue(format!("zip archive: {}", other))),
+    }
+}
+
+fn read_file<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+    let mut file = try!(archive.by_name(name).map_err(zip_error));
+    let mut data = Vec::with_capacity(file.size() as usize);
+    try!(file.read_to_end(&mut data));
+    Ok(data)
+}
+
+// Find the tensors in dicts and lists, and name them by their path.
+fn collect_tensors(path: String, value: Value, tensors: &mut Vec<(String, Vec<Value>)>) {
+    let join = |key: String| if path.is_empty() { key } else { format!("{}.{}", path, key) };
+    match value {
+        Value::Object(obj) => {
+            let obj = *obj;
+            if obj.class.0 == "torch" && obj.class.1 == "Tensor" {
+                tensors.push((path, obj.args));
+            } else if obj.class.0 == "collections" && obj.class.1 == "OrderedDict" {
+                // State dicts with attributes, like `_metadata`.
+                for arg in obj.args {
+                    collect_tensors(path.clone(), arg, tensors);
+                }
+            }
+        }
+        Value::Dict(items) => for (key, value) in items {
+            if let Some(key) = key_name(key) {
+                collect_tensors(join(key), value, tensors);
+            }
+        },
+        Value::OrderedDict(items) => for (key, value) in items {
+            if let Some(key) = key_name(key) {
+                collect_tensors(join(key), value, tensors);
+            }
+        },
+        Value::List(items) | Value::Tuple(items) =>
+            for (i, value) in items.into_iter().enumerate() {
+                collect_tensors(join(i.to_string()), value, tensors);
+            },
+        _ => {}
+    }
+}
+
+fn key_name(key: HashableValue) -> Option<String> {
+    match key {
+        HashableValue::String(s) => Some(s),
+        HashableValue::I64(i) => Some(i.to_string()),
+        _ => None,
+    }
+}
+
+fn to_usizes(value: &Value) -> Option<Vec<usize>> {
+    match *value {
+        Value::Tuple(ref items) | Value::List(ref items) => items.iter().map(|item| match *item {
+            Value::I64(i) if i >= 0 => Some(i as usize),
+            _ => None,
+        }).collect(),
+        _ => None,
+    }
+}
+
+// Get the storage key, the tensor without data, and the offset in the
+// storage from the arguments (storage, offset, size, stride).
+fn tensor_layout(args: &[Value]) -> Result<(String, Tensor, usize)> {
+    if args.len() < 4 {
+        return invalid("tensor");
+    }
+    let (dtype, key) = match args[0] {
+        Value::Object(ref storage) => match (DType::from_storage(&format!(
+            "{}.{}", storage.class.0, storage.class.1)), storage.args.first()) {
+            (Some(dtype), Some(&Value::String(ref key))) => (dtype, key.clone()),
+            _ => return invalid("storage"),
+        },
+        _ => return invalid("storage"),
+    };
+    match (&args[1], to_usizes(&args[2]), to_usizes(&args[3])) {
+        (&Value::I64(offset), Some(shape), Some(strides))
+            if offset >= 0 && shape.len() == strides.len() =>
+            Ok((key, Tensor { dtype: dtype, shape: shape, strides: strides, data: Vec::new() },
+                offset as usize)),
+        _ => invalid("tensor"),
+    }
+}
+
+// Get the data of the tensor from its storage.
+fn tensor_data(tensor: &Tensor, storage: &[u8], offset: usize, big_endian: bool)
+               -> Result<Vec<u8>> {
+    // The number of elements from the first to the last one of the tensor.
+    let extent = if tensor.shape.contains(&0) {
+        Some(0)
+    } else {
+        tensor.shape.iter().zip(&tensor.strides).fold(Some(1usize), |n, (&len, &stride)| {
+            n.and_then(|n| (len - 1).checked_mul(stride).and_then(|m| m.checked_add(n)))
+        })
+    };
+    let itemsize = tensor.dtype.itemsize();
+    let range = match (offset.checked_mul(itemsize), extent.and_then(|n| n.checked_mul(itemsize))) {
+        (Some(start), Some(len)) => start.checked_add(len).map(|end| (start, end)),
+        _ => None,
+    };
+    let mut data = match range {
+        Some((start, end)) if end <= storage.len() => storage[start..end].to_vec(),
+        _ => return invalid("storage size"),
+    };
+    if big_endian {
+        // Complex numbers are swapped as two floats.
+        let size = match tensor.dtype {
+            DType::Complex128 | DType::Complex64 => itemsize / 2,
+            _ => itemsize,
+        };
+        for item in data.chunks_mut(size) {
+            item.reverse();
+        }
+    }
+    Ok(data)
+}
@@ -7,14 +7,16 @@
 //! Python values, and serialization instances for them.
 
 use std::fmt;
-use std::cmp::Ordering;
+use std::cmp::{self, Ordering};
 use std::collections::{BTreeMap, BTreeSet};
 use num_bigint::BigInt;
-use num_traits::{Signed, ToPrimitive};
+use num_traits::{self, Signed, ToPrimitive, Float, Zero};
 
 pub use value_impls::{to_value, from_value};
 
 use error::{Error, ErrorCode};
+use datetime::{Date, Time, DateTime, TimeDelta};
+use numbers::{Decimal, Fraction, Complex};
 
 /// Represents all primitive builtin Python values that can be restored by
 /// unpickling.
@@ -36,8 +38,16 @@ pub enum Value {
     Int(BigInt),
     /// Float
     F64(f64),
+    /// Decimal (`decimal.Decimal`)
+    Decimal(Decimal),
+    /// Rational number (`fractions.Fraction`)
+    Fraction(Fraction),
+    /// Complex number
+    Complex(Complex),
     /// Bytestring
     Bytes(Vec<u8>),
+    /// Mutable bytestring (`bytearray`)
+    ByteArray(Vec<u8>),
     /// Unicode string
     String(String),
     /// List
@@ -50,6 +60,59 @@ pub enum Value {
     FrozenSet(BTreeSet<HashableValue>),
     /// Dictionary (map)
     Dict(BTreeMap<HashableValue, Value>),
+    /// Ordered dictionary (`collections.OrderedDict`), in insertion order
+    OrderedDict(Vec<(HashableValue, Value)>),
+    /// Dictionary with default factory (`collections.defaultdict`)
+    DefaultDict(Box<DefaultDict>),
+    /// Double-ended queue (`collections.deque`)
+    Deque(Deque),
+    /// Multiset (`collections.Counter`)
+    Counter(BTreeMap<HashableValue, Value>),
+    /// Instance of a Python class
+    Object(Box<Object>),
+    /// Date (`datetime.date`)
+    Date(Date),
+    /// Time of day (`datetime.time`)
+    Time(Time),
+    /// Date and time (`datetime.datetime`)
+    DateTime(DateTime),
+    /// Duration (`datetime.timedelta`)
+    TimeDelta(TimeDelta),
+}
+
+/// Represents an instance of a Python class, identified by module and class
+/// name.
+///
+/// Unpickling creates it by calling `class.__new__(class, *args, **kwargs)`,
+/// and then restores `state` (usually the instance `__dict__`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    /// Module and name of the class
+    pub class: (String, String),
+    /// Positional arguments for `__new__`
+    pub args: Vec<Value>,
+    /// Keyword arguments for `__new__`
+    pub kwargs: BTreeMap<HashableValue, Value>,
+    /// State of the instance, if any
+    pub state: Option<Value>,
+}
+
+/// Represents a `collections.defaultdict`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefaultDict {
+    /// Module and name of the default factory, if any
+    pub factory: Option<(String, String)>,
+    /// The items
+    pub items: BTreeMap<HashableValue, Value>,
+}
+
+/// Represents a `collections.deque`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deque {
+    /// The items
+    pub items: Vec<Value>,
+    /// Maximum length, if any
+    pub maxlen: Option<usize>,
 }
 
 /// Represents all primitive builtin Python values that can be contained
@@ -71,6 +134,12 @@ pub enum HashableValue {
     Int(BigInt),
     /// Float
     F64(f64),
+    /// Decimal (`decimal.Decimal`)
+    Decimal(Decimal),
+    /// Rational number (`fractions.Fraction`)
+    Fraction(Fraction),
+    /// Complex number
+    Complex(Complex),
     /// Bytestring
     Bytes(Vec<u8>),
     /// Unicode string
@@ -79,6 +148,14 @@ pub enum HashableValue {
     Tuple(Vec<HashableValue>),
     /// Frozen (immutable) set
     FrozenSet(BTreeSet<HashableValue>),
+    /// Date (`datetime.date`)
+    Date(Date),
+    /// Time of day (`datetime.time`)
+    Time(Time),
+    /// Date and time (`datetime.datetime`)
+    DateTime(DateTime),
+    /// Duration (`datetime.timedelta`)
+    TimeDelta(TimeDelta),
 }
 
 fn values_to_hashable(values: Vec<Value>) -> Result<Vec<HashableValue>, Error> {
@@ -99,10 +176,17 @@ impl Value {
             Value::I64(i)       => Ok(HashableValue::I64(i)),
             Value::Int(i)       => Ok(HashableValue::Int(i)),
             Value::F64(f)       => Ok(HashableValue::F64(f)),
+            Value::Decimal(d)   => Ok(HashableValue::Decimal(d)),
+            Value::Fraction(f)  => Ok(HashableValue::Fraction(f)),
+            Value::Complex(c)   => Ok(HashableValue::Complex(c)),
             Value::Bytes(b)     => Ok(HashableValue::Bytes(b)),
             Value::String(s)    => Ok(HashableValue::String(s)),
             Value::FrozenSet(v) => Ok(HashableValue::FrozenSet(v)),
             Value::Tuple(v)     => values_to_hashable(v).map(HashableValue::Tuple),
+            Value::Date(d)      => Ok(HashableValue::Date(d)),
+            Value::Time(t)      => Ok(HashableValue::Time(t)),
+            Value::DateTime(d)  => Ok(HashableValue::DateTime(d)),
+            Value::TimeDelta(d) => Ok(HashableValue::TimeDelta(d)),
             _                   => Err(Error::Syntax(ErrorCode::ValueNotHashable))
         }
     }
@@ -117,10 +201,17 @@ impl HashableValue {
             HashableValue::I64(i)       => Value::I64(i),
             HashableValue::Int(i)       => Value::Int(i),
             HashableValue::F64(f)       => Value::F64(f),
+            HashableValue::Decimal(d)   => Value::Decimal(d),
+            HashableValue::Fraction(f)  => Value::Fraction(f),
+            HashableValue::Complex(c)   => Value::Complex(c),
             HashableValue::Bytes(b)     => Value::Bytes(b),
             HashableValue::String(s)    => Value::String(s),
             HashableValue::FrozenSet(v) => Value::FrozenSet(v),
             HashableValue::Tuple(v)     => Value::Tuple(hashable_to_values(v)),
+            HashableValue::Date(d)      => Value::Date(d),
+            HashableValue::Time(t)      => Value::Time(t),
+            HashableValue::DateTime(d)  => Value::DateTime(d),
+            HashableValue::TimeDelta(d) => Value::TimeDelta(d),
         }
     }
 }
@@ -141,6 +232,19 @@ fn write_elements<'a, I, T>(f: &mut fmt::Formatter, it: I,
     f.write_str(suffix)
 }
 
+fn write_items<'a, I>(f: &mut fmt::Formatter, it: I) -> fmt::Result
+    where I: Iterator<Item=(&'a HashableValue, &'a Value)>
+{
+    try!(write!(f, "{{"));
+    for (i, (key, value)) in it.enumerate() {
+        if i > 0 {
+            try!(write!(f, ", "));
+        }
+        try!(write!(f, "{}: {}", key, value));
+    }
+    write!(f, "}}")
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -149,7 +253,11 @@ impl fmt::Display for Value {
             Value::I64(i)        => write!(f, "{}", i),
             Value::Int(ref i)    => write!(f, "{}", i),
             Value::F64(v)        => write!(f, "{}", v),
+            Value::Decimal(ref d) => write!(f, "Decimal('{}')", d),
+            Value::Fraction(ref v) => write!(f, "Fraction({}, {})", v.numerator(), v.denominator()),
+            Value::Complex(c)    => write!(f, "{}", c),
             Value::Bytes(ref b)  => write!(f, "b{:?}", b), //
+            Value::ByteArray(ref b) => write!(f, "bytearray(b{:?})", b),
             Value::String(ref s) => write!(f, "{:?}", s),
             Value::List(ref v)   => write_elements(f, v.iter(), "[", "]", v.len(), false),
             Value::Tuple(ref v)  => write_elements(f, v.iter(), "(", ")", v.len(), v.len() == 1),
@@ -160,17 +268,60 @@ impl fmt::Display for Value {
             } else {
                 write_elements(f, v.iter(), "{", "}", v.len(), false)
             },
-            Value::Dict(ref v) => {
-                try!(write!(f, "{{"));
-                for (i, (key, value)) in v.iter().enumerate() {
-                    if i < v.len() - 1 {
-                        try!(write!(f, "{}: {}, ", key, value));
-                    } else {
-                        try!(write!(f, "{}: {}", key, value));
+            Value::Dict(ref v) => write_items(f, v.iter()),
+            Value::OrderedDict(ref v) => {
+                try!(write!(f, "OrderedDict("));
+                try!(write_items(f, v.iter().map(|&(ref k, ref v)| (k, v))));
+                write!(f, ")")
+            },
+            Value::DefaultDict(ref d) => {
+                match d.factory {
+                    Some((ref module, ref name)) => try!(write!(f, "defaultdict({}.{}, ",
+                                                                module, name)),
+                    None => try!(write!(f, "defaultdict(None, ")),
+                }
+                try!(write_items(f, d.items.iter()));
+                write!(f, ")")
+            },
+            Value::Deque(ref d) => {
+                try!(write_elements(f, d.items.iter(), "deque([", "]", d.items.len(), false));
+                match d.maxlen {
+                    Some(maxlen) => write!(f, ", maxlen={})", maxlen),
+                    None => write!(f, ")"),
+                }
+            },
+            Value::Counter(ref v) => {
+                try!(write!(f, "Counter("));
+                try!(write_items(f, v.iter()));
+                write!(f, ")")
+            },
+            Value::Object(ref obj) => {
+                try!(write!(f, "{}.{}(", obj.class.0, obj.class.1));
+                for (i, arg) in obj.args.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ", "));
+                    }
+                    try!(write!(f, "{}", arg));
+                }
+                for (i, (key, value)) in obj.kwargs.iter().enumerate() {
+                    if i > 0 || !obj.args.is_empty() {
+                        try!(write!(f, ", "));
+                    }
+                    match *key {
+                        HashableValue::String(ref s) => try!(write!(f, "{}={}", s, value)),
+                        _ => try!(write!(f, "{}={}", key, value)),
                     }
                 }
-                write!(f, "}}")
+                try!(write!(f, ")"));
+                match obj.state {
+                    Some(ref state) => write!(f, ".__setstate__({})", state),
+                    None => Ok(())
+                }
             },
+            Value::Date(ref d)      => write!(f, "{}", d),
+            Value::Time(ref t)      => write!(f, "{}", t),
+            Value::DateTime(ref d)  => write!(f, "{}", d),
+            Value::TimeDelta(ref d) => write!(f, "{}", d),
         }
     }
 }
@@ -183,12 +334,20 @@ impl fmt::Display for HashableValue {
             HashableValue::I64(i)           => write!(f, "{}", i),
             HashableValue::Int(ref i)       => write!(f, "{}", i),
             HashableValue::F64(v)           => write!(f, "{}", v),
+            HashableValue::Decimal(ref d)   => write!(f, "Decimal('{}')", d),
+            HashableValue::Fraction(ref v)  => write!(f, "Fraction({}, {})", v.numerator(),
+                                                      v.denominator()),
+            HashableValue::Complex(c)       => write!(f, "{}", c),
             HashableValue::Bytes(ref b)     => write!(f, "b{:?}", b), //
             HashableValue::String(ref s)    => write!(f, "{:?}", s),
             HashableValue::Tuple(ref v)     => write_elements(f, v.iter(), "(", ")",
                                                               v.len(), v.len() == 1),
             HashableValue::FrozenSet(ref v) => write_elements(f, v.iter(), "frozenset([", "])",
                                                               v.len(), false),
+            HashableValue::Date(ref d)      => write!(f, "{}", d),
+            HashableValue::Time(ref t)      => write!(f, "{}", t),
+            HashableValue::DateTime(ref d)  => write!(f, "{}", d),
+            HashableValue::TimeDelta(ref d) => write!(f, "{}", d),
         }
     }
 }
@@ -230,6 +389,8 @@ impl Ord for HashableValue {
                 I64(i2)      => (b as i64).cmp(&i2),
                 Int(ref bi)  => BigInt::from(b as i64).cmp(bi),
                 F64(f)       => float_ord(b as i64 as f64, f),
+                Decimal(_) | Fraction(_) |
+                Complex(_)   => number_ord(self, other),
                 _            => Ordering::Less
             },
             I64(i) => match *other {
@@ -238,6 +399,8 @@ impl Ord for HashableValue {
                 I64(i2)      => i.cmp(&i2),
                 Int(ref bi)  => BigInt::from(i).cmp(bi),
                 F64(f)       => float_ord(i as f64, f),
+                Decimal(_) | Fraction(_) |
+                Complex(_)   => number_ord(self, other),
                 _            => Ordering::Less
             },
             Int(ref bi) => match *other {
@@ -246,6 +409,8 @@ impl Ord for HashableValue {
                 I64(i)       => bi.cmp(&BigInt::from(i)),
                 Int(ref bi2) => bi.cmp(bi2),
                 F64(f)       => float_bigint_ord(bi, f),
+                Decimal(_) | Fraction(_) |
+                Complex(_)   => number_ord(self, other),
                 _            => Ordering::Less
             },
             F64(f) => match *other {
@@ -254,29 +419,61 @@ impl Ord for HashableValue {
                 I64(i)       => float_ord(f, i as f64),
                 Int(ref bi)  => BigInt::from(f as i64).cmp(bi),
                 F64(f2)      => float_ord(f, f2),
+                Decimal(_) | Fraction(_) |
+                Complex(_)   => number_ord(self, other),
+                _            => Ordering::Less
+            },
+            Decimal(_) | Fraction(_) | Complex(_) => match *other {
+                None         => Ordering::Greater,
+                Bool(_) | I64(_) | Int(_) | F64(_) | Decimal(_) | Fraction(_) |
+                Complex(_)   => number_ord(self, other),
                 _            => Ordering::Less
             },
             Bytes(ref bs) => match *other {
-                String(_) | FrozenSet(_) |
-                Tuple(_)       => Ordering::Less,
+                None | Bool(_) | I64(_) | Int(_) | F64(_) | Decimal(_) | Fraction(_) |
+                Complex(_)     => Ordering::Greater,
                 Bytes(ref bs2) => bs.cmp(bs2),
-                _              => Ordering::Greater
+                _              => Ordering::Less
             },
             String(ref s) => match *other {
-                FrozenSet(_) |
-                Tuple(_)       => Ordering::Less,
+                None | Bool(_) | I64(_) | Int(_) | F64(_) | Decimal(_) | Fraction(_) |
+                Complex(_) | Bytes(_) => Ordering::Greater,
                 String(ref s2) => s.cmp(s2),
-                _              => Ordering::Greater
+                _              => Ordering::Less
             },
             FrozenSet(ref s) => match *other {
-                Tuple(_)          => Ordering::Less,
+                None | Bool(_) | I64(_) | Int(_) | F64(_) | Decimal(_) | Fraction(_) |
+                Complex(_) | Bytes(_) | String(_) => Ordering::Greater,
                 FrozenSet(ref s2) => s.cmp(s2),
-                _                 => Ordering::Greater
+                _                 => Ordering::Less
             },
             Tuple(ref t) => match *other {
+                None | Bool(_) | I64(_) | Int(_) | F64(_) | Decimal(_) | Fraction(_) |
+                Complex(_) | Bytes(_) | String(_) | FrozenSet(_) => Ordering::Greater,
                 Tuple(ref t2) => t.cmp(t2),
+                _             => Ordering::Less
+            },
+            Date(ref d) => match *other {
+                Time(_) | DateTime(_) |
+                TimeDelta(_)  => Ordering::Less,
+                Date(ref d2)  => d.cmp(d2),
+                _             => Ordering::Greater
+            },
+            Time(ref t) => match *other {
+                DateTime(_) |
+                TimeDelta(_)  => Ordering::Less,
+                Time(ref t2)  => t.cmp(t2),
                 _             => Ordering::Greater
             },
+            DateTime(ref d) => match *other {
+                TimeDelta(_)     => Ordering::Less,
+                DateTime(ref d2) => d.cmp(d2),
+                _                => Ordering::Greater
+            },
+            TimeDelta(ref d) => match *other {
+                TimeDelta(ref d2) => d.cmp(d2),
+                _                 => Ordering::Greater
+            },
         }
     }
 }
@@ -289,6 +486,92 @@ fn float_ord(f: f64, g: f64) -> Ordering {
     }
 }
 
+/// Exact ordering between numbers, used for decimals, fractions and complex
+/// numbers.  Complex numbers are ordered by their real part first, so that
+/// those with a zero imaginary part compare equal to real numbers.
+fn number_ord(a: &HashableValue, b: &HashableValue) -> Ordering {
+    let (re_a, im_a) = to_real(a);
+    let (re_b, im_b) = to_real(b);
+    real_ord(&re_a, &re_b).then_with(|| float_ord(im_a, im_b))
+}
+
+/// An exact real number `numerator / denominator * 10**exponent`, or one
+/// of the non-finite values.
+enum Real {
+    NaN,
+    NegInf,
+    Finite(BigInt, BigInt, i64),
+    PosInf,
+}
+
+fn to_real(value: &HashableValue) -> (Real, f64) {
+    let real = match *value {
+        HashableValue::Bool(b) => Real::Finite(BigInt::from(b as i64), BigInt::from(1), 0),
+        HashableValue::I64(i) => Real::Finite(BigInt::from(i), BigInt::from(1), 0),
+        HashableValue::Int(ref bi) => Real::Finite(bi.clone(), BigInt::from(1), 0),
+        HashableValue::F64(f) => float_to_real(f),
+        HashableValue::Decimal(ref d) => match d.to_parts() {
+            Some((coefficient, exponent)) => Real::Finite(coefficient, BigInt::from(1), exponent),
+            None => float_to_real(d.to_f64()),
+        },
+        HashableValue::Fraction(ref f) =>
+            Real::Finite(f.numerator().clone(), f.denominator().clone(), 0),
+        HashableValue::Complex(c) => return (float_to_real(c.re), c.im),
+        _ => unreachable!("not a number"),
+    };
+    (real, 0.0)
+}
+
+fn float_to_real(f: f64) -> Real {
+    if f.is_nan() {
+        return Real::NaN;
+    } else if f.is_infinite() {
+        return if f > 0.0 { Real::PosInf } else { Real::NegInf };
+    }
+    let (mantissa, exponent, sign) = f.integer_decode();
+    let numerator = BigInt::from(mantissa as i64 * sign as i64);
+    if exponent >= 0 {
+        Real::Finite(numerator << exponent as usize, BigInt::from(1), 0)
+    } else {
+        Real::Finite(numerator, BigInt::from(1) << -exponent as usize, 0)
+    }
+}
+
+fn real_ord(a: &Real, b: &Real) -> Ordering {
+    fn rank(real: &Real) -> u8 {
+        match *real {
+            Real::NaN => 0,
+            Real::NegInf => 1,
+            Real::Finite(..) => 2,
+            Real::PosInf => 3,
+        }
+    }
+    let (n1, d1, e1, n2, d2, e2) = match (a, b) {
+        (&Real::Finite(ref n1, ref d1, e1), &Real::Finite(ref n2, ref d2, e2)) =>
+            (n1, d1, e1, n2, d2, e2),
+        _ => return rank(a).cmp(&rank(b)),
+    };
+    let sign = n1.sign().cmp(&n2.sign());
+    if sign != Ordering::Equal || n1.is_zero() {
+        return sign;
+    }
+    // Compare the magnitudes by an estimate of their decimal logarithm
+    // first, so that huge exponents don't have to be expanded.
+    let log2_10 = 10f64.log2();
+    let est1 = (n1.bits() as f64 - d1.bits() as f64) / log2_10 + e1 as f64;
+    let est2 = (n2.bits() as f64 - d2.bits() as f64) / log2_10 + e2 as f64;
+    let ord = if (est1 - est2).abs() > 2.0 {
+        float_ord(est1, est2)
+    } else {
+        let exponent = cmp::min(e1, e2);
+        let ten = BigInt::from(10);
+        let lhs = n1.abs() * d2 * num_traits::pow(ten.clone(), (e1 - exponent) as usize);
+        let rhs = n2.abs() * d1 * num_traits::pow(ten, (e2 - exponent) as usize);
+        lhs.cmp(&rhs)
+    };
+    if n1.is_negative() { ord.reverse() } else { ord }
+}
+
 /// Ordering between floats and big integers.
 fn float_bigint_ord(bi: &BigInt, g: f64) -> Ordering {
     match bi.to_f64() {
@@ -8,7 +8,7 @@
 
 use std::vec;
 use std::result::Result as StdResult;
-use std::collections::{btree_map, BTreeMap};
+use std::collections::BTreeMap;
 use num_bigint::BigInt;
 use num_traits::ToPrimitive;
 use serde::{ser, de};
@@ -209,7 +209,7 @@ impl de::Deserializer for Deserializer {
                 }
             },
             Value::F64(v) => visitor.visit_f64(v),
-            Value::Bytes(v) => visitor.visit_byte_buf(v),
+            Value::Bytes(v) | Value::ByteArray(v) => visitor.visit_byte_buf(v),
             Value::String(v) => visitor.visit_string(v),
             Value::List(v) => {
                 let len = v.len();
@@ -243,6 +243,71 @@ impl de::Deserializer for Deserializer {
                     len: len,
                 })
             },
+            Value::Object(obj) => {
+                // Objects are visited as their state, which is usually the
+                // instance dictionary.
+                match obj.state {
+                    Some(state) => {
+                        self.value = Some(state);
+                        de::Deserializer::deserialize(self, visitor)
+                    }
+                    None => visitor.visit_map(MapDeserializer {
+                        de: self,
+                        iter: Vec::new().into_iter(),
+                        value: None,
+                        len: 0,
+                    }),
+                }
+            },
+            Value::OrderedDict(v) => {
+                visitor.visit_map(MapDeserializer {
+                    de: self,
+                    len: v.len(),
+                    iter: v.into_iter(),
+                    value: None,
+                })
+            }
+            Value::DefaultDict(d) => {
+                let d = *d;
+                visitor.visit_map(MapDeserializer {
+                    de: self,
+                    len: d.items.len(),
+                    iter: d.items.into_iter(),
+                    value: None,
+                })
+            }
+            Value::Deque(d) => {
+                visitor.visit_seq(SeqDeserializer {
+                    de: self,
+                    len: d.items.len(),
+                    iter: d.items.into_iter(),
+                })
+            }
+            Value::Counter(v) => {
+                visitor.visit_map(MapDeserializer {
+                    de: self,
+                    len: v.len(),
+                    iter: v.into_iter(),
+                    value: None,
+                })
+            }
+            // Exact numbers are visited as their string representation, and
+            // complex numbers as a tuple of real and imaginary part.
+            Value::Decimal(v) => visitor.visit_string(v.to_string()),
+            Value::Fraction(v) => visitor.visit_string(v.to_string()),
+            Value::Complex(v) => {
+                visitor.visit_seq(SeqDeserializer {
+                    de: self,
+                    iter: vec![Value::F64(v.re), Value::F64(v.im)].into_iter(),
+                    len: 2,
+                })
+            }
+            // Dates and times are visited as ISO 8601 strings, and
+            // timedeltas as their number of seconds.
+            Value::Date(v) => visitor.visit_string(v.to_string()),
+            Value::Time(v) => visitor.visit_string(v.to_string()),
+            Value::DateTime(v) => visitor.visit_string(v.isoformat()),
+            Value::TimeDelta(v) => visitor.visit_f64(v.total_seconds()),
         }
     }
 
@@ -411,6 +476,20 @@ impl de::VariantVisitor for Deserializer {
 
     fn visit_variant<V>(&mut self) -> Result<V> where V: de::Deserialize {
         match self.value.take() {
+            // Unit variants written as a plain string.
+            Some(value @ Value::String(_)) => {
+                self.value = Some(value);
+                de::Deserialize::deserialize(self)
+            }
+            // Variants written as a dict {'Variant': value}.
+            Some(Value::Dict(ref mut v)) if v.len() == 1 => {
+                let variant = v.keys().next().cloned().unwrap();
+                let args = v.remove(&variant);
+                self.value = Some(variant.into_value());
+                let res = de::Deserialize::deserialize(self);
+                self.value = args;
+                res
+            }
             Some(Value::Tuple(mut v)) => {
                 if v.len() == 2 {
                     let args = v.pop();
@@ -423,7 +502,7 @@ impl de::VariantVisitor for Deserializer {
                     de::Deserialize::deserialize(self)
                 }
             }
-            _ => Err(Error::Syntax(ErrorCode::Custom("enums must be tuples".into())))
+            _ => Err(Error::Syntax(ErrorCode::Custom("enums must be tuples or dicts".into())))
         }
     }
 
@@ -478,14 +557,16 @@ impl<'a> de::SeqVisitor for SeqDeserializer<'a> {
     }
 }
 
-struct MapDeserializer<'a> {
+struct MapDeserializer<'a, I> {
     de: &'a mut Deserializer,
-    iter: btree_map::IntoIter<HashableValue, Value>,
+    iter: I,
     value: Option<Value>,
     len: usize,
 }
 
-impl<'a> de::MapVisitor for MapDeserializer<'a> {
+impl<'a, I> de::MapVisitor for MapDeserializer<'a, I>
+    where I: Iterator<Item=(HashableValue, Value)>
+{
     type Error = Error;
 
     fn visit_key<T>(&mut self) -> Result<Option<T>> where T: de::Deserialize {
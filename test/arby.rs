@@ -87,12 +87,18 @@ impl Arbitrary for Value {
             Value::Int(_) => empty_shrinker(),
             Value::F64(v) => Box::new(Arbitrary::shrink(&v).map(Value::F64)),
             Value::Bytes(ref v) => Box::new(Arbitrary::shrink(v).map(Value::Bytes)),
+            Value::ByteArray(ref v) => Box::new(Arbitrary::shrink(v).map(Value::ByteArray)),
             Value::String(ref v) => Box::new(Arbitrary::shrink(v).map(Value::String)),
             Value::List(ref v) => Box::new(Arbitrary::shrink(v).map(Value::List)),
             Value::Tuple(ref v) => Box::new(Arbitrary::shrink(v).map(Value::List)),
             Value::Set(ref v) => Box::new(Arbitrary::shrink(v).map(Value::Set)),
             Value::FrozenSet(ref v) => Box::new(Arbitrary::shrink(v).map(Value::FrozenSet)),
             Value::Dict(ref v) => Box::new(Arbitrary::shrink(v).map(Value::Dict)),
+            Value::OrderedDict(_) | Value::DefaultDict(_) | Value::Deque(_) |
+            Value::Counter(_) => empty_shrinker(),
+            Value::Decimal(_) | Value::Fraction(_) | Value::Complex(_) => empty_shrinker(),
+            Value::Object(_) | Value::Date(_) | Value::Time(_) | Value::DateTime(_) |
+            Value::TimeDelta(_) => empty_shrinker(),
         }
     }
 }
@@ -113,6 +119,10 @@ impl Arbitrary for HashableValue {
             HashableValue::String(ref v) => Box::new(Arbitrary::shrink(v).map(HashableValue::String)),
             HashableValue::Tuple(ref v) => Box::new(Arbitrary::shrink(v).map(HashableValue::Tuple)),
             HashableValue::FrozenSet(ref v) => Box::new(Arbitrary::shrink(v).map(HashableValue::FrozenSet)),
+            HashableValue::Decimal(_) | HashableValue::Fraction(_) |
+            HashableValue::Complex(_) => empty_shrinker(),
+            HashableValue::Date(_) | HashableValue::Time(_) | HashableValue::DateTime(_) |
+            HashableValue::TimeDelta(_) => empty_shrinker(),
         }
     }
 }
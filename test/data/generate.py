@@ -46,6 +46,17 @@ for proto in range(max_proto + 1):
 if major == 2:
     sys.exit()
 
+# Generate instances of a user-defined class.
+class Point(object):
+    def __init__(self, x, y):
+        self.x = x
+        self.y = y
+
+point = Point(1, u"two")
+for proto in range(max_proto + 1):
+    with open('test_objects_proto%d.pickle' % proto, 'wb') as fp:
+        pickle.dump([point, point], fp, proto)
+
 # Generate recursive structure.
 rec_list = []
 rec_list.append(([rec_list], ))
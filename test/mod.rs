@@ -47,7 +47,7 @@ mod struct_tests {
     use std::collections::BTreeMap;
     use serde::{ser, de};
     use {to_vec, value_to_vec, from_slice, value_from_slice, to_value, from_value,
-         Value, HashableValue};
+         to_vec_with_options, SerOptions, EnumStyle, Value, HashableValue, Object};
 
     #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
     struct Inner {
@@ -147,6 +147,26 @@ mod struct_tests {
                        pyobj!(t=(s="Cat", d={s="age" => i=5, s="name" => s="Molyneux"})));
     }
 
+    #[test]
+    fn encode_enum_styles() {
+        let animals = vec![Animal::Dog,
+                           Animal::AntHive(vec!["ant".into(), "aunt".into()]),
+                           Animal::Frog("Henry".into(), vec![1, 5]),
+                           Animal::Cat { age: 5, name: "Molyneux".into() }];
+        let target = pyobj!(l=[s="Dog",
+                               d={s="AntHive" => l=[s="ant", s="aunt"]},
+                               d={s="Frog" => l=[s="Henry", l=[i=1, i=5]]},
+                               d={s="Cat" => d={s="age" => i=5, s="name" => s="Molyneux"}}]);
+        for proto in 0..6 {
+            let options = SerOptions::new().proto(proto);
+            let vec = to_vec_with_options(&animals, options.clone()).unwrap();
+            assert_eq!(from_slice::<Vec<Animal>>(&vec).unwrap(), animals);
+            let vec = to_vec_with_options(&animals, options.enum_style(EnumStyle::Dict)).unwrap();
+            assert_eq!(value_from_slice(&vec).unwrap(), target);
+            assert_eq!(from_slice::<Vec<Animal>>(&vec).unwrap(), animals);
+        }
+    }
+
     #[test]
     fn decode_types() {
         test_decode_ok(pyobj!(n=None), ());
@@ -178,6 +198,18 @@ mod struct_tests {
                        Inner { a: (), b: 32, c: vec!["doc".into()] });
     }
 
+    #[test]
+    fn decode_object() {
+        // Class instances are decoded from their state, the instance dict.
+        let object = Value::Object(Box::new(Object {
+            class: ("module".into(), "Inner".into()),
+            args: vec![],
+            kwargs: BTreeMap::new(),
+            state: Some(pyobj!(d={s="a" => n=None, s="b" => i=32, s="c" => l=[s="doc"]})),
+        }));
+        test_decode_ok(object, Inner { a: (), b: 32, c: vec!["doc".into()] });
+    }
+
     #[test]
     fn decode_enum() {
         test_decode_ok(pyobj!(t=(s="Dog")),
@@ -196,15 +228,25 @@ mod struct_tests {
 
 mod value_tests {
     use std::fs::File;
+    use std::io::Read;
     use std::collections::{BTreeMap, BTreeSet};
     use std::iter::FromIterator;
     use num_bigint::BigInt;
     use super::rand::{Rng, thread_rng};
     use super::quickcheck::{QuickCheck, StdGen};
     use super::serde_json;
-    use {value_from_reader, value_to_vec, value_from_slice, to_vec, from_slice};
-    use {Value, HashableValue};
+    use serde::bytes::ByteBuf;
+    use {value_from_reader, value_to_vec, value_from_slice, to_vec, from_slice, from_reader,
+         graph_from_reader, graph_from_slice, graph_to_vec, value_to_vec_with_options,
+         to_vec_with_options, SerOptions, value_from_reader_with_buffers, from_reader_with_buffers,
+         value_to_vec_with_buffers, to_vec_with_buffers, value_from_slice_with_options,
+         from_slice_with_options, value_from_reader_with_options, DeOptions, ListWriter};
+    use {Value, HashableValue, Object, DefaultDict, Deque, Serializer, Deserializer,
+         GlobalResolver, GraphValue, StreamDeserializer, PersistentLoad, PersistentId};
     use error::{Error, ErrorCode};
+    use datetime::{Date, Time, DateTime, TimeDelta, TimeZone};
+    use numbers::{Decimal, Fraction, Complex};
+    use {disasm, ser};
 
     // combinations of (python major, pickle proto) to test
     const TEST_CASES: &'static [(u32, u32)] = &[
@@ -252,6 +294,25 @@ mod value_tests {
         assert_eq!(dict, tripped);
     }
 
+    #[test]
+    fn roundtrip_proto4() {
+        let dict = get_test_object();
+        for &memoize in &[false, true] {
+            let options = SerOptions::new().proto(4).memoize(memoize);
+            let vec = value_to_vec_with_options(&dict, options).unwrap();
+            assert_eq!(&vec[..3], b"\x80\x04\x95");
+            assert_eq!(value_from_slice(&vec).unwrap(), dict);
+        }
+        // Big payloads are written outside of frames, and so are frames too
+        // small to be worth it.
+        let big = Value::List(vec![Value::Bytes(vec![b'x'; 100000]), pyobj!(ss=(i=1, i=2))]);
+        let vec = value_to_vec_with_options(&big, SerOptions::new().proto(4)).unwrap();
+        assert_eq!(&vec[..9], b"\x80\x04](B\xa0\x86\x01\x00");
+        assert_eq!(&vec[100009..], b"\x95\x0f\x00\x00\x00\x00\x00\x00\x00\
+                                     \x8f(J\x01\x00\x00\x00J\x02\x00\x00\x00\x90e.");
+        assert_eq!(value_from_slice(&vec).unwrap(), big);
+    }
+
     #[test]
     fn recursive() {
         for proto in &[0, 1, 2, 3, 4] {
@@ -263,6 +324,1180 @@ mod value_tests {
         }
     }
 
+    #[test]
+    fn recursive_graph() {
+        for proto in &[0, 1, 2, 3, 4] {
+            let file = File::open(format!("test/data/test_recursive_proto{}.pickle", proto)).unwrap();
+            let graph = graph_from_reader(file).unwrap();
+            // rec_list = [([rec_list],)]
+            let outer = match *graph.root() {
+                GraphValue::Ref(id) => id,
+                ref other => panic!("unexpected root: {:?}", other),
+            };
+            let tuple = match *graph.node(outer) {
+                GraphValue::List(ref items) if items.len() == 1 => graph.get(&items[0]),
+                ref other => panic!("unexpected list: {:?}", other),
+            };
+            let inner = match *tuple {
+                GraphValue::Tuple(ref items) if items.len() == 1 => graph.get(&items[0]),
+                ref other => panic!("unexpected tuple: {:?}", other),
+            };
+            assert_eq!(*inner, GraphValue::List(vec![GraphValue::Ref(outer)]));
+            match graph.to_value() {
+                Err(Error::Syntax(ErrorCode::Recursive)) => { }
+                _ => assert!(false, "wrong/no error returned for recursive structure")
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_graph() {
+        for proto in &[0, 1, 2, 3, 4] {
+            let file = File::open(format!("test/data/test_recursive_proto{}.pickle", proto)).unwrap();
+            let graph = graph_from_reader(file).unwrap();
+            for out_proto in &[2, 3, 4] {
+                let vec = graph_to_vec(&graph, SerOptions::new().proto(*out_proto)).unwrap();
+                assert_eq!(graph_from_slice(&vec).unwrap(), graph);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_graph() {
+        for proto in &[0, 1, 2, 3, 4] {
+            let file = File::open(format!("test/data/test_objects_proto{}.pickle", proto)).unwrap();
+            let graph = graph_from_reader(file).unwrap();
+            // Both list items must refer to the same instance.
+            match *graph.get(graph.root()) {
+                GraphValue::List(ref items) => {
+                    assert_eq!(items.len(), 2);
+                    assert!(match items[0] { GraphValue::Ref(_) => true, _ => false });
+                    assert_eq!(items[0], items[1]);
+                }
+                ref other => panic!("unexpected root: {:?}", other),
+            }
+            let point = get_test_point();
+            assert_eq!(graph.to_value().unwrap(), Value::List(vec![point.clone(), point]));
+        }
+    }
+
+    fn get_test_point() -> Value {
+        // Reproduces the Point instance from test/data/generate.py.
+        Value::Object(Box::new(Object {
+            class: ("__main__".into(), "Point".into()),
+            args: vec![],
+            kwargs: BTreeMap::new(),
+            state: Some(pyobj!(d={s="x" => i=1, s="y" => s="two"})),
+        }))
+    }
+
+    #[test]
+    fn unpickle_objects() {
+        let point = get_test_point();
+        for proto in &[0, 1, 2, 3, 4] {
+            let file = File::open(format!("test/data/test_objects_proto{}.pickle", proto)).unwrap();
+            let unpickled = value_from_reader(file).unwrap();
+            assert_eq!(unpickled, Value::List(vec![point.clone(), point.clone()]));
+        }
+        // Objects are deserialized through serde as their state.
+        let file = File::open("test/data/test_objects_proto2.pickle").unwrap();
+        let decoded: serde_json::Value = from_reader(file).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(
+            r#"[{"x": 1, "y": "two"}, {"x": 1, "y": "two"}]"#).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn unpickle_object_opcodes() {
+        let make = |args, kwargs: Vec<(HashableValue, Value)>| Value::Object(Box::new(Object {
+            class: ("mod".into(), "Cls".into()),
+            args: args,
+            kwargs: BTreeMap::from_iter(kwargs),
+            state: Some(pyobj!(d={s="a" => i=1})),
+        }));
+        // INST and OBJ, written by Python 2 for old-style classes.
+        let inst = b"(K\x01imod\nCls\n}X\x01\x00\x00\x00aK\x01sb.";
+        assert_eq!(value_from_slice(inst).unwrap(), make(vec![pyobj!(i=1)], vec![]));
+        let obj = b"(cmod\nCls\nK\x01o}X\x01\x00\x00\x00aK\x01sb.";
+        assert_eq!(value_from_slice(obj).unwrap(), make(vec![pyobj!(i=1)], vec![]));
+        // NEWOBJ_EX, written by protocol 4 for __getnewargs_ex__.
+        let newobj_ex = b"\x80\x04cmod\nCls\nK\x01\x85}X\x01\x00\x00\x00bK\x02s\x92\
+                          }X\x01\x00\x00\x00aK\x01sb.";
+        assert_eq!(value_from_slice(newobj_ex).unwrap(),
+                   make(vec![pyobj!(i=1)], vec![(hpyobj!(s="b"), pyobj!(i=2))]));
+        // BUILD needs an object to work on.
+        assert!(value_from_slice(b"]}b.").is_err());
+        // Attributes of an OrderedDict make it an object.
+        let dict = b"ccollections\nOrderedDict\n)R(K\x01K\x02u}X\x01\x00\x00\x00aK\x01sb.";
+        assert_eq!(value_from_slice(dict).unwrap(), Value::Object(Box::new(Object {
+            class: ("collections".into(), "OrderedDict".into()),
+            args: vec![Value::OrderedDict(vec![(hpyobj!(i=1), pyobj!(i=2))])],
+            kwargs: BTreeMap::new(),
+            state: Some(pyobj!(d={s="a" => i=1})),
+        })));
+    }
+
+    #[test]
+    fn roundtrip_objects() {
+        let point = get_test_point();
+        let vec: Vec<_> = value_to_vec(&point, true).unwrap();
+        assert_eq!(value_from_slice(&vec).unwrap(), point);
+        let mut kwargs = BTreeMap::new();
+        kwargs.insert(hpyobj!(s="kw"), pyobj!(l=[i=1]));
+        let object = Value::Object(Box::new(Object {
+            class: ("mod".into(), "Cls".into()),
+            args: vec![pyobj!(i=1), pyobj!(s="arg")],
+            kwargs: kwargs,
+            state: None,
+        }));
+        // NEWOBJ_EX is only available with protocol 4.
+        for proto in 0..6 {
+            let data = value_to_vec_with_options(&object, SerOptions::new().proto(proto)).unwrap();
+            let names: Vec<_> = disasm::disassemble(&data).unwrap().into_iter()
+                                                          .map(|i| i.name).collect();
+            assert_eq!(names.contains(&"NEWOBJ_EX"), proto >= 4);
+            assert_eq!(names.contains(&"REDUCE"), proto < 4);
+            assert_eq!(value_from_slice(&data).unwrap(), object);
+        }
+    }
+
+    struct TestResolver;
+
+    impl GlobalResolver for TestResolver {
+        fn handles(&self, module: &str, name: &str) -> bool {
+            match (module, name) {
+                ("operator", "add") | ("__main__", "Registry") | ("__main__", "Echo") => true,
+                _ => false,
+            }
+        }
+
+        fn reduce(&self, _: &str, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+            match (name, &args[..]) {
+                ("add", &[Value::I64(a), Value::I64(b)]) => Ok(Value::I64(a + b)),
+                ("Registry", &[]) => Ok(Value::Dict(BTreeMap::new())),
+                ("Echo", _) => Ok(Value::Tuple(args.clone())),
+                _ => Err(Error::Syntax(ErrorCode::InvalidValue("args".into()))),
+            }
+        }
+    }
+
+    #[test]
+    fn global_resolver() {
+        fn decode(data: &[u8]) -> Result<Value, Error> {
+            let mut de = Deserializer::new(data, false);
+            de.add_resolver(TestResolver);
+            de.decode_value()
+        }
+        let add = b"coperator\nadd\n(K\x01K\x02tR.";
+        assert_eq!(decode(add).unwrap(), pyobj!(i=3));
+        // Arguments given by memo references are resolved as well.
+        let add = b"coperator\nadd\nK\x05\x94h\x00\x86R.";
+        assert_eq!(decode(add).unwrap(), pyobj!(i=10));
+        let registry = b"c__main__\nRegistry\n)R(X\x01\x00\x00\x00aK\x01u.";
+        assert_eq!(decode(registry).unwrap(), pyobj!(d={s="a" => i=1}));
+        // Nested globals are given as names.
+        let echo = b"c__main__\nEcho\n(]cbuiltins\nint\naK\x01\x86tR.";
+        assert_eq!(decode(echo).unwrap(), pyobj!(t=(t=(l=[s="builtins.int"], i=1))));
+        match decode(b"coperator\nadd\n(K\x01tR.") {
+            Err(Error::Eval(ErrorCode::InvalidValue(_), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Globals that are not handled by a resolver still fail.
+        match decode(b"coperator\nsub\n(K\x01K\x02tR.") {
+            Err(Error::Eval(ErrorCode::UnsupportedGlobal(..), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // Stores bytestrings out of line, and refers to them by their length.
+    struct BlobStore;
+
+    impl PersistentLoad for BlobStore {
+        fn persistent_load(&self, pid: Value) -> Result<Value, Error> {
+            let len = match pid {
+                Value::String(ref s) if s.starts_with("blob") => s[4..].parse().ok(),
+                Value::Tuple(ref t) if t.len() == 2 && t[0] == pyobj!(s="echo") => {
+                    return Ok(t[1].clone());
+                }
+                Value::Tuple(ref t) if t.len() == 2 && t[0] == pyobj!(s="blob") => match t[1] {
+                    Value::I64(len) => Some(len as usize),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match len {
+                Some(len) => Ok(Value::Bytes(b"abcdefg"[..len].to_vec())),
+                None => Err(Error::Syntax(ErrorCode::InvalidValue("pid".into()))),
+            }
+        }
+    }
+
+    impl PersistentId for BlobStore {
+        fn persistent_id(&self, value: &Value) -> Option<Value> {
+            match *value {
+                Value::Bytes(ref b) if b.len() > 1 => Some(pyobj!(t=(s="blob", i=(b.len() as i64)))),
+                Value::String(ref s) if s == "text" => Some(pyobj!(s="blob4")),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn persistent_ids() {
+        fn decode(data: &[u8]) -> Result<Value, Error> {
+            let mut de = Deserializer::new(data, false);
+            de.set_persistent_load(BlobStore);
+            de.decode_value()
+        }
+        let expected = pyobj!(l=[i=1, bb=b"abc", d={s="k" => bb=b"abcd"}]);
+        // Written by Python with a persistent_id method.
+        let proto0 = b"(lp0\nI1\naPblob3\na(dp1\nVk\np2\nPblob4\nsa.";
+        assert_eq!(decode(proto0).unwrap(), expected);
+        let proto2 = b"\x80\x02]q\x00(K\x01X\x04\x00\x00\x00blobq\x01K\x03\x86q\x02Q}q\x03\
+                       X\x01\x00\x00\x00kq\x04h\x01K\x04\x86q\x05Qse.";
+        assert_eq!(decode(proto2).unwrap(), expected);
+        // Globals anywhere in an ID are given as names.
+        let nested = b"\x80\x02X\x04\x00\x00\x00echo]cbuiltins\nint\na\x85\x86Q.";
+        assert_eq!(decode(nested).unwrap(), pyobj!(t=(l=[s="builtins.int"])));
+        match value_from_slice(proto2) {
+            Err(Error::Eval(ErrorCode::Unsupported('Q'), 25)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match decode(b"Pfoo\n.") {
+            Err(Error::Eval(ErrorCode::InvalidValue(_), 5)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Writing with a hook.
+        for proto in 0..6 {
+            let mut ser = Serializer::with_options(Vec::new(), SerOptions::new().proto(proto));
+            ser.set_persistent_id(BlobStore);
+            let value = if proto == 0 {
+                pyobj!(l=[i=1, s="text", bb=b"a"])
+            } else {
+                pyobj!(l=[i=1, bb=b"xyz", d={s="k" => bb=b"wxyz"}, s="text"])
+            };
+            ser.encode_value(&value).unwrap();
+            let data = ser.into_inner();
+            let expected = if proto == 0 {
+                pyobj!(l=[i=1, bb=b"abcd", bb=b"a"])
+            } else {
+                pyobj!(l=[i=1, bb=b"abc", d={s="k" => bb=b"abcd"}, bb=b"abcd"])
+            };
+            assert_eq!(decode(&data).unwrap(), expected);
+        }
+        let mut ser = Serializer::with_options(Vec::new(), SerOptions::new().proto(0));
+        ser.set_persistent_id(BlobStore);
+        assert!(ser.encode_value(&pyobj!(bb=b"abc")).is_err());
+    }
+
+    #[test]
+    fn out_of_band_buffers() {
+        // Written by Python for [bytearray(b'abc'), PickleBuffer(b'xyz'),
+        // PickleBuffer(bytearray(b'123'))] with a buffer_callback.
+        let data = b"\x80\x05\x95\x15\x00\x00\x00\x00\x00\x00\x00]\x94(\x96\x03\x00\x00\x00\
+                     \x00\x00\x00\x00abc\x94\x97\x98\x97e.";
+        let buffers = vec![b"xyz".to_vec(), b"123".to_vec()];
+        assert_eq!(value_from_reader_with_buffers(&data[..], buffers.clone()).unwrap(),
+                   Value::List(vec![Value::ByteArray(b"abc".to_vec()), pyobj!(bb=b"xyz"),
+                                    Value::ByteArray(b"123".to_vec())]));
+        match value_from_slice(data) {
+            Err(Error::Eval(ErrorCode::MissingBuffer, _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Bytestrings are written out-of-band, except for hashable ones.
+        let value = pyobj!(l=[bb=b"xyz", d={bb=b"key" => bb=b"123"}]);
+        let (vec, written) = value_to_vec_with_buffers(&value, SerOptions::new().proto(5)).unwrap();
+        assert_eq!(written, buffers);
+        assert_eq!(value_from_reader_with_buffers(&vec[..], written).unwrap(), value);
+        // Bytearrays are written as writable buffers.
+        let value = Value::List(vec![Value::ByteArray(b"123".to_vec()), pyobj!(bb=b"xyz")]);
+        let (vec, written) = value_to_vec_with_buffers(&value, SerOptions::new().proto(5)).unwrap();
+        assert_eq!(vec, &b"\x80\x05\x95\x07\x00\x00\x00\x00\x00\x00\x00](\x97\x97\x98e."[..]);
+        assert_eq!(value_from_reader_with_buffers(&vec[..], written).unwrap(), value);
+        let bytes = (1, ByteBuf::from(b"xyz".to_vec()));
+        let (vec, written) = to_vec_with_buffers(&bytes, SerOptions::new().proto(5)).unwrap();
+        assert_eq!(written, vec![b"xyz".to_vec()]);
+        assert_eq!(from_reader_with_buffers::<_, (i32, ByteBuf)>(&vec[..], written).unwrap(), bytes);
+        match value_to_vec_with_buffers(&value, SerOptions::new().proto(4)) {
+            Err(Error::Syntax(ErrorCode::UnsupportedProtocol(4))) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bytearrays() {
+        let value = Value::ByteArray(b"ab\xff".to_vec());
+        // As written by Python 3 with protocols 0, 2, 3 and 5, and by Python 2.
+        let written: [&[u8]; 5] = [
+            b"c__builtin__\nbytearray\n(c_codecs\nencode\n(Vab\xff\nVlatin1\ntRtR.",
+            b"\x80\x02c__builtin__\nbytearray\nc_codecs\nencode\nX\x04\x00\x00\x00ab\xc3\xbf\
+              X\x06\x00\x00\x00latin1\x86R\x85R.",
+            b"\x80\x03cbuiltins\nbytearray\nC\x03ab\xff\x85R.",
+            b"\x80\x05\x95\r\x00\x00\x00\x00\x00\x00\x00\x96\x03\x00\x00\x00\x00\x00\x00\x00\
+              ab\xff.",
+            b"\x80\x02c__builtin__\nbytearray\nq\x00X\x04\x00\x00\x00ab\xc3\xbfq\x01U\x07latin-1\
+              q\x02\x86q\x03Rq\x04.",
+        ];
+        for data in &written {
+            assert_eq!(value_from_slice(data).unwrap(), value);
+        }
+        assert_eq!(value_from_slice(b"\x80\x03cbuiltins\nbytearray\n)R.").unwrap(),
+                   Value::ByteArray(vec![]));
+        // Without memoization, the output is the same as Python's.
+        for (&proto, data) in [0, 2, 3, 5].iter().zip(&written) {
+            let options = SerOptions::new().proto(proto).memoize(false);
+            assert_eq!(value_to_vec_with_options(&value, options).unwrap(), *data);
+        }
+        let list = Value::List(vec![value.clone(), Value::ByteArray(vec![]), pyobj!(bb=b"ab")]);
+        for proto in 0..6 {
+            let data = value_to_vec_with_options(&list, SerOptions::new().proto(proto)).unwrap();
+            assert_eq!(value_from_slice(&data).unwrap(), list);
+        }
+        match value.clone().into_hashable() {
+            Err(Error::Syntax(ErrorCode::ValueNotHashable)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(from_slice::<ByteBuf>(&value_to_vec(&value, true).unwrap()).unwrap(),
+                   ByteBuf::from(b"ab\xff".to_vec()));
+    }
+
+    #[cfg(feature = "numpy")]
+    #[test]
+    fn numpy_arrays() {
+        use numpy::{NdArray, DType};
+        // np.array([[1, 2, 3], [4, 5, 6]], '<i4') with protocols 2 and 3.
+        let written: [&[u8]; 2] = [
+            b"\x80\x02cnumpy.core.multiarray\n_reconstruct\nq\x00cnumpy\nndarray\nq\x01K\x00\x85q\
+              \x02c_codecs\nencode\nq\x03X\x01\x00\x00\x00bq\x04X\x06\x00\x00\x00latin1q\x05\x86q\
+              \x06Rq\x07\x87q\x08Rq\t(K\x01K\x02K\x03\x86q\ncnumpy\ndtype\nq\x0bX\x02\x00\x00\x00i4\
+              q\x0c\x89\x88\x87q\rRq\x0e(K\x03X\x01\x00\x00\x00<q\x0fNNNJ\xff\xff\xff\xffJ\xff\xff\
+              \xff\xffK\x00tq\x10b\x89h\x03X\x18\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x03\
+              \x00\x00\x00\x04\x00\x00\x00\x05\x00\x00\x00\x06\x00\x00\x00q\x11h\x05\x86q\x12Rq\
+              \x13tq\x14b.",
+            b"\x80\x03cnumpy.core.multiarray\n_reconstruct\nq\x00cnumpy\nndarray\nq\x01K\x00\x85q\
+              \x02C\x01bq\x03\x87q\x04Rq\x05(K\x01K\x02K\x03\x86q\x06cnumpy\ndtype\nq\x07X\x02\x00\
+              \x00\x00i4q\x08\x89\x88\x87q\tRq\n(K\x03X\x01\x00\x00\x00<q\x0bNNNJ\xff\xff\xff\xffJ\
+              \xff\xff\xff\xffK\x00tq\x0cb\x89C\x18\x01\x00\x00\x00\x02\x00\x00\x00\x03\x00\x00\x00\
+              \x04\x00\x00\x00\x05\x00\x00\x00\x06\x00\x00\x00q\rtq\x0eb.",
+        ];
+        let options = DeOptions::new().safe(true);
+        for data in &written {
+            let value = value_from_slice_with_options(data, options.clone()).unwrap();
+            let array = NdArray::from_value(&value).unwrap();
+            assert_eq!(array.dtype, DType::from_descr("<i4").unwrap());
+            assert_eq!(array.shape, vec![2, 3]);
+            assert_eq!(array.to_vec::<i32>().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+            assert!(array.to_vec::<f64>().is_err());
+        }
+        // np.array([1.5, -2.0]) pickled out-of-band with protocol 5.
+        let data = b"\x80\x05\x95\x83\x00\x00\x00\x00\x00\x00\x00\x8c\x12numpy.core.numeric\x94\
+                     \x8c\x0b_frombuffer\x94\x93\x94(\x96\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                     \x00\x00\x00\x00\xf8?\x00\x00\x00\x00\x00\x00\x00\xc0\x94\x8c\x05numpy\x94\x8c\
+                     \x05dtype\x94\x93\x94\x8c\x02f8\x94\x89\x88\x87\x94R\x94(K\x03\x8c\x01<\x94NNN\
+                     J\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00t\x94bK\x02\x85\x94\x8c\x01C\x94t\x94R\
+                     \x94.";
+        let value = value_from_slice_with_options(data, options.clone()).unwrap();
+        let array = NdArray::from_value(&value).unwrap();
+        assert_eq!(array.to_vec::<f64>().unwrap(), vec![1.5, -2.0]);
+        // Fortran order and big-endian data are converted.
+        let mut array = NdArray::from_vec(vec![2, 3], &[1i16, 4, 2, 5, 3, 6]).unwrap();
+        array.fortran_order = true;
+        assert_eq!(array.to_vec::<i16>().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        array.dtype.big_endian = true;
+        assert_eq!(array.to_vec::<i16>().unwrap()[..2], [256, 512]);
+        assert_eq!(array.dtype.descr(), ">i2");
+        assert!(NdArray::from_vec(vec![2, 2], &[1u8, 2, 3]).is_err());
+        // Shapes whose size overflows are rejected.
+        let huge = 1 << (usize::max_value().count_ones() / 2);
+        assert!(NdArray::from_vec(vec![huge, huge], &[0u8; 0]).is_err());
+        let mut empty = NdArray::from_vec(vec![0], &[0u8; 0]).unwrap();
+        empty.shape = vec![huge, huge, 0];
+        assert_eq!(empty.len(), None);
+        assert!(NdArray::from_value(&empty.to_value()).is_err());
+        empty.fortran_order = true;
+        assert!(empty.to_vec::<u8>().is_err());
+        let complex = NdArray::from_vec(vec![1], &[Complex::new(1., -1.)]).unwrap();
+        assert_eq!(complex.dtype.descr(), "<c16");
+        assert_eq!(DType::from_descr("U3").unwrap().itemsize, 12);
+        for array in &[array, complex, NdArray::from_vec(vec![3], &[true, false, true]).unwrap()] {
+            for proto in 0..6 {
+                let data = value_to_vec_with_options(&array.to_value(),
+                                                     SerOptions::new().proto(proto)).unwrap();
+                let value = value_from_slice_with_options(&data, options.clone()).unwrap();
+                assert_eq!(NdArray::from_value(&value).unwrap(), *array);
+            }
+        }
+    }
+
+    #[cfg(feature = "pandas")]
+    #[test]
+    fn pandas_frames() {
+        use pandas::{self, DataFrame, Series, Column};
+        fn open(name: &str) -> File {
+            File::open(format!("test/data/pandas_{}.pickle", name)).unwrap()
+        }
+        fn decode(name: &str) -> Value {
+            pandas::value_from_reader(open(name), DeOptions::new().safe(true)).unwrap()
+        }
+        // Laid out like pandas 2 pickles with protocol 5, and with the
+        // block manager state of pandas 1.x with protocol 2.
+        let frame = DataFrame {
+            index: Column::Int(vec![0, 1, 2]),
+            columns: vec![
+                (pyobj!(s="a"), Column::Int(vec![1, 2, 3])),
+                (pyobj!(s="b"), Column::Float(vec![0.5, 1.5, -1.0])),
+                (pyobj!(s="c"), Column::Object(vec![pyobj!(s="x"), pyobj!(s="y"),
+                                                    pyobj!(n=None)])),
+                (pyobj!(s="d"), Column::DateTime(vec![Some(0), Some(86_400_000_000_000), None])),
+                (pyobj!(s="e"), Column::Float(vec![2.0, 3.0, 4.0])),
+            ],
+        };
+        for name in &["dataframe", "dataframe_old"] {
+            assert_eq!(DataFrame::from_reader(open(name), DeOptions::new()).unwrap(), frame);
+        }
+        // Without the pandas resolver, the calls are not supported.
+        match value_from_reader(open("dataframe")) {
+            Err(Error::Eval(ErrorCode::UnsupportedGlobal(..), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let series = Series::from_reader(open("series"), DeOptions::new().safe(true)).unwrap();
+        assert_eq!(series, Series {
+            name: pyobj!(s="s"),
+            index: Column::DateTime(vec![Some(0), Some(60_000_000_000)]),
+            values: Column::Float(vec![1.5, 2.5]),
+        });
+        assert!(Series::from_value(&decode("dataframe")).is_err());
+        assert_eq!(frame.columns[0].1.clone().into_values(),
+                   vec![pyobj!(i=1), pyobj!(i=2), pyobj!(i=3)]);
+        // Range indexes are checked against the number of rows.
+        fn set_range(value: &mut Value, start: i64, stop: i64) {
+            match *value {
+                Value::Object(ref mut obj) => {
+                    if obj.kwargs.contains_key(&hpyobj!(s="stop")) {
+                        obj.kwargs.insert(hpyobj!(s="start"), Value::I64(start));
+                        obj.kwargs.insert(hpyobj!(s="stop"), Value::I64(stop));
+                    }
+                    obj.args.iter_mut().chain(obj.kwargs.values_mut()).chain(obj.state.as_mut())
+                                       .for_each(|item| set_range(item, start, stop));
+                }
+                Value::Dict(ref mut items) => {
+                    items.values_mut().for_each(|item| set_range(item, start, stop));
+                }
+                Value::List(ref mut items) | Value::Tuple(ref mut items) => {
+                    items.iter_mut().for_each(|item| set_range(item, start, stop));
+                }
+                _ => {}
+            }
+        }
+        let check = |start, stop| {
+            let mut value = decode("dataframe");
+            set_range(&mut value, start, stop);
+            DataFrame::from_value(&value).map(|frame| frame.index)
+        };
+        assert_eq!(check(5, 8).unwrap(), Column::Int(vec![5, 6, 7]));
+        assert!(check(0, i64::max_value()).is_err());
+        assert!(check(i64::min_value(), i64::max_value()).is_err());
+        assert!(check(i64::max_value() - 2, i64::max_value()).is_err());
+    }
+
+    #[cfg(feature = "torch")]
+    #[test]
+    fn torch_checkpoint() {
+        use std::io::Write;
+        use torch::{self, DType, Tensor};
+        fn floats(data: &[f32]) -> Vec<u8> {
+            data.iter().flat_map(|f| f.to_bits().to_le_bytes().to_vec()).collect()
+        }
+        // Laid out like the output of torch.save, with a state dict and
+        // views of the same storage.
+        let tensors = torch::load_file("test/data/torch_checkpoint.pt").unwrap();
+        assert_eq!(tensors.keys().collect::<Vec<_>>(),
+                   vec!["extra.0", "model.fc.bias", "model.fc.weight", "model.fc.weight_t",
+                        "model.steps"]);
+        assert_eq!(tensors["model.fc.weight"], Tensor {
+            dtype: DType::Float32,
+            shape: vec![2, 3],
+            strides: vec![3, 1],
+            data: floats(&[1., 2., 3., 4., 5., 6.]),
+        });
+        assert_eq!(tensors["model.fc.bias"].data, floats(&[0.5, -0.5]));
+        assert_eq!(tensors["model.fc.weight_t"].strides, vec![1, 3]);
+        assert_eq!(tensors["model.fc.weight_t"].data, tensors["model.fc.weight"].data);
+        assert_eq!(tensors["extra.0"].data, floats(&[3.]));
+        assert_eq!(tensors["model.steps"], Tensor {
+            dtype: DType::Int64,
+            shape: vec![],
+            strides: vec![],
+            data: vec![0xe8, 3, 0, 0, 0, 0, 0, 0],
+        });
+        assert!(torch::load(::std::io::Cursor::new(b"not a zip archive")).is_err());
+        // A tensor object that wasn't rebuilt from a storage.
+        let mut zip = ::zip::ZipWriter::new(::std::io::Cursor::new(Vec::new()));
+        let options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        zip.start_file("archive/data.pkl", options).unwrap();
+        zip.write_all(b"\x80\x02ctorch\nTensor\n)\x81.").unwrap();
+        let archive = zip.finish().unwrap().into_inner();
+        assert!(torch::load(::std::io::Cursor::new(archive)).is_err());
+    }
+
+    #[test]
+    fn memoize() {
+        let inner = pyobj!(l=[s="shared", d={s="x" => i=1}, t=(i=1, i=2)]);
+        let value = Value::List(vec![inner.clone(); 100]);
+        let plain = value_to_vec(&value, true).unwrap();
+        let memoized = value_to_vec_with_options(&value, SerOptions::new().memoize(true)).unwrap();
+        assert!(memoized.len() < plain.len() / 10);
+        assert_eq!(value_from_slice(&memoized).unwrap(), value);
+        // The repeated list is the same object when decoded.
+        let graph = graph_from_slice(&memoized).unwrap();
+        match *graph.get(graph.root()) {
+            GraphValue::List(ref items) => assert!(items.iter().all(|item| *item == items[0])),
+            ref other => panic!("unexpected root: {:?}", other),
+        }
+        type Inner = (String, BTreeMap<String, i32>, (i32, i32));
+        let expected: Vec<Inner> = vec![("shared".into(), BTreeMap::from_iter(vec![
+            ("x".into(), 1)]), (1, 2)); 100];
+        assert_eq!(from_slice::<Vec<Inner>>(&memoized).unwrap(), expected);
+        assert_eq!(from_reader::<_, Vec<Inner>>(&memoized[..]).unwrap(), expected);
+        // Duplicated memo references.
+        let data = b"]q\x00K\x01a2\x86.";
+        assert_eq!(value_from_slice(data).unwrap(), pyobj!(t=(l=[i=1], l=[i=1])));
+        assert_eq!(from_slice::<(Vec<i32>, Vec<i32>)>(data).unwrap(), (vec![1], vec![1]));
+        // Strings are memoized when serializing other types as well.
+        let maps = vec![BTreeMap::from_iter(vec![("key".to_string(), 1)]); 10];
+        let vec = to_vec_with_options(&maps, SerOptions::new().proto(2).memoize(true)).unwrap();
+        assert_eq!(vec.windows(3).filter(|w| w == b"key").count(), 1);
+        assert_eq!(from_slice::<Vec<BTreeMap<String, i32>>>(&vec).unwrap(), maps);
+        match to_vec_with_options(&maps, SerOptions::new().proto(7)) {
+            Err(Error::Syntax(ErrorCode::UnsupportedProtocol(7))) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn optimize() {
+        // Compare with the output of pickletools.optimize.
+        let data = b"\x80\x02]q\x00(]q\x01X\x01\x00\x00\x00sq\x02a}q\x03X\x01\x00\x00\x00dq\x04Nsh\x01e.";
+        assert_eq!(ser::optimize(data).unwrap(),
+                   b"\x80\x02](]q\x00X\x01\x00\x00\x00sa}X\x01\x00\x00\x00dNsh\x00e.".to_vec());
+        let data = b"\x80\x04\x95\x16\x00\x00\x00\x00\x00\x00\x00]\x94(]\x94\x8c\x01s\x94a}\x94\
+                     \x8c\x01d\x94Nsh\x01e.";
+        assert_eq!(ser::optimize(data).unwrap(),
+                   b"\x80\x04\x95\x12\x00\x00\x00\x00\x00\x00\x00](]\x94\x8c\x01sa}\x8c\x01dNsh\x00e."
+                   .to_vec());
+        assert_eq!(ser::optimize_with_framing(data, false).unwrap(),
+                   b"\x80\x04](]\x94\x8c\x01sa}\x8c\x01dNsh\x00e.".to_vec());
+        // Shared values stay shared.
+        let inner = pyobj!(l=[s="shared", t=(i=1, i=2)]);
+        let value = Value::List(vec![inner.clone(); 10]);
+        for proto in 0..6 {
+            let options = SerOptions::new().proto(proto).memoize(true);
+            let memoized = value_to_vec_with_options(&value, options).unwrap();
+            let optimized = ser::optimize(&memoized).unwrap();
+            assert!(optimized.len() <= memoized.len());
+            assert_eq!(value_from_slice(&optimized).unwrap(), value);
+            let graph = graph_from_slice(&optimized).unwrap();
+            match *graph.get(graph.root()) {
+                GraphValue::List(ref items) => assert!(items.iter().all(|item| *item == items[0])),
+                ref other => panic!("unexpected root: {:?}", other),
+            }
+        }
+        match ser::optimize(b"\x80\x02h\x00.") {
+            Err(Error::Eval(ErrorCode::MissingMemo(0), 2)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn list_writer() {
+        for proto in 0..6 {
+            let options = SerOptions::new().proto(proto).batch_size(10);
+            let mut writer = ListWriter::new(Vec::new(), options.clone()).unwrap();
+            for i in 0..25 {
+                writer.push(&i).unwrap();
+            }
+            writer.push_value(&pyobj!(fs=(i=1))).unwrap();
+            let data = writer.finish().unwrap();
+            let mut items: Vec<_> = (0..25).map(Value::I64).collect();
+            items.push(pyobj!(fs=(i=1)));
+            assert_eq!(value_from_slice(&data).unwrap(), Value::List(items.clone()));
+            // The same as serializing the whole list at once.
+            let value = Value::List(items);
+            assert_eq!(data, value_to_vec_with_options(&value, options).unwrap());
+        }
+        let data = ListWriter::new(Vec::new(), SerOptions::new()).unwrap().finish().unwrap();
+        assert_eq!(value_from_slice(&data).unwrap(), Value::List(vec![]));
+        assert!(ListWriter::new(Vec::new(), SerOptions::new().memoize(true)).is_err());
+    }
+
+    #[test]
+    fn ser_options() {
+        let mut kwargs = BTreeMap::new();
+        kwargs.insert(hpyobj!(s="kw"), pyobj!(l=[i=1]));
+        let object = Value::Object(Box::new(Object {
+            class: ("mod".into(), "Cls".into()),
+            args: vec![pyobj!(i=1), pyobj!(s="arg")],
+            kwargs: kwargs,
+            state: None,
+        }));
+        let strings = pyobj!(l=[bb=b"'\\\n\x00\xff", s="'\\\n\x00\u{1a}\u{e4}\u{20ac}\u{1f600}"]);
+        let numbers = pyobj!(l=[i=(-1), i=255, i=65536, i=(-2147483648), i=9223372036854775807,
+                                f=(-0.5), f=1e100, b=True]);
+        for proto in 0..6 {
+            for &memoize in &[false, true] {
+                let options = SerOptions::new().proto(proto).memoize(memoize);
+                for value in &[get_test_object(), get_test_point(), object.clone(),
+                               strings.clone(), numbers.clone()] {
+                    let vec = value_to_vec_with_options(value, options.clone()).unwrap();
+                    assert_eq!(vec[0] == b'\x80', proto >= 2);
+                    assert_eq!(&value_from_slice(&vec).unwrap(), value);
+                }
+            }
+        }
+        // Protocol 0 is a text format.
+        let vec = value_to_vec_with_options(&pyobj!(l=[i=1, s="a", f=0.5]),
+                                            SerOptions::new().proto(0)).unwrap();
+        assert_eq!(vec, b"(lI1\naVa\naF0.5\na.");
+        // Framing can be turned off.
+        let options = SerOptions::new().proto(4).framing(false);
+        let vec = value_to_vec_with_options(&get_test_object(), options).unwrap();
+        assert_eq!(&vec[..3], b"\x80\x04}");
+        assert_eq!(value_from_slice(&vec).unwrap(), get_test_object());
+        // Items are added in batches of the given size.
+        let list = pyobj!(l=[i=1, i=2, i=3]);
+        let vec = value_to_vec_with_options(&list, SerOptions::new().proto(2).batch_size(2)).unwrap();
+        assert_eq!(vec, b"\x80\x02](J\x01\x00\x00\x00J\x02\x00\x00\x00e(J\x03\x00\x00\x00e.");
+        let vec = to_vec_with_options(&vec![1, 2, 3], SerOptions::new().batch_size(2)).unwrap();
+        assert_eq!(vec, b"\x80\x03](J\x01\x00\x00\x00J\x02\x00\x00\x00e(J\x03\x00\x00\x00e.");
+        // Strings can be written as Python 2 bytestrings.
+        let vec = to_vec_with_options(&"abc", SerOptions::new().proto(2).py2_strings(true)).unwrap();
+        assert_eq!(vec, b"\x80\x02U\x03abc.");
+        let vec = to_vec_with_options(&"abc", SerOptions::new().proto(3).py2_strings(true)).unwrap();
+        assert_eq!(vec, b"\x80\x03X\x03\x00\x00\x00abc.");
+    }
+
+    #[test]
+    fn limits() {
+        fn check(data: &[u8], options: DeOptions, code: ErrorCode) {
+            match value_from_slice_with_options(data, options.clone()) {
+                Err(Error::Eval(ref c, _)) if *c == code => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            match from_slice_with_options::<Value>(data, options) {
+                Err(Error::Eval(ref c, _)) if *c == code => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        // Huge length prefixes are rejected before anything is read.
+        check(b"\x80\x04\x8e\xff\xff\xff\xff\xff\xff\xff\x7f", DeOptions::new().max_string_len(1000),
+              ErrorCode::LengthLimitExceeded(0x7fff_ffff_ffff_ffff));
+        check(b"X\x05\x00\x00\x00abcde.", DeOptions::new().max_string_len(4),
+              ErrorCode::LengthLimitExceeded(5));
+        check(b"I12345\n.", DeOptions::new().max_string_len(4),
+              ErrorCode::LengthLimitExceeded(5));
+        // Nesting is limited both on the stack and within values.
+        check(b"((((N.", DeOptions::new().max_depth(3), ErrorCode::DepthLimitExceeded);
+        check(b"]]]]aaa.", DeOptions::new().max_depth(3), ErrorCode::DepthLimitExceeded);
+        // Deeply nested values are rejected while they are built.
+        let mut nested = b"\x80\x02N".to_vec();
+        nested.extend(vec![b'\x85'; 1000000]);
+        nested.push(b'.');
+        check(&nested, DeOptions::new().max_depth(100).max_stack_size(1000),
+              ErrorCode::DepthLimitExceeded);
+        // Without a limit, they are dropped without overflowing the stack.
+        nested.pop();
+        nested.push(b'\xff');
+        check(&nested, DeOptions::new(), ErrorCode::Unsupported('\u{ff}'));
+        check(b"NNNN(NN.", DeOptions::new().max_stack_size(5), ErrorCode::StackLimitExceeded);
+        check(b"Nq\x00q\x01q\x02.", DeOptions::new().max_memo_size(2),
+              ErrorCode::MemoLimitExceeded);
+        // Repeated references to memoized values would expand to 10**8 items.
+        let mut laughs = b"\x80\x02]q\x00".to_vec();
+        for i in 1..9 {
+            laughs.push(b'(');
+            for _ in 0..10 {
+                laughs.extend_from_slice(&[b'h', i - 1]);
+            }
+            laughs.extend_from_slice(&[b'l', b'q', i]);
+        }
+        laughs.push(b'.');
+        check(&laughs, DeOptions::new().max_alloc(1000000), ErrorCode::AllocationLimitExceeded);
+        // Everything else is not affected by the limits.
+        let options = DeOptions::new().max_depth(10).max_stack_size(100).max_memo_size(100)
+                                      .max_string_len(100).max_alloc(100000);
+        for &(major, proto) in TEST_CASES {
+            let file = File::open(format!("test/data/tests_py{}_proto{}.pickle", major, proto)).unwrap();
+            let mut data = Vec::new();
+            file.take(10000).read_to_end(&mut data).unwrap();
+            assert_eq!(value_from_slice_with_options(&data, options.clone()).unwrap(),
+                       get_test_object());
+        }
+    }
+
+    #[test]
+    fn safe_mode() {
+        let options = DeOptions::new().safe(true);
+        // Builtins known to the deserializer are allowed by default.
+        for &(major, proto) in TEST_CASES {
+            let file = File::open(format!("test/data/tests_py{}_proto{}.pickle", major, proto)).unwrap();
+            let mut data = Vec::new();
+            file.take(10000).read_to_end(&mut data).unwrap();
+            assert_eq!(value_from_slice_with_options(&data, options.clone()).unwrap(),
+                       get_test_object());
+        }
+        // Everything else must be explicitly allowed.
+        match value_from_slice_with_options(b"cos\nsystem\n(S'ls'\ntR.", options.clone()) {
+            Err(Error::Eval(ErrorCode::UnsupportedGlobal(ref m, ref g), _))
+                if m == b"os" && g == b"system" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let data = value_to_vec(&get_test_point(), false).unwrap();
+        match from_slice_with_options::<Value>(&data, options.clone()) {
+            Err(Error::Eval(ErrorCode::UnsupportedGlobal(ref m, ref g), _))
+                if m == b"__main__" && g == b"Point" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let options = options.allow_global("__main__", "Point");
+        assert_eq!(value_from_slice_with_options(&data, options.clone()).unwrap(),
+                   get_test_point());
+        // Resolvers can't bypass the allowlist.
+        let data = b"\x80\x02coperator\nadd\nK\x01K\x02\x86R.";
+        let mut de = Deserializer::with_options(&data[..], options);
+        de.add_resolver(TestResolver);
+        match de.decode_value() {
+            Err(Error::Eval(ErrorCode::UnsupportedGlobal(ref m, _), _)) if m == b"operator" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let point = get_test_point();
+        for &(code, opcode) in &[(0x12, "EXT1"), (0x1234, "EXT2"), (0x123456, "EXT4")] {
+            for proto in 0..6 {
+                let options = SerOptions::new().proto(proto).add_extension(code, "__main__", "Point");
+                let data = value_to_vec_with_options(&point, options).unwrap();
+                let names: Vec<_> = disasm::disassemble(&data).unwrap().into_iter()
+                                                              .map(|i| i.name).collect();
+                assert_eq!(names.contains(&opcode), proto >= 2);
+                let options = DeOptions::new().add_extension(code, "__main__", "Point");
+                assert_eq!(value_from_slice_with_options(&data, options).unwrap(), point);
+                if proto < 2 {
+                    continue;
+                }
+                match value_from_slice(&data) {
+                    Err(Error::Eval(ErrorCode::UnknownExtension(c), _)) if c == code => {}
+                    other => panic!("unexpected result: {:?}", other),
+                }
+                // Extensions are subject to the allowlist in safe mode.
+                let options = DeOptions::new().safe(true).add_extension(code, "__main__", "Point");
+                match value_from_slice_with_options(&data, options.clone()) {
+                    Err(Error::Eval(ErrorCode::UnsupportedGlobal(ref m, ref g), _))
+                        if m == b"__main__" && g == b"Point" => {}
+                    other => panic!("unexpected result: {:?}", other),
+                }
+                let options = options.allow_global("__main__", "Point");
+                assert_eq!(value_from_slice_with_options(&data, options).unwrap(), point);
+            }
+        }
+        // As written by Python after copyreg.add_extension('builtins', 'set', 1).
+        let options = DeOptions::new().add_extension(1, "builtins", "set");
+        assert_eq!(value_from_slice_with_options(b"\x80\x02\x82\x01]q\x00K\x05a\x85q\x01Rq\x02.",
+                                                 options).unwrap(),
+                   pyobj!(ss=(i=5)));
+        // Registering the same extension again is allowed.
+        DeOptions::new().add_extension(1, "builtins", "set").add_extension(1, "builtins", "set");
+        SerOptions::new().add_extension(1, "builtins", "set").add_extension(1, "builtins", "set");
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn extension_code_range() {
+        SerOptions::new().add_extension(0x8000_0000, "builtins", "set");
+    }
+
+    #[test]
+    #[should_panic(expected = "already registered")]
+    fn extension_code_conflict() {
+        DeOptions::new().add_extension(1, "builtins", "set").add_extension(1, "builtins", "list");
+    }
+
+    #[test]
+    #[should_panic(expected = "already registered")]
+    fn extension_global_conflict() {
+        SerOptions::new().add_extension(1, "builtins", "set").add_extension(2, "builtins", "set");
+    }
+
+    #[test]
+    fn numbers() {
+        let decimal = Value::Decimal(Decimal::new("1.10").unwrap());
+        let fraction = Value::Fraction(Fraction::new(BigInt::from(3), BigInt::from(4)).unwrap());
+        let complex = Value::Complex(Complex::new(1.0, 2.0));
+        let list = Value::List(vec![decimal.clone(), fraction.clone(), complex.clone()]);
+        // As written by Python 2 and 3.
+        for data in &[&b"(lp0\ncdecimal\nDecimal\np1\n(S'1.10'\np2\ntp3\nRp4\nacfractions\nFraction\n\
+                         p5\n(S'3/4'\np6\ntp7\nRp8\nac__builtin__\ncomplex\np9\n(F1.0\nF2.0\ntp10\n\
+                         Rp11\na."[..],
+                      &b"\x80\x03]q\x00(cdecimal\nDecimal\nq\x01X\x04\x00\x00\x001.10q\x02\x85q\x03\
+                         Rq\x04cfractions\nFraction\nq\x05K\x03K\x04\x86q\x06Rq\x07cbuiltins\ncomplex\n\
+                         q\x08G?\xf0\x00\x00\x00\x00\x00\x00G@\x00\x00\x00\x00\x00\x00\x00\x86q\tRq\ne."[..]] {
+            assert_eq!(value_from_slice(data).unwrap(), list);
+        }
+        // Without memoization, the output is the same as Python's.
+        let options = SerOptions::new().proto(3).memoize(false);
+        assert_eq!(value_to_vec_with_options(&list, options).unwrap(),
+                   &b"\x80\x03](cdecimal\nDecimal\nX\x04\x00\x00\x001.10\x85Rcfractions\nFraction\n\
+                      K\x03K\x04\x86Rcbuiltins\ncomplex\nG?\xf0\x00\x00\x00\x00\x00\x00G@\x00\x00\
+                      \x00\x00\x00\x00\x00\x86Re."[..]);
+        let big = Fraction::new(-(BigInt::from(1) << 70), BigInt::from(3)).unwrap();
+        for proto in 0..6 {
+            for value in &[&list, &Value::Fraction(big.clone()),
+                           &Value::Decimal(Decimal::new("-sNaN12").unwrap())] {
+                let data = value_to_vec_with_options(value, SerOptions::new().proto(proto)).unwrap();
+                assert_eq!(value_from_slice(&data).unwrap(), **value);
+            }
+        }
+
+        // Numbers of all types with the same value are the same key, like in
+        // Python.
+        let frac = |n: i64, d: i64| HashableValue::Fraction(Fraction::new(BigInt::from(n),
+                                                                          BigInt::from(d)).unwrap());
+        let dec = |s: &str| HashableValue::Decimal(Decimal::new(s).unwrap());
+        let set = BTreeSet::from_iter(vec![
+            HashableValue::I64(1), HashableValue::F64(1.0), dec("1.0"), frac(2, 2),
+            HashableValue::Complex(Complex::new(1.0, 0.0)), HashableValue::Bool(true)]);
+        assert_eq!(set.len(), 1);
+        assert_eq!(dec("0.5"), frac(1, 2));
+        assert_eq!(dec("0.5"), HashableValue::F64(0.5));
+        assert!(dec("0.1") != HashableValue::F64(0.1));
+        assert!(dec("-1E+1000000000") < HashableValue::I64(i64::min_value()));
+        assert!(dec("1E-1000000000") < frac(1, 1000000));
+        assert!(dec("-Infinity") < HashableValue::F64(-1e300));
+        assert!(HashableValue::Complex(Complex::new(1.0, -1.0)) < HashableValue::I64(1));
+        assert!(frac(-1, 3) < dec("-0.3333333"));
+
+        assert_eq!(from_slice::<String>(&value_to_vec(&decimal, true).unwrap()).unwrap(), "1.10");
+        assert_eq!(from_slice::<String>(&value_to_vec(&fraction, true).unwrap()).unwrap(), "3/4");
+        assert_eq!(from_slice::<(f64, f64)>(&value_to_vec(&complex, true).unwrap()).unwrap(),
+                   (1.0, 2.0));
+        match value_from_slice(b"\x80\x03cdecimal\nDecimal\nX\x03\x00\x00\x001.x\x85R.") {
+            Err(Error::Eval(ErrorCode::InvalidValue(_), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn datetimes() {
+        let mut datetime = DateTime::new(Date::new(2020, 1, 2).unwrap(),
+                                         Time::new(3, 4, 5, 678).unwrap());
+        datetime.time.tzinfo = Some(TimeZone::from_offset(3600).unwrap());
+        let datetime = Value::DateTime(datetime);
+        // As written by Python 3, with and without memoization.
+        for data in &[&b"\x80\x02cdatetime\ndatetime\nq\x00c_codecs\nencode\nq\x01X\x0c\x00\x00\x00\
+                         \x07\xc3\xa4\x01\x02\x03\x04\x05\x00\x02\xc2\xa6q\x02X\x06\x00\x00\x00\
+                         latin1q\x03\x86q\x04Rq\x05cdatetime\ntimezone\nq\x06cdatetime\ntimedelta\n\
+                         q\x07K\x00M\x10\x0eK\x00\x87q\x08Rq\t\x85q\nRq\x0b\x86q\x0cRq\r."[..],
+                      &b"\x80\x04\x95X\x00\x00\x00\x00\x00\x00\x00\x8c\x08datetime\x94\x8c\x08\
+                         datetime\x94\x93\x94C\n\x07\xe4\x01\x02\x03\x04\x05\x00\x02\xa6\x94h\x00\
+                         \x8c\x08timezone\x94\x93\x94h\x00\x8c\ttimedelta\x94\x93\x94K\x00M\x10\x0eK\
+                         \x00\x87\x94R\x94\x85\x94R\x94\x86\x94R\x94."[..]] {
+            assert_eq!(value_from_slice(data).unwrap(), datetime);
+        }
+        // Without memoization, the output is the same as Python's.
+        let options = SerOptions::new().proto(3).memoize(false);
+        assert_eq!(value_to_vec_with_options(&datetime, options).unwrap(),
+                   &b"\x80\x03cdatetime\ndatetime\nC\n\x07\xe4\x01\x02\x03\x04\x05\x00\x02\xa6\
+                      cdatetime\ntimezone\ncdatetime\ntimedelta\nK\x00M\x10\x0eK\x00\x87R\x85R\
+                      \x86R."[..]);
+        let date = Value::Date(Date::new(2020, 1, 2).unwrap());
+        let options = SerOptions::new().proto(0).memoize(false);
+        assert_eq!(value_to_vec_with_options(&date, options).unwrap(),
+                   &b"cdatetime\ndate\n(c_codecs\nencode\n(V\x07\xe4\x01\x02\nVlatin1\ntRtR."[..]);
+        let delta = Value::TimeDelta(TimeDelta::new(-1, 2, 300000).unwrap());
+        let options = SerOptions::new().proto(2).memoize(false);
+        assert_eq!(value_to_vec_with_options(&delta, options).unwrap(),
+                   &b"\x80\x02cdatetime\ntimedelta\nJ\xff\xff\xff\xffK\x02J\xe0\x93\x04\x00\x87R."[..]);
+        // As written by Python 2.
+        assert_eq!(value_from_slice(b"cdatetime\ndate\np0\n(S'\\x07\\xe4\\x01\\x02'\np1\ntp2\nRp3\n.")
+                   .unwrap(), date);
+
+        // The fold is only kept by protocols above 3.
+        let mut time = Time::new(1, 30, 0, 0).unwrap();
+        time.fold = true;
+        for proto in 0..6 {
+            for value in &[&datetime, &date, &delta, &Value::Time(time.clone())] {
+                let data = value_to_vec_with_options(value, SerOptions::new().proto(proto)).unwrap();
+                let result = value_from_slice(&data).unwrap();
+                match result {
+                    Value::Time(ref t) => assert_eq!(t.fold, proto > 3),
+                    ref result => assert_eq!(result, *value),
+                }
+            }
+        }
+
+        assert_eq!(from_slice::<String>(&value_to_vec(&datetime, true).unwrap()).unwrap(),
+                   "2020-01-02T03:04:05.000678+01:00");
+        assert_eq!(from_slice::<f64>(&value_to_vec(&delta, true).unwrap()).unwrap(), -86397.7);
+        assert_eq!(datetime.to_string(), "2020-01-02 03:04:05.000678+01:00");
+        assert_eq!(delta.to_string(), "-1 day, 0:00:02.300000");
+
+        // A time zone by itself is a generic object.
+        let data = b"\x80\x03cdatetime\ntimezone\ncdatetime\ntimedelta\nK\x00M\x10\x0eK\x00\x87R\
+                     X\x03\x00\x00\x00CET\x86R.";
+        assert_eq!(value_from_slice(data).unwrap(), Value::Object(Box::new(Object {
+            class: ("datetime".into(), "timezone".into()),
+            args: vec![Value::TimeDelta(TimeDelta::new(0, 3600, 0).unwrap()), Value::String("CET".into())],
+            kwargs: BTreeMap::new(),
+            state: None,
+        })));
+        match value_from_slice(b"\x80\x03cdatetime\ndate\nC\x04\x07\xe4\x02\x1e\x85R.") {
+            Err(Error::Eval(ErrorCode::InvalidValue(_), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn datetimes_chrono() {
+        use chrono::{self, TimeZone as ChronoTimeZone};
+        let offset = chrono::FixedOffset::east(3600);
+        let dt = offset.ymd(2020, 1, 2).and_hms_micro(3, 4, 5, 678);
+        let converted = DateTime::from_chrono(&dt).unwrap();
+        assert_eq!(converted.isoformat(), "2020-01-02T03:04:05.000678+01:00");
+        assert_eq!(converted.to_chrono().unwrap(), dt);
+        assert_eq!(converted.to_naive().unwrap(), dt.naive_local());
+        let duration = chrono::Duration::microseconds(-86397_700_000);
+        let delta = TimeDelta::from_duration(&duration).unwrap();
+        assert_eq!(delta, TimeDelta::new(-1, 2, 300000).unwrap());
+        assert_eq!(delta.to_duration(), duration);
+    }
+
+    #[test]
+    fn collections() {
+        let ordered = Value::OrderedDict(vec![(hpyobj!(s="b"), pyobj!(i=1)),
+                                              (hpyobj!(s="a"), pyobj!(i=2))]);
+        let defaultdict = Value::DefaultDict(Box::new(DefaultDict {
+            factory: Some(("builtins".into(), "list".into())),
+            items: BTreeMap::from_iter(vec![(hpyobj!(s="x"), pyobj!(l=[i=1]))]),
+        }));
+        let deque = Value::Deque(Deque { items: vec![pyobj!(i=2), pyobj!(i=3)], maxlen: Some(2) });
+        let counter = Value::Counter(BTreeMap::from_iter(vec![
+            (hpyobj!(s="a"), pyobj!(i=2)), (hpyobj!(s="b"), pyobj!(i=1)),
+            (hpyobj!(s="c"), pyobj!(i=1))]));
+        // As written by Python 3 and 2, the deque as deque([1, 2, 3], 2).
+        let cases: &[(&[u8], &Value)] = &[
+            (b"\x80\x02ccollections\nOrderedDict\n)R(X\x01\x00\x00\x00bK\x01X\x01\x00\x00\x00a\
+               K\x02u.", &ordered),
+            (b"\x80\x02ccollections\ndefaultdict\nc__builtin__\nlist\n\x85RX\x01\x00\x00\x00x]\
+               K\x01as.", &defaultdict),
+            (b"\x80\x02ccollections\ndeque\n)K\x02\x86R(K\x02K\x03e.", &deque),
+            (b"\x80\x02ccollections\nCounter\n}(X\x01\x00\x00\x00aK\x02X\x01\x00\x00\x00bK\x01\
+               X\x01\x00\x00\x00cK\x01u\x85R.", &counter),
+            (b"\x80\x02ccollections\nOrderedDict\nq\x00]q\x01(]q\x02(U\x01bq\x03K\x01e]q\x04(U\x01a\
+               q\x05K\x02ee\x85q\x06Rq\x07.", &ordered),
+            (b"\x80\x02ccollections\ndefaultdict\nq\x00c__builtin__\nlist\nq\x01\x85q\x02Rq\x03U\x01x\
+               q\x04]q\x05K\x01as.", &defaultdict),
+            (b"\x80\x02ccollections\ndeque\nq\x00]q\x01(K\x01K\x02K\x03eK\x02\x86q\x02Rq\x03.", &deque),
+        ];
+        for &(data, value) in cases {
+            let options = DeOptions::new().decode_strings(true);
+            assert_eq!(value_from_slice_with_options(data, options).unwrap(), *value);
+        }
+        // Without memoization, the output is the same as Python's.
+        let strings = Value::OrderedDict(vec![(hpyobj!(s="b"), pyobj!(s="x")),
+                                              (hpyobj!(s="a"), pyobj!(s="y"))]);
+        let options = SerOptions::new().proto(2).memoize(false);
+        assert_eq!(value_to_vec_with_options(&strings, options).unwrap(),
+                   &b"\x80\x02ccollections\nOrderedDict\n)R(X\x01\x00\x00\x00bX\x01\x00\x00\x00x\
+                      X\x01\x00\x00\x00aX\x01\x00\x00\x00yu."[..]);
+        let options = SerOptions::new().proto(0).memoize(false);
+        assert_eq!(value_to_vec_with_options(&strings, options).unwrap(),
+                   &b"ccollections\nOrderedDict\n(tRVb\nVx\nsVa\nVy\ns."[..]);
+        let strings = Value::Deque(Deque { items: vec![pyobj!(s="q"), pyobj!(s="r")],
+                                           maxlen: Some(2) });
+        let options = SerOptions::new().proto(2).memoize(false);
+        assert_eq!(value_to_vec_with_options(&strings, options).unwrap(),
+                   &b"\x80\x02ccollections\ndeque\n)K\x02\x86R(X\x01\x00\x00\x00q\
+                      X\x01\x00\x00\x00re."[..]);
+        let value = Value::List(vec![ordered.clone(), defaultdict.clone(), deque.clone(),
+                                     counter.clone(),
+                                     Value::Deque(Deque { items: vec![], maxlen: None })]);
+        for proto in 0..6 {
+            let data = value_to_vec_with_options(&value, SerOptions::new().proto(proto)).unwrap();
+            assert_eq!(value_from_slice(&data).unwrap(), value);
+            assert_eq!(graph_from_slice(&data).unwrap().to_value().unwrap(), value);
+        }
+
+        // A repeated key keeps its first position, and a full deque drops
+        // its oldest items.
+        let data = b"\x80\x02ccollections\nOrderedDict\n)R(K\x01K\x01K\x02K\x02K\x01K\x03u\
+                     ccollections\ndeque\n)K\x01\x86R(K\x01K\x02e\x86.";
+        assert_eq!(value_from_slice(data).unwrap(),
+                   Value::Tuple(vec![Value::OrderedDict(vec![(hpyobj!(i=1), pyobj!(i=3)),
+                                                             (hpyobj!(i=2), pyobj!(i=2))]),
+                                     Value::Deque(Deque { items: vec![pyobj!(i=2)],
+                                                          maxlen: Some(1) })]));
+        // Dicts of all kinds are visited as maps, and deques as sequences.
+        let data = value_to_vec(&ordered, true).unwrap();
+        assert_eq!(from_slice::<BTreeMap<String, i32>>(&data).unwrap(),
+                   BTreeMap::from_iter(vec![("a".into(), 2), ("b".into(), 1)]));
+        assert_eq!(from_slice::<Vec<i32>>(&value_to_vec(&deque, true).unwrap()).unwrap(),
+                   vec![2, 3]);
+        // The default factory is a global that must be allowed in safe mode.
+        match value_from_slice_with_options(cases[1].0, DeOptions::new().safe(true)) {
+            Err(Error::Eval(ErrorCode::UnsupportedGlobal(..), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let options = DeOptions::new().allow_global("__builtin__", "list");
+        assert!(value_from_slice_with_options(cases[1].0, options).is_ok());
+    }
+
+    #[test]
+    fn disassemble() {
+        let data = b"\x80\x04\x95\x1a\x00\x00\x00\x00\x00\x00\x00]\x94(K\x01\x8c\x02ab\x94C\x01c\
+                     \x94\x86\x94}\x94\x8c\x01d\x94Nse.";
+        let instrs = disasm::disassemble(data).unwrap();
+        assert_eq!(instrs.len(), 20);
+        assert_eq!(instrs[5], disasm::Instruction { offset: 14, opcode: b'K', name: "BININT1",
+                                                    arg: Some(disasm::Arg::Int(1)) });
+        assert_eq!(instrs[8].arg, Some(disasm::Arg::Bytes(b"c".to_vec())));
+        // Output of pickletools.dis for the same pickle.
+        let expected = "    0: \\x80 PROTO      4
+    2: \\x95 FRAME      26
+   11: ]    EMPTY_LIST
+   12: \\x94 MEMOIZE    (as 0)
+   13: (    MARK
+   14: K        BININT1    1
+   16: \\x8c     SHORT_BINUNICODE 'ab'
+   20: \\x94     MEMOIZE    (as 1)
+   21: C        SHORT_BINBYTES b'c'
+   24: \\x94     MEMOIZE    (as 2)
+   25: \\x86     TUPLE2
+   26: \\x94     MEMOIZE    (as 3)
+   27: }        EMPTY_DICT
+   28: \\x94     MEMOIZE    (as 4)
+   29: \\x8c     SHORT_BINUNICODE 'd'
+   32: \\x94     MEMOIZE    (as 5)
+   33: N        NONE
+   34: s        SETITEM
+   35: e        APPENDS    (MARK at 13)
+   36: .    STOP
+highest protocol among opcodes = 4
+";
+        let mut out = Vec::new();
+        disasm::dis(&data[..], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        // Protocol 0, with a POP consuming the MARK.
+        let data = b"(lp0\nI01\na(I2\n00S'x\\'y'\np1\na.";
+        let expected = "    0: (    MARK
+    1: l        LIST       (MARK at 0)
+    2: p    PUT        0
+    5: I    INT        True
+    9: a    APPEND
+   10: (    MARK
+   11: I        INT        2
+   14: 0        POP
+   15: 0        POP        (MARK at 10)
+   16: S    STRING     \"x'y\"
+   24: p    PUT        1
+   27: a    APPEND
+   28: .    STOP
+highest protocol among opcodes = 0
+";
+        let mut out = Vec::new();
+        disasm::dis(&data[..], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        match disasm::dis(&b"I1\nt."[..], &mut Vec::new()) {
+            Err(Error::Eval(ErrorCode::StackUnderflow, 3)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stream() {
+        // Pickles appended by repeated pickle.dump calls each start a new memo.
+        let first = pyobj!(l=[s="a", s="a"]);
+        let second = pyobj!(t=(s="b", s="b", i=2));
+        let mut data = value_to_vec_with_options(&first, SerOptions::new().memoize(true))
+            .unwrap();
+        let split = data.len();
+        data.extend(value_to_vec_with_options(&second, SerOptions::new().proto(4).memoize(true))
+                    .unwrap());
+        let mut stream = StreamDeserializer::values(&data[..], DeOptions::new());
+        assert_eq!(stream.byte_offset(), 0);
+        assert_eq!(stream.next().unwrap().unwrap(), first);
+        assert_eq!(stream.byte_offset(), split);
+        assert_eq!(stream.next().unwrap().unwrap(), second);
+        assert_eq!(stream.byte_offset(), data.len());
+        assert!(stream.next().is_none());
+        // With serde types.
+        let data = [to_vec(&1, true).unwrap(), to_vec(&2, false).unwrap()].concat();
+        let stream = StreamDeserializer::<_, i32>::new(&data[..], DeOptions::new());
+        assert_eq!(stream.collect::<Result<Vec<_>, _>>().unwrap(), vec![1, 2]);
+        // Iteration stops after an error.
+        let data = [to_vec(&1, true).unwrap(), b"\xff".to_vec()].concat();
+        let mut stream = StreamDeserializer::<_, i32>::new(&data[..], DeOptions::new());
+        assert_eq!(stream.next().unwrap().unwrap(), 1);
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn lazy_visit() {
+        let rows: Vec<BTreeMap<String, i32>> = (0..10).map(|i| {
+            BTreeMap::from_iter(vec![("x".into(), i), ("y".into(), -i)])
+        }).collect();
+        for proto in 0..6 {
+            let options = SerOptions::new().proto(proto).memoize(true).batch_size(3);
+            let data = to_vec_with_options(&rows, options.clone()).unwrap();
+            let decoded: Vec<BTreeMap<String, i32>> = from_slice(&data).unwrap();
+            assert_eq!(decoded, rows);
+            let data = to_vec_with_options(&rows[0], options).unwrap();
+            let decoded: BTreeMap<String, i32> = from_slice(&data).unwrap();
+            assert_eq!(decoded, rows[0]);
+        }
+        // Items shared between batches.
+        let inner = pyobj!(l=[i=1, s="a"]);
+        let shared = Value::List(vec![inner.clone(); 5]);
+        let data = value_to_vec_with_options(&shared, SerOptions::new().memoize(true)
+                                             .batch_size(2)).unwrap();
+        let decoded: Vec<(i32, String)> = from_slice(&data).unwrap();
+        assert_eq!(decoded, vec![(1, "a".into()); 5]);
+        // Too many items for the target type.
+        let data = to_vec(&vec![1, 2, 3], true).unwrap();
+        assert!(from_slice::<(i32, i32)>(&data).is_err());
+        // The final value need not be the first container.
+        assert_eq!(from_slice::<i32>(b"]K\x01.").unwrap(), 1);
+        assert_eq!(from_slice::<i32>(b"](K\x01K\x02e0K\x03.").unwrap(), 3);
+        let decoded: (Vec<i32>, Vec<i32>) = from_slice(b"](K\x01K\x02e]K\x03a\x86.").unwrap();
+        assert_eq!(decoded, (vec![1, 2], vec![3]));
+        // pickle.dumps((["a"], 1), 2)
+        let data = b"\x80\x02]q\x00X\x01\x00\x00\x00aq\x01aK\x01\x86q\x02.";
+        let decoded: (Vec<String>, i64) = from_slice(data).unwrap();
+        assert_eq!(decoded, (vec!["a".into()], 1));
+        // Memoized items referenced from several batches.
+        let data = b"](]q\x00K\x01ah\x00h\x00e.";
+        let decoded: Vec<Vec<i32>> = from_slice(data).unwrap();
+        assert_eq!(decoded, vec![vec![1]; 3]);
+        let decoded: Vec<i32> = from_slice(b"](I1\r\np0\r\ng0\r\ne.").unwrap();
+        assert_eq!(decoded, vec![1, 1]);
+        // References to the list itself can't be resolved.
+        for proto in &[0, 1, 2, 3, 4] {
+            let path = format!("test/data/test_recursive_proto{}.pickle", proto);
+            let mut data = Vec::new();
+            File::open(path).unwrap().read_to_end(&mut data).unwrap();
+            match from_reader::<_, Vec<(Vec<Vec<()>>,)>>(&data[..]) {
+                Err(Error::Syntax(ErrorCode::Recursive)) => { }
+                _ => assert!(false, "wrong/no error returned for recursive structure")
+            }
+            match from_slice::<Vec<(Vec<Vec<()>>,)>>(&data) {
+                Err(Error::Syntax(ErrorCode::Recursive)) => { }
+                _ => assert!(false, "wrong/no error returned for recursive structure")
+            }
+        }
+    }
+
     #[test]
     fn fuzzing() {
         // Tries to ensure that we don't panic when encountering strange streams.
@@ -273,6 +1508,7 @@ mod value_tests {
             // These must all fail with an error, since we skip the check if the
             // last byte is a STOP opcode.
             assert!(value_from_slice(&stream).is_err());
+            let _ = disasm::dis(&stream[..], &mut Vec::new());
         }
     }
 
@@ -312,7 +1548,7 @@ mod benches {
     use std::collections::BTreeMap;
     use byteorder::{LittleEndian, WriteBytesExt};
     use self::test::Bencher;
-    use {Value, HashableValue, value_from_slice, value_to_vec};
+    use {Value, HashableValue, value_from_slice, value_to_vec, from_slice};
 
     #[bench]
     fn unpickle_list(b: &mut Bencher) {
@@ -334,6 +1570,53 @@ mod benches {
         b.iter(|| value_from_slice(&buffer).unwrap());
     }
 
+    // Creates [l, l, l, ...] where l = ["0", "1", ...], either referring to
+    // the memoized l, or with unmemoized copies of it.
+    fn shared_list(copies: bool) -> Vec<u8> {
+        let mut buffer = b"\x80\x02](".to_vec();
+        for j in 0..1000 {
+            if j > 0 && !copies {
+                buffer.extend(b"h\x01");
+                continue;
+            }
+            buffer.extend(if copies { &b"]("[..] } else { &b"]q\x01("[..] });
+            for i in 0..100 {
+                let s = i.to_string();
+                buffer.push(b'X');
+                buffer.write_u32::<LittleEndian>(s.len() as u32).unwrap();
+                buffer.extend(s.as_bytes());
+            }
+            buffer.extend(b"e");
+        }
+        buffer.extend(b"e.");
+        buffer
+    }
+
+    #[bench]
+    fn unpickle_shared_list(b: &mut Bencher) {
+        let buffer = shared_list(false);
+        b.iter(|| value_from_slice(&buffer).unwrap());
+    }
+
+    #[bench]
+    fn unpickle_shared_list_copies(b: &mut Bencher) {
+        // Same as above, but with copies instead of references
+        let buffer = shared_list(true);
+        b.iter(|| value_from_slice(&buffer).unwrap());
+    }
+
+    #[bench]
+    fn deserialize_shared_list(b: &mut Bencher) {
+        let buffer = shared_list(false);
+        b.iter(|| from_slice::<Vec<Vec<String>>>(&buffer).unwrap());
+    }
+
+    #[bench]
+    fn deserialize_shared_list_copies(b: &mut Bencher) {
+        let buffer = shared_list(true);
+        b.iter(|| from_slice::<Vec<Vec<String>>>(&buffer).unwrap());
+    }
+
     #[bench]
     fn unpickle_list_no_memo(b: &mut Bencher) {
         // Same as above, but doesn't use the memo